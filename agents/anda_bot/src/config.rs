@@ -28,6 +28,16 @@ pub struct Llm {
     pub openai_embedding_model: String,
     #[serde(default)]
     pub openai_completion_model: String,
+    #[serde(default)]
+    pub gemini_api_key: String,
+    #[serde(default)]
+    pub gemini_endpoint: String,
+    #[serde(default)]
+    pub gemini_embedding_model: String,
+    #[serde(default)]
+    pub gemini_embedding_ndims: u16,
+    #[serde(default)]
+    pub gemini_completion_model: String,
 }
 
 /// Configuration for the X should be encrypted and stored in the ICP COSE canister.
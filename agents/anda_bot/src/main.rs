@@ -11,7 +11,7 @@ use anda_engine::{
         segmenter::DocumentSegmenter,
     },
     management::SYSTEM_PATH,
-    model::{Model, cohere, deepseek, openai},
+    model::{Model, cohere, deepseek, gemini, openai},
     store::{LocalFileSystem, ObjectStore, Store},
 };
 use anda_icp::ledger::{BalanceOfTool, ICPLedgers};
@@ -561,7 +561,23 @@ async fn connect_knowledge_store(
 }
 
 fn connect_model(cfg: &config::Llm) -> Result<Model, BoxError> {
-    if cfg.openai_api_key.is_empty() {
+    if !cfg.gemini_api_key.is_empty() {
+        let cli = gemini::Client::new(
+            &cfg.gemini_api_key,
+            if cfg.gemini_endpoint.is_empty() {
+                None
+            } else {
+                Some(cfg.gemini_endpoint.clone())
+            },
+        );
+        Ok(Model::new(
+            Arc::new(cli.completion_model(&cfg.gemini_completion_model)),
+            Arc::new(cli.embedding_model(
+                &cfg.gemini_embedding_model,
+                cfg.gemini_embedding_ndims as usize,
+            )?),
+        ))
+    } else if cfg.openai_api_key.is_empty() {
         Ok(Model::new(
             Arc::new(
                 deepseek::Client::new(
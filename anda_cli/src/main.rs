@@ -1,4 +1,6 @@
-use anda_core::{AgentInput, AgentOutput, BoxError, HttpFeatures, ToolInput, ToolOutput};
+use anda_core::{
+    AgentInput, AgentOutput, ApprovalInput, BoxError, HttpFeatures, ToolInput, ToolOutput,
+};
 use anda_web3_client::client::{Client as Web3Client, load_identity};
 use base64::{Engine, prelude::BASE64_URL_SAFE_NO_PAD};
 use ciborium::value::Value;
@@ -79,6 +81,28 @@ pub enum Commands {
         #[arg(short, long)]
         args: String,
     },
+
+    /// Approve or reject the tool calls suspended for approval on the endpoint.
+    ApproveAction {
+        #[arg(short, long, default_value = "http://127.0.0.1:8042/default")]
+        endpoint: String,
+
+        /// Path to ICP identity pem file or 32 bytes identity secret in hex.
+        #[arg(short, long, env = "ID_SECRET", default_value = "Anonymous")]
+        id_secret: String,
+
+        /// The approval id returned by the agent
+        #[arg(long)]
+        id: String,
+
+        /// Reject the tool calls instead of approving them
+        #[arg(long)]
+        reject: bool,
+
+        /// The reason sent to the model
+        #[arg(short, long)]
+        reason: Option<String>,
+    },
 }
 
 #[tokio::main]
@@ -186,6 +210,37 @@ async fn main() -> Result<(), BoxError> {
             println!("{}", serde_json::to_string_pretty(&res)?);
         }
 
+        Some(Commands::ApproveAction {
+            endpoint,
+            id_secret,
+            id,
+            reject,
+            reason,
+        }) => {
+            let identity = load_identity(id_secret)?;
+            let web3 = Web3Client::builder()
+                .with_ic_host(&cli.ic_host)
+                .with_identity(Arc::new(identity))
+                .with_allow_http(true, None)
+                .build()
+                .await?;
+
+            println!("principal: {}", web3.get_principal());
+
+            let res: AgentOutput = web3
+                .https_signed_rpc(
+                    endpoint,
+                    "approve_action",
+                    &(&ApprovalInput {
+                        id: id.clone(),
+                        approved: !reject,
+                        reason: reason.clone(),
+                    },),
+                )
+                .await?;
+            println!("{:?}", res);
+        }
+
         None => {
             println!("no command");
         }
@@ -0,0 +1,349 @@
+//! Structured errors for Anda engines and their clients.
+//!
+//! Most APIs return [`BoxError`] for flexibility. [`AndaError`] can be boxed into it and
+//! recovered with [`AndaError::from`], so that the engine server can tell clients what
+//! went wrong with a stable [`ErrorCode`], an HTTP status and a retryable flag.
+//!
+//! # Example
+//! ```rust,ignore
+//! use anda_core::{AndaError, BoxError, ErrorCode};
+//!
+//! fn find_agent(name: &str) -> Result<(), BoxError> {
+//!     Err(AndaError::not_found(format!("agent {} not found", name)).into())
+//! }
+//!
+//! let err = AndaError::from(find_agent("foo").unwrap_err());
+//! assert_eq!(err.code, ErrorCode::NotFound);
+//! assert_eq!(err.status(), 404);
+//! ```
+
+use serde::{Deserialize, Serialize};
+
+use crate::{BoxError, HttpRPCError};
+
+/// Stable error codes, they are serialized in snake_case, e.g. "not_found".
+/// New codes may be added, unknown codes from newer engines are deserialized as
+/// [`ErrorCode::Internal`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+#[non_exhaustive]
+pub enum ErrorCode {
+    /// The request or the arguments are invalid.
+    InvalidArgs,
+    /// The caller is not authenticated.
+    Unauthenticated,
+    /// The caller does not have permission to do the operation.
+    PermissionDenied,
+    /// The agent, tool, thread or other resource is not found.
+    NotFound,
+    /// Too many requests, the caller should retry later.
+    RateLimited,
+    /// The quota of the caller is exhausted.
+    QuotaExceeded,
+    /// The operation was cancelled.
+    Cancelled,
+    /// The operation timed out.
+    Timeout,
+    /// The upstream service (model provider, remote engine) is temporarily unavailable.
+    Unavailable,
+    /// The upstream service (model provider, remote engine) returned an error.
+    Upstream,
+    /// Internal error, or an unknown code.
+    #[serde(other)]
+    Internal,
+}
+
+impl ErrorCode {
+    /// Returns the stable string of the code.
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            ErrorCode::InvalidArgs => "invalid_args",
+            ErrorCode::Unauthenticated => "unauthenticated",
+            ErrorCode::PermissionDenied => "permission_denied",
+            ErrorCode::NotFound => "not_found",
+            ErrorCode::RateLimited => "rate_limited",
+            ErrorCode::QuotaExceeded => "quota_exceeded",
+            ErrorCode::Cancelled => "cancelled",
+            ErrorCode::Timeout => "timeout",
+            ErrorCode::Unavailable => "unavailable",
+            ErrorCode::Upstream => "upstream",
+            ErrorCode::Internal => "internal",
+        }
+    }
+
+    /// Returns the HTTP status code of the error code.
+    pub fn status(&self) -> u16 {
+        match self {
+            ErrorCode::InvalidArgs => 400,
+            ErrorCode::Unauthenticated => 401,
+            ErrorCode::PermissionDenied => 403,
+            ErrorCode::NotFound => 404,
+            ErrorCode::RateLimited | ErrorCode::QuotaExceeded => 429,
+            ErrorCode::Cancelled => 499,
+            ErrorCode::Timeout => 504,
+            ErrorCode::Unavailable => 503,
+            ErrorCode::Upstream => 502,
+            ErrorCode::Internal => 500,
+        }
+    }
+
+    /// Returns true if the operation may succeed when retried later.
+    pub fn retryable(&self) -> bool {
+        matches!(
+            self,
+            ErrorCode::RateLimited | ErrorCode::Timeout | ErrorCode::Unavailable
+        )
+    }
+
+    /// Maps an HTTP status code from an upstream service to an error code.
+    /// The authentication failures of upstream services (401, 403) are mapped to
+    /// [`ErrorCode::Upstream`], they are not the caller's fault.
+    pub fn from_status(status: u16) -> Self {
+        match status {
+            400 | 413 | 422 => ErrorCode::InvalidArgs,
+            404 => ErrorCode::NotFound,
+            408 | 504 => ErrorCode::Timeout,
+            429 => ErrorCode::RateLimited,
+            499 => ErrorCode::Cancelled,
+            500 | 502 | 503 | 529 => ErrorCode::Unavailable,
+            _ => ErrorCode::Upstream,
+        }
+    }
+}
+
+impl std::str::FromStr for ErrorCode {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "invalid_args" => Ok(ErrorCode::InvalidArgs),
+            "unauthenticated" => Ok(ErrorCode::Unauthenticated),
+            "permission_denied" => Ok(ErrorCode::PermissionDenied),
+            "not_found" => Ok(ErrorCode::NotFound),
+            "rate_limited" => Ok(ErrorCode::RateLimited),
+            "quota_exceeded" => Ok(ErrorCode::QuotaExceeded),
+            "cancelled" => Ok(ErrorCode::Cancelled),
+            "timeout" => Ok(ErrorCode::Timeout),
+            "unavailable" => Ok(ErrorCode::Unavailable),
+            "upstream" => Ok(ErrorCode::Upstream),
+            "internal" => Ok(ErrorCode::Internal),
+            _ => Err(format!("unknown error code {:?}", s)),
+        }
+    }
+}
+
+impl std::fmt::Display for ErrorCode {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+/// A structured error with a stable code and a human readable message.
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize, Serialize, thiserror::Error)]
+#[serde(from = "AndaErrorRepr")]
+#[error("{code}: {message}")]
+pub struct AndaError {
+    /// The stable error code.
+    pub code: ErrorCode,
+    /// The error message.
+    pub message: String,
+}
+
+/// Accepts both the structured error and the plain string error of older engines,
+/// see [`AndaError::to_plain`].
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum AndaErrorRepr {
+    Structured { code: ErrorCode, message: String },
+    Plain(String),
+}
+
+impl From<AndaErrorRepr> for AndaError {
+    fn from(repr: AndaErrorRepr) -> Self {
+        match repr {
+            AndaErrorRepr::Structured { code, message } => AndaError { code, message },
+            AndaErrorRepr::Plain(message) => AndaError::from_plain(message),
+        }
+    }
+}
+
+impl AndaError {
+    /// Creates a new error with the given code and message.
+    pub fn new(code: ErrorCode, message: impl Into<String>) -> Self {
+        Self {
+            code,
+            message: message.into(),
+        }
+    }
+
+    pub fn invalid_args(message: impl Into<String>) -> Self {
+        Self::new(ErrorCode::InvalidArgs, message)
+    }
+
+    pub fn unauthenticated(message: impl Into<String>) -> Self {
+        Self::new(ErrorCode::Unauthenticated, message)
+    }
+
+    pub fn permission_denied(message: impl Into<String>) -> Self {
+        Self::new(ErrorCode::PermissionDenied, message)
+    }
+
+    pub fn not_found(message: impl Into<String>) -> Self {
+        Self::new(ErrorCode::NotFound, message)
+    }
+
+    pub fn rate_limited(message: impl Into<String>) -> Self {
+        Self::new(ErrorCode::RateLimited, message)
+    }
+
+    pub fn quota_exceeded(message: impl Into<String>) -> Self {
+        Self::new(ErrorCode::QuotaExceeded, message)
+    }
+
+    pub fn cancelled(message: impl Into<String>) -> Self {
+        Self::new(ErrorCode::Cancelled, message)
+    }
+
+    pub fn timeout(message: impl Into<String>) -> Self {
+        Self::new(ErrorCode::Timeout, message)
+    }
+
+    pub fn unavailable(message: impl Into<String>) -> Self {
+        Self::new(ErrorCode::Unavailable, message)
+    }
+
+    pub fn internal(message: impl Into<String>) -> Self {
+        Self::new(ErrorCode::Internal, message)
+    }
+
+    /// Creates an error from the HTTP status and body of an upstream service,
+    /// e.g. a model provider.
+    pub fn from_status(status: u16, message: impl Into<String>) -> Self {
+        Self::new(ErrorCode::from_status(status), message)
+    }
+
+    /// Returns the HTTP status code of the error.
+    pub fn status(&self) -> u16 {
+        self.code.status()
+    }
+
+    /// Returns the error as a plain string "{code}: {message}", it is the error format
+    /// of the RPC responses to older clients, see [`crate::RPC_VERSION_HEADER`].
+    pub fn to_plain(&self) -> String {
+        self.to_string()
+    }
+
+    /// Parses a plain string error. The code is recovered from the "{code}: " prefix,
+    /// other strings are mapped to [`ErrorCode::Internal`].
+    pub fn from_plain(message: String) -> Self {
+        if let Some((code, msg)) = message.split_once(": ") {
+            if let Ok(code) = code.parse::<ErrorCode>() {
+                return Self::new(code, msg);
+            }
+        }
+        Self::internal(message)
+    }
+
+    /// Returns true if the operation may succeed when retried later.
+    pub fn retryable(&self) -> bool {
+        self.code.retryable()
+    }
+}
+
+impl From<HttpRPCError> for AndaError {
+    fn from(err: HttpRPCError) -> Self {
+        match err {
+            HttpRPCError::RequestError { .. } => AndaError::unavailable(err.to_string()),
+            HttpRPCError::ResponseError { status, .. } => {
+                AndaError::from_status(status, err.to_string())
+            }
+            HttpRPCError::ResultError { .. } => {
+                AndaError::new(ErrorCode::Upstream, err.to_string())
+            }
+            // keeps the error code from the remote engine
+            HttpRPCError::RemoteError { error, .. } => error,
+        }
+    }
+}
+
+impl From<BoxError> for AndaError {
+    /// Recovers the structured error from a [`BoxError`].
+    /// Unknown errors are mapped to [`ErrorCode::Internal`].
+    fn from(err: BoxError) -> Self {
+        let err = match err.downcast::<AndaError>() {
+            Ok(err) => return *err,
+            Err(err) => err,
+        };
+        let err = match err.downcast::<HttpRPCError>() {
+            Ok(err) => return AndaError::from(*err),
+            Err(err) => err,
+        };
+        if let Some(e) = err.downcast_ref::<reqwest::Error>() {
+            if e.is_timeout() {
+                return AndaError::timeout(err.to_string());
+            }
+            if e.is_connect() {
+                return AndaError::unavailable(err.to_string());
+            }
+            if let Some(status) = e.status() {
+                return AndaError::from_status(status.as_u16(), err.to_string());
+            }
+        }
+
+        AndaError::internal(err.to_string())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ciborium::from_reader;
+    use ic_cose_types::to_cbor_bytes;
+
+    #[test]
+    fn test_anda_error() {
+        let err: BoxError = AndaError::not_found("agent foo not found").into();
+        assert_eq!(err.to_string(), "not_found: agent foo not found");
+        let err = AndaError::from(err);
+        assert_eq!(err.code, ErrorCode::NotFound);
+        assert_eq!(err.status(), 404);
+        assert!(!err.retryable());
+
+        let err = AndaError::from(BoxError::from("something went wrong"));
+        assert_eq!(err.code, ErrorCode::Internal);
+
+        let err = AndaError::from_status(429, "too many requests");
+        assert_eq!(err.code, ErrorCode::RateLimited);
+        assert!(err.retryable());
+
+        let json = serde_json::to_string(&err).unwrap();
+        assert_eq!(
+            json,
+            r#"{"code":"rate_limited","message":"too many requests"}"#
+        );
+        let err2: AndaError = serde_json::from_str(&json).unwrap();
+        assert_eq!(err, err2);
+
+        // plain string error from older engines
+        let res: Result<(), String> = Err("agent foo not found".to_string());
+        let data = to_cbor_bytes(&res);
+        let res: Result<(), AndaError> = from_reader(&data[..]).unwrap();
+        assert_eq!(res.unwrap_err(), AndaError::internal("agent foo not found"));
+
+        // the plain string error round-trips between older and newer engines and clients
+        let err = AndaError::not_found("agent foo: not found");
+        let res: Result<(), String> = Err(err.to_plain());
+        let data = to_cbor_bytes(&res);
+        let res: Result<(), String> = from_reader(&data[..]).unwrap();
+        assert_eq!(res.as_ref().unwrap_err(), "not_found: agent foo: not found");
+        let res: Result<(), AndaError> = from_reader(&data[..]).unwrap();
+        assert_eq!(res.unwrap_err(), err);
+
+        // unknown codes from newer engines
+        let err: AndaError =
+            serde_json::from_str(r#"{"code":"payment_required","message":"pay"}"#).unwrap();
+        assert_eq!(err, AndaError::internal("pay"));
+        let err = AndaError::from_plain("payment_required: pay".to_string());
+        assert_eq!(err, AndaError::internal("payment_required: pay"));
+    }
+}
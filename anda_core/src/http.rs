@@ -26,10 +26,21 @@ use serde::{Serialize, de::DeserializeOwned};
 use serde_bytes::ByteBuf;
 use std::fmt::Display;
 
+use crate::AndaError;
+
 pub static CONTENT_TYPE_CBOR: &str = "application/cbor";
 pub static CONTENT_TYPE_JSON: &str = "application/json";
 pub static CONTENT_TYPE_TEXT: &str = "text/plain";
 
+/// The header of the RPC protocol version of the client.
+/// Engines respond errors as structured [`AndaError`] with the HTTP status of their code
+/// to clients of [`RPC_VERSION`] or later, and as plain strings (see [`AndaError::to_plain`])
+/// with status 200 to older clients.
+pub static RPC_VERSION_HEADER: &str = "anda-rpc-version";
+
+/// The RPC protocol version of this client.
+pub const RPC_VERSION: u32 = 2;
+
 /// Represents an RPC request with method name and CBOR-encoded parameters.
 #[derive(Clone, Debug, Serialize)]
 pub struct RPCRequest<'a> {
@@ -62,8 +73,9 @@ pub struct CanisterRequest<'a> {
 
 /// Represents an RPC response that can be either:
 /// - Ok(ByteBuf): CBOR or Candid encoded successful response;
-/// - Err(String): Error message as a string.
-pub type RPCResponse = Result<ByteBuf, String>;
+/// - Err(AndaError): Structured error, a plain string error is also accepted,
+///   see [`RPC_VERSION_HEADER`].
+pub type RPCResponse = Result<ByteBuf, AndaError>;
 
 /// Possible errors when working with http_rpc.
 #[derive(Debug, thiserror::Error)]
@@ -89,6 +101,13 @@ pub enum HttpRPCError {
         path: String,
         error: String,
     },
+
+    #[error("http_rpc({endpoint:?}, {path:?}): remote error: {error}")]
+    RemoteError {
+        endpoint: String,
+        path: String,
+        error: AndaError,
+    },
 }
 
 /// Makes an HTTP RPC call with CBOR-encoded parameters and returns the decoded response.
@@ -193,6 +212,7 @@ pub async fn cbor_rpc(
     let ct: http::HeaderValue = CONTENT_TYPE_CBOR.parse().unwrap();
     headers.insert(header::CONTENT_TYPE, ct.clone());
     headers.insert(header::ACCEPT, ct);
+    headers.insert(RPC_VERSION_HEADER, RPC_VERSION.into());
     let res = client
         .post(endpoint)
         .headers(headers)
@@ -205,28 +225,36 @@ pub async fn cbor_rpc(
             error: format!("{e:?}"),
         })?;
     let status = res.status().as_u16();
+    let data = res.bytes().await.map_err(|e| HttpRPCError::ResultError {
+        endpoint: endpoint.to_string(),
+        path: path.to_string(),
+        error: format!("{e:?}"),
+    })?;
     if status != 200 {
+        // the engine server responds the error with the status of its code
+        if let Ok(Err(error)) = from_reader::<RPCResponse, _>(&data[..]) {
+            return Err(HttpRPCError::RemoteError {
+                endpoint: endpoint.to_string(),
+                path: path.to_string(),
+                error,
+            });
+        }
         return Err(HttpRPCError::ResponseError {
             endpoint: endpoint.to_string(),
             path: path.to_string(),
             status,
-            error: res.text().await.unwrap_or_default(),
+            error: String::from_utf8_lossy(&data).to_string(),
         });
     }
 
-    let data = res.bytes().await.map_err(|e| HttpRPCError::ResultError {
-        endpoint: endpoint.to_string(),
-        path: path.to_string(),
-        error: format!("{e:?}"),
-    })?;
     let res: RPCResponse = from_reader(&data[..]).map_err(|e| HttpRPCError::ResultError {
         endpoint: endpoint.to_string(),
         path: path.to_string(),
         error: format!("{e:?}"),
     })?;
-    res.map_err(|e| HttpRPCError::ResultError {
+    res.map_err(|error| HttpRPCError::RemoteError {
         endpoint: endpoint.to_string(),
         path: path.to_string(),
-        error: format!("{e:?}"),
+        error,
     })
 }
@@ -3,6 +3,18 @@ use schemars::{
     r#gen::SchemaSettings,
     schema::{RootSchema, Schema, SchemaObject, SingleOrVec},
 };
+use serde_json::{Map, Value};
+use std::{
+    collections::HashMap,
+    sync::{LazyLock, Mutex},
+};
+
+/// Max number of compiled `pattern` regexes cached by [`validate_json_schema`].
+const MAX_CACHED_PATTERNS: usize = 1024;
+
+/// The compiled `pattern` regexes, None for the invalid patterns.
+static PATTERNS: LazyLock<Mutex<HashMap<String, Option<fancy_regex::Regex>>>> =
+    LazyLock::new(|| Mutex::new(HashMap::new()));
 
 /// Generate JSON schema for a given type T.
 pub fn root_schema_for<T: JsonSchema>() -> RootSchema {
@@ -23,7 +35,7 @@ pub fn gen_schema_for<T: JsonSchema>() -> serde_json::Value {
 /// Function Calling has strict requirements for JsonSchema, use fix_json_schema to fix it.
 /// 1. Remove $schema field;
 /// 2. Remove $format field;
-/// 3. Object type Schema must set additionalProperties: false;
+/// 3. Object type Schema must set additionalProperties: false, the maps keep the schema of their values;
 /// 4. required field should include all properties fields, meaning all struct fields are required (no Option).
 pub fn fix_json_schema(schema: &mut RootSchema) {
     schema.meta_schema = None; // Remove the $schema field
@@ -34,7 +46,12 @@ fn fix_obj_schema(schema: &mut SchemaObject) {
     schema.format = None; // Remove the $format field
     if let Some(obj) = &mut schema.object {
         // https://platform.openai.com/docs/guides/structured-outputs#additionalproperties-false-must-always-be-set-in-objects
-        obj.additional_properties = Some(Box::new(Schema::Bool(false)));
+        if !matches!(
+            obj.additional_properties.as_deref(),
+            Some(Schema::Object(_))
+        ) {
+            obj.additional_properties = Some(Box::new(Schema::Bool(false)));
+        }
         if obj.required.len() != obj.properties.len() {
             obj.required = obj.properties.keys().cloned().collect();
         }
@@ -63,3 +80,589 @@ fn fix_obj_schema(schema: &mut SchemaObject) {
         }
     }
 }
+
+/// Returns the `json_schema` response format of a completion request for type T.
+pub fn json_schema_format_for<T: JsonSchema>() -> Value {
+    let name: String = T::schema_name()
+        .chars()
+        .map(|c| {
+            if c.is_ascii_alphanumeric() || c == '_' || c == '-' {
+                c
+            } else {
+                '_'
+            }
+        })
+        .take(64)
+        .collect();
+    serde_json::json!({
+        "type": "json_schema",
+        "json_schema": {
+            "name": name,
+            "schema": gen_schema_for::<T>(),
+            "strict": true,
+        },
+    })
+}
+
+/// Returns the instruction of a response format, for the providers that do not
+/// support the `json_schema` response format natively.
+pub fn response_format_instruction(format: &Value) -> String {
+    match format.get("json_schema").and_then(|v| v.get("schema")) {
+        Some(schema) => format!(
+            "Respond with a JSON object only, it must match the JSON schema:\n{}",
+            schema
+        ),
+        None => "Respond with a JSON object only.".to_string(),
+    }
+}
+
+/// Parses the JSON value in the text output of a model.
+/// The JSON may be wrapped in a markdown code block or surrounded by other text.
+pub fn parse_json_output(text: &str) -> Result<Value, serde_json::Error> {
+    let text = text.trim();
+    let err = match serde_json::from_str(text) {
+        Ok(val) => return Ok(val),
+        Err(err) => err,
+    };
+
+    if let Some(start) = text.find("```") {
+        let block = &text[start + 3..];
+        // skip the language tag, e.g. "json"
+        let block = block.split_once('\n').map(|(_, b)| b).unwrap_or(block);
+        if let Some(end) = block.find("```") {
+            if let Ok(val) = serde_json::from_str(block[..end].trim()) {
+                return Ok(val);
+            }
+        }
+    }
+
+    for (open, close) in [('{', '}'), ('[', ']')] {
+        if let (Some(start), Some(end)) = (text.find(open), text.rfind(close)) {
+            if start < end {
+                if let Ok(val) = serde_json::from_str(&text[start..=end]) {
+                    return Ok(val);
+                }
+            }
+        }
+    }
+    Err(err)
+}
+
+const MAX_SCHEMA_DEPTH: usize = 64;
+
+/// Validates a JSON value against a JSON schema.
+///
+/// It supports the subset of JSON Schema used by function calling: `type`, `enum`, `const`,
+/// `properties`, `required`, `additionalProperties`, `items`, `minItems`, `maxItems`,
+/// `minLength`, `maxLength`, `pattern`, `minimum`, `maximum`, `exclusiveMinimum`,
+/// `exclusiveMaximum`, `allOf`, `anyOf`, `oneOf` and local `$ref`.
+///
+/// When `strict` is false, missing nullable properties are allowed, the same as deserializing
+/// with serde. Unknown properties are allowed unless `additionalProperties` is false.
+/// Integers must be JSON integers, `1.0` is not an integer.
+///
+/// Returns the errors with the JSON path of the invalid values,
+/// e.g. `$.items[0].name: expected "string", got number`.
+pub fn validate_json_schema(
+    schema: &Value,
+    value: &Value,
+    strict: bool,
+) -> Result<(), Vec<String>> {
+    let validator = SchemaValidator {
+        root: schema,
+        strict,
+    };
+    let mut errors = Vec::new();
+    validator.validate(schema, value, "$", 0, &mut errors);
+    if errors.is_empty() {
+        Ok(())
+    } else {
+        Err(errors)
+    }
+}
+
+struct SchemaValidator<'a> {
+    root: &'a Value,
+    strict: bool,
+}
+
+impl<'a> SchemaValidator<'a> {
+    fn validate(
+        &self,
+        schema: &'a Value,
+        value: &Value,
+        path: &str,
+        depth: usize,
+        errors: &mut Vec<String>,
+    ) {
+        if depth > MAX_SCHEMA_DEPTH {
+            errors.push(format!("{}: schema is too deep", path));
+            return;
+        }
+
+        let schema = match schema {
+            Value::Object(schema) => schema,
+            Value::Bool(false) => {
+                errors.push(format!("{}: value is not allowed", path));
+                return;
+            }
+            _ => return,
+        };
+
+        if let Some(r) = schema.get("$ref").and_then(|r| r.as_str()) {
+            match self.resolve(r) {
+                Some(sub) => self.validate(sub, value, path, depth + 1, errors),
+                None => errors.push(format!("{}: unresolvable $ref {:?}", path, r)),
+            }
+            return;
+        }
+
+        if let Some(ty) = schema.get("type") {
+            if !type_matches(ty, value) {
+                errors.push(format!(
+                    "{}: expected {}, got {}",
+                    path,
+                    ty,
+                    type_name(value)
+                ));
+                return;
+            }
+        }
+
+        if let Some(values) = schema.get("enum").and_then(|v| v.as_array()) {
+            if !values.contains(value) {
+                errors.push(format!(
+                    "{}: expected one of {}, got {}",
+                    path,
+                    Value::from(values.clone()),
+                    value
+                ));
+            }
+        }
+
+        if let Some(expected) = schema.get("const") {
+            if expected != value {
+                errors.push(format!("{}: expected {}, got {}", path, expected, value));
+            }
+        }
+
+        if let Some(subs) = schema.get("allOf").and_then(|v| v.as_array()) {
+            for sub in subs {
+                self.validate(sub, value, path, depth + 1, errors);
+            }
+        }
+
+        if let Some(subs) = schema.get("anyOf").and_then(|v| v.as_array()) {
+            if let Err(errs) = self.validate_any(subs, value, path, depth) {
+                errors.extend(errs);
+            }
+        }
+
+        if let Some(subs) = schema.get("oneOf").and_then(|v| v.as_array()) {
+            match self.validate_any(subs, value, path, depth) {
+                Ok(matched) if matched > 1 => {
+                    errors.push(format!(
+                        "{}: matches {} schemas in oneOf, expected 1",
+                        path, matched
+                    ));
+                }
+                Ok(_) => {}
+                Err(errs) => errors.extend(errs),
+            }
+        }
+
+        match value {
+            Value::Object(obj) => self.validate_object(schema, obj, path, depth, errors),
+            Value::Array(arr) => {
+                match schema.get("items") {
+                    Some(Value::Array(items)) => {
+                        for (i, (item, v)) in items.iter().zip(arr.iter()).enumerate() {
+                            self.validate(item, v, &format!("{}[{}]", path, i), depth + 1, errors);
+                        }
+                    }
+                    Some(items) => {
+                        for (i, v) in arr.iter().enumerate() {
+                            self.validate(items, v, &format!("{}[{}]", path, i), depth + 1, errors);
+                        }
+                    }
+                    None => {}
+                }
+                if let Some(min) = schema.get("minItems").and_then(|v| v.as_u64()) {
+                    if (arr.len() as u64) < min {
+                        errors.push(format!(
+                            "{}: expected at least {} items, got {}",
+                            path,
+                            min,
+                            arr.len()
+                        ));
+                    }
+                }
+                if let Some(max) = schema.get("maxItems").and_then(|v| v.as_u64()) {
+                    if (arr.len() as u64) > max {
+                        errors.push(format!(
+                            "{}: expected at most {} items, got {}",
+                            path,
+                            max,
+                            arr.len()
+                        ));
+                    }
+                }
+            }
+            Value::String(s) => {
+                let len = s.chars().count() as u64;
+                if let Some(min) = schema.get("minLength").and_then(|v| v.as_u64()) {
+                    if len < min {
+                        errors.push(format!(
+                            "{}: expected at least {} characters, got {}",
+                            path, min, len
+                        ));
+                    }
+                }
+                if let Some(max) = schema.get("maxLength").and_then(|v| v.as_u64()) {
+                    if len > max {
+                        errors.push(format!(
+                            "{}: expected at most {} characters, got {}",
+                            path, max, len
+                        ));
+                    }
+                }
+                if let Some(pattern) = schema.get("pattern").and_then(|v| v.as_str()) {
+                    // ignores the invalid patterns
+                    if let Some(re) = compiled_pattern(pattern) {
+                        if !re.is_match(s).unwrap_or(true) {
+                            errors
+                                .push(format!("{}: expected to match pattern {:?}", path, pattern));
+                        }
+                    }
+                }
+            }
+            Value::Number(n) => {
+                let n = n.as_f64().unwrap_or_default();
+                if let Some(min) = schema.get("minimum").and_then(|v| v.as_f64()) {
+                    if n < min {
+                        errors.push(format!("{}: expected >= {}, got {}", path, min, n));
+                    }
+                }
+                if let Some(max) = schema.get("maximum").and_then(|v| v.as_f64()) {
+                    if n > max {
+                        errors.push(format!("{}: expected <= {}, got {}", path, max, n));
+                    }
+                }
+                if let Some(min) = schema.get("exclusiveMinimum").and_then(|v| v.as_f64()) {
+                    if n <= min {
+                        errors.push(format!("{}: expected > {}, got {}", path, min, n));
+                    }
+                }
+                if let Some(max) = schema.get("exclusiveMaximum").and_then(|v| v.as_f64()) {
+                    if n >= max {
+                        errors.push(format!("{}: expected < {}, got {}", path, max, n));
+                    }
+                }
+            }
+            _ => {}
+        }
+    }
+
+    fn validate_object(
+        &self,
+        schema: &'a Map<String, Value>,
+        obj: &Map<String, Value>,
+        path: &str,
+        depth: usize,
+        errors: &mut Vec<String>,
+    ) {
+        let properties = schema.get("properties").and_then(|v| v.as_object());
+        if let Some(required) = schema.get("required").and_then(|v| v.as_array()) {
+            for name in required.iter().filter_map(|v| v.as_str()) {
+                if obj.contains_key(name) {
+                    continue;
+                }
+                let nullable = properties
+                    .and_then(|props| props.get(name))
+                    .map(|sub| self.is_nullable(sub, depth))
+                    .unwrap_or(false);
+                if self.strict || !nullable {
+                    errors.push(format!("{}: missing required property {:?}", path, name));
+                }
+            }
+        }
+
+        for (name, v) in obj {
+            let sub_path = format!("{}.{}", path, name);
+            match properties.and_then(|props| props.get(name)) {
+                Some(sub) => self.validate(sub, v, &sub_path, depth + 1, errors),
+                None => match schema.get("additionalProperties") {
+                    Some(Value::Bool(false)) => {
+                        errors.push(format!("{}: unknown property {:?}", path, name));
+                    }
+                    Some(sub @ Value::Object(_)) => {
+                        self.validate(sub, v, &sub_path, depth + 1, errors)
+                    }
+                    _ => {}
+                },
+            }
+        }
+    }
+
+    /// Validates the value against the schemas, returns the number of matched schemas,
+    /// or the errors of the closest schema if none matched.
+    /// The schemas with a matched type are closer than the others.
+    fn validate_any(
+        &self,
+        subs: &'a [Value],
+        value: &Value,
+        path: &str,
+        depth: usize,
+    ) -> Result<usize, Vec<String>> {
+        let mut matched = 0;
+        let mut closest: Option<((bool, usize), Vec<String>)> = None;
+        for sub in subs {
+            let mut errs = Vec::new();
+            self.validate(sub, value, path, depth + 1, &mut errs);
+            if errs.is_empty() {
+                matched += 1;
+                continue;
+            }
+
+            let type_mismatch = sub
+                .get("type")
+                .map(|ty| !type_matches(ty, value))
+                .unwrap_or(false);
+            let score = (type_mismatch, errs.len());
+            if closest.as_ref().is_none_or(|(s, _)| score < *s) {
+                closest = Some((score, errs));
+            }
+        }
+
+        match closest {
+            Some((_, errs)) if matched == 0 => Err(errs),
+            _ => Ok(matched),
+        }
+    }
+
+    fn is_nullable(&self, schema: &'a Value, depth: usize) -> bool {
+        let mut errs = Vec::new();
+        self.validate(schema, &Value::Null, "$", depth + 1, &mut errs);
+        errs.is_empty()
+    }
+
+    fn resolve(&self, r: &str) -> Option<&'a Value> {
+        let pointer = r.strip_prefix('#')?;
+        if pointer.is_empty() {
+            Some(self.root)
+        } else {
+            self.root.pointer(pointer)
+        }
+    }
+}
+
+/// Returns the compiled regex of the pattern from the cache, None if it is invalid.
+/// The patterns are not cached once the cache is full, the cached ones are kept.
+fn compiled_pattern(pattern: &str) -> Option<fancy_regex::Regex> {
+    let mut patterns = PATTERNS.lock().expect("patterns lock poisoned");
+    if let Some(re) = patterns.get(pattern) {
+        return re.clone();
+    }
+    let re = fancy_regex::Regex::new(pattern).ok();
+    if patterns.len() < MAX_CACHED_PATTERNS {
+        patterns.insert(pattern.to_string(), re.clone());
+    }
+    re
+}
+
+fn type_matches(ty: &Value, value: &Value) -> bool {
+    match ty {
+        Value::String(ty) => type_name_matches(ty, value),
+        Value::Array(types) => types
+            .iter()
+            .filter_map(|t| t.as_str())
+            .any(|t| type_name_matches(t, value)),
+        _ => true,
+    }
+}
+
+fn type_name_matches(ty: &str, value: &Value) -> bool {
+    match ty {
+        "null" => value.is_null(),
+        "boolean" => value.is_boolean(),
+        "object" => value.is_object(),
+        "array" => value.is_array(),
+        "string" => value.is_string(),
+        "number" => value.is_number(),
+        "integer" => value.is_i64() || value.is_u64(),
+        _ => true,
+    }
+}
+
+fn type_name(value: &Value) -> &'static str {
+    match value {
+        Value::Null => "null",
+        Value::Bool(_) => "boolean",
+        Value::Number(_) => "number",
+        Value::String(_) => "string",
+        Value::Array(_) => "array",
+        Value::Object(_) => "object",
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde::Deserialize;
+    use serde_json::json;
+
+    #[derive(Debug, Deserialize, JsonSchema)]
+    #[allow(dead_code)]
+    struct TestArgs {
+        /// The name.
+        name: String,
+        /// The amount.
+        amount: u64,
+        /// The optional memo.
+        memo: Option<String>,
+        /// The items.
+        items: Vec<TestItem>,
+    }
+
+    #[derive(Debug, Deserialize, JsonSchema)]
+    #[allow(dead_code)]
+    struct TestItem {
+        id: u32,
+    }
+
+    #[test]
+    fn test_validate_json_schema() {
+        let schema = gen_schema_for::<TestArgs>();
+        let args = json!({"name": "a", "amount": 1, "memo": null, "items": [{"id": 1}]});
+        assert!(validate_json_schema(&schema, &args, true).is_ok());
+
+        // missing nullable property is allowed only when not strict
+        let args = json!({"name": "a", "amount": 1, "items": []});
+        assert!(validate_json_schema(&schema, &args, false).is_ok());
+        let errs = validate_json_schema(&schema, &args, true).unwrap_err();
+        assert_eq!(
+            errs,
+            vec![r#"$: missing required property "memo""#.to_string()]
+        );
+
+        let args =
+            json!({"name": "a", "amount": -1, "memo": 1, "items": [{"id": "x"}], "extra": 1});
+        let errs = validate_json_schema(&schema, &args, true).unwrap_err();
+        assert!(
+            errs.iter()
+                .any(|e| e.starts_with("$.amount: expected >= 0")),
+            "{:?}",
+            errs
+        );
+        assert!(
+            errs.iter().any(|e| e.starts_with("$.memo: expected")),
+            "{:?}",
+            errs
+        );
+        assert!(
+            errs.iter()
+                .any(|e| e == r#"$.items[0].id: expected "integer", got string"#),
+            "{:?}",
+            errs
+        );
+        assert!(
+            errs.iter().any(|e| e == r#"$: unknown property "extra""#),
+            "{:?}",
+            errs
+        );
+
+        // unknown properties are not allowed by `additionalProperties: false` even when not strict
+        let args = json!({"name": "a", "amount": 1, "items": [], "extra": 1});
+        let errs = validate_json_schema(&schema, &args, false).unwrap_err();
+        assert_eq!(errs, vec![r#"$: unknown property "extra""#.to_string()]);
+
+        // a float is not an integer
+        let args = json!({"name": "a", "amount": 1.0, "items": []});
+        let errs = validate_json_schema(&schema, &args, false).unwrap_err();
+        assert!(
+            errs.iter()
+                .any(|e| e == r#"$.amount: expected "integer", got number"#),
+            "{:?}",
+            errs
+        );
+
+        let errs = validate_json_schema(&schema, &json!("a"), false).unwrap_err();
+        assert_eq!(
+            errs,
+            vec![r#"$: expected "object", got string"#.to_string()]
+        );
+
+        let schema = json!({
+            "definitions": {"id": {"type": "string", "minLength": 2}},
+            "type": "object",
+            "properties": {
+                "id": {"$ref": "#/definitions/id"},
+                "kind": {"enum": ["a", "b"]},
+                "value": {"anyOf": [{"type": "integer"}, {"type": "string", "maxLength": 1}]}
+            }
+        });
+        assert!(
+            validate_json_schema(&schema, &json!({"id": "ab", "kind": "a", "value": 1}), true)
+                .is_ok()
+        );
+        let errs = validate_json_schema(
+            &schema,
+            &json!({"id": "a", "kind": "c", "value": "xy"}),
+            true,
+        )
+        .unwrap_err();
+        assert_eq!(errs.len(), 3, "{:?}", errs);
+        assert!(errs[0].starts_with("$.id: expected at least 2 characters"));
+        assert!(errs[1].starts_with("$.kind: expected one of"));
+        assert!(errs[2].starts_with("$.value: expected at most 1 characters"));
+
+        // the patterns are compiled once, the invalid ones are ignored
+        let schema = json!({"type": "string", "pattern": "^(?!x)[a-z]+$"});
+        for _ in 0..2 {
+            assert!(validate_json_schema(&schema, &json!("abc"), true).is_ok());
+            let errs = validate_json_schema(&schema, &json!("xyz"), true).unwrap_err();
+            assert!(errs[0].starts_with("$: expected to match pattern"));
+        }
+        assert!(PATTERNS.lock().unwrap().contains_key("^(?!x)[a-z]+$"));
+        let schema = json!({"type": "string", "pattern": "(["});
+        assert!(validate_json_schema(&schema, &json!("abc"), true).is_ok());
+        assert!(PATTERNS.lock().unwrap().get("([").unwrap().is_none());
+
+        // the cached patterns are kept when the cache is full
+        for i in 0..MAX_CACHED_PATTERNS {
+            compiled_pattern(&format!("^{}$", i));
+        }
+        assert_eq!(PATTERNS.lock().unwrap().len(), MAX_CACHED_PATTERNS);
+        let schema = json!({"type": "string", "pattern": "^[0-9]+x$"});
+        assert!(validate_json_schema(&schema, &json!("12x"), true).is_ok());
+        assert!(validate_json_schema(&schema, &json!("12"), true).is_err());
+        assert!(!PATTERNS.lock().unwrap().contains_key("^[0-9]+x$"));
+        assert!(PATTERNS.lock().unwrap().contains_key("^(?!x)[a-z]+$"));
+    }
+
+    #[test]
+    fn test_parse_json_output() {
+        let val = json!({"name": "Anda", "age": 1});
+        assert_eq!(
+            parse_json_output(r#" {"name": "Anda", "age": 1} "#).unwrap(),
+            val
+        );
+        assert_eq!(
+            parse_json_output("```json\n{\"name\": \"Anda\", \"age\": 1}\n```").unwrap(),
+            val
+        );
+        assert_eq!(
+            parse_json_output("Here it is: {\"name\": \"Anda\", \"age\": 1}. Done").unwrap(),
+            val
+        );
+        assert!(parse_json_output("no json").is_err());
+
+        let format = json_schema_format_for::<TestItem>();
+        assert_eq!(format["json_schema"]["name"], "TestItem");
+        assert_eq!(
+            format["json_schema"]["schema"],
+            gen_schema_for::<TestItem>()
+        );
+        assert!(response_format_instruction(&format).contains("JSON schema"));
+    }
+}
@@ -3,6 +3,7 @@ use std::{future::Future, pin::Pin};
 
 pub mod agent;
 pub mod context;
+pub mod error;
 pub mod http;
 pub mod json;
 pub mod model;
@@ -10,6 +11,7 @@ pub mod tool;
 
 pub use agent::*;
 pub use context::*;
+pub use error::*;
 pub use http::*;
 pub use json::*;
 pub use model::*;
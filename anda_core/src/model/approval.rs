@@ -0,0 +1,93 @@
+use candid::Principal;
+use serde::{Deserialize, Serialize};
+
+use super::{ThreadId, ToolCall};
+
+/// The prefix of `failed_reason` in [`AgentOutput`](super::AgentOutput) when the execution
+/// is suspended for approval of sensitive tool calls.
+pub const APPROVAL_REQUIRED: &str = "approval required";
+
+/// The status of a [`PendingAction`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ApprovalStatus {
+    Pending,
+    Approved,
+    Rejected,
+}
+
+/// Represents tool calls suspended by the engine until an authorized principal
+/// approves or rejects them.
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct PendingAction {
+    /// The unique identifier for the action, it is the approval id returned to the caller.
+    pub id: String,
+
+    /// The agent whose execution is suspended.
+    pub agent: String,
+
+    /// The caller of the suspended execution.
+    pub caller: Principal,
+
+    /// The thread of the suspended execution.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub thread: Option<ThreadId>,
+
+    /// The tool calls that require approval.
+    pub tool_calls: Vec<ToolCall>,
+
+    /// The status of the action.
+    pub status: ApprovalStatus,
+
+    /// The principal who approved or rejected the action.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub reviewer: Option<Principal>,
+
+    /// The reason of the approval or rejection, it is sent to the model.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub reason: Option<String>,
+
+    /// The timestamp when the action was created.
+    pub created_at: u64,
+
+    /// The timestamp when the action was last updated.
+    pub updated_at: u64,
+}
+
+impl PendingAction {
+    /// Creates a new pending action with a unique id.
+    pub fn new(
+        agent: String,
+        caller: Principal,
+        thread: Option<ThreadId>,
+        tool_calls: Vec<ToolCall>,
+        now_ms: u64,
+    ) -> Self {
+        Self {
+            id: xid::new().to_string(),
+            agent,
+            caller,
+            thread,
+            tool_calls,
+            status: ApprovalStatus::Pending,
+            reviewer: None,
+            reason: None,
+            created_at: now_ms,
+            updated_at: now_ms,
+        }
+    }
+}
+
+/// Represents a request to approve or reject a [`PendingAction`].
+#[derive(Debug, Clone, Default, Deserialize, Serialize)]
+pub struct ApprovalInput {
+    /// The approval id returned to the caller.
+    pub id: String,
+
+    /// Whether to approve the action.
+    pub approved: bool,
+
+    /// The optional reason, it is sent to the model.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub reason: Option<String>,
+}
@@ -1,8 +1,16 @@
-use serde::{Deserialize, Serialize};
+use base64::{Engine, prelude::BASE64_STANDARD};
+use futures::stream::{self, BoxStream, StreamExt};
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize, de::DeserializeOwned};
 use std::{collections::BTreeMap, convert::Infallible, str::FromStr};
 
-use super::{AgentOutput, FunctionDefinition, Knowledge, Resource, Value};
-use crate::BoxError;
+use super::{AgentOutput, FunctionDefinition, Knowledge, Resource, Usage, Value, evaluate_tokens};
+use crate::{
+    AndaError, BoxError, ErrorCode, json_schema_format_for, parse_json_output, validate_json_schema,
+};
+
+/// The max number of corrective retries of [`CompletionFeatures::completion_json`].
+pub const MAX_JSON_RETRIES: usize = 2;
 
 /// Provides LLM completion capabilities for agents.
 pub trait CompletionFeatures: Sized {
@@ -12,6 +20,185 @@ pub trait CompletionFeatures: Sized {
         req: CompletionRequest,
         resources: Option<Vec<Resource>>,
     ) -> impl Future<Output = Result<AgentOutput, BoxError>> + Send;
+
+    /// Generates a streaming completion based on the given request and optional resources.
+    ///
+    /// The stream yields text deltas and tool call fragments as they are produced,
+    /// followed by the final [`CompletionChunk::Usage`] and [`CompletionChunk::Done`] chunks.
+    /// The default implementation streams the output of [`CompletionFeatures::completion`]
+    /// at once.
+    fn completion_stream(
+        &self,
+        req: CompletionRequest,
+        resources: Option<Vec<Resource>>,
+    ) -> impl Future<Output = Result<CompletionStream, BoxError>> + Send {
+        let fut = self.completion(req, resources);
+        async move {
+            let output = fut.await?;
+            Ok(completion_stream_from(output))
+        }
+    }
+
+    /// Returns the number of tokens in the text by the tokenizer of the model.
+    /// The default implementation uses [`evaluate_tokens`].
+    fn count_tokens(&self, text: &str) -> usize {
+        evaluate_tokens(text)
+    }
+
+    /// Generates a structured output of type T.
+    ///
+    /// The request is sent with the `json_schema` response format of T, providers that
+    /// do not support it natively add the schema to the prompt.
+    /// The output is parsed and validated against the schema, an invalid output is sent
+    /// back to the model with the errors for correction, up to [`MAX_JSON_RETRIES`] times.
+    ///
+    /// Returns the value and the last output, the usage of the output is accumulated
+    /// from all attempts.
+    fn completion_json<T>(
+        &self,
+        mut req: CompletionRequest,
+        resources: Option<Vec<Resource>>,
+    ) -> impl Future<Output = Result<(T, AgentOutput), BoxError>> + Send
+    where
+        T: JsonSchema + DeserializeOwned + Send,
+        Self: Sync,
+    {
+        async move {
+            let format = json_schema_format_for::<T>();
+            let schema = format["json_schema"]["schema"].clone();
+            req.response_format = Some(format);
+
+            let mut usage = Usage::default();
+            let mut retries = 0;
+            loop {
+                let mut output = self.completion(req.clone(), resources.clone()).await?;
+                usage.accumulate(&output.usage);
+                if let Some(reason) = &output.failed_reason {
+                    return Err(AndaError::new(
+                        ErrorCode::Upstream,
+                        format!("structured output failed: {}", reason),
+                    )
+                    .into());
+                }
+
+                let errors = match parse_json_output(&output.content) {
+                    Ok(val) => match validate_json_schema(&schema, &val, false) {
+                        Ok(()) => match serde_json::from_value::<T>(val) {
+                            Ok(val) => {
+                                output.usage = usage;
+                                return Ok((val, output));
+                            }
+                            Err(err) => vec![err.to_string()],
+                        },
+                        Err(errors) => errors,
+                    },
+                    Err(err) => vec![format!("invalid JSON: {}", err)],
+                };
+                if retries >= MAX_JSON_RETRIES {
+                    return Err(AndaError::new(
+                        ErrorCode::Upstream,
+                        format!(
+                            "invalid structured output after {} attempts: {}",
+                            retries + 1,
+                            errors.join("; ")
+                        ),
+                    )
+                    .into());
+                }
+                retries += 1;
+
+                // continues the conversation with the errors
+                match output.full_history.take() {
+                    Some(history) => {
+                        req.system = None;
+                        req.chat_history = history;
+                    }
+                    None => {
+                        if let Some(prompt) = req.prompt_with_context() {
+                            req.chat_history.push(serde_json::json!(Message {
+                                role: "user".into(),
+                                content: prompt.into(),
+                                ..Default::default()
+                            }));
+                        }
+                        req.chat_history.push(serde_json::json!(Message {
+                            role: "assistant".into(),
+                            content: output.content.into(),
+                            ..Default::default()
+                        }));
+                    }
+                }
+                req.documents.clear();
+                req.content_parts.clear();
+                req.prompt = format!(
+                    "The response is invalid: {}\nRespond again with a JSON object that matches the JSON schema only.",
+                    errors.join("; ")
+                );
+            }
+        }
+    }
+}
+
+/// A stream of completion chunks, see [`CompletionFeatures::completion_stream`].
+pub type CompletionStream = BoxStream<'static, Result<CompletionChunk, BoxError>>;
+
+/// A chunk emitted by a streaming completion.
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(tag = "type", content = "data", rename_all = "snake_case")]
+pub enum CompletionChunk {
+    /// A text delta of the assistant message.
+    Text(String),
+
+    /// A fragment of a tool call requested by the model.
+    ToolCall(ToolCallDelta),
+
+    /// The tokens usage of the completion.
+    Usage(Usage),
+
+    /// The final assembled output, it is always the last chunk of a successful stream.
+    Done(AgentOutput),
+}
+
+/// A fragment of a tool call in a streaming completion.
+/// Fragments with the same `index` belong to the same tool call,
+/// their `args` should be concatenated in order.
+#[derive(Debug, Clone, Default, Deserialize, Serialize)]
+pub struct ToolCallDelta {
+    /// The index of the tool call in the current completion round.
+    pub index: usize,
+
+    /// The tool call ID, usually present in the first fragment only.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub id: Option<String>,
+
+    /// The tool name, usually present in the first fragment only.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub name: Option<String>,
+
+    /// A fragment of the tool arguments in JSON string.
+    pub args: String,
+}
+
+/// Converts a finished [`AgentOutput`] into a [`CompletionStream`].
+/// It is useful for completion providers that do not support streaming.
+pub fn completion_stream_from(output: AgentOutput) -> CompletionStream {
+    let mut chunks: Vec<Result<CompletionChunk, BoxError>> = Vec::new();
+    if !output.content.is_empty() {
+        chunks.push(Ok(CompletionChunk::Text(output.content.clone())));
+    }
+    if let Some(tool_calls) = &output.tool_calls {
+        for (index, tool) in tool_calls.iter().enumerate() {
+            chunks.push(Ok(CompletionChunk::ToolCall(ToolCallDelta {
+                index,
+                id: Some(tool.id.clone()),
+                name: Some(tool.name.clone()),
+                args: tool.args.clone(),
+            })));
+        }
+    }
+    chunks.push(Ok(CompletionChunk::Usage(output.usage.clone())));
+    chunks.push(Ok(CompletionChunk::Done(output)));
+    stream::iter(chunks).boxed()
 }
 
 /// Represents a general completion request that can be sent to a completion model provider.
@@ -61,6 +248,59 @@ pub struct CompletionRequest {
 
     /// The stop sequence to be sent to the completion model provider.
     pub stop: Option<Vec<String>>,
+
+    /// How to handle the reasoning content returned by reasoning models.
+    pub reasoning: ReasoningMode,
+}
+
+/// Controls how the reasoning content of reasoning models is handled.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ReasoningMode {
+    /// The reasoning content is dropped.
+    #[default]
+    Omit,
+
+    /// The reasoning content is returned in [`AgentOutput::reasoning`].
+    Return,
+
+    /// The reasoning content is returned in [`AgentOutput::reasoning`] and also kept
+    /// as `reasoning_content` in the assistant message of `full_history`.
+    /// Providers never send it back to the model.
+    Keep,
+}
+
+impl AgentOutput {
+    /// Applies the reasoning mode to the output.
+    /// The reasoning content is removed from [`AgentOutput::reasoning`] and the last
+    /// assistant message of `full_history` if not requested.
+    pub fn with_reasoning_mode(mut self, mode: ReasoningMode) -> Self {
+        if mode == ReasoningMode::Omit {
+            self.reasoning = None;
+        }
+        if mode != ReasoningMode::Keep {
+            if let Some(Value::Object(msg)) = self.full_history.as_mut().and_then(|h| h.last_mut())
+            {
+                if msg.get("role").and_then(|r| r.as_str()) == Some("assistant") {
+                    msg.remove("reasoning_content");
+                }
+            }
+        }
+        self
+    }
+
+    /// Removes the reasoning content from the output and its `full_history`.
+    /// It should be called before the output leaves a trusted boundary.
+    pub fn redact_reasoning(&mut self) {
+        self.reasoning = None;
+        if let Some(history) = &mut self.full_history {
+            for msg in history.iter_mut() {
+                if let Value::Object(msg) = msg {
+                    msg.remove("reasoning_content");
+                }
+            }
+        }
+    }
 }
 
 impl CompletionRequest {
@@ -86,6 +326,33 @@ impl CompletionRequest {
         self
     }
 
+    /// Adds the image and audio resources to the request as content parts,
+    /// the converted resources are removed from `resources`.
+    /// The prompt with context becomes the leading text part, so it should be called
+    /// after the prompt and the documents are set. The context manager of the model
+    /// rebuilds the text part when it drops documents.
+    pub fn append_resources(mut self, resources: &mut Vec<Resource>) -> Self {
+        let mut parts = Vec::new();
+        resources.retain(|r| match ContentPart::from_resource(r) {
+            Some(part) => {
+                parts.push(part);
+                false
+            }
+            None => true,
+        });
+
+        if parts.is_empty() {
+            return self;
+        }
+        if self.content_parts.is_empty() {
+            if let Some(prompt) = self.prompt_with_context() {
+                self.content_parts.push(prompt.into());
+            }
+        }
+        self.content_parts.extend(parts);
+        self
+    }
+
     /// Returns the prompt with context if available.
     pub fn prompt_with_context(&self) -> Option<String> {
         if self.documents.0.is_empty() && self.prompt.is_empty() {
@@ -118,6 +385,11 @@ pub struct Message {
     /// Tool call that this message is responding to. If this message is a response to a tool call, this field should be set to the tool call ID.
     #[serde(skip_serializing_if = "Option::is_none")]
     pub tool_call_id: Option<String>,
+
+    /// The tool calls of an assistant message, in the OpenAI style,
+    /// e.g. `{"id": "call_1", "type": "function", "function": {"name": "...", "arguments": "..."}}`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub tool_calls: Option<Vec<Value>>,
 }
 
 /// Knowledge document with text and additional props.
@@ -278,9 +550,95 @@ impl FromStr for ContentPart {
     }
 }
 
+impl ContentPart {
+    /// Converts an image or audio [`Resource`] into a content part.
+    /// The kind of the resource is determined by its `mime_type`, or by its `tag`
+    /// ("image" or "audio") if the MIME type is not provided.
+    ///
+    /// Images can be provided by `blob` or `uri`, audios must be provided by `blob`.
+    /// Returns `None` for other resources.
+    pub fn from_resource(resource: &Resource) -> Option<Self> {
+        let mime_type = resource.mime_type.as_deref().unwrap_or_default();
+        let kind = match mime_type.split_once('/') {
+            Some((kind, _)) => kind,
+            None => resource.tag.as_str(),
+        };
+
+        match kind {
+            "image" => {
+                let url = match (&resource.blob, &resource.uri) {
+                    (Some(blob), _) => {
+                        let mime_type = if mime_type.is_empty() {
+                            image_mime_type(blob)?
+                        } else {
+                            mime_type
+                        };
+                        format!(
+                            "data:{};base64,{}",
+                            mime_type,
+                            BASE64_STANDARD.encode(blob.as_slice())
+                        )
+                    }
+                    (None, Some(uri)) => uri.clone(),
+                    (None, None) => return None,
+                };
+                Some(ContentPart::Image {
+                    image_url: ImageDetail { url, detail: None },
+                })
+            }
+            "audio" => {
+                let blob = resource.blob.as_ref()?;
+                let format = match mime_type {
+                    "audio/wav" | "audio/x-wav" | "audio/wave" => "wav",
+                    "audio/mpeg" | "audio/mp3" => "mp3",
+                    "" => resource
+                        .name
+                        .as_deref()
+                        .and_then(|name| name.rsplit_once('.'))
+                        .map(|(_, ext)| ext)?,
+                    mime_type => mime_type.split_once('/').map(|(_, sub)| sub)?,
+                };
+                Some(ContentPart::Audio {
+                    input_audio: AudioDetail {
+                        data: BASE64_STANDARD.encode(blob.as_slice()),
+                        format: format.to_ascii_lowercase(),
+                    },
+                })
+            }
+            _ => None,
+        }
+    }
+}
+
+/// Detects the MIME type of the image data from its magic bytes.
+fn image_mime_type(data: &[u8]) -> Option<&'static str> {
+    match data {
+        [0x89, b'P', b'N', b'G', ..] => Some("image/png"),
+        [0xFF, 0xD8, 0xFF, ..] => Some("image/jpeg"),
+        [b'G', b'I', b'F', b'8', ..] => Some("image/gif"),
+        [
+            b'R',
+            b'I',
+            b'F',
+            b'F',
+            _,
+            _,
+            _,
+            _,
+            b'W',
+            b'E',
+            b'B',
+            b'P',
+            ..,
+        ] => Some("image/webp"),
+        _ => None,
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::ToolCall;
     use serde_json::{json, to_string};
 
     #[test]
@@ -324,6 +682,247 @@ mod tests {
         );
     }
 
+    #[tokio::test(flavor = "current_thread")]
+    async fn test_completion_stream_from() {
+        let output = AgentOutput {
+            content: "Hello".to_string(),
+            tool_calls: Some(vec![ToolCall {
+                id: "call_1".to_string(),
+                name: "tool_1".to_string(),
+                args: "{}".to_string(),
+                result: None,
+            }]),
+            ..Default::default()
+        };
+        let chunks: Vec<CompletionChunk> = completion_stream_from(output)
+            .map(|c| c.unwrap())
+            .collect()
+            .await;
+        assert_eq!(chunks.len(), 4);
+        assert!(matches!(&chunks[0], CompletionChunk::Text(text) if text == "Hello"));
+        assert!(
+            matches!(&chunks[1], CompletionChunk::ToolCall(tc) if tc.index == 0 && tc.args == "{}")
+        );
+        assert!(matches!(&chunks[2], CompletionChunk::Usage(_)));
+        assert!(matches!(&chunks[3], CompletionChunk::Done(out) if out.content == "Hello"));
+    }
+
+    #[derive(Debug, PartialEq, Deserialize, JsonSchema)]
+    struct Profile {
+        name: String,
+        age: u8,
+    }
+
+    /// Responds with the scripted contents and records the requests.
+    struct ScriptedModel {
+        contents: std::sync::Mutex<Vec<String>>,
+        requests: std::sync::Mutex<Vec<CompletionRequest>>,
+    }
+
+    impl CompletionFeatures for ScriptedModel {
+        async fn completion(
+            &self,
+            req: CompletionRequest,
+            _resources: Option<Vec<Resource>>,
+        ) -> Result<AgentOutput, BoxError> {
+            self.requests.lock().unwrap().push(req);
+            Ok(AgentOutput {
+                content: self.contents.lock().unwrap().remove(0),
+                usage: Usage {
+                    requests: 1,
+                    ..Default::default()
+                },
+                ..Default::default()
+            })
+        }
+    }
+
+    #[tokio::test(flavor = "current_thread")]
+    async fn test_completion_json() {
+        let model = ScriptedModel {
+            contents: std::sync::Mutex::new(vec![
+                "not json".to_string(),
+                r#"{"name": "Anda"}"#.to_string(),
+                "```json\n{\"name\": \"Anda\", \"age\": 1}\n```".to_string(),
+            ]),
+            requests: std::sync::Mutex::new(Vec::new()),
+        };
+        let req = CompletionRequest {
+            system: Some("You are a helpful assistant.".to_string()),
+            prompt: "Who are you?".to_string(),
+            ..Default::default()
+        };
+        let (profile, output) = model
+            .completion_json::<Profile>(req.clone(), None)
+            .await
+            .unwrap();
+        assert_eq!(
+            profile,
+            Profile {
+                name: "Anda".to_string(),
+                age: 1
+            }
+        );
+        assert_eq!(output.usage.requests, 3);
+
+        {
+            let requests = model.requests.lock().unwrap();
+            assert_eq!(requests.len(), 3);
+            let format = requests[0].response_format.as_ref().unwrap();
+            assert_eq!(format["type"], "json_schema");
+            assert_eq!(format["json_schema"]["name"], "Profile");
+            assert!(
+                requests[1]
+                    .prompt
+                    .starts_with("The response is invalid: invalid JSON")
+            );
+            assert_eq!(requests[1].chat_history.len(), 2);
+            assert_eq!(requests[1].chat_history[1]["content"], "not json");
+            assert!(
+                requests[2]
+                    .prompt
+                    .contains(r#"missing required property "age""#),
+                "{}",
+                requests[2].prompt
+            );
+            assert_eq!(requests[2].chat_history.len(), 4);
+        }
+
+        // gives up after the max retries
+        let model = ScriptedModel {
+            contents: std::sync::Mutex::new(vec!["not json".to_string(); MAX_JSON_RETRIES + 1]),
+            requests: std::sync::Mutex::new(Vec::new()),
+        };
+        let err = model
+            .completion_json::<Profile>(req, None)
+            .await
+            .unwrap_err();
+        assert!(
+            err.to_string()
+                .contains("invalid structured output after 3 attempts")
+        );
+    }
+
+    #[test]
+    fn test_reasoning_mode() {
+        let output = AgentOutput {
+            content: "42".into(),
+            reasoning: Some("Let me think.".into()),
+            full_history: Some(vec![
+                json!({"role": "user", "content": "?"}),
+                json!({"role": "assistant", "content": "42", "reasoning_content": "Let me think."}),
+            ]),
+            ..Default::default()
+        };
+
+        let out = output.clone().with_reasoning_mode(ReasoningMode::Keep);
+        assert_eq!(out.reasoning.as_deref(), Some("Let me think."));
+        assert_eq!(
+            out.full_history.unwrap()[1]["reasoning_content"],
+            "Let me think."
+        );
+
+        let out = output.clone().with_reasoning_mode(ReasoningMode::Return);
+        assert_eq!(out.reasoning.as_deref(), Some("Let me think."));
+        assert!(
+            out.full_history.unwrap()[1]
+                .get("reasoning_content")
+                .is_none()
+        );
+
+        let out = output.clone().with_reasoning_mode(ReasoningMode::Omit);
+        assert!(out.reasoning.is_none());
+        assert!(
+            out.full_history.unwrap()[1]
+                .get("reasoning_content")
+                .is_none()
+        );
+
+        let mut out = output;
+        out.redact_reasoning();
+        assert!(out.reasoning.is_none());
+        assert!(
+            out.full_history.unwrap()[1]
+                .get("reasoning_content")
+                .is_none()
+        );
+
+        let mode: ReasoningMode = serde_json::from_str(r#""keep""#).unwrap();
+        assert_eq!(mode, ReasoningMode::Keep);
+    }
+
+    #[test]
+    fn test_append_resources() {
+        let mut resources = vec![
+            Resource {
+                tag: "image".to_string(),
+                blob: Some(vec![0x89, b'P', b'N', b'G', 0x0D, 0x0A].into()),
+                ..Default::default()
+            },
+            Resource {
+                tag: "photo".to_string(),
+                uri: Some("https://example.com/image.jpg".to_string()),
+                mime_type: Some("image/jpeg".to_string()),
+                ..Default::default()
+            },
+            Resource {
+                tag: "audio".to_string(),
+                mime_type: Some("audio/x-wav".to_string()),
+                blob: Some(vec![1, 2, 3].into()),
+                ..Default::default()
+            },
+            Resource {
+                tag: "md".to_string(),
+                blob: Some(b"# Hello".to_vec().into()),
+                ..Default::default()
+            },
+        ];
+
+        let req = CompletionRequest {
+            prompt: "What's in this image?".to_string(),
+            ..Default::default()
+        }
+        .append_resources(&mut resources);
+        assert_eq!(resources.len(), 1);
+        assert_eq!(resources[0].tag, "md");
+        assert_eq!(
+            req.content_parts,
+            vec![
+                ContentPart::Text {
+                    text: "What's in this image?".to_string()
+                },
+                ContentPart::Image {
+                    image_url: ImageDetail {
+                        url: "data:image/png;base64,iVBORw0K".to_string(),
+                        detail: None,
+                    },
+                },
+                ContentPart::Image {
+                    image_url: ImageDetail {
+                        url: "https://example.com/image.jpg".to_string(),
+                        detail: None,
+                    },
+                },
+                ContentPart::Audio {
+                    input_audio: AudioDetail {
+                        data: "AQID".to_string(),
+                        format: "wav".to_string(),
+                    },
+                },
+            ]
+        );
+
+        // unknown image data without MIME type is not converted
+        let mut resources = vec![Resource {
+            tag: "image".to_string(),
+            blob: Some(vec![1, 2, 3].into()),
+            ..Default::default()
+        }];
+        let req = CompletionRequest::default().append_resources(&mut resources);
+        assert_eq!(resources.len(), 1);
+        assert!(req.content_parts.is_empty());
+    }
+
     #[test]
     fn test_content_part() {
         let content = ContentPart::Text {
@@ -12,17 +12,23 @@ use candid::Principal;
 use serde::{Deserialize, Serialize};
 use serde_json::Value;
 
+use crate::{AndaError, validate_json_schema};
+
+mod approval;
 mod completion;
 mod embedding;
 mod knowledge;
 mod resource;
 mod thread;
+mod tokenizer;
 
+pub use approval::*;
 pub use completion::*;
 pub use embedding::*;
 pub use knowledge::*;
 pub use resource::*;
 pub use thread::*;
+pub use tokenizer::*;
 
 pub const ANONYMOUS: Principal = Principal::anonymous();
 
@@ -78,6 +84,11 @@ pub struct AgentOutput {
     #[serde(skip_serializing_if = "Option::is_none")]
     pub tool_calls: Option<Vec<ToolCall>>,
 
+    /// The reasoning content of reasoning models (e.g. DeepSeek reasoner),
+    /// only present when requested by [`CompletionRequest::reasoning`].
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub reasoning: Option<String>,
+
     /// full_history will be included in `ctx.completion` response,
     /// but not be included in the engine response.
     #[serde(skip_serializing_if = "Option::is_none")]
@@ -86,6 +97,16 @@ pub struct AgentOutput {
     /// The resources generated by the agent execution.
     #[serde(skip_serializing_if = "Option::is_none")]
     pub resources: Option<Vec<Resource>>,
+
+    /// The approval id if the execution is suspended until sensitive tool calls are approved,
+    /// see [`PendingAction`].
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub approval_id: Option<String>,
+
+    /// The name of the model that generated the output, set by the completers that route
+    /// requests to several models, e.g. a model router with fallback providers.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub model: Option<String>,
 }
 
 /// Represents a request to a tool for processing.
@@ -159,6 +180,84 @@ pub struct RequestMeta {
     /// of the user interacting with the bot.
     #[serde(skip_serializing_if = "Option::is_none")]
     pub user: Option<String>,
+
+    /// The execution budget for the request.
+    /// It can only tighten the budget configured by the engine, not loosen it.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub budget: Option<ExecutionBudget>,
+}
+
+/// The failed reason prefix of an [`AgentOutput`] when the execution budget is exhausted.
+pub const BUDGET_EXCEEDED: &str = "execution budget exceeded";
+
+/// Represents the execution budget of the automatic tool call loop in a completion.
+/// `None` means no limit.
+#[derive(Debug, Clone, Default, Deserialize, Serialize, PartialEq, Eq)]
+pub struct ExecutionBudget {
+    /// Max number of completion rounds sent to the model.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub max_rounds: Option<u64>,
+
+    /// Max number of accumulated input and output tokens.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub max_tokens: Option<u64>,
+
+    /// Max number of accumulated requests made to the model, agents and tools.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub max_requests: Option<u64>,
+
+    /// Max wall-clock time in milliseconds since the completion started.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub timeout_ms: Option<u64>,
+}
+
+impl ExecutionBudget {
+    /// Returns a budget with the smaller limit of each field.
+    pub fn min(&self, other: &ExecutionBudget) -> ExecutionBudget {
+        fn min_of(a: Option<u64>, b: Option<u64>) -> Option<u64> {
+            match (a, b) {
+                (Some(a), Some(b)) => Some(a.min(b)),
+                (a, b) => a.or(b),
+            }
+        }
+
+        ExecutionBudget {
+            max_rounds: min_of(self.max_rounds, other.max_rounds),
+            max_tokens: min_of(self.max_tokens, other.max_tokens),
+            max_requests: min_of(self.max_requests, other.max_requests),
+            timeout_ms: min_of(self.timeout_ms, other.timeout_ms),
+        }
+    }
+
+    /// Checks the budget against the consumed resources.
+    /// Returns the failed reason if the budget is exhausted.
+    pub fn check(&self, rounds: u64, usage: &Usage, elapsed_ms: u64) -> Option<String> {
+        if let Some(max) = self.max_rounds {
+            if rounds >= max {
+                return Some(format!("{}: max rounds {}", BUDGET_EXCEEDED, max));
+            }
+        }
+
+        if let Some(max) = self.max_tokens {
+            if usage.input_tokens.saturating_add(usage.output_tokens) >= max {
+                return Some(format!("{}: max tokens {}", BUDGET_EXCEEDED, max));
+            }
+        }
+
+        if let Some(max) = self.max_requests {
+            if usage.requests >= max {
+                return Some(format!("{}: max requests {}", BUDGET_EXCEEDED, max));
+            }
+        }
+
+        if let Some(max) = self.timeout_ms {
+            if elapsed_ms >= max {
+                return Some(format!("{}: timeout {}ms", BUDGET_EXCEEDED, max));
+            }
+        }
+
+        None
+    }
 }
 
 /// Represents the usage statistics for the agent or tool execution.
@@ -172,6 +271,14 @@ pub struct Usage {
 
     /// number of requests made to agents and tools
     pub requests: u64,
+
+    /// input tokens read from the prompt cache of the LLM, included in `input_tokens`
+    #[serde(default)]
+    pub cached_input_tokens: u64,
+
+    /// reasoning tokens generated by the LLM, included in `output_tokens`
+    #[serde(default)]
+    pub reasoning_tokens: u64,
 }
 
 impl Usage {
@@ -180,6 +287,10 @@ impl Usage {
         self.input_tokens = self.input_tokens.saturating_add(other.input_tokens);
         self.output_tokens = self.output_tokens.saturating_add(other.output_tokens);
         self.requests = self.requests.saturating_add(other.requests);
+        self.cached_input_tokens = self
+            .cached_input_tokens
+            .saturating_add(other.cached_input_tokens);
+        self.reasoning_tokens = self.reasoning_tokens.saturating_add(other.reasoning_tokens);
     }
 }
 
@@ -225,6 +336,11 @@ pub struct FunctionDefinition {
     /// Whether to enable strict schema adherence when generating the function call. If set to true, the model will follow the exact schema defined in the parameters field. Only a subset of JSON Schema is supported when strict is true.
     #[serde(skip_serializing_if = "Option::is_none")]
     pub strict: Option<bool>,
+
+    /// JSON schema defining the function's output, it is not sent to the LLM.
+    /// Tools can generate it from their output type with [`gen_schema_for`](crate::gen_schema_for).
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub output_schema: Option<Value>,
 }
 
 impl FunctionDefinition {
@@ -233,9 +349,41 @@ impl FunctionDefinition {
         self.name = format!("{}{}", prefix, self.name);
         self
     }
-}
 
-/// Returns the number of tokens in the given content in the simplest way.
-pub fn evaluate_tokens(content: &str) -> usize {
-    content.len() / 3
+    /// Validates the arguments against the parameters schema.
+    /// All properties are required and unknown properties are rejected if `strict` is true.
+    /// The error contains the JSON path of every invalid argument.
+    pub fn validate_args(&self, args: &Value) -> Result<(), AndaError> {
+        if self.parameters.is_null() {
+            return Ok(());
+        }
+
+        validate_json_schema(&self.parameters, args, self.strict.unwrap_or(false)).map_err(
+            |errors| {
+                AndaError::invalid_args(format!(
+                    "tool {}, invalid args: {}",
+                    self.name,
+                    errors.join("; ")
+                ))
+            },
+        )
+    }
+
+    /// Validates the output of the function against the output schema if it is set.
+    /// Unknown properties and missing nullable properties are allowed, so that the
+    /// output can evolve without breaking the callers.
+    pub fn validate_output(&self, output: &Value) -> Result<(), AndaError> {
+        let schema = match &self.output_schema {
+            Some(schema) if !schema.is_null() => schema,
+            _ => return Ok(()),
+        };
+
+        validate_json_schema(schema, output, false).map_err(|errors| {
+            AndaError::internal(format!(
+                "tool {}, invalid output: {}",
+                self.name,
+                errors.join("; ")
+            ))
+        })
+    }
 }
@@ -1,4 +1,5 @@
 use candid::Principal;
+use schemars::{JsonSchema, r#gen::SchemaGenerator, schema::Schema};
 use serde::{Deserialize, Serialize};
 use serde_bytes::ByteArray;
 use std::{
@@ -7,6 +8,8 @@ use std::{
     str::FromStr,
 };
 
+use super::Message;
+
 /// Represents a unique identifier of a thread.
 #[derive(Debug, Clone, Deserialize, Serialize, PartialEq, Eq, PartialOrd, Ord)]
 pub struct ThreadId(pub ByteArray<12>);
@@ -54,6 +57,21 @@ impl Deref for ThreadId {
     }
 }
 
+// a thread ID is serialized as 12 bytes, an array of 12 integers in JSON
+impl JsonSchema for ThreadId {
+    fn is_referenceable() -> bool {
+        false
+    }
+
+    fn schema_name() -> String {
+        "ThreadId".to_string()
+    }
+
+    fn json_schema(generator: &mut SchemaGenerator) -> Schema {
+        <[u8; 12]>::json_schema(generator)
+    }
+}
+
 impl Default for ThreadId {
     fn default() -> Self {
         EMPTY_THREAD
@@ -80,22 +98,26 @@ impl ThreadId {
 }
 
 /// Represents the metadata for a thread of conversation.
-#[derive(Debug, Clone, Deserialize, Serialize)]
+#[derive(Debug, Clone, Deserialize, Serialize, JsonSchema)]
 pub struct ThreadMeta {
     /// The unique identifier for the thread.
     pub id: ThreadId,
 
     /// The principal of the agent that created and serve the thread.
+    #[schemars(with = "String")]
     pub agent: Principal,
 
     /// The initiator of the thread, typically an agent or user principal.
+    #[schemars(with = "String")]
     pub initiator: Principal,
 
     /// The participants of the thread.
+    #[schemars(with = "BTreeSet<String>")]
     pub participants: BTreeSet<Principal>,
 
     /// The children threads of this thread.
     /// The key is the principal of the agent that created the child thread.
+    #[schemars(with = "BTreeMap<String, ThreadId>")]
     pub children: BTreeMap<Principal, ThreadId>,
 
     /// The timestamp when the thread was last updated.
@@ -170,10 +192,12 @@ impl MyThreads {
 
     /// Returns the threads served by the agent.
     pub fn list_threads_by(&self, agent: &Principal) -> Option<Vec<ThreadId>> {
-        self.agents.get(agent).map(|idx| self.threads
-                    .iter()
-                    .filter_map(|(tid, i)| if idx == i { Some(tid.clone()) } else { None })
-                    .collect())
+        self.agents.get(agent).map(|idx| {
+            self.threads
+                .iter()
+                .filter_map(|(tid, i)| if idx == i { Some(tid.clone()) } else { None })
+                .collect()
+        })
     }
 
     /// Adds a thread to the list of threads.
@@ -197,3 +221,30 @@ impl MyThreads {
         }
     }
 }
+
+/// Represents a message stored in the history of a thread.
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct ThreadMessage {
+    /// The sequence number of the message in the thread, starting from 0.
+    pub seq: u64,
+
+    /// The principal that appended the message.
+    pub caller: Principal,
+
+    /// The message, can be a user, assistant or tool message.
+    pub message: Message,
+
+    /// The timestamp in milliseconds when the message was appended.
+    pub timestamp: u64,
+}
+
+/// Represents a page of messages in the history of a thread.
+#[derive(Debug, Clone, Default, Deserialize, Serialize)]
+pub struct ThreadMessages {
+    /// The messages in chronological order.
+    pub messages: Vec<ThreadMessage>,
+
+    /// The cursor to page through older messages, None if there are no more messages.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub next_cursor: Option<u64>,
+}
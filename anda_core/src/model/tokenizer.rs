@@ -0,0 +1,323 @@
+//! Tokenizers for estimating the tokens of prompts and documents.
+//!
+//! [`evaluate_tokens`] is used by attention thresholds, document segmenting and
+//! context window budgeting. It uses the default tokenizer set by
+//! [`set_default_tokenizer`], or falls back to [`HeuristicTokenizer`].
+//!
+//! [`BpeTokenizer`] is an offline byte pair encoding tokenizer that loads the
+//! `cl100k_base` or `o200k_base` vocab files in tiktoken format from disk:
+//! ```rust,ignore
+//! use anda_core::{BpeTokenizer, set_default_tokenizer};
+//! use std::sync::Arc;
+//!
+//! let tokenizer = BpeTokenizer::o200k_base("./tokenizers/o200k_base.tiktoken")?;
+//! set_default_tokenizer(Arc::new(tokenizer));
+//! ```
+
+use base64::{Engine, prelude::BASE64_STANDARD};
+use fancy_regex::Regex;
+use std::{
+    collections::HashMap,
+    path::Path,
+    sync::{Arc, OnceLock},
+};
+
+use crate::BoxError;
+
+/// Counts the tokens of text for a model.
+pub trait Tokenizer: Send + Sync {
+    /// Returns the name of the tokenizer, e.g. "cl100k_base".
+    fn name(&self) -> &str;
+
+    /// Returns the number of tokens in the given text.
+    fn count_tokens(&self, text: &str) -> usize;
+}
+
+/// Estimates tokens as 1 token per 3 bytes, it is the fallback when no vocab is available.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct HeuristicTokenizer;
+
+impl Tokenizer for HeuristicTokenizer {
+    fn name(&self) -> &str {
+        "heuristic"
+    }
+
+    fn count_tokens(&self, text: &str) -> usize {
+        text.len() / 3
+    }
+}
+
+static DEFAULT_TOKENIZER: OnceLock<Arc<dyn Tokenizer>> = OnceLock::new();
+
+/// Sets the default tokenizer used by [`evaluate_tokens`].
+/// It can be set only once, returns false if it has been set.
+pub fn set_default_tokenizer(tokenizer: Arc<dyn Tokenizer>) -> bool {
+    DEFAULT_TOKENIZER.set(tokenizer).is_ok()
+}
+
+/// Returns the default tokenizer, [`HeuristicTokenizer`] if it is not set.
+pub fn default_tokenizer() -> Arc<dyn Tokenizer> {
+    DEFAULT_TOKENIZER
+        .get()
+        .cloned()
+        .unwrap_or_else(|| Arc::new(HeuristicTokenizer))
+}
+
+/// Returns the number of tokens in the given content by the default tokenizer.
+pub fn evaluate_tokens(content: &str) -> usize {
+    match DEFAULT_TOKENIZER.get() {
+        Some(tokenizer) => tokenizer.count_tokens(content),
+        None => HeuristicTokenizer.count_tokens(content),
+    }
+}
+
+/// Pre-tokenization pattern of the `cl100k_base` encoding (GPT-4, GPT-3.5).
+pub const CL100K_BASE_PATTERN: &str = r"(?i:'s|'t|'re|'ve|'m|'ll|'d)|[^\r\n\p{L}\p{N}]?\p{L}+|\p{N}{1,3}| ?[^\s\p{L}\p{N}]+[\r\n]*|\s*[\r\n]+|\s+(?!\S)|\s+";
+
+/// Pre-tokenization pattern of the `o200k_base` encoding (GPT-4o, o1, o3).
+pub const O200K_BASE_PATTERN: &str = concat!(
+    r"[^\r\n\p{L}\p{N}]?[\p{Lu}\p{Lt}\p{Lm}\p{Lo}\p{M}]*[\p{Ll}\p{Lm}\p{Lo}\p{M}]+(?i:'s|'t|'re|'ve|'m|'ll|'d)?",
+    r"|[^\r\n\p{L}\p{N}]?[\p{Lu}\p{Lt}\p{Lm}\p{Lo}\p{M}]+[\p{Ll}\p{Lm}\p{Lo}\p{M}]*(?i:'s|'t|'re|'ve|'m|'ll|'d)?",
+    r"|\p{N}{1,3}| ?[^\s\p{L}\p{N}]+[\r\n/]*|\s*[\r\n]+|\s+(?!\S)|\s+"
+);
+
+/// Byte pair encoding tokenizer with a tiktoken style vocab.
+/// Special tokens are not recognized, they are encoded as ordinary text.
+pub struct BpeTokenizer {
+    name: String,
+    ranks: HashMap<Vec<u8>, u32>,
+    pattern: Regex,
+}
+
+impl BpeTokenizer {
+    /// Creates a tokenizer with the vocab ranks and the pre-tokenization pattern.
+    pub fn new(
+        name: String,
+        ranks: HashMap<Vec<u8>, u32>,
+        pattern: &str,
+    ) -> Result<Self, BoxError> {
+        if ranks.is_empty() {
+            return Err(format!("tokenizer {} has an empty vocab", name).into());
+        }
+        let pattern = Regex::new(pattern)?;
+        Ok(Self {
+            name,
+            ranks,
+            pattern,
+        })
+    }
+
+    /// Loads a tokenizer from a tiktoken vocab file, each line is a base64 encoded token and its rank.
+    pub fn from_tiktoken_file(
+        name: String,
+        path: impl AsRef<Path>,
+        pattern: &str,
+    ) -> Result<Self, BoxError> {
+        let data = std::fs::read_to_string(path.as_ref()).map_err(|err| {
+            format!(
+                "failed to read tokenizer file {:?}: {}",
+                path.as_ref().display(),
+                err
+            )
+        })?;
+        let ranks = parse_tiktoken(&data)?;
+        Self::new(name, ranks, pattern)
+    }
+
+    /// Loads the `cl100k_base` tokenizer from the `cl100k_base.tiktoken` file.
+    pub fn cl100k_base(path: impl AsRef<Path>) -> Result<Self, BoxError> {
+        Self::from_tiktoken_file("cl100k_base".to_string(), path, CL100K_BASE_PATTERN)
+    }
+
+    /// Loads the `o200k_base` tokenizer from the `o200k_base.tiktoken` file.
+    pub fn o200k_base(path: impl AsRef<Path>) -> Result<Self, BoxError> {
+        Self::from_tiktoken_file("o200k_base".to_string(), path, O200K_BASE_PATTERN)
+    }
+
+    /// Encodes the text into token ranks.
+    pub fn encode(&self, text: &str) -> Vec<u32> {
+        let mut tokens = Vec::new();
+        for piece in self.pieces(text) {
+            let piece = piece.as_bytes();
+            match self.ranks.get(piece) {
+                Some(rank) => tokens.push(*rank),
+                None => tokens.extend(self.byte_pair_encode(piece)),
+            }
+        }
+        tokens
+    }
+
+    fn pieces<'a>(&'a self, text: &'a str) -> impl Iterator<Item = &'a str> + 'a {
+        // the patterns never fail to match, it only fails when exceeding the backtrack limit
+        self.pattern
+            .find_iter(text)
+            .filter_map(|m| m.ok())
+            .map(|m| m.as_str())
+    }
+
+    fn byte_pair_encode(&self, piece: &[u8]) -> Vec<u32> {
+        if piece.len() < 2 {
+            return piece
+                .iter()
+                .filter_map(|b| self.ranks.get(&[*b][..]).copied())
+                .collect();
+        }
+
+        let parts = self.byte_pair_merge(piece);
+        parts
+            .windows(2)
+            .map(|w| {
+                self.ranks
+                    .get(&piece[w[0].0..w[1].0])
+                    .copied()
+                    .unwrap_or(u32::MAX)
+            })
+            .collect()
+    }
+
+    /// Merges the adjacent parts with the lowest rank until no more parts can be merged.
+    /// Returns the start positions of the parts, the last one is the end of the piece.
+    fn byte_pair_merge(&self, piece: &[u8]) -> Vec<(usize, u32)> {
+        let rank_of = |parts: &[(usize, u32)], i: usize| -> u32 {
+            if i + 3 < parts.len() {
+                self.ranks
+                    .get(&piece[parts[i].0..parts[i + 3].0])
+                    .copied()
+                    .unwrap_or(u32::MAX)
+            } else {
+                u32::MAX
+            }
+        };
+
+        let mut parts: Vec<(usize, u32)> = Vec::with_capacity(piece.len() + 1);
+        let mut min_rank = (u32::MAX, usize::MAX);
+        for i in 0..piece.len() - 1 {
+            let rank = self
+                .ranks
+                .get(&piece[i..i + 2])
+                .copied()
+                .unwrap_or(u32::MAX);
+            if rank < min_rank.0 {
+                min_rank = (rank, i);
+            }
+            parts.push((i, rank));
+        }
+        parts.push((piece.len() - 1, u32::MAX));
+        parts.push((piece.len(), u32::MAX));
+
+        while min_rank.0 != u32::MAX {
+            let i = min_rank.1;
+            if i > 0 {
+                parts[i - 1].1 = rank_of(&parts, i - 1);
+            }
+            parts[i].1 = rank_of(&parts, i);
+            parts.remove(i + 1);
+
+            min_rank = (u32::MAX, usize::MAX);
+            for (i, &(_, rank)) in parts[..parts.len() - 1].iter().enumerate() {
+                if rank < min_rank.0 {
+                    min_rank = (rank, i);
+                }
+            }
+        }
+        parts
+    }
+}
+
+impl Tokenizer for BpeTokenizer {
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn count_tokens(&self, text: &str) -> usize {
+        self.pieces(text)
+            .map(|piece| {
+                let piece = piece.as_bytes();
+                if self.ranks.contains_key(piece) {
+                    1
+                } else if piece.len() < 2 {
+                    piece.len()
+                } else {
+                    self.byte_pair_merge(piece).len() - 1
+                }
+            })
+            .sum()
+    }
+}
+
+/// Parses a tiktoken vocab file.
+fn parse_tiktoken(data: &str) -> Result<HashMap<Vec<u8>, u32>, BoxError> {
+    let mut ranks = HashMap::new();
+    for (i, line) in data.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() {
+            continue;
+        }
+        let (token, rank) = line
+            .split_once(' ')
+            .ok_or_else(|| format!("invalid tiktoken line {}: {:?}", i + 1, line))?;
+        let token = BASE64_STANDARD
+            .decode(token)
+            .map_err(|err| format!("invalid tiktoken line {}: {}", i + 1, err))?;
+        let rank: u32 = rank
+            .parse()
+            .map_err(|err| format!("invalid tiktoken line {}: {}", i + 1, err))?;
+        ranks.insert(token, rank);
+    }
+    Ok(ranks)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn test_ranks() -> HashMap<Vec<u8>, u32> {
+        let mut ranks: HashMap<Vec<u8>, u32> = (0..=255u8).map(|b| (vec![b], b as u32)).collect();
+        for (i, token) in ["he", "ll", "hell", "hello", " w", "or", " wor", " world"]
+            .iter()
+            .enumerate()
+        {
+            ranks.insert(token.as_bytes().to_vec(), 256 + i as u32);
+        }
+        ranks
+    }
+
+    #[test]
+    fn test_bpe_tokenizer() {
+        let tokenizer =
+            BpeTokenizer::new("test".to_string(), test_ranks(), CL100K_BASE_PATTERN).unwrap();
+        assert_eq!(tokenizer.encode("hello world"), vec![259, 263]);
+        assert_eq!(tokenizer.count_tokens("hello world"), 2);
+        // "helo" -> "he" + "l" + "o"
+        assert_eq!(
+            tokenizer.encode("helo"),
+            vec![256, b'l' as u32, b'o' as u32]
+        );
+        assert_eq!(tokenizer.count_tokens("helo"), 3);
+        // multi-byte characters fall back to bytes
+        assert_eq!(tokenizer.count_tokens("你好"), 6);
+        assert_eq!(tokenizer.count_tokens(""), 0);
+
+        let data: String = test_ranks()
+            .iter()
+            .map(|(token, rank)| format!("{} {}\n", BASE64_STANDARD.encode(token), rank))
+            .collect();
+        let path = std::env::temp_dir().join(format!("anda_test_{}.tiktoken", xid::new()));
+        std::fs::write(&path, data).unwrap();
+        let tokenizer =
+            BpeTokenizer::from_tiktoken_file("test".to_string(), &path, O200K_BASE_PATTERN)
+                .unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(tokenizer.name(), "test");
+        assert_eq!(tokenizer.encode("hello world"), vec![259, 263]);
+
+        assert!(parse_tiktoken("aGVsbG8=").is_err());
+        assert!(BpeTokenizer::cl100k_base("/not/exists.tiktoken").is_err());
+    }
+
+    #[test]
+    fn test_heuristic_tokenizer() {
+        assert_eq!(HeuristicTokenizer.count_tokens("hello world"), 3);
+        assert_eq!(evaluate_tokens("hello world"), 3);
+    }
+}
@@ -31,7 +31,7 @@ use serde::{Serialize, de::DeserializeOwned};
 use std::{collections::BTreeMap, future::Future, marker::PhantomData, sync::Arc};
 
 use crate::{
-    BoxError, BoxPinFut, Function, Resource, ToolOutput, Value, context::BaseContext,
+    AndaError, BoxError, BoxPinFut, Function, Resource, ToolOutput, Value, context::BaseContext,
     model::FunctionDefinition, select_resources, validate_function_name,
 };
 
@@ -109,12 +109,17 @@ where
         resources: Option<Vec<Resource>>,
     ) -> impl Future<Output = Result<ToolOutput<Value>, BoxError>> + Send {
         async move {
-            let args: Self::Args = serde_json::from_str(&args)
-                .map_err(|err| format!("tool {}, invalid args: {}", self.name(), err))?;
-            let mut result = self
-                .call(ctx, args, resources)
-                .await
-                .map_err(|err| format!("tool {}, call failed: {}", self.name(), err))?;
+            let args: Self::Args = serde_json::from_str(&args).map_err(|err| {
+                AndaError::invalid_args(format!("tool {}, invalid args: {}", self.name(), err))
+            })?;
+            let mut result = self.call(ctx, args, resources).await.map_err(|err| {
+                // keeps the error code from the tool
+                let err = AndaError::from(err);
+                AndaError::new(
+                    err.code,
+                    format!("tool {}, call failed: {}", self.name(), err.message),
+                )
+            })?;
             let output = serde_json::to_value(&result.output)?;
             if result.usage.requests == 0 {
                 result.usage.requests = 1;
@@ -26,23 +26,175 @@
 //! agents or tools while maintaining access to the core functionality.
 
 use anda_core::{
-    AgentArgs, AgentContext, AgentInput, AgentOutput, AgentSet, BaseContext, BoxError, CacheExpiry,
-    CacheFeatures, CacheStoreFeatures, CancellationToken, CanisterCaller, CompletionFeatures,
-    CompletionRequest, Embedding, EmbeddingFeatures, FunctionDefinition, HttpFeatures,
-    KeysFeatures, Message, ObjectMeta, Path, PutMode, PutResult, RequestMeta, Resource,
-    StateFeatures, StoreFeatures, ToolCall, ToolInput, ToolOutput, ToolSet, Usage, Value,
+    APPROVAL_REQUIRED, AgentArgs, AgentContext, AgentInput, AgentOutput, AgentSet, AndaError,
+    ApprovalStatus, BUDGET_EXCEEDED, BaseContext, BoxError, CacheExpiry, CacheFeatures,
+    CacheStoreFeatures, CancellationToken, CanisterCaller, CompletionChunk, CompletionFeatures,
+    CompletionRequest, CompletionStream, Embedding, EmbeddingFeatures, ExecutionBudget,
+    FunctionDefinition, HttpFeatures, KeysFeatures, Message, ObjectMeta, Path, PendingAction,
+    PutMode, PutResult, ReasoningMode, RequestMeta, Resource, StateFeatures, StoreFeatures,
+    ToolCall, ToolInput, ToolOutput, ToolSet, Usage, Value,
 };
 use bytes::Bytes;
 use candid::{CandidType, Principal, utils::ArgumentEncoder};
-use serde::{Serialize, de::DeserializeOwned};
+use futures::{StreamExt, channel::mpsc};
+use serde::{Deserialize, Serialize, de::DeserializeOwned};
 use serde_json::json;
-use std::{future::Future, sync::Arc, time::Duration};
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    future::Future,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+use structured_logger::unix_ms;
 
 use super::{base::BaseCtx, engine::RemoteEngines};
-use crate::{management::Management, model::Model};
+use crate::{
+    ledger::{UsageKind, UsageLedger, UsageRecord},
+    management::Management,
+    model::Model,
+};
 
 pub static DYNAMIC_REMOTE_ENGINES: &str = "_engines";
 
+type CompletionSender = mpsc::UnboundedSender<Result<CompletionChunk, BoxError>>;
+
+/// Options for the automatic tool call loop in [`AgentCtx`]'s completion.
+#[derive(Clone, Debug)]
+pub struct CompletionOptions {
+    /// The max number of tool and agent calls to run concurrently in one round.
+    /// `1` means the calls run one after another.
+    pub tool_concurrency: usize,
+
+    /// How to handle the failures of tool and agent calls.
+    pub tool_error_policy: ToolErrorPolicy,
+
+    /// The execution budget of the loop, it can be tightened by [`RequestMeta`].
+    pub budget: ExecutionBudget,
+
+    /// The tools (local tools or remote tools with the `RT_` prefix) that the model can't call
+    /// on its own. Their calls are suspended as a [`PendingAction`] until they are approved.
+    pub approval_tools: BTreeSet<String>,
+}
+
+impl Default for CompletionOptions {
+    fn default() -> Self {
+        Self {
+            tool_concurrency: 1,
+            tool_error_policy: ToolErrorPolicy::FailFast,
+            budget: ExecutionBudget::default(),
+            approval_tools: BTreeSet::new(),
+        }
+    }
+}
+
+/// Policy for the failures of tool and agent calls in the automatic tool call loop,
+/// including the failures of parsing the arguments generated by the model.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub enum ToolErrorPolicy {
+    /// Stops the loop at the first failure and returns it as `failed_reason`.
+    #[default]
+    FailFast,
+
+    /// Sends the failure back to the model as a `tool` message, so that it can
+    /// correct the arguments and call the tool again.
+    /// A tool can fail at most `max_retries` times, the next failure stops the loop.
+    Feedback { max_retries: usize },
+}
+
+/// A tool or agent call requested by the model.
+enum ToolCallJob {
+    Tool(ToolInput<Value>),
+    Agent(AgentInput),
+    /// The call can't be dispatched, e.g. the arguments are invalid.
+    Invalid(String),
+}
+
+/// The state of a completion loop suspended for approval, it is stored with the [`PendingAction`].
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub(crate) struct SuspendedCompletion {
+    pub(crate) meta: RequestMeta,
+    chat_history: Vec<Value>,
+    tools: Vec<FunctionDefinition>,
+    tool_choice_required: bool,
+    temperature: Option<f64>,
+    max_tokens: Option<usize>,
+    response_format: Option<Value>,
+    stop: Option<Vec<String>>,
+    #[serde(default)]
+    reasoning: ReasoningMode,
+    resources: Vec<Resource>,
+    /// All tool calls of the loop, including the pending ones.
+    tool_calls: Vec<ToolCall>,
+    usage: Usage,
+    /// The tool call id and the input of the pending tool calls.
+    pending: Vec<(String, ToolInput<Value>)>,
+    /// The tool messages of the finished calls of the suspended round, they are sent
+    /// together with the messages of the pending calls in the model's order.
+    #[serde(default)]
+    tool_messages: Vec<Value>,
+    /// The first failure of the tool calls of the suspended round,
+    /// it stops the loop with the [`ToolErrorPolicy::FailFast`] policy.
+    #[serde(default)]
+    failed_reason: Option<String>,
+}
+
+impl SuspendedCompletion {
+    /// Sets the output of a nested agent call that was suspended for approval.
+    pub(crate) fn set_nested_output(&mut self, tool_call_id: &str, output: &AgentOutput) {
+        self.usage.accumulate(&output.usage);
+        let content: Value = match &output.failed_reason {
+            None => {
+                if let Some(tool) = self.tool_calls.iter_mut().find(|t| t.id == tool_call_id) {
+                    tool.result = serde_json::to_value(output).ok();
+                }
+                if let Some(resources) = &output.resources {
+                    self.resources.extend(resources.iter().cloned());
+                }
+                output.content.clone().into()
+            }
+            Some(reason) => {
+                if self.failed_reason.is_none() {
+                    self.failed_reason = Some(reason.clone());
+                }
+                format!("Error: {}", reason).into()
+            }
+        };
+        self.tool_messages.push(json!(Message {
+            role: "tool".to_string(),
+            content,
+            name: None,
+            tool_call_id: Some(tool_call_id.to_string()),
+            ..Default::default()
+        }));
+    }
+}
+
+/// A completion loop suspended until its nested agent calls, which were suspended
+/// for approval, are resumed. It is resumed by the engine after the last of them.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub(crate) struct Continuation {
+    pub(crate) id: String,
+    pub(crate) agent: String,
+    pub(crate) caller: Principal,
+    pub(crate) state: SuspendedCompletion,
+    /// The suspended nested executions, keyed by the id of their root (a pending action
+    /// or a continuation), with the tool call id and the approval id to review.
+    pub(crate) waiting: BTreeMap<String, (String, String)>,
+    /// The continuation waiting for this one, if nested.
+    pub(crate) parent: Option<String>,
+}
+
+/// The result of a [`ToolCallJob`].
+struct ToolCallResult {
+    content: Value,
+    resources: Option<Vec<Resource>>,
+    usage: Usage,
+    result: Value,
+    failed_reason: Option<String>,
+    /// The approval id if the call is a local agent suspended for approval.
+    approval_id: Option<String>,
+}
+
 /// Context for agent operations, providing access to models, tools, and other agents.
 #[derive(Clone)]
 pub struct AgentCtx {
@@ -54,8 +206,14 @@ pub struct AgentCtx {
     pub(crate) tools: Arc<ToolSet<BaseCtx>>,
     /// Set of available agents that can be invoked.
     pub(crate) agents: Arc<AgentSet<AgentCtx>>,
+    /// Options for the automatic tool call loop.
+    pub(crate) options: CompletionOptions,
 
     management: Arc<Management>,
+    /// Usage ledger of the model calls, if enabled.
+    ledger: Option<Arc<UsageLedger>>,
+    /// Usage of the model calls of the execution, shared with the child agent contexts.
+    consumed: Arc<Mutex<Usage>>,
 }
 
 impl AgentCtx {
@@ -66,19 +224,27 @@ impl AgentCtx {
     /// * `model` - AI model instance.
     /// * `tools` - Set of available tools.
     /// * `agents` - Set of available agents.
+    /// * `options` - Options for the automatic tool call loop.
+    /// * `management` - Management of the engine.
+    /// * `ledger` - Usage ledger of the model calls.
     pub(crate) fn new(
         base: BaseCtx,
         model: Model,
         tools: Arc<ToolSet<BaseCtx>>,
         agents: Arc<AgentSet<AgentCtx>>,
+        options: CompletionOptions,
         management: Arc<Management>,
+        ledger: Option<Arc<UsageLedger>>,
     ) -> Self {
         Self {
             base,
             model,
             tools,
             agents,
+            options,
             management,
+            ledger,
+            consumed: Arc::new(Mutex::new(Usage::default())),
         }
     }
 
@@ -89,10 +255,13 @@ impl AgentCtx {
     pub(crate) fn child(&self, agent_name: &str) -> Result<Self, BoxError> {
         Ok(Self {
             base: self.base.child(format!("A:{}", agent_name))?,
-            model: self.model.clone(),
+            model: self.model.for_agent(agent_name),
             tools: self.tools.clone(),
             agents: self.agents.clone(),
+            options: self.options.clone(),
             management: self.management.clone(),
+            ledger: self.ledger.clone(),
+            consumed: self.consumed.clone(),
         })
     }
 
@@ -120,10 +289,14 @@ impl AgentCtx {
             base: self
                 .base
                 .child_with(caller, format!("A:{}", agent_name), meta)?,
-            model: self.model.clone(),
+            model: self.model.for_agent(agent_name),
             tools: self.tools.clone(),
             agents: self.agents.clone(),
+            options: self.options.clone(),
             management: self.management.clone(),
+            ledger: self.ledger.clone(),
+            // a new execution
+            consumed: Arc::new(Mutex::new(Usage::default())),
         })
     }
 
@@ -142,6 +315,43 @@ impl AgentCtx {
         self.base
             .child_with(caller, format!("T:{}", tool_name), meta)
     }
+
+    /// Loads the latest messages of the current thread into the `chat_history` of the request,
+    /// before the existing chat history. It does nothing if the request has no thread.
+    ///
+    /// # Arguments
+    /// * `req` - The completion request to fill;
+    /// * `max_messages` - Max number of messages to load.
+    pub async fn load_thread_history(
+        &self,
+        req: &mut CompletionRequest,
+        max_messages: usize,
+    ) -> Result<(), BoxError> {
+        if let Some(thread_id) = &self.base.meta.thread {
+            let history = self
+                .management
+                .thread_history(&self.base.caller, thread_id, max_messages)
+                .await?;
+            let mut chat_history: Vec<Value> = history.into_iter().map(|m| json!(m)).collect();
+            chat_history.append(&mut req.chat_history);
+            req.chat_history = chat_history;
+        }
+        Ok(())
+    }
+
+    /// Appends messages to the history of the current thread.
+    /// Returns the total number of messages in the thread, or an error if the request has no thread.
+    pub async fn append_thread_messages(&self, messages: Vec<Message>) -> Result<u64, BoxError> {
+        let thread_id = self
+            .base
+            .meta
+            .thread
+            .as_ref()
+            .ok_or_else(|| AndaError::invalid_args("thread is required"))?;
+        self.management
+            .append_thread_messages(&self.base.caller, thread_id, messages)
+            .await
+    }
 }
 
 impl CacheStoreFeatures for AgentCtx {}
@@ -309,12 +519,16 @@ impl AgentContext for AgentCtx {
         if !input.name.starts_with("RT_") {
             let ctx = self.child_base(&input.name)?;
             let tool = self.tools.get(&input.name).expect("tool not found");
+            tool.definition().validate_args(&input.args)?;
             let args = serde_json::to_string(&input.args)?;
             return tool.call(ctx, args, input.resources).await;
         }
 
         // find registered remote tool and call it
         if let Some((endpoint, tool_name)) = self.base.remote.get_tool_endpoint(&input.name) {
+            if let Some(def) = self.base.remote.get_tool_definition(&endpoint, &tool_name) {
+                def.validate_args(&input.args)?;
+            }
             input.name = tool_name;
             return self.base.remote_tool_call(&endpoint, input).await;
         }
@@ -325,12 +539,17 @@ impl AgentContext for AgentCtx {
             .await
         {
             if let Some((endpoint, tool_name)) = engines.get_tool_endpoint(&input.name) {
-                input.name = tool_name;
-                return self.base.remote_tool_call(&endpoint, input).await;
+                if let Some(def) = engines.get_tool_definition(&endpoint, &tool_name) {
+                    def.validate_args(&input.args)?;
+                }
+                input.name = tool_name.clone();
+                let output = self.base.remote_tool_call(&endpoint, input).await?;
+                engines.validate_tool_output(&endpoint, &tool_name, &output.output)?;
+                return Ok(output);
             }
         }
 
-        Err(format!("tool {} not found", &input.name).into())
+        Err(AndaError::not_found(format!("tool {} not found", &input.name)).into())
     }
 
     /// Runs a local agent.
@@ -366,7 +585,7 @@ impl AgentContext for AgentCtx {
             }
         }
 
-        Err(format!("agent {} not found", input.name).into())
+        Err(AndaError::not_found(format!("agent {} not found", input.name)).into())
     }
 
     /// Runs a remote agent via HTTP RPC.
@@ -386,7 +605,9 @@ impl AgentContext for AgentCtx {
             .base
             .remote
             .get_id_by_endpoint(endpoint)
-            .ok_or_else(|| format!("remote engine endpoint {} not found", endpoint))?;
+            .ok_or_else(|| {
+                AndaError::not_found(format!("remote engine endpoint {} not found", endpoint))
+            })?;
         let mut meta = self.base.self_meta(target);
         if let Some(thread_id) = &meta.thread {
             let thread = self.management.get_thread_meta(thread_id).await?;
@@ -448,21 +669,445 @@ impl CompletionFeatures for AgentCtx {
     ///    - Repeats the completion with updated history;
     /// 3. Returns final result when no more tool calls need processing.
     async fn completion(
+        &self,
+        req: CompletionRequest,
+        resources: Option<Vec<Resource>>,
+    ) -> Result<AgentOutput, BoxError> {
+        self.completion_with(req, resources, None).await
+    }
+
+    /// Executes a streaming completion request with automatic tool call handling.
+    ///
+    /// The tool call loop is the same as [`CompletionFeatures::completion`], text deltas and
+    /// tool call fragments of every round are streamed out as they are produced.
+    /// The stream ends with the accumulated [`CompletionChunk::Usage`] and the final
+    /// [`CompletionChunk::Done`] chunks.
+    async fn completion_stream(
+        &self,
+        req: CompletionRequest,
+        resources: Option<Vec<Resource>>,
+    ) -> Result<CompletionStream, BoxError> {
+        let (tx, rx) = mpsc::unbounded();
+        let ctx = self.clone();
+        tokio::spawn(async move {
+            match ctx.completion_with(req, resources, Some(&tx)).await {
+                Ok(output) => {
+                    let _ = tx.unbounded_send(Ok(CompletionChunk::Usage(output.usage.clone())));
+                    let _ = tx.unbounded_send(Ok(CompletionChunk::Done(output)));
+                }
+                Err(err) => {
+                    let _ = tx.unbounded_send(Err(err));
+                }
+            }
+        });
+
+        Ok(rx.boxed())
+    }
+
+    /// Returns the number of tokens in the text by the tokenizer of the model.
+    fn count_tokens(&self, text: &str) -> usize {
+        self.model.count_tokens(text)
+    }
+}
+
+impl AgentCtx {
+    /// Runs a single completion round on the model.
+    /// If `tx` is provided, the round is streamed and its chunks are forwarded to `tx`.
+    async fn model_completion(
+        &self,
+        req: CompletionRequest,
+        tx: Option<&CompletionSender>,
+    ) -> Result<AgentOutput, BoxError> {
+        let tx = match tx {
+            Some(tx) => tx,
+            None => {
+                let output = self.model.completion(req).await?;
+                self.record_usage(
+                    UsageKind::Completion,
+                    output.model.as_deref(),
+                    &output.usage,
+                )
+                .await;
+                return Ok(output);
+            }
+        };
+
+        let mut stream = self.model.completion_stream(req).await?;
+        while let Some(chunk) = stream.next().await {
+            match chunk? {
+                CompletionChunk::Done(output) => {
+                    self.record_usage(
+                        UsageKind::Completion,
+                        output.model.as_deref(),
+                        &output.usage,
+                    )
+                    .await;
+                    return Ok(output);
+                }
+                // the accumulated usage will be sent at the end
+                CompletionChunk::Usage(_) => {}
+                chunk => {
+                    if tx.unbounded_send(Ok(chunk)).is_err() {
+                        return Err("completion stream closed by receiver".into());
+                    }
+                }
+            }
+        }
+
+        Err("completion stream ended without output".into())
+    }
+
+    /// Returns the usage of the model calls made so far by the execution,
+    /// including the calls of the child agents. It is available even if the execution
+    /// failed or was cancelled.
+    pub(crate) fn consumed_usage(&self) -> Usage {
+        self.consumed.lock().unwrap().clone()
+    }
+
+    /// Records the usage of a model call in the usage ledger, if enabled.
+    /// `model` is the model that served the call, if reported by the output.
+    async fn record_usage(&self, kind: UsageKind, model: Option<&str>, usage: &Usage) {
+        self.consumed.lock().unwrap().accumulate(usage);
+        let ledger = match &self.ledger {
+            Some(ledger) => ledger,
+            None => return,
+        };
+        if usage.input_tokens == 0 && usage.output_tokens == 0 {
+            return;
+        }
+
+        let agent: &str = self.base.path.as_ref();
+        let record = UsageRecord {
+            kind,
+            model: match (kind, model) {
+                (_, Some(model)) => model.to_string(),
+                (UsageKind::Completion, None) => self.model.completion_model_name(),
+                (UsageKind::Embedding, None) => self.model.embedding_model_name(),
+            },
+            caller: self.base.caller,
+            agent: agent.strip_prefix("A:").unwrap_or(agent).to_string(),
+            thread: self.base.meta.thread.clone(),
+            usage: usage.clone(),
+            cost: None,
+            timestamp: unix_ms(),
+        };
+        if let Err(err) = ledger.record(record).await {
+            log::warn!("failed to record usage: {}", err);
+        }
+    }
+
+    /// Runs a batch of tool and agent calls requested by the model in one round.
+    ///
+    /// Up to `tool_concurrency` calls run concurrently, the results are returned
+    /// in the model's original order. A failed call is returned with `failed_reason`.
+    /// If `stop_on_failure` is true, the first failure cancels the rest of the batch.
+    async fn run_tool_jobs(
+        &self,
+        jobs: Vec<(usize, ToolCallJob)>,
+        stop_on_failure: bool,
+    ) -> Vec<(usize, ToolCallResult)> {
+        let mut results: Vec<(usize, ToolCallResult)> = Vec::with_capacity(jobs.len());
+        {
+            let mut stream = futures::stream::iter(
+                jobs.into_iter()
+                    .map(|(idx, job)| async move { (idx, self.run_tool_job(job).await) }),
+            )
+            .buffer_unordered(self.options.tool_concurrency.max(1));
+
+            while let Some((idx, res)) = stream.next().await {
+                let res = res.unwrap_or_else(|err| ToolCallResult {
+                    content: Value::Null,
+                    resources: None,
+                    usage: Usage::default(),
+                    result: Value::Null,
+                    failed_reason: Some(err.to_string()),
+                    approval_id: None,
+                });
+                // a suspended agent call is not a failure
+                let failed = res.failed_reason.is_some() && res.approval_id.is_none();
+                results.push((idx, res));
+                if failed && stop_on_failure {
+                    // dropping the stream cancels the pending calls
+                    break;
+                }
+            }
+        }
+
+        results.sort_by_key(|(idx, _)| *idx);
+        results
+    }
+
+    async fn run_tool_job(&self, job: ToolCallJob) -> Result<ToolCallResult, BoxError> {
+        match job {
+            ToolCallJob::Tool(input) => {
+                let mut res = self.tool_call(input).await?;
+                let content: Value = if res.output.is_string() {
+                    res.output.clone()
+                } else {
+                    serde_json::to_string(&res.output)?.into()
+                };
+                let resources = res.resources.take();
+                Ok(ToolCallResult {
+                    content,
+                    resources,
+                    usage: res.usage.clone(),
+                    result: serde_json::to_value(&res)?,
+                    failed_reason: None,
+                    approval_id: None,
+                })
+            }
+            ToolCallJob::Agent(input) => {
+                // only the suspended local agents can be resumed by this engine
+                let local = !input.name.starts_with("RA_");
+                let mut res = self.agent_run(input).await?;
+                let resources = res.resources.take();
+                Ok(ToolCallResult {
+                    content: res.content.clone().into(),
+                    resources,
+                    usage: res.usage.clone(),
+                    failed_reason: res.failed_reason.clone(),
+                    approval_id: if local { res.approval_id.clone() } else { None },
+                    result: serde_json::to_value(&res)?,
+                })
+            }
+            ToolCallJob::Invalid(reason) => Err(reason.into()),
+        }
+    }
+
+    /// Stores the suspended completion loop as a [`PendingAction`], returns the approval id.
+    async fn suspend_completion(&self, state: SuspendedCompletion) -> Result<String, BoxError> {
+        let tool_calls: Vec<ToolCall> = state
+            .tool_calls
+            .iter()
+            .filter(|tc| state.pending.iter().any(|(id, _)| id == &tc.id))
+            .cloned()
+            .collect();
+        let action = PendingAction::new(
+            self.agent_name(),
+            self.base.caller,
+            state.meta.thread.clone(),
+            tool_calls,
+            unix_ms(),
+        );
+        let id = action.id.clone();
+        self.management.save_pending_action(action, state).await?;
+        Ok(id)
+    }
+
+    /// Returns the name of the agent of the context.
+    fn agent_name(&self) -> String {
+        let agent: &str = self.base.path.as_ref();
+        agent.strip_prefix("A:").unwrap_or(agent).to_string()
+    }
+
+    /// Resumes a completion loop suspended for approval after the [`PendingAction`] is reviewed.
+    /// The approved tool calls are executed, the rejected ones are reported to the model
+    /// as failures, then the loop continues.
+    pub(crate) async fn resume_completion(
+        &self,
+        action: &PendingAction,
+        mut state: SuspendedCompletion,
+    ) -> Result<AgentOutput, BoxError> {
+        let mut resources_out: Vec<Resource> = Vec::new();
+        let (ids, jobs): (Vec<String>, Vec<(usize, ToolCallJob)>) =
+            std::mem::take(&mut state.pending)
+                .into_iter()
+                .enumerate()
+                .map(|(idx, (id, input))| (id, (idx, ToolCallJob::Tool(input))))
+                .unzip();
+
+        let results = if action.status == ApprovalStatus::Approved {
+            let stop_on_failure = self.options.tool_error_policy == ToolErrorPolicy::FailFast;
+            self.run_tool_jobs(jobs, stop_on_failure).await
+        } else {
+            Vec::new()
+        };
+
+        for (idx, id) in ids.iter().enumerate() {
+            let content: Value = match results.iter().find(|(i, _)| *i == idx) {
+                Some((_, res)) => {
+                    state.usage.accumulate(&res.usage);
+                    match &res.failed_reason {
+                        None => {
+                            if let Some(tool) = state.tool_calls.iter_mut().find(|t| &t.id == id) {
+                                tool.result = Some(res.result.clone());
+                            }
+                            if let Some(resources) = &res.resources {
+                                resources_out.extend(resources.iter().cloned());
+                            }
+                            res.content.clone()
+                        }
+                        Some(reason) => {
+                            if state.failed_reason.is_none() {
+                                state.failed_reason = Some(reason.clone());
+                            }
+                            format!("Error: {}", reason).into()
+                        }
+                    }
+                }
+                None if action.status == ApprovalStatus::Approved => {
+                    // cancelled by the failure of another call
+                    continue;
+                }
+                None => match &action.reason {
+                    Some(reason) => format!("Error: the tool call was rejected: {}", reason).into(),
+                    None => "Error: the tool call was rejected".into(),
+                },
+            };
+
+            state.tool_messages.push(json!(Message {
+                role: "tool".to_string(),
+                content,
+                name: None,
+                tool_call_id: Some(id.clone()),
+                ..Default::default()
+            }));
+        }
+
+        if !resources_out.is_empty() {
+            state.resources = resources_out;
+        }
+        self.continue_completion(state).await
+    }
+
+    /// Continues a suspended completion loop with the tool messages of the suspended round.
+    /// The round is suspended again if it still has tool calls to approve.
+    pub(crate) async fn continue_completion(
+        &self,
+        mut state: SuspendedCompletion,
+    ) -> Result<AgentOutput, BoxError> {
+        if !state.pending.is_empty() {
+            let chat_history = state.chat_history.clone();
+            let tool_calls = state.tool_calls.clone();
+            let usage = state.usage.clone();
+            let id = self.suspend_completion(state).await?;
+            return Ok(AgentOutput {
+                failed_reason: Some(format!("{}: {}", APPROVAL_REQUIRED, id)),
+                approval_id: Some(id),
+                tool_calls: Some(tool_calls),
+                usage,
+                full_history: Some(chat_history),
+                ..Default::default()
+            });
+        }
+
+        // the tool messages must follow the order of the tool calls
+        let tool_calls = &state.tool_calls;
+        state.tool_messages.sort_by_key(|msg| {
+            let id = msg.get("tool_call_id").and_then(Value::as_str);
+            tool_calls.iter().position(|t| Some(t.id.as_str()) == id)
+        });
+        let mut chat_history = state.chat_history;
+        chat_history.append(&mut state.tool_messages);
+        let fail_fast = self.options.tool_error_policy == ToolErrorPolicy::FailFast;
+        if let Some(reason) = state.failed_reason.filter(|_| fail_fast) {
+            return Ok(AgentOutput {
+                failed_reason: Some(reason),
+                tool_calls: Some(state.tool_calls),
+                usage: state.usage,
+                full_history: Some(chat_history),
+                ..Default::default()
+            });
+        }
+
+        let req = CompletionRequest {
+            chat_history,
+            tools: state.tools,
+            tool_choice_required: state.tool_choice_required,
+            temperature: state.temperature,
+            max_tokens: state.max_tokens,
+            response_format: state.response_format,
+            stop: state.stop,
+            reasoning: state.reasoning,
+            ..Default::default()
+        };
+        self.completion_loop(
+            req,
+            Some(state.resources),
+            None,
+            state.tool_calls,
+            state.usage,
+        )
+        .await
+    }
+
+    /// The completion loop with automatic tool call handling,
+    /// see [`CompletionFeatures::completion`].
+    async fn completion_with(
+        &self,
+        req: CompletionRequest,
+        resources: Option<Vec<Resource>>,
+        tx: Option<&CompletionSender>,
+    ) -> Result<AgentOutput, BoxError> {
+        self.completion_loop(req, resources, tx, Vec::new(), Usage::default())
+            .await
+    }
+
+    /// The completion loop, starting with the tool calls and the usage of previous rounds.
+    async fn completion_loop(
         &self,
         mut req: CompletionRequest,
         resources: Option<Vec<Resource>>,
+        tx: Option<&CompletionSender>,
+        mut tool_calls_result: Vec<ToolCall>,
+        mut usage: Usage,
     ) -> Result<AgentOutput, BoxError> {
-        let mut tool_calls_result: Vec<ToolCall> = Vec::new();
-        let mut usage = Usage::default();
         let mut resources = resources.unwrap_or_default();
+        let feedback_errors = self.options.tool_error_policy != ToolErrorPolicy::FailFast;
+        let all_tools = req.tools.clone();
+        let mut tool_failures: BTreeMap<String, usize> = BTreeMap::new();
+        let budget = match &self.meta().budget {
+            Some(budget) => self.options.budget.min(budget),
+            None => self.options.budget.clone(),
+        };
+        let started = Instant::now();
+        // the timeout applies to the whole loop, including the tool calls
+        let deadline = budget
+            .timeout_ms
+            .map(|ms| started + Duration::from_millis(ms));
+        let timeout_reason = || {
+            format!(
+                "{}: timeout {}ms",
+                BUDGET_EXCEEDED,
+                budget.timeout_ms.unwrap_or_default()
+            )
+        };
+        let mut rounds: u64 = 0;
         loop {
             let mut resources_out: Vec<Resource> = Vec::new();
-            let mut output = self.model.completion(req.clone()).await?;
+            let completion = self.model_completion(req.clone(), tx);
+            let mut output = match within_deadline(deadline, completion).await {
+                Some(res) => res?,
+                None => {
+                    return Ok(AgentOutput {
+                        failed_reason: Some(timeout_reason()),
+                        tool_calls: if tool_calls_result.is_empty() {
+                            None
+                        } else {
+                            Some(tool_calls_result)
+                        },
+                        usage,
+                        // the history of a continued loop
+                        full_history: if req.prompt.is_empty() {
+                            Some(req.chat_history)
+                        } else {
+                            None
+                        },
+                        ..Default::default()
+                    });
+                }
+            };
+            rounds += 1;
             usage.accumulate(&output.usage);
             // automatically executes tools calls
             let mut tool_calls_continue: Vec<Value> = Vec::new();
+            // the tool calls that must be approved before running
+            let mut pending: Vec<(String, ToolInput<Value>)> = Vec::new();
+            // the tool call id and the approval id of the agent calls suspended for approval
+            let mut nested: Vec<(String, String)> = Vec::new();
             if let Some(tool_calls) = &mut output.tool_calls {
-                for tool in tool_calls.iter_mut() {
+                let mut jobs: Vec<(usize, ToolCallJob)> = Vec::new();
+                for (idx, tool) in tool_calls.iter().enumerate() {
                     if !req.tools.iter().any(|t| t.name == tool.name) {
                         // tool already called, skip
                         continue;
@@ -471,94 +1116,182 @@ impl CompletionFeatures for AgentCtx {
                     // remove called tool from req.tools
                     req.tools.retain(|t| t.name != tool.name);
                     if self.tools.contains(&tool.name) || tool.name.starts_with("RT_") {
-                        match self
-                            .tool_call(ToolInput {
+                        let job = match serde_json::from_str(&tool.args) {
+                            Ok(args) => ToolCallJob::Tool(ToolInput {
                                 name: tool.name.clone(),
-                                args: serde_json::from_str(&tool.args)?,
+                                args,
                                 resources: self
                                     .select_tool_resources(&tool.name, &mut resources)
                                     .await,
                                 meta: Some(self.meta().clone()),
-                            })
-                            .await
-                        {
-                            Ok(mut res) => {
-                                usage.accumulate(&res.usage);
-                                let content: Value = if res.output.is_string() {
-                                    res.output.clone()
-                                } else {
-                                    serde_json::to_string(&res.output)?.into()
-                                };
-
-                                tool_calls_continue.push(json!(Message {
-                                    role: "tool".to_string(),
-                                    content,
-                                    name: None,
-                                    tool_call_id: Some(tool.id.clone()),
-                                }));
-
-                                if let Some(resource) = res.resources {
-                                    resources_out.extend(resource);
-                                    res.resources = None;
-                                }
-
-                                tool.result = Some(serde_json::to_value(&res)?);
-                            }
-                            Err(err) => {
-                                output.failed_reason = Some(err.to_string());
-                                output.usage = usage;
-                                return Ok(output);
+                            }),
+                            Err(err) if feedback_errors => ToolCallJob::Invalid(format!(
+                                "tool {}, invalid args: {}",
+                                tool.name, err
+                            )),
+                            Err(err) => return Err(err.into()),
+                        };
+                        match job {
+                            ToolCallJob::Tool(input)
+                                if self.options.approval_tools.contains(&tool.name) =>
+                            {
+                                pending.push((tool.id.clone(), input));
                             }
+                            job => jobs.push((idx, job)),
                         }
                     } else if self.agents.contains(&tool.name)
                         || tool.name.starts_with("LA_")
                         || tool.name.starts_with("RA_")
                     {
-                        let args: AgentArgs = serde_json::from_str(&tool.args)?;
-                        match self
-                            .agent_run(AgentInput {
+                        let job = match serde_json::from_str::<AgentArgs>(&tool.args) {
+                            Ok(args) => ToolCallJob::Agent(AgentInput {
                                 name: tool.name.clone(),
                                 prompt: args.prompt,
                                 resources: self.agents.select_resources(&tool.name, &mut resources),
                                 meta: Some(self.meta().clone()),
-                            })
-                            .await
-                        {
-                            Ok(mut res) => {
-                                usage.accumulate(&res.usage);
-                                if res.failed_reason.is_some() {
-                                    output.failed_reason = res.failed_reason;
-                                    return Ok(output);
-                                }
-
-                                tool_calls_continue.push(json!(Message {
-                                    role: "tool".to_string(),
-                                    content: res.content.clone().into(),
-                                    name: None,
-                                    tool_call_id: Some(tool.id.clone()),
-                                }));
+                            }),
+                            Err(err) if feedback_errors => ToolCallJob::Invalid(format!(
+                                "agent {}, invalid args: {}",
+                                tool.name, err
+                            )),
+                            Err(err) => return Err(err.into()),
+                        };
+                        jobs.push((idx, job));
+                    }
+                    // ignore unknown tool
+                }
 
-                                if let Some(resource) = res.resources {
-                                    resources_out.extend(resource);
-                                    res.resources = None;
-                                }
+                // checks the budget before dispatching the tool calls
+                if !jobs.is_empty() || !pending.is_empty() {
+                    let elapsed_ms = started.elapsed().as_millis() as u64;
+                    if let Some(reason) = budget.check(rounds, &usage, elapsed_ms) {
+                        // returns the partial output, the tool calls are not executed
+                        tool_calls_result.append(tool_calls);
+                        output.failed_reason = Some(reason);
+                        output.tool_calls = Some(tool_calls_result);
+                        output.usage = usage;
+                        return Ok(output);
+                    }
+                }
 
-                                tool.result = Some(serde_json::to_value(&res)?);
+                let tool_jobs = self.run_tool_jobs(jobs, !feedback_errors);
+                let results = match within_deadline(deadline, tool_jobs).await {
+                    Some(results) => results,
+                    None => {
+                        // the unfinished tool calls are cancelled
+                        tool_calls_result.append(tool_calls);
+                        output.failed_reason = Some(timeout_reason());
+                        output.tool_calls = Some(tool_calls_result);
+                        output.usage = usage;
+                        return Ok(output);
+                    }
+                };
+                for (idx, res) in results {
+                    let tool = &mut tool_calls[idx];
+                    if let Some(approval_id) = res.approval_id {
+                        // the resumed output of the agent carries its full usage
+                        nested.push((tool.id.clone(), approval_id));
+                        continue;
+                    }
+                    usage.accumulate(&res.usage);
+                    let content = match res.failed_reason {
+                        None => {
+                            if let Some(resource) = res.resources {
+                                resources_out.extend(resource);
                             }
-                            Err(err) => {
-                                output.failed_reason = Some(err.to_string());
-                                output.usage = usage;
-                                return Ok(output);
+                            tool.result = Some(res.result);
+                            res.content
+                        }
+                        Some(reason) => {
+                            let failures = tool_failures.entry(tool.name.clone()).or_default();
+                            match self.options.tool_error_policy {
+                                ToolErrorPolicy::Feedback { max_retries }
+                                    if *failures < max_retries =>
+                                {
+                                    *failures += 1;
+                                    // let the model call the tool again with corrected arguments
+                                    if !req.tools.iter().any(|t| t.name == tool.name) {
+                                        if let Some(def) =
+                                            all_tools.iter().find(|t| t.name == tool.name)
+                                        {
+                                            req.tools.push(def.clone());
+                                        }
+                                    }
+                                    format!("Error: {}", reason).into()
+                                }
+                                _ => {
+                                    output.failed_reason = Some(reason);
+                                    output.usage = usage;
+                                    return Ok(output);
+                                }
                             }
                         }
-                    }
-                    // ignore unknown tool
+                    };
+
+                    tool_calls_continue.push(json!(Message {
+                        role: "tool".to_string(),
+                        content,
+                        name: None,
+                        tool_call_id: Some(tool.id.clone()),
+                        ..Default::default()
+                    }));
                 }
 
                 tool_calls_result.append(tool_calls);
             }
 
-            if tool_calls_continue.is_empty() {
+            if !pending.is_empty() || !nested.is_empty() {
+                let chat_history = output.full_history.take().unwrap_or_default();
+                if !resources_out.is_empty() {
+                    resources = resources_out;
+                }
+                let state = SuspendedCompletion {
+                    meta: self.meta().clone(),
+                    chat_history: chat_history.clone(),
+                    tools: req.tools,
+                    tool_choice_required: req.tool_choice_required,
+                    temperature: req.temperature,
+                    max_tokens: req.max_tokens,
+                    response_format: req.response_format,
+                    stop: req.stop,
+                    reasoning: req.reasoning,
+                    resources,
+                    tool_calls: tool_calls_result.clone(),
+                    usage: usage.clone(),
+                    pending,
+                    tool_messages: tool_calls_continue,
+                    failed_reason: None,
+                };
+                // the nested agent calls are resumed first, then the pending tool calls
+                let id = match nested.first() {
+                    Some((_, approval_id)) => {
+                        let approval_id = approval_id.clone();
+                        self.management
+                            .save_continuation(self.agent_name(), self.base.caller, state, nested)
+                            .await?;
+                        approval_id
+                    }
+                    None => self.suspend_completion(state).await?,
+                };
+                output.failed_reason = Some(format!("{}: {}", APPROVAL_REQUIRED, id));
+                output.approval_id = Some(id);
+                output.tool_calls = Some(tool_calls_result);
+                output.usage = usage;
+                output.full_history = Some(chat_history);
+                return Ok(output);
+            }
+
+            let budget_exceeded = if tool_calls_continue.is_empty() {
+                None
+            } else {
+                budget.check(rounds, &usage, started.elapsed().as_millis() as u64)
+            };
+
+            if tool_calls_continue.is_empty() || budget_exceeded.is_some() {
+                if budget_exceeded.is_some() {
+                    // returns the partial output
+                    output.failed_reason = budget_exceeded;
+                }
                 output.tool_calls = if tool_calls_result.is_empty() {
                     None
                 } else {
@@ -576,6 +1309,7 @@ impl CompletionFeatures for AgentCtx {
 
             req.system = None;
             req.documents.clear();
+            req.content_parts.clear();
             req.prompt = "".to_string();
             req.chat_history = output.full_history.unwrap_or_default();
             req.chat_history.append(&mut tool_calls_continue);
@@ -586,6 +1320,14 @@ impl CompletionFeatures for AgentCtx {
     }
 }
 
+/// Runs the future before the deadline, returns None if the deadline is reached first.
+async fn within_deadline<F: Future>(deadline: Option<Instant>, fut: F) -> Option<F::Output> {
+    match deadline {
+        None => Some(fut.await),
+        Some(deadline) => tokio::time::timeout_at(deadline.into(), fut).await.ok(),
+    }
+}
+
 impl EmbeddingFeatures for AgentCtx {
     /// Gets the number of dimensions for the embedding model.
     fn ndims(&self) -> usize {
@@ -603,7 +1345,9 @@ impl EmbeddingFeatures for AgentCtx {
         &self,
         texts: impl IntoIterator<Item = String> + Send,
     ) -> Result<(Vec<Embedding>, Usage), BoxError> {
-        self.model.embed(texts).await
+        let (embeddings, usage) = self.model.embed(texts).await?;
+        self.record_usage(UsageKind::Embedding, None, &usage).await;
+        Ok((embeddings, usage))
     }
 
     /// Generates an embedding for a single query text.
@@ -614,7 +1358,9 @@ impl EmbeddingFeatures for AgentCtx {
     /// # Returns
     /// Embedding vector for the input text.
     async fn embed_query(&self, text: &str) -> Result<(Embedding, Usage), BoxError> {
-        self.model.embed_query(text).await
+        let (embedding, usage) = self.model.embed_query(text).await?;
+        self.record_usage(UsageKind::Embedding, None, &usage).await;
+        Ok((embedding, usage))
     }
 }
 
@@ -952,8 +1698,13 @@ impl HttpFeatures for AgentCtx {
 
 #[cfg(test)]
 mod tests {
+    use super::*;
+    use crate::{engine::EngineBuilder, model::Model};
+    use anda_core::{ApprovalInput, Tool, gen_schema_for};
     use ciborium::from_reader;
     use ic_cose_types::to_cbor_bytes;
+    use schemars::JsonSchema;
+    use serde::Deserialize;
     use serde_json::json;
 
     #[test]
@@ -972,4 +1723,508 @@ mod tests {
         let val: serde_json::Value = from_reader(&data[..]).unwrap();
         assert_eq!(json, val);
     }
+
+    #[tokio::test(flavor = "current_thread")]
+    async fn test_completion_stream() {
+        let ctx = EngineBuilder::new()
+            .with_model(Model::mock_implemented())
+            .mock_ctx();
+        let req = CompletionRequest {
+            prompt: "Hello".to_string(),
+            ..Default::default()
+        };
+        let chunks: Vec<CompletionChunk> = ctx
+            .completion_stream(req, None)
+            .await
+            .unwrap()
+            .map(|c| c.unwrap())
+            .collect()
+            .await;
+        assert_eq!(chunks.len(), 3);
+        assert!(matches!(&chunks[0], CompletionChunk::Text(text) if text == "Hello"));
+        assert!(matches!(&chunks[1], CompletionChunk::Usage(_)));
+        assert!(matches!(&chunks[2], CompletionChunk::Done(output) if output.content == "Hello"));
+    }
+
+    struct DelayTool {
+        name: String,
+        delay_ms: u64,
+        fail: bool,
+    }
+
+    impl Tool<BaseCtx> for DelayTool {
+        type Args = Value;
+        type Output = String;
+
+        fn name(&self) -> String {
+            self.name.clone()
+        }
+
+        fn description(&self) -> String {
+            "Returns its name after a delay.".to_string()
+        }
+
+        fn definition(&self) -> FunctionDefinition {
+            FunctionDefinition {
+                name: self.name(),
+                description: self.description(),
+                parameters: json!({"type": "object", "properties": {}}),
+                strict: None,
+                output_schema: None,
+            }
+        }
+
+        async fn call(
+            &self,
+            _ctx: BaseCtx,
+            _args: Self::Args,
+            _resources: Option<Vec<Resource>>,
+        ) -> Result<ToolOutput<Self::Output>, BoxError> {
+            tokio::time::sleep(Duration::from_millis(self.delay_ms)).await;
+            if self.fail {
+                return Err("something went wrong".into());
+            }
+            Ok(ToolOutput::new(self.name.clone()))
+        }
+    }
+
+    #[tokio::test(flavor = "current_thread")]
+    async fn test_parallel_tool_calls() {
+        let ctx = EngineBuilder::new()
+            .with_model(Model::mock_implemented())
+            .with_tool_concurrency(2)
+            .register_tool(DelayTool {
+                name: "slow_tool".to_string(),
+                delay_ms: 50,
+                fail: false,
+            })
+            .unwrap()
+            .register_tool(DelayTool {
+                name: "fast_tool".to_string(),
+                delay_ms: 0,
+                fail: false,
+            })
+            .unwrap()
+            .mock_ctx();
+
+        let req = CompletionRequest {
+            prompt: "{}".to_string(),
+            tools: ctx.tool_definitions(Some(&["slow_tool", "fast_tool"])),
+            ..Default::default()
+        };
+        let output = ctx.completion(req, None).await.unwrap();
+        assert!(output.failed_reason.is_none());
+        let tool_calls = output.tool_calls.unwrap();
+        assert_eq!(tool_calls.len(), 2);
+        // the results keep the model's original order
+        for tool in tool_calls {
+            let res: ToolOutput<Value> = serde_json::from_value(tool.result.unwrap()).unwrap();
+            assert_eq!(res.output, json!(tool.name));
+        }
+    }
+
+    #[tokio::test(flavor = "current_thread")]
+    async fn test_tool_error_policy() {
+        let builder = || {
+            EngineBuilder::new()
+                .with_model(Model::mock_implemented())
+                .register_tool(DelayTool {
+                    name: "fail_tool".to_string(),
+                    delay_ms: 0,
+                    fail: true,
+                })
+                .unwrap()
+        };
+        let req = CompletionRequest {
+            prompt: "{}".to_string(),
+            tools: vec![
+                DelayTool {
+                    name: "fail_tool".to_string(),
+                    delay_ms: 0,
+                    fail: true,
+                }
+                .definition(),
+            ],
+            ..Default::default()
+        };
+
+        let ctx = builder().mock_ctx();
+        let output = ctx.completion(req.clone(), None).await.unwrap();
+        let reason = output.failed_reason.unwrap();
+        assert!(reason.contains("something went wrong"), "{}", reason);
+
+        let ctx = builder()
+            .with_tool_error_policy(ToolErrorPolicy::Feedback { max_retries: 1 })
+            .mock_ctx();
+        let output = ctx.completion(req, None).await.unwrap();
+        // the error is sent back to the model, and the mock model calls the tool again
+        // with the empty prompt as arguments, which is invalid.
+        let reason = output.failed_reason.unwrap();
+        assert!(reason.contains("invalid args"), "{}", reason);
+    }
+
+    #[derive(Deserialize, JsonSchema)]
+    struct SumArgs {
+        a: i64,
+        b: i64,
+    }
+
+    struct SumTool;
+
+    impl Tool<BaseCtx> for SumTool {
+        type Args = SumArgs;
+        type Output = i64;
+
+        fn name(&self) -> String {
+            "sum_tool".to_string()
+        }
+
+        fn description(&self) -> String {
+            "Returns the sum of a and b.".to_string()
+        }
+
+        fn definition(&self) -> FunctionDefinition {
+            FunctionDefinition {
+                name: self.name(),
+                description: self.description(),
+                parameters: gen_schema_for::<SumArgs>(),
+                strict: Some(true),
+                output_schema: Some(gen_schema_for::<Self::Output>()),
+            }
+        }
+
+        async fn call(
+            &self,
+            _ctx: BaseCtx,
+            args: Self::Args,
+            _resources: Option<Vec<Resource>>,
+        ) -> Result<ToolOutput<Self::Output>, BoxError> {
+            Ok(ToolOutput::new(args.a + args.b))
+        }
+    }
+
+    #[tokio::test(flavor = "current_thread")]
+    async fn test_tool_args_validation() {
+        let ctx = EngineBuilder::new()
+            .with_model(Model::mock_implemented())
+            .with_tool_error_policy(ToolErrorPolicy::Feedback { max_retries: 1 })
+            .register_tool(SumTool)
+            .unwrap()
+            .mock_ctx();
+
+        let res = ctx
+            .tool_call(ToolInput::new(
+                "sum_tool".to_string(),
+                json!({"a": 1, "b": 2}),
+            ))
+            .await
+            .unwrap();
+        assert_eq!(res.output, json!(3));
+
+        let err = ctx
+            .tool_call(ToolInput::new(
+                "sum_tool".to_string(),
+                json!({"a": 1, "b": "2", "c": 3}),
+            ))
+            .await
+            .unwrap_err();
+        let err = AndaError::from(err);
+        assert_eq!(err.code, anda_core::ErrorCode::InvalidArgs);
+        assert!(
+            err.message
+                .contains(r#"$.b: expected "integer", got string"#),
+            "{}",
+            err.message
+        );
+        assert!(
+            err.message.contains(r#"$: unknown property "c""#),
+            "{}",
+            err.message
+        );
+
+        // the validation error is sent back to the model
+        let req = CompletionRequest {
+            prompt: r#"{"a": 1}"#.to_string(),
+            tools: vec![SumTool.definition()],
+            ..Default::default()
+        };
+        let output = ctx.completion(req, None).await.unwrap();
+        let reason = output.failed_reason.unwrap();
+        assert!(
+            reason.contains(r#"$: missing required property "b""#),
+            "{}",
+            reason
+        );
+    }
+
+    #[test]
+    fn test_tool_output_schema() {
+        let def = SumTool.definition();
+        assert_eq!(
+            def.output_schema.as_ref().unwrap()["type"],
+            json!("integer")
+        );
+        assert!(def.validate_output(&json!(3)).is_ok());
+        let err = def.validate_output(&json!("3")).unwrap_err();
+        assert_eq!(err.code, anda_core::ErrorCode::Internal);
+        assert!(
+            err.message.contains(r#"$: expected "integer", got string"#),
+            "{}",
+            err.message
+        );
+
+        // the output schema is published with the engine information
+        let info = crate::context::Information {
+            id: candid::Principal::anonymous(),
+            name: "remote".to_string(),
+            description: "remote engine".to_string(),
+            agents: vec![],
+            tools: vec![anda_core::Function {
+                definition: def.clone(),
+                supported_resource_tags: vec![],
+            }],
+            endpoint: "https://example.com".to_string(),
+        };
+        let data = serde_json::to_vec(&info).unwrap();
+        let info: crate::context::Information = serde_json::from_slice(&data).unwrap();
+        assert_eq!(info.tools[0].definition.output_schema, def.output_schema);
+
+        let mut remote = RemoteEngines::new();
+        remote.engines.insert("remote".to_string(), info);
+        assert!(
+            remote
+                .validate_tool_output("https://example.com", "sum_tool", &json!(3))
+                .is_ok()
+        );
+        let err = remote
+            .validate_tool_output("https://example.com", "sum_tool", &json!({"sum": 3}))
+            .unwrap_err();
+        assert_eq!(err.code, anda_core::ErrorCode::Upstream);
+        // unknown tools are not validated
+        assert!(
+            remote
+                .validate_tool_output("https://example.com", "other_tool", &json!("3"))
+                .is_ok()
+        );
+
+        // the output schema is not sent to the model
+        let tool = crate::model::openai::ToolDefinition::from(def);
+        assert!(tool.function.output_schema.is_none());
+        assert!(json!(tool)["function"].get("output_schema").is_none());
+    }
+
+    #[tokio::test(flavor = "current_thread")]
+    async fn test_tool_approval() {
+        let controller = Principal::from_slice(&[1]);
+        let ctx = EngineBuilder::new()
+            .with_model(Model::mock_implemented())
+            .with_controller(controller)
+            .with_tool_approval(vec!["sum_tool".to_string()])
+            .register_tool(SumTool)
+            .unwrap()
+            .mock_ctx();
+        let req = CompletionRequest {
+            prompt: r#"{"a": 1, "b": 2}"#.to_string(),
+            tools: vec![SumTool.definition()],
+            ..Default::default()
+        };
+
+        // the tool call is suspended
+        let output = ctx.completion(req.clone(), None).await.unwrap();
+        let id = output.approval_id.unwrap();
+        assert_eq!(
+            output.failed_reason.unwrap(),
+            format!("{}: {}", APPROVAL_REQUIRED, id)
+        );
+        let tool_calls = output.tool_calls.unwrap();
+        assert_eq!(tool_calls.len(), 1);
+        assert!(tool_calls[0].result.is_none());
+
+        let action = ctx
+            .management
+            .get_pending_action(&controller, &id)
+            .await
+            .unwrap();
+        assert_eq!(action.status, ApprovalStatus::Pending);
+        assert_eq!(action.tool_calls[0].name, "sum_tool");
+
+        let input = ApprovalInput {
+            id: id.clone(),
+            approved: true,
+            reason: None,
+        };
+        let err = ctx
+            .management
+            .review_pending_action(&Principal::from_slice(&[2]), &input)
+            .await
+            .unwrap_err();
+        assert_eq!(
+            AndaError::from(err).code,
+            anda_core::ErrorCode::PermissionDenied
+        );
+
+        // approves and resumes the loop
+        let (action, state) = ctx
+            .management
+            .review_pending_action(&controller, &input)
+            .await
+            .unwrap();
+        assert_eq!(action.status, ApprovalStatus::Approved);
+        assert_eq!(action.reviewer, Some(controller));
+        let output = ctx.resume_completion(&action, state).await.unwrap();
+        assert!(output.failed_reason.is_none());
+        assert!(output.approval_id.is_none());
+        let tool_calls = output.tool_calls.unwrap();
+        assert_eq!(tool_calls[0].result.as_ref().unwrap()["output"], json!(3));
+
+        // an action can only be reviewed once
+        let err = ctx
+            .management
+            .review_pending_action(&controller, &input)
+            .await
+            .unwrap_err();
+        assert_eq!(AndaError::from(err).code, anda_core::ErrorCode::InvalidArgs);
+
+        // rejects the tool call
+        let output = ctx.completion(req, None).await.unwrap();
+        let input = ApprovalInput {
+            id: output.approval_id.unwrap(),
+            approved: false,
+            reason: Some("not now".to_string()),
+        };
+        let (action, state) = ctx
+            .management
+            .review_pending_action(&controller, &input)
+            .await
+            .unwrap();
+        assert_eq!(action.status, ApprovalStatus::Rejected);
+        let output = ctx.resume_completion(&action, state).await.unwrap();
+        assert!(output.failed_reason.is_none());
+        assert!(output.tool_calls.unwrap()[0].result.is_none());
+    }
+
+    #[tokio::test(flavor = "current_thread")]
+    async fn test_tool_approval_order() {
+        let controller = Principal::from_slice(&[1]);
+        let ctx = EngineBuilder::new()
+            .with_model(Model::mock_implemented())
+            .with_controller(controller)
+            .with_tool_approval(vec!["gated_tool".to_string()])
+            .register_tool(DelayTool {
+                name: "gated_tool".to_string(),
+                delay_ms: 0,
+                fail: true,
+            })
+            .unwrap()
+            .register_tool(DelayTool {
+                name: "free_tool".to_string(),
+                delay_ms: 0,
+                fail: false,
+            })
+            .unwrap()
+            .mock_ctx();
+        let req = CompletionRequest {
+            prompt: "{}".to_string(),
+            tools: ctx.tool_definitions(Some(&["gated_tool", "free_tool"])),
+            ..Default::default()
+        };
+
+        // the free tool runs, the gated tool is suspended
+        let output = ctx.completion(req, None).await.unwrap();
+        let input = ApprovalInput {
+            id: output.approval_id.unwrap(),
+            approved: true,
+            reason: None,
+        };
+        let (action, state) = ctx
+            .management
+            .review_pending_action(&controller, &input)
+            .await
+            .unwrap();
+
+        // the failure stops the loop with the full history,
+        // the tool messages keep the model's order
+        let output = ctx.resume_completion(&action, state).await.unwrap();
+        assert!(
+            output
+                .failed_reason
+                .unwrap()
+                .contains("something went wrong")
+        );
+        let history = output.full_history.unwrap();
+        let ids: Vec<&str> = history
+            .iter()
+            .filter_map(|msg| msg["tool_call_id"].as_str())
+            .collect();
+        assert_eq!(ids, vec!["gated_tool", "free_tool"]);
+    }
+
+    #[tokio::test(flavor = "current_thread")]
+    async fn test_execution_budget() {
+        let tool = DelayTool {
+            name: "delay_tool".to_string(),
+            delay_ms: 0,
+            fail: false,
+        };
+        let req = CompletionRequest {
+            prompt: "{}".to_string(),
+            tools: vec![tool.definition()],
+            ..Default::default()
+        };
+        let ctx = EngineBuilder::new()
+            .with_model(Model::mock_implemented())
+            .with_execution_budget(ExecutionBudget {
+                max_rounds: Some(1),
+                ..Default::default()
+            })
+            .register_tool(tool)
+            .unwrap()
+            .mock_ctx();
+
+        let output = ctx.completion(req.clone(), None).await.unwrap();
+        let reason = output.failed_reason.unwrap();
+        assert!(reason.starts_with(BUDGET_EXCEEDED), "{}", reason);
+        // the budget is checked before the tool calls are dispatched,
+        // the partial output keeps the tool calls that are not executed
+        let tool_calls = output.tool_calls.unwrap();
+        assert_eq!(tool_calls.len(), 1);
+        assert!(tool_calls[0].result.is_none());
+
+        // the timeout applies to the tool calls
+        let ctx = EngineBuilder::new()
+            .with_model(Model::mock_implemented())
+            .with_execution_budget(ExecutionBudget {
+                timeout_ms: Some(50),
+                ..Default::default()
+            })
+            .register_tool(DelayTool {
+                name: "delay_tool".to_string(),
+                delay_ms: 5000,
+                fail: false,
+            })
+            .unwrap()
+            .mock_ctx();
+        let started = Instant::now();
+        let output = ctx.completion(req, None).await.unwrap();
+        assert!(started.elapsed() < Duration::from_millis(5000));
+        let reason = output.failed_reason.unwrap();
+        assert_eq!(reason, format!("{}: timeout 50ms", BUDGET_EXCEEDED));
+        assert!(output.tool_calls.unwrap()[0].result.is_none());
+
+        let budget = ExecutionBudget {
+            max_rounds: Some(10),
+            timeout_ms: Some(1000),
+            ..Default::default()
+        }
+        .min(&ExecutionBudget {
+            max_rounds: Some(3),
+            max_tokens: Some(100),
+            ..Default::default()
+        });
+        assert_eq!(budget.max_rounds, Some(3));
+        assert_eq!(budget.max_tokens, Some(100));
+        assert_eq!(budget.timeout_ms, Some(1000));
+        assert!(budget.check(2, &Usage::default(), 0).is_none());
+        assert!(budget.check(3, &Usage::default(), 0).is_some());
+    }
 }
@@ -24,7 +24,7 @@
 //! - Time tracking for operation duration.
 
 use anda_core::{
-    ANONYMOUS, BaseContext, BoxError, CacheExpiry, CacheFeatures, CacheStoreFeatures,
+    ANONYMOUS, AndaError, BaseContext, BoxError, CacheExpiry, CacheFeatures, CacheStoreFeatures,
     CancellationToken, CanisterCaller, HttpFeatures, KeysFeatures, ObjectMeta, Path, PutMode,
     PutResult, RequestMeta, StateFeatures, StoreFeatures, ToolInput, ToolOutput, Value,
     derivation_path_with,
@@ -188,6 +188,7 @@ impl BaseCtx {
             engine: Some(target),
             thread: self.meta.thread.clone(),
             user: Some(self.name.clone()),
+            budget: self.meta.budget.clone(),
         }
     }
 }
@@ -206,13 +207,16 @@ impl BaseContext for BaseCtx {
         endpoint: &str,
         mut args: ToolInput<Value>,
     ) -> Result<ToolOutput<Value>, BoxError> {
-        let target = self
-            .remote
-            .get_id_by_endpoint(endpoint)
-            .ok_or_else(|| format!("remote engine endpoint {} not found", endpoint))?;
+        let target = self.remote.get_id_by_endpoint(endpoint).ok_or_else(|| {
+            AndaError::not_found(format!("remote engine endpoint {} not found", endpoint))
+        })?;
         args.meta = Some(self.self_meta(target));
-        self.https_signed_rpc(endpoint, "tool_call", &(&args,))
-            .await
+        let output: ToolOutput<Value> = self
+            .https_signed_rpc(endpoint, "tool_call", &(&args,))
+            .await?;
+        self.remote
+            .validate_tool_output(endpoint, &args.name, &output.output)?;
+        Ok(output)
     }
 }
 
@@ -1,6 +1,6 @@
 use anda_core::{
-    Agent, AgentContext, AgentInput, AgentOutput, BaseContext, BoxError, Function,
-    FunctionDefinition, HttpFeatures, Resource, Tool, ToolInput, ToolOutput, Value,
+    Agent, AgentContext, AgentInput, AgentOutput, AndaError, BaseContext, BoxError, ErrorCode,
+    Function, FunctionDefinition, HttpFeatures, Resource, Tool, ToolInput, ToolOutput, Value,
     select_resources, validate_function_name,
 };
 use candid::Principal;
@@ -141,6 +141,31 @@ impl RemoteEngines {
         None
     }
 
+    /// Retrieves the definition of a remote tool by the engine endpoint and the tool name.
+    pub fn get_tool_definition(&self, endpoint: &str, name: &str) -> Option<FunctionDefinition> {
+        self.engines
+            .values()
+            .find(|engine| engine.endpoint == endpoint)
+            .and_then(|engine| engine.tools.iter().find(|d| d.definition.name == name))
+            .map(|d| d.definition.clone())
+    }
+
+    /// Validates the output of a remote tool against its output schema, if the tool
+    /// is registered and publishes one. An invalid output is an upstream error.
+    pub fn validate_tool_output(
+        &self,
+        endpoint: &str,
+        name: &str,
+        output: &Value,
+    ) -> Result<(), AndaError> {
+        match self.get_tool_definition(endpoint, name) {
+            Some(def) => def
+                .validate_output(output)
+                .map_err(|err| AndaError::new(ErrorCode::Upstream, err.message)),
+            None => Ok(()),
+        }
+    }
+
     /// Retrieves a remote agent endpoint and name from a prefixed name.
     pub fn get_agent_endpoint(&self, prefixed_name: &str) -> Option<(String, String)> {
         if let Some(name) = prefixed_name.strip_prefix("RA_") {
@@ -30,8 +30,10 @@
 //! ```
 
 use anda_core::{
-    Agent, AgentInput, AgentOutput, AgentSet, BoxError, Function, Path, RequestMeta, ThreadMeta,
-    Tool, ToolInput, ToolOutput, ToolSet, Value, validate_function_name,
+    ANONYMOUS, APPROVAL_REQUIRED, Agent, AgentInput, AgentOutput, AgentSet, AndaError,
+    ApprovalInput, BoxError, ExecutionBudget, Function, Message, Path, PendingAction, RequestMeta,
+    ThreadId, ThreadMessages, ThreadMeta, Tool, ToolInput, ToolOutput, ToolSet, Usage, Value,
+    validate_function_name,
 };
 use async_trait::async_trait;
 use candid::Principal;
@@ -43,9 +45,14 @@ use std::{
 use tokio_util::sync::CancellationToken;
 
 use crate::{
-    context::{AgentCtx, BaseCtx, Web3Client, Web3SDK},
-    management::{Management, SYSTEM_PATH, ThreadMetaTool},
+    context::{
+        AgentCtx, BaseCtx, CompletionOptions, SuspendedCompletion, ToolErrorPolicy, Web3Client,
+        Web3SDK,
+    },
+    ledger::{PricingTable, UsageLedger, UsageQuery, UsageReport},
+    management::{AccessControl, Management, ManagersTool, SYSTEM_PATH, ThreadMetaTool},
     model::Model,
+    quota::{Limits, QuotaLimiter},
     store::Store,
 };
 
@@ -64,6 +71,9 @@ pub struct Engine {
     export_tools: BTreeSet<String>,
     hooks: Arc<Hooks>,
     management: Arc<Management>,
+    limiter: Arc<QuotaLimiter>,
+    ledger: Option<Arc<UsageLedger>>,
+    record_thread_history: bool,
 }
 
 /// Hook trait for customizing engine behavior.
@@ -200,6 +210,11 @@ impl Engine {
         self.default_agent.clone()
     }
 
+    /// Returns the system management of the engine, e.g. to update the access control rules.
+    pub fn management(&self) -> Arc<Management> {
+        self.management.clone()
+    }
+
     /// Cancels all tasks in the engine by triggering the cancellation token.
     pub fn cancel(&self) {
         self.ctx.base.cancellation_token.cancel()
@@ -220,7 +235,7 @@ impl Engine {
     ) -> Result<AgentCtx, BoxError> {
         let name = agent_name.to_ascii_lowercase();
         if !self.export_agents.contains(&name) || !self.ctx.agents.contains(&name) {
-            return Err(format!("agent {} not found", name).into());
+            return Err(AndaError::not_found(format!("agent {} not found", name)).into());
         }
 
         self.ctx.child_with(caller, &name, meta)
@@ -244,16 +259,22 @@ impl Engine {
             .ctx
             .agents
             .get(&input.name)
-            .ok_or_else(|| format!("agent {} not found", input.name))?;
+            .ok_or_else(|| AndaError::not_found(format!("agent {} not found", input.name)))?;
+        self.management.check_agent_access(&caller, &input.name)?;
         let mut meta = input.meta.unwrap_or_default();
         if meta.engine.is_some() && meta.engine != Some(self.id) {
-            return Err(format!(
+            return Err(AndaError::invalid_args(format!(
                 "invalid engine ID, expected {}, got {}",
                 self.id.to_text(),
                 meta.engine.unwrap().to_text()
-            )
+            ))
             .into());
         }
+        // the invalid requests are rejected before they are counted
+        let limited = self.is_limited(&caller);
+        if limited {
+            self.limiter.acquire(&caller, Some(&input.name)).await?;
+        }
 
         let thread = self
             .management
@@ -268,10 +289,33 @@ impl Engine {
         // should save the thread meta before running the agent
         self.management.save_thread_meta(thread).await?;
 
-        let output = agent
-            .run(ctx.clone(), input.prompt, input.resources)
-            .await?;
+        let prompt = input.prompt;
+        let res = tokio::select! {
+            res = agent.run(ctx.clone(), prompt.clone(), input.resources) => res,
+            _ = ctx.base.cancellation_token.cancelled() => {
+                Err(AndaError::cancelled(format!("agent {} cancelled", input.name)).into())
+            }
+        };
+        if limited {
+            self.limiter
+                .record_usage(&caller, Some(&input.name), &Self::run_usage(&ctx, &res))
+                .await;
+        }
+        let output = res?;
         let mut output = self.hooks.on_agent_end(&ctx, &input.name, output).await?;
+        if self.record_thread_history && output.failed_reason.is_none() {
+            if let Some(thread_id) = &meta.thread {
+                let messages = Self::run_messages(prompt, meta.user.clone(), &output);
+                // the run is done and charged, a failed recording does not fail it
+                if let Err(err) = self
+                    .management
+                    .append_thread_messages(&caller, thread_id, messages)
+                    .await
+                {
+                    log::warn!("failed to append messages to thread {}: {}", thread_id, err);
+                }
+            }
+        }
         output.thread = meta.thread;
         output.full_history = None; // clear full history
         Ok(output)
@@ -285,29 +329,265 @@ impl Engine {
         input: ToolInput<Value>,
     ) -> Result<ToolOutput<Value>, BoxError> {
         if !self.export_tools.contains(&input.name) || !self.ctx.tools.contains(&input.name) {
-            return Err(format!("tool {} not found", &input.name).into());
+            return Err(AndaError::not_found(format!("tool {} not found", &input.name)).into());
         }
 
         let tool = self
             .ctx
             .tools
             .get(&input.name)
-            .ok_or_else(|| format!("tool {} not found", &input.name))?;
+            .ok_or_else(|| AndaError::not_found(format!("tool {} not found", &input.name)))?;
+        self.management.check_tool_access(&caller, &input.name)?;
         let meta = input.meta.unwrap_or_default();
         if meta.engine.is_some() && meta.engine != Some(self.id) {
-            return Err(format!(
+            return Err(AndaError::invalid_args(format!(
                 "invalid engine ID, expected {}, got {}",
                 self.id.to_text(),
                 meta.engine.unwrap().to_text()
-            )
+            ))
             .into());
         }
+        let definition = tool.definition();
+        definition.validate_args(&input.args)?;
+        // the invalid requests are rejected before they are counted
+        let limited = self.is_limited(&caller);
+        if limited {
+            self.limiter.acquire(&caller, None).await?;
+        }
 
         let ctx = self.ctx.child_base_with(caller, &input.name, meta)?;
         self.hooks.on_tool_start(&ctx, &input.name).await?;
         let args = serde_json::to_string(&input.args)?;
-        let output = tool.call(ctx.clone(), args, input.resources).await?;
-        self.hooks.on_tool_end(&ctx, &input.name, output).await
+        // tools report the usage in the output only, a failed call has no usage to charge
+        let output = tokio::select! {
+            res = tool.call(ctx.clone(), args, input.resources) => res?,
+            _ = ctx.cancellation_token.cancelled() => {
+                return Err(AndaError::cancelled(format!("tool {} cancelled", input.name)).into());
+            }
+        };
+        if limited {
+            self.limiter
+                .record_usage(&caller, None, &output.usage)
+                .await;
+        }
+        let output = self.hooks.on_tool_end(&ctx, &input.name, output).await?;
+        // the output leaves the engine, it must match the published output schema
+        definition.validate_output(&output.output)?;
+        Ok(output)
+    }
+
+    /// Approves or rejects the tool calls suspended for approval, and resumes the suspended
+    /// completion loop of the agent. Returns the output of the resumed execution.
+    /// The caller must be the caller of the suspended execution, a manager or the controller.
+    pub async fn approve_action(
+        &self,
+        caller: Principal,
+        input: ApprovalInput,
+    ) -> Result<AgentOutput, BoxError> {
+        // the resumed execution is limited and charged as the caller of the suspended execution
+        let pending = self
+            .management
+            .get_pending_action(&caller, &input.id)
+            .await?;
+        let limited = self.is_limited(&pending.caller);
+        if limited {
+            self.limiter
+                .acquire(&pending.caller, Some(&pending.agent))
+                .await?;
+        }
+        let (action, state) = self
+            .management
+            .review_pending_action(&caller, &input)
+            .await?;
+        let mut usage = Usage::default();
+        let res = self.resume_action(&action, state, &mut usage).await;
+        if limited {
+            self.limiter
+                .record_usage(&action.caller, Some(&action.agent), &usage)
+                .await;
+        }
+        let (ctx, agent, output) = res?;
+        let mut output = self.hooks.on_agent_end(&ctx, &agent, output).await?;
+        output.thread = action.thread;
+        output.full_history = None; // clear full history
+        Ok(output)
+    }
+
+    /// Resumes the execution suspended by the reviewed action, then the completion loops
+    /// of the parent agents waiting for it, up to the top-level run.
+    /// Returns the context and the name of the last resumed agent with its output.
+    async fn resume_action(
+        &self,
+        action: &PendingAction,
+        state: SuspendedCompletion,
+        usage: &mut Usage,
+    ) -> Result<(AgentCtx, String, AgentOutput), BoxError> {
+        let mut ctx = self
+            .ctx
+            .child_with(action.caller, &action.agent, state.meta.clone())?;
+        let mut agent = action.agent.clone();
+        let res = tokio::select! {
+            res = ctx.resume_completion(action, state) => res,
+            _ = ctx.base.cancellation_token.cancelled() => {
+                Err(AndaError::cancelled(format!("action {} cancelled", action.id)).into())
+            }
+        };
+        usage.accumulate(&ctx.consumed_usage());
+        let mut output = res?;
+
+        let mut child = action.id.clone();
+        let mut parent = self.management.pending_action_parent(&action.id).await;
+        while let Some(parent_id) = parent {
+            if let Some(approval_id) = &output.approval_id {
+                // suspended again, the parent waits for the new approval
+                self.management
+                    .relink_nested(&parent_id, &child, approval_id)
+                    .await?;
+                break;
+            }
+
+            let continuation = self
+                .management
+                .complete_nested(&parent_id, &child, &output)
+                .await?;
+            if let Some((_, approval_id)) = continuation.waiting.values().next() {
+                // other nested executions of the parent are still suspended
+                output = AgentOutput {
+                    failed_reason: Some(format!("{}: {}", APPROVAL_REQUIRED, approval_id)),
+                    approval_id: Some(approval_id.clone()),
+                    usage: output.usage,
+                    ..Default::default()
+                };
+                break;
+            }
+
+            ctx = self.ctx.child_with(
+                continuation.caller,
+                &continuation.agent,
+                continuation.state.meta.clone(),
+            )?;
+            agent = continuation.agent;
+            child = continuation.id;
+            parent = continuation.parent;
+            let res = tokio::select! {
+                res = ctx.continue_completion(continuation.state) => res,
+                _ = ctx.base.cancellation_token.cancelled() => {
+                    Err(AndaError::cancelled(format!("action {} cancelled", action.id)).into())
+                }
+            };
+            usage.accumulate(&ctx.consumed_usage());
+            output = res?;
+        }
+        Ok((ctx, agent, output))
+    }
+
+    /// Returns the rate limits and usage quotas of the engine.
+    pub fn limits(&self) -> &Limits {
+        self.limiter.limits()
+    }
+
+    /// Queries the usage records of model calls by caller and time range.
+    /// Managers and the controller can query the records of all callers,
+    /// other callers can only query their own records.
+    pub async fn query_usage(
+        &self,
+        caller: &Principal,
+        mut query: UsageQuery,
+    ) -> Result<UsageReport, BoxError> {
+        let ledger = self
+            .ledger
+            .as_ref()
+            .ok_or_else(|| AndaError::not_found("usage ledger is not enabled"))?;
+        if caller == &ANONYMOUS {
+            return Err(AndaError::unauthenticated("anonymous caller can not query usage").into());
+        }
+        if !self.management.is_manager(caller) {
+            match query.caller {
+                None => query.caller = Some(*caller),
+                Some(c) if &c == caller => {}
+                Some(c) => {
+                    return Err(AndaError::permission_denied(format!(
+                        "caller {} can not query the usage of {}",
+                        caller.to_text(),
+                        c.to_text()
+                    ))
+                    .into());
+                }
+            }
+        }
+        Ok(ledger.query(&query).await?)
+    }
+
+    // Managers and the controller are not limited.
+    fn is_limited(&self, caller: &Principal) -> bool {
+        caller == &ANONYMOUS || !self.management.is_manager(caller)
+    }
+
+    // Returns the usage to charge for an agent execution. A failed or cancelled execution
+    // is charged with the usage of the model calls made before it stopped.
+    /// Returns the messages of the run to record in the thread history: the prompt,
+    /// the tool calls and tool results of the completion loop, and the final reply.
+    fn run_messages(prompt: String, user: Option<String>, output: &AgentOutput) -> Vec<Message> {
+        let mut messages = vec![Message {
+            role: "user".to_string(),
+            content: prompt.into(),
+            name: user,
+            ..Default::default()
+        }];
+        if let Some(history) = &output.full_history {
+            // the messages after the prompt
+            let start = history
+                .iter()
+                .rposition(|msg| msg.get("role").and_then(Value::as_str) == Some("user"))
+                .map(|i| i + 1)
+                .unwrap_or(history.len());
+            let mut call_ids: BTreeSet<String> = BTreeSet::new();
+            for msg in &history[start..] {
+                let msg: Message = match serde_json::from_value(msg.clone()) {
+                    Ok(msg) => msg,
+                    Err(_) => continue,
+                };
+                let keep = match (msg.role.as_str(), &msg.tool_calls, &msg.tool_call_id) {
+                    ("assistant", Some(tool_calls), _) => {
+                        call_ids.extend(
+                            tool_calls
+                                .iter()
+                                .filter_map(|tc| tc["id"].as_str().map(String::from)),
+                        );
+                        true
+                    }
+                    // a tool message without its tool call is rejected by the models
+                    ("tool", _, Some(id)) => call_ids.contains(id),
+                    _ => false,
+                };
+                if keep {
+                    messages.push(msg);
+                }
+            }
+        }
+        messages.push(Message {
+            role: "assistant".to_string(),
+            content: output.content.clone().into(),
+            ..Default::default()
+        });
+        messages
+    }
+
+    fn run_usage(ctx: &AgentCtx, res: &Result<AgentOutput, BoxError>) -> Usage {
+        match res {
+            Ok(output) => output.usage.clone(),
+            Err(_) => ctx.consumed_usage(),
+        }
+    }
+
+    /// Returns the tool calls suspended for approval.
+    /// The caller must be the caller of the suspended execution, a manager or the controller.
+    pub async fn pending_action(
+        &self,
+        caller: &Principal,
+        id: &str,
+    ) -> Result<PendingAction, BoxError> {
+        self.management.get_pending_action(caller, id).await
     }
 
     /// Returns function definitions for the specified agents.
@@ -322,6 +602,20 @@ impl Engine {
         self.ctx.tools.functions(names)
     }
 
+    /// Lists the messages of the thread page by page, from the latest to the oldest.
+    /// The caller must have permission to access the thread.
+    pub async fn thread_messages(
+        &self,
+        caller: &Principal,
+        thread_id: &ThreadId,
+        cursor: Option<u64>,
+        limit: usize,
+    ) -> Result<ThreadMessages, BoxError> {
+        self.management
+            .list_thread_messages(caller, thread_id, cursor, limit)
+            .await
+    }
+
     /// Returns information about the engine, including agent and tool definitions.
     pub fn information(&self) -> Information {
         Information {
@@ -364,6 +658,11 @@ pub struct EngineBuilder {
     export_agents: BTreeSet<String>,
     export_tools: BTreeSet<String>,
     controller: Principal,
+    completion_options: CompletionOptions,
+    record_thread_history: bool,
+    access_control: AccessControl,
+    limits: Limits,
+    usage_pricing: Option<PricingTable>,
 }
 
 impl Default for EngineBuilder {
@@ -391,6 +690,11 @@ impl EngineBuilder {
             export_agents: BTreeSet::new(),
             export_tools: BTreeSet::new(),
             controller: Principal::anonymous(),
+            completion_options: CompletionOptions::default(),
+            record_thread_history: false,
+            access_control: AccessControl::default(),
+            limits: Limits::default(),
+            usage_pricing: None,
         }
     }
 
@@ -435,6 +739,69 @@ impl EngineBuilder {
         self
     }
 
+    /// Sets the max number of tool and agent calls that run concurrently
+    /// when the model requests several calls in one completion round.
+    /// Defaults to `1`, the calls run one after another.
+    pub fn with_tool_concurrency(mut self, concurrency: usize) -> Self {
+        self.completion_options.tool_concurrency = concurrency.max(1);
+        self
+    }
+
+    /// Sets the policy for the failures of tool and agent calls in the completion loop.
+    /// Defaults to [`ToolErrorPolicy::FailFast`].
+    pub fn with_tool_error_policy(mut self, policy: ToolErrorPolicy) -> Self {
+        self.completion_options.tool_error_policy = policy;
+        self
+    }
+
+    /// Sets the execution budget of the automatic tool call loop in completions.
+    /// A request can tighten it by [`RequestMeta::budget`].
+    pub fn with_execution_budget(mut self, budget: ExecutionBudget) -> Self {
+        self.completion_options.budget = budget;
+        self
+    }
+
+    /// Sets the tools that the model can't call on its own in the completion loop, such as
+    /// transferring tokens. Their calls are suspended as a [`PendingAction`] and the agent
+    /// returns the approval id, the execution resumes after [`Engine::approve_action`].
+    /// The tools can still be called directly by [`Engine::tool_call`].
+    pub fn with_tool_approval(mut self, tools: Vec<String>) -> Self {
+        self.completion_options.approval_tools.extend(tools);
+        self
+    }
+
+    /// Enables the engine to record the user prompt and the assistant reply of every
+    /// successful agent run into the thread history. Agents can load it by
+    /// [`AgentCtx::load_thread_history`].
+    ///
+    /// The tool calls and tool results of the completion loop are recorded between them,
+    /// in the OpenAI style of the `full_history`.
+    pub fn with_thread_history(mut self, enable: bool) -> Self {
+        self.record_thread_history = enable;
+        self
+    }
+
+    /// Sets the initial access control rules for agents and tools.
+    /// The rules updated at runtime by managers are saved in the store and take precedence.
+    pub fn with_access_control(mut self, access_control: AccessControl) -> Self {
+        self.access_control = access_control;
+        self
+    }
+
+    /// Sets the per-caller rate limits and daily usage quotas for the engine and its agents.
+    /// The counters are saved in the store. Managers and the controller are not limited.
+    pub fn with_limits(mut self, limits: Limits) -> Self {
+        self.limits = limits;
+        self
+    }
+
+    /// Enables the usage ledger, which records every completion and embedding call
+    /// of the agents in the store. The cost is estimated with the pricing table.
+    pub fn with_usage_ledger(mut self, pricing: PricingTable) -> Self {
+        self.usage_pricing = Some(pricing);
+        self
+    }
+
     /// Sets the storage backend for the engine.
     pub fn with_store(mut self, store: Store) -> Self {
         self.store = store;
@@ -577,9 +944,17 @@ impl EngineBuilder {
             Arc::new(remote),
         );
         let management = Management::new(&ctx, self.controller);
+        management.init_access_control(self.access_control).await?;
+        management.init_managers().await?;
         let management = Arc::new(management);
+        let limiter = Arc::new(QuotaLimiter::new(&ctx, self.limits));
+        limiter.spawn_flush(ctx.cancellation_token.clone());
+        let ledger = self
+            .usage_pricing
+            .map(|pricing| Arc::new(UsageLedger::new(&ctx, pricing)));
         let thread_meta_tool = ThreadMetaTool::new(management.clone());
         self.tools.add(thread_meta_tool)?;
+        self.tools.add(ManagersTool::new(management.clone()))?;
 
         let tools = Arc::new(self.tools);
         let agents = Arc::new(self.agents);
@@ -588,7 +963,9 @@ impl EngineBuilder {
             self.model,
             tools.clone(),
             agents.clone(),
+            self.completion_options,
             management.clone(),
+            ledger.clone(),
         );
 
         let meta = RequestMeta::default();
@@ -612,6 +989,9 @@ impl EngineBuilder {
             export_tools: self.export_tools,
             hooks: self.hooks,
             management,
+            limiter,
+            ledger,
+            record_thread_history: self.record_thread_history,
         })
     }
 
@@ -642,7 +1022,39 @@ impl EngineBuilder {
             self.model,
             Arc::new(self.tools),
             Arc::new(self.agents),
+            self.completion_options,
             management,
+            None,
         )
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn test_run_messages() {
+        let output = AgentOutput {
+            content: "It is sunny.".to_string(),
+            full_history: Some(vec![
+                json!({"role": "system", "content": "You are a bot."}),
+                json!({"role": "user", "content": "Weather?"}),
+                json!({"role": "assistant", "content": "", "tool_calls": [
+                    {"id": "call_1", "type": "function", "function": {"name": "weather", "arguments": "{}"}}
+                ]}),
+                json!({"role": "tool", "content": "sunny", "tool_call_id": "call_1"}),
+                json!({"role": "tool", "content": "orphan", "tool_call_id": "call_2"}),
+                json!({"role": "assistant", "content": "It is sunny."}),
+            ]),
+            ..Default::default()
+        };
+        let messages = Engine::run_messages("Weather?".to_string(), None, &output);
+        let roles: Vec<&str> = messages.iter().map(|m| m.role.as_str()).collect();
+        assert_eq!(roles, vec!["user", "assistant", "tool", "assistant"]);
+        assert_eq!(messages[1].tool_calls.as_ref().unwrap()[0]["id"], "call_1");
+        assert_eq!(messages[2].tool_call_id.as_deref(), Some("call_1"));
+        assert_eq!(messages[3].content, json!("It is sunny."));
+    }
+}
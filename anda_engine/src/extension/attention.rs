@@ -20,7 +20,7 @@
 //! and interactions in complex environments. It provides both automated decision-making and
 //! configurable parameters for fine-tuning behavior.
 
-use anda_core::{AgentOutput, CompletionFeatures, CompletionRequest, Message, evaluate_tokens};
+use anda_core::{AgentOutput, CompletionFeatures, CompletionRequest, Message};
 
 static HIGH_REWARD_COMMAND: &str = "HIGH_REWARD";
 static MEDIUM_REWARD_COMMAND: &str = "MEDIUM_REWARD";
@@ -117,7 +117,7 @@ impl Attention {
         content: &str,
     ) -> ContentQuality {
         // Ignore very short content
-        if evaluate_tokens(content) < self.min_content_tokens {
+        if ctx.count_tokens(content) < self.min_content_tokens {
             return ContentQuality::Ignore;
         }
 
@@ -189,7 +189,7 @@ impl Attention {
         }
 
         // Ignore very short messages
-        if evaluate_tokens(&content) < self.min_prompt_tokens {
+        if ctx.count_tokens(&content) < self.min_prompt_tokens {
             return AttentionCommand::Ignore;
         }
 
@@ -296,7 +296,7 @@ impl Attention {
         content: &str,
     ) -> bool {
         // Ignore very short content
-        if evaluate_tokens(content) < self.min_prompt_tokens {
+        if ctx.count_tokens(content) < self.min_prompt_tokens {
             return false;
         }
 
@@ -336,7 +336,7 @@ impl Attention {
     /// Boolean indicating whether to retweet the post
     pub async fn should_retweet(&self, ctx: &impl CompletionFeatures, content: &str) -> bool {
         // Ignore very short content
-        if evaluate_tokens(content) < self.min_prompt_tokens {
+        if ctx.count_tokens(content) < self.min_prompt_tokens {
             return false;
         }
 
@@ -374,7 +374,7 @@ impl Attention {
     /// Boolean indicating whether to quote the post
     pub async fn should_quote(&self, ctx: &impl CompletionFeatures, content: &str) -> bool {
         // Ignore very short content
-        if evaluate_tokens(content) < self.min_prompt_tokens {
+        if ctx.count_tokens(content) < self.min_prompt_tokens {
             return false;
         }
 
@@ -30,7 +30,7 @@
 use anda_core::{
     Agent, AgentContext, AgentOutput, BoxError, CacheExpiry, CacheFeatures, CompletionFeatures,
     CompletionRequest, Documents, Embedding, EmbeddingFeatures, Knowledge, KnowledgeFeatures,
-    KnowledgeInput, Message, Resource, StateFeatures, VectorSearchFeatures, evaluate_tokens,
+    KnowledgeInput, Message, Resource, StateFeatures, VectorSearchFeatures, select_resources,
 };
 use ic_cose_types::to_cbor_bytes;
 use serde::{Deserialize, Serialize};
@@ -42,10 +42,12 @@ use super::{
     segmenter::DocumentSegmenter,
 };
 
-use crate::{context::AgentCtx, store::MAX_STORE_OBJECT_SIZE};
+use crate::{context::AgentCtx, model::batch::split_batches, store::MAX_STORE_OBJECT_SIZE};
 
 const MAX_CHAT_HISTORY: usize = 42;
 const CHAT_HISTORY_TTI: Duration = Duration::from_secs(3600 * 24 * 7);
+// the max tokens of the segments embedded in one batch of the knowledge task
+const KNOWLEDGE_BATCH_TOKENS: usize = 8192;
 
 /// Represents a character definition with attributes, traits, and behaviors
 /// Contains all necessary information to define an AI agent's personality and capabilities.
@@ -369,7 +371,7 @@ impl<K: KnowledgeFeatures + VectorSearchFeatures> CharacterAgent<K> {
         F: CompletionFeatures + StateFeatures,
     {
         // Ignore very short content
-        if evaluate_tokens(content) < 5 {
+        if ctx.count_tokens(content) < 5 {
             return false;
         }
 
@@ -415,18 +417,23 @@ where
         self.character.learning.tools.clone()
     }
 
+    /// Accepts image resources, they are sent to the model with the user message
+    fn supported_resource_tags(&self) -> Vec<String> {
+        vec!["image".to_string()]
+    }
+
     /// Main execution method for handling user interactions
     /// # Arguments
     /// * `ctx` - Agent context containing environment and state
     /// * `prompt` - User input message
-    /// * `resources` - Optional additional resources (currently unused)
+    /// * `resources` - Optional image resources to send to the model
     /// # Returns
     /// Result with AgentOutput containing response or error
     async fn run(
         &self,
         ctx: AgentCtx,
         prompt: String,
-        _resources: Option<Vec<Resource>>,
+        resources: Option<Vec<Resource>>,
     ) -> Result<AgentOutput, BoxError> {
         // read chat history from store
         let meta = ctx.meta();
@@ -442,7 +449,7 @@ where
         };
 
         let mut content_quality = ContentQuality::Ignore;
-        if evaluate_tokens(&prompt) <= self.attention.min_content_tokens {
+        if ctx.count_tokens(&prompt) <= self.attention.min_content_tokens {
             let recent_messages: Vec<Message> = vec![];
             match self
                 .attention
@@ -494,9 +501,14 @@ where
             // save high quality content to knowledge store in background
             tokio::spawn(async move {
                 let (docs, _) = segmenter.segment(&ctx, &content).await?;
-                let mut vecs: Vec<Embedding> = Vec::with_capacity(docs.segments.len());
-                for texts in docs.segments.chunks(16) {
-                    match ctx.embed(texts.to_owned()).await {
+                // a failed batch does not discard the segments of the other batches
+                let batches =
+                    split_batches(docs.segments, None, Some(KNOWLEDGE_BATCH_TOKENS), |text| {
+                        ctx.count_tokens(text)
+                    });
+                let mut vecs: Vec<Embedding> = Vec::new();
+                for texts in batches {
+                    match ctx.embed(texts).await {
                         Ok((embeddings, _)) => vecs.extend(embeddings),
                         Err(err) => {
                             log::error!("Failed to embed segments: {}", err);
@@ -538,6 +550,11 @@ where
             .to_request(prompt, meta.user.clone())
             .append_documents(knowledges)
             .append_tools(tools);
+        if let Some(mut resources) = resources {
+            if let Some(mut images) = select_resources(&mut resources, &["image"]) {
+                req = req.append_resources(&mut images);
+            }
+        }
 
         if let Some((user, chat)) = &mut chat_history {
             req.chat_history = chat.clone().into_iter().map(|m| json!(m)).collect();
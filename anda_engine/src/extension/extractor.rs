@@ -131,6 +131,7 @@ where
             description: self.description(),
             parameters: self.schema.clone(),
             strict: Some(true),
+            output_schema: Some(self.schema.clone()),
         }
     }
 
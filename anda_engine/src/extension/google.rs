@@ -179,6 +179,7 @@ impl Tool<BaseCtx> for GoogleSearchTool {
             description: self.description(),
             parameters: self.schema.clone(),
             strict: Some(true),
+            output_schema: Some(gen_schema_for::<Self::Output>()),
         }
     }
 
@@ -26,9 +26,7 @@
 //! let segments = segmenter.segment(&ctx, long_document).await?;
 //! ```
 
-use anda_core::{
-    Agent, AgentOutput, BoxError, CompletionFeatures, Resource, Tool, ToolCall, evaluate_tokens,
-};
+use anda_core::{Agent, AgentOutput, BoxError, CompletionFeatures, Resource, Tool, ToolCall};
 use schemars::JsonSchema;
 
 use super::extractor::{Deserialize, Extractor, Serialize, SubmitTool};
@@ -105,7 +103,7 @@ impl DocumentSegmenter {
         ctx: &impl CompletionFeatures,
         content: &str,
     ) -> Result<(SegmentOutput, AgentOutput), BoxError> {
-        if evaluate_tokens(content) <= self.segment_tokens {
+        if ctx.count_tokens(content) <= self.segment_tokens {
             let res = SegmentOutput {
                 segments: vec![content.to_string()],
             };
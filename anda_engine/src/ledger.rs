@@ -0,0 +1,605 @@
+//! Usage accounting ledger of the engine.
+//!
+//! Every completion and embedding call of the agents is recorded with the model name,
+//! caller, agent, thread and token usage. The cost is estimated with a [`PricingTable`].
+//! The records are kept in the [`Store`](crate::store::Store) under [`SYSTEM_PATH`],
+//! partitioned by UTC day, and can be queried by caller and time range.
+//! Each record is appended as its own object `UL_{day}/{timestamp}_{caller}_{xid}.cbor`,
+//! so recording never rewrites the records of the day and concurrent calls don't contend.
+//! The totals of every caller in a day are kept up to date in `UR_{day}/{caller}.cbor`,
+//! a query reads them for the whole days in its range and reads the records only for
+//! the partial days at the ends, or when the records are requested.
+//!
+//! # Example
+//! ```rust,ignore
+//! let pricing = PricingTable {
+//!     models: BTreeMap::from([(
+//!         "gpt-4o".to_string(),
+//!         ModelPrice { input: 2.5, cached_input: Some(1.25), output: 10.0, reasoning: None },
+//!     )]),
+//! };
+//! let engine = EngineBuilder::new().with_usage_ledger(pricing);
+//! ```
+
+use anda_core::{AndaError, BoxError, Path, PutMode, StoreFeatures, ThreadId, Usage};
+use candid::Principal;
+use ciborium::from_reader;
+use futures::stream::{self, StreamExt};
+use ic_cose_types::to_cbor_bytes;
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::{BTreeMap, HashMap},
+    sync::{Arc, RwLock},
+};
+use structured_logger::unix_ms;
+use tokio::sync::Mutex;
+
+use crate::{context::BaseCtx, management::SYSTEM_PATH};
+
+const DAY_MS: u64 = 24 * 3600 * 1000;
+
+/// Max days of a usage query.
+pub const MAX_QUERY_DAYS: u64 = 92;
+
+/// Max number of records returned by a usage query.
+pub const MAX_QUERY_RECORDS: usize = 1000;
+
+/// Max number of concurrent store reads of a usage query.
+const QUERY_CONCURRENCY: usize = 16;
+
+/// Prices of a model in USD per million tokens.
+#[derive(Debug, Clone, Default, PartialEq, Deserialize, Serialize)]
+pub struct ModelPrice {
+    /// Price of the input tokens.
+    pub input: f64,
+
+    /// Price of the input tokens read from the prompt cache, `input` is used if None.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub cached_input: Option<f64>,
+
+    /// Price of the output tokens.
+    pub output: f64,
+
+    /// Price of the reasoning tokens, `output` is used if None.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub reasoning: Option<f64>,
+}
+
+impl ModelPrice {
+    /// Computes the cost in USD of the usage.
+    pub fn cost(&self, usage: &Usage) -> f64 {
+        let cached = usage.cached_input_tokens.min(usage.input_tokens);
+        let reasoning = usage.reasoning_tokens.min(usage.output_tokens);
+        let cost = (usage.input_tokens - cached) as f64 * self.input
+            + cached as f64 * self.cached_input.unwrap_or(self.input)
+            + (usage.output_tokens - reasoning) as f64 * self.output
+            + reasoning as f64 * self.reasoning.unwrap_or(self.output);
+        cost / 1_000_000.0
+    }
+}
+
+/// Prices of models, keyed by the model name.
+#[derive(Debug, Clone, Default, PartialEq, Deserialize, Serialize)]
+pub struct PricingTable {
+    pub models: BTreeMap<String, ModelPrice>,
+}
+
+impl PricingTable {
+    /// Returns the price of the model.
+    /// If there is no exact match, the longest model name that prefixes it is used,
+    /// e.g. "gpt-4o" for "gpt-4o-2024-08-06".
+    pub fn price_of(&self, model: &str) -> Option<&ModelPrice> {
+        if let Some(price) = self.models.get(model) {
+            return Some(price);
+        }
+        self.models
+            .iter()
+            .filter(|(name, _)| !name.is_empty() && model.starts_with(name.as_str()))
+            .max_by_key(|(name, _)| name.len())
+            .map(|(_, price)| price)
+    }
+
+    /// Computes the cost in USD of the usage, None if the model has no price.
+    pub fn cost(&self, model: &str, usage: &Usage) -> Option<f64> {
+        self.price_of(model).map(|price| price.cost(usage))
+    }
+}
+
+/// The kind of a model call.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum UsageKind {
+    Completion,
+    Embedding,
+}
+
+/// Represents the usage of a model call.
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct UsageRecord {
+    /// The kind of the call.
+    pub kind: UsageKind,
+
+    /// The name of the model, may be empty if the model does not provide it.
+    pub model: String,
+
+    /// The caller of the agent.
+    pub caller: Principal,
+
+    /// The agent that made the call.
+    pub agent: String,
+
+    /// The thread of the call.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub thread: Option<ThreadId>,
+
+    /// The token usage of the call.
+    pub usage: Usage,
+
+    /// The estimated cost in USD, None if the model has no price.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub cost: Option<f64>,
+
+    /// The timestamp of the call.
+    pub timestamp: u64,
+}
+
+/// Represents a query of usage records.
+#[derive(Debug, Clone, Default, Deserialize, Serialize)]
+pub struct UsageQuery {
+    /// Filters the records by caller, all callers if None.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub caller: Option<Principal>,
+
+    /// The start timestamp (inclusive) in milliseconds.
+    pub start_ms: u64,
+
+    /// The end timestamp (exclusive) in milliseconds, now if None.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub end_ms: Option<u64>,
+
+    /// Max number of records to return, the latest records are returned.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub limit: Option<usize>,
+
+    /// Returns the matched records if true, only the totals are returned if false.
+    #[serde(default)]
+    pub details: bool,
+}
+
+/// Represents the result of a usage query.
+#[derive(Debug, Clone, Default, Deserialize, Serialize)]
+pub struct UsageReport {
+    /// The matched records, from the latest to the oldest, up to the limit.
+    /// Empty if the records are not requested by [`UsageQuery::details`].
+    pub records: Vec<UsageRecord>,
+
+    /// The total usage of all matched records.
+    pub usage: Usage,
+
+    /// The total estimated cost in USD of all matched records.
+    pub cost: f64,
+
+    /// The number of all matched records.
+    pub total: u64,
+}
+
+/// The totals of the usage records of a caller in a day.
+#[derive(Debug, Clone, Default, Deserialize, Serialize)]
+struct UsageRollup {
+    usage: Usage,
+    cost: f64,
+    total: u64,
+}
+
+/// Records the usage of model calls in the store.
+pub struct UsageLedger {
+    ctx: BaseCtx,
+    pricing: PricingTable,
+    // Serializes the updating of the rollup of a caller in a day, keyed by the day and caller.
+    rollup_locks: RwLock<HashMap<(u64, String), Arc<Mutex<()>>>>,
+}
+
+impl UsageLedger {
+    pub(crate) fn new(ctx: &BaseCtx, pricing: PricingTable) -> Self {
+        Self {
+            ctx: ctx
+                .child(SYSTEM_PATH.to_string())
+                .expect("failed to create system context"),
+            pricing,
+            rollup_locks: RwLock::new(HashMap::new()),
+        }
+    }
+
+    /// Returns the pricing table.
+    pub fn pricing(&self) -> &PricingTable {
+        &self.pricing
+    }
+
+    /// Appends the record to the ledger, the cost is estimated with the pricing table.
+    /// The totals of the caller in the day are updated too.
+    pub async fn record(&self, mut record: UsageRecord) -> Result<(), BoxError> {
+        record.cost = self.pricing.cost(&record.model, &record.usage);
+        let day = record.timestamp / DAY_MS;
+        let caller = record.caller.to_text();
+        let name = format!("{:013}_{}_{}.cbor", record.timestamp, caller, xid::new());
+        let path = Self::day_path(day).child(name.as_str());
+        self.ctx
+            .store_put(&path, PutMode::Create, to_cbor_bytes(&record).into())
+            .await?;
+
+        let lock = self.rollup_lock(day, &caller);
+        let _guard = lock.lock().await;
+        let path = Self::rollup_path(day).child(format!("{}.cbor", caller).as_str());
+        let mut rollup = match self.get_rollup(&path).await {
+            Ok(rollup) => rollup,
+            Err(err) if is_not_found(&err) => UsageRollup::default(),
+            Err(err) => return Err(err),
+        };
+        rollup.usage.accumulate(&record.usage);
+        rollup.cost += record.cost.unwrap_or_default();
+        rollup.total += 1;
+        self.ctx
+            .store_put(&path, PutMode::Overwrite, to_cbor_bytes(&rollup).into())
+            .await?;
+        Ok(())
+    }
+
+    /// Queries the usage records by caller and time range.
+    pub async fn query(&self, query: &UsageQuery) -> Result<UsageReport, AndaError> {
+        let end_ms = query.end_ms.unwrap_or_else(unix_ms);
+        if end_ms <= query.start_ms {
+            return Ok(UsageReport::default());
+        }
+
+        let start_day = query.start_ms / DAY_MS;
+        let end_day = (end_ms - 1) / DAY_MS;
+        if end_day - start_day >= MAX_QUERY_DAYS {
+            return Err(AndaError::invalid_args(format!(
+                "time range of the usage query should be within {} days",
+                MAX_QUERY_DAYS
+            )));
+        }
+
+        let limit = if query.details {
+            query
+                .limit
+                .unwrap_or(MAX_QUERY_RECORDS)
+                .min(MAX_QUERY_RECORDS)
+        } else {
+            0
+        };
+        let caller = query.caller.map(|caller| caller.to_text());
+        let mut report = UsageReport::default();
+        for day in (start_day..=end_day).rev() {
+            let whole_day = query.start_ms <= day * DAY_MS && end_ms >= (day + 1) * DAY_MS;
+            if whole_day {
+                // the totals of the whole day are read from the rollups
+                let prefix = Self::rollup_path(day);
+                let metas = self.ctx.store_list(Some(&prefix), &prefix).await?;
+                for meta in metas {
+                    let name = match meta.location.filename() {
+                        Some(name) => name,
+                        None => continue,
+                    };
+                    if caller
+                        .as_ref()
+                        .is_some_and(|caller| name.strip_suffix(".cbor") != Some(caller.as_str()))
+                    {
+                        continue;
+                    }
+                    match self.get_rollup(&prefix.child(name)).await {
+                        Ok(rollup) => {
+                            report.usage.accumulate(&rollup.usage);
+                            report.cost += rollup.cost;
+                            report.total += rollup.total;
+                        }
+                        Err(err) => log::warn!("failed to read usage rollup: {}", err),
+                    }
+                }
+                if report.records.len() >= limit {
+                    continue;
+                }
+            }
+
+            let prefix = Self::day_path(day);
+            let metas = self.ctx.store_list(Some(&prefix), &prefix).await?;
+            // filters the records by the object names before reading them
+            let mut names: Vec<(u64, String)> = metas
+                .iter()
+                .filter_map(|meta| {
+                    let name = meta.location.filename()?;
+                    let (timestamp, record_caller) = parse_record_name(name)?;
+                    if timestamp < query.start_ms || timestamp >= end_ms {
+                        return None;
+                    }
+                    if caller
+                        .as_ref()
+                        .is_some_and(|caller| caller != record_caller)
+                    {
+                        return None;
+                    }
+                    Some((timestamp, name.to_string()))
+                })
+                .collect();
+            // from the latest to the oldest
+            names.sort_unstable_by(|a, b| b.cmp(a));
+            if whole_day {
+                // only the requested records are read
+                names.truncate(limit - report.records.len());
+            }
+
+            let mut records = stream::iter(
+                names
+                    .into_iter()
+                    .map(|(_, name)| self.get_record(prefix.child(name.as_str()))),
+            )
+            .buffered(QUERY_CONCURRENCY);
+            while let Some(record) = records.next().await {
+                let record = match record {
+                    Ok(record) => record,
+                    Err(err) => {
+                        log::warn!("failed to read usage record: {}", err);
+                        continue;
+                    }
+                };
+
+                if !whole_day {
+                    report.usage.accumulate(&record.usage);
+                    report.cost += record.cost.unwrap_or_default();
+                    report.total += 1;
+                }
+                if report.records.len() < limit {
+                    report.records.push(record);
+                }
+            }
+        }
+
+        Ok(report)
+    }
+
+    async fn get_record(&self, path: Path) -> Result<UsageRecord, BoxError> {
+        let (data, _) = self.ctx.store_get(&path).await?;
+        Ok(from_reader(&data[..])?)
+    }
+
+    async fn get_rollup(&self, path: &Path) -> Result<UsageRollup, BoxError> {
+        let (data, _) = self.ctx.store_get(path).await?;
+        Ok(from_reader(&data[..])?)
+    }
+
+    /// Returns the lock of the rollup, the unused locks of the earlier days are dropped.
+    fn rollup_lock(&self, day: u64, caller: &str) -> Arc<Mutex<()>> {
+        let key = (day, caller.to_string());
+        if let Some(lock) = self.rollup_locks.read().expect("RwLock poisoned").get(&key) {
+            return lock.clone();
+        }
+        let mut locks = self.rollup_locks.write().expect("RwLock poisoned");
+        if !locks.contains_key(&key) {
+            locks.retain(|(d, _), lock| *d + 1 >= day || Arc::strong_count(lock) > 1);
+        }
+        locks.entry(key).or_default().clone()
+    }
+
+    fn day_path(day: u64) -> Path {
+        Path::from(format!("UL_{}", day))
+    }
+
+    fn rollup_path(day: u64) -> Path {
+        Path::from(format!("UR_{}", day))
+    }
+}
+
+fn is_not_found(err: &BoxError) -> bool {
+    matches!(
+        err.downcast_ref::<object_store::Error>(),
+        Some(object_store::Error::NotFound { .. })
+    )
+}
+
+/// Parses the timestamp and caller from the object name of a record.
+fn parse_record_name(name: &str) -> Option<(u64, &str)> {
+    let mut parts = name.strip_suffix(".cbor")?.splitn(3, '_');
+    let timestamp = parts.next()?.parse().ok()?;
+    let caller = parts.next()?;
+    Some((timestamp, caller))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::engine::EngineBuilder;
+
+    fn assert_cost(cost: f64, expected: f64) {
+        assert!(
+            (cost - expected).abs() < 1e-9,
+            "cost {} != expected {}",
+            cost,
+            expected
+        );
+    }
+
+    #[test]
+    fn test_pricing_table() {
+        let pricing = PricingTable {
+            models: BTreeMap::from([
+                (
+                    "gpt-4o".to_string(),
+                    ModelPrice {
+                        input: 2.0,
+                        cached_input: Some(1.0),
+                        output: 10.0,
+                        reasoning: None,
+                    },
+                ),
+                (
+                    "gpt-4o-mini".to_string(),
+                    ModelPrice {
+                        input: 0.2,
+                        cached_input: None,
+                        output: 0.6,
+                        reasoning: None,
+                    },
+                ),
+            ]),
+        };
+        let usage = Usage {
+            input_tokens: 1_000_000,
+            output_tokens: 100_000,
+            requests: 1,
+            cached_input_tokens: 500_000,
+            reasoning_tokens: 50_000,
+        };
+        assert_cost(pricing.cost("gpt-4o", &usage).unwrap(), 2.5);
+        assert_cost(pricing.cost("gpt-4o-2024-08-06", &usage).unwrap(), 2.5);
+        assert_cost(
+            pricing.cost("gpt-4o-mini-2024-07-18", &usage).unwrap(),
+            0.26,
+        );
+        assert_eq!(pricing.cost("deepseek-chat", &usage), None);
+
+        let price = ModelPrice {
+            input: 1.0,
+            cached_input: None,
+            output: 2.0,
+            reasoning: Some(4.0),
+        };
+        assert_cost(price.cost(&usage), 1.3);
+    }
+
+    #[tokio::test(flavor = "current_thread")]
+    async fn test_usage_ledger() {
+        let ctx = EngineBuilder::new().mock_ctx();
+        let pricing = PricingTable {
+            models: BTreeMap::from([(
+                "m1".to_string(),
+                ModelPrice {
+                    input: 1.0,
+                    cached_input: None,
+                    output: 2.0,
+                    reasoning: None,
+                },
+            )]),
+        };
+        let ledger = UsageLedger::new(&ctx.base, pricing);
+        let caller = Principal::from_slice(&[1]);
+        let other = Principal::from_slice(&[2]);
+        let now_ms = unix_ms();
+        let usage = Usage {
+            input_tokens: 1000,
+            output_tokens: 500,
+            requests: 1,
+            ..Default::default()
+        };
+        for (i, (caller, model)) in [(caller, "m1"), (other, "m1"), (caller, "m2")]
+            .into_iter()
+            .enumerate()
+        {
+            ledger
+                .record(UsageRecord {
+                    kind: UsageKind::Completion,
+                    model: model.to_string(),
+                    caller,
+                    agent: "assistant".to_string(),
+                    thread: None,
+                    usage: usage.clone(),
+                    cost: None,
+                    timestamp: now_ms - DAY_MS + i as u64,
+                })
+                .await
+                .unwrap();
+        }
+
+        let report = ledger
+            .query(&UsageQuery {
+                caller: Some(caller),
+                start_ms: now_ms - DAY_MS,
+                details: true,
+                ..Default::default()
+            })
+            .await
+            .unwrap();
+        assert_eq!(report.total, 2);
+        assert_eq!(report.records[0].model, "m2");
+        assert_eq!(report.records[0].cost, None);
+        assert_cost(report.records[1].cost.unwrap(), 0.002);
+        assert_eq!(report.usage.input_tokens, 2000);
+        assert_cost(report.cost, 0.002);
+
+        let report = ledger
+            .query(&UsageQuery {
+                caller: None,
+                start_ms: now_ms - DAY_MS,
+                end_ms: Some(now_ms - DAY_MS + 2),
+                limit: Some(1),
+                details: true,
+            })
+            .await
+            .unwrap();
+        assert_eq!(report.total, 2);
+        assert_eq!(report.records.len(), 1);
+        assert_eq!(report.records[0].caller, other);
+
+        let report = ledger
+            .query(&UsageQuery {
+                caller: None,
+                start_ms: now_ms,
+                ..Default::default()
+            })
+            .await
+            .unwrap();
+        assert_eq!(report.total, 0);
+
+        let err = ledger
+            .query(&UsageQuery {
+                caller: None,
+                start_ms: now_ms - DAY_MS * MAX_QUERY_DAYS,
+                ..Default::default()
+            })
+            .await
+            .unwrap_err();
+        assert_eq!(err.code, anda_core::ErrorCode::InvalidArgs);
+
+        // the totals of the whole days are read from the rollups
+        let day_ms = (now_ms / DAY_MS - 3) * DAY_MS;
+        for i in 0..3 {
+            ledger
+                .record(UsageRecord {
+                    kind: UsageKind::Embedding,
+                    model: "m1".to_string(),
+                    caller: if i == 0 { other } else { caller },
+                    agent: "assistant".to_string(),
+                    thread: None,
+                    usage: usage.clone(),
+                    cost: None,
+                    timestamp: day_ms + i,
+                })
+                .await
+                .unwrap();
+        }
+        let query = UsageQuery {
+            caller: Some(caller),
+            start_ms: day_ms,
+            end_ms: Some(day_ms + DAY_MS),
+            ..Default::default()
+        };
+        let report = ledger.query(&query).await.unwrap();
+        assert_eq!(report.total, 2);
+        assert!(report.records.is_empty());
+        assert_eq!(report.usage.input_tokens, 2000);
+        assert_cost(report.cost, 0.004);
+
+        let report = ledger
+            .query(&UsageQuery {
+                caller: None,
+                limit: Some(1),
+                details: true,
+                ..query
+            })
+            .await
+            .unwrap();
+        assert_eq!(report.total, 3);
+        assert_eq!(report.records.len(), 1);
+        assert_eq!(report.records[0].timestamp, day_ms + 2);
+        assert_cost(report.cost, 0.006);
+    }
+}
@@ -3,8 +3,10 @@ use rand::Rng;
 pub mod context;
 pub mod engine;
 pub mod extension;
+pub mod ledger;
 pub mod management;
 pub mod model;
+pub mod quota;
 pub mod store;
 
 /// Gets current unix timestamp in milliseconds
@@ -1,24 +1,107 @@
 use anda_core::{
-    BaseContext, BoxError, CacheStoreFeatures, FunctionDefinition, MyThreads, RequestMeta,
-    Resource, StateFeatures, ThreadId, ThreadMeta, Tool, ToolInput, ToolOutput, Value,
-    gen_schema_for,
+    ANONYMOUS, AgentOutput, AndaError, ApprovalInput, ApprovalStatus, BaseContext, BoxError,
+    CacheStoreFeatures, FunctionDefinition, Message, MyThreads, PendingAction, RequestMeta,
+    Resource, StateFeatures, ThreadId, ThreadMessage, ThreadMessages, ThreadMeta, Tool, ToolInput,
+    ToolOutput, Value, gen_schema_for,
 };
 use candid::Principal;
 use schemars::JsonSchema;
 use serde::{Deserialize, Serialize};
 use serde_json::json;
-use std::{collections::BTreeSet, str::FromStr, sync::Arc};
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    str::FromStr,
+    sync::{Arc, RwLock},
+};
 use structured_logger::unix_ms;
+use tokio::sync::Mutex;
 
-use crate::context::BaseCtx;
+use crate::context::{BaseCtx, Continuation, SuspendedCompletion};
 
 pub static SYSTEM_PATH: &str = "_";
 
+/// The number of messages stored in one page of the thread history.
+const THREAD_MESSAGES_PAGE_SIZE: u64 = 100;
+
+/// The path of the access control rules under [`SYSTEM_PATH`].
+const ACCESS_CONTROL_PATH: &str = "ACL.cbor";
+
+/// The path of the managers under [`SYSTEM_PATH`].
+const MANAGERS_PATH: &str = "MANAGERS.cbor";
+
+/// Access rule for an agent or a tool.
+/// The controller and managers of the engine can always access.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Deserialize, Serialize)]
+pub struct AccessRule {
+    /// Only the controller and managers can access, other fields are ignored.
+    #[serde(default)]
+    pub manager_only: bool,
+
+    /// Whether anonymous callers can access.
+    #[serde(default)]
+    pub allow_anonymous: bool,
+
+    /// The principals that can access. If empty, all authenticated callers can access.
+    #[serde(default)]
+    pub allowed: BTreeSet<Principal>,
+}
+
+impl AccessRule {
+    /// Returns true if the caller (not a manager) is allowed by the rule.
+    pub fn allows(&self, caller: &Principal) -> bool {
+        if self.manager_only {
+            return false;
+        }
+        if caller == &ANONYMOUS {
+            return self.allow_anonymous;
+        }
+        self.allowed.is_empty() || self.allowed.contains(caller)
+    }
+}
+
+/// Access control rules for the agents and tools of the engine.
+/// Agents and tools without a rule use the default rule,
+/// all callers can access them if there is no default rule.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Deserialize, Serialize)]
+pub struct AccessControl {
+    /// The default rule for agents and tools without a rule.
+    #[serde(default)]
+    pub default: Option<AccessRule>,
+
+    /// The rules for agents, keyed by the lowercase agent name.
+    #[serde(default)]
+    pub agents: BTreeMap<String, AccessRule>,
+
+    /// The rules for tools, keyed by the tool name.
+    #[serde(default)]
+    pub tools: BTreeMap<String, AccessRule>,
+}
+
 /// Represents system management tools for the Anda engine.
 pub struct Management {
     ctx: BaseCtx,
     controller: Principal,
-    managers: BTreeSet<Principal>,
+    managers: RwLock<BTreeSet<Principal>>,
+    // Serializes the updating of managers.
+    managers_lock: Mutex<()>,
+    // Serializes the appending of thread messages.
+    messages_lock: Mutex<()>,
+    // Serializes the reviewing of pending actions, an action can only be reviewed once.
+    approvals_lock: Mutex<()>,
+    access_control: RwLock<AccessControl>,
+    // Serializes the updating of access control rules.
+    access_control_lock: Mutex<()>,
+}
+
+/// A pending action with the state of the suspended completion loop.
+/// The state is dropped once the action is reviewed.
+#[derive(Debug, Clone, Deserialize, Serialize)]
+struct PendingActionRecord {
+    action: PendingAction,
+    state: Option<SuspendedCompletion>,
+    /// The continuation waiting for the resumed execution, if it is a nested agent call.
+    #[serde(default)]
+    parent: Option<String>,
 }
 
 impl Management {
@@ -32,11 +115,17 @@ impl Management {
                         engine: None,
                         thread: None,
                         user: Some(ctx.name.clone()),
+                        budget: None,
                     },
                 )
                 .expect("failed to create system context"),
             controller,
-            managers: BTreeSet::new(),
+            managers: RwLock::new(BTreeSet::new()),
+            managers_lock: Mutex::new(()),
+            messages_lock: Mutex::new(()),
+            approvals_lock: Mutex::new(()),
+            access_control: RwLock::new(AccessControl::default()),
+            access_control_lock: Mutex::new(()),
         }
     }
 
@@ -44,10 +133,32 @@ impl Management {
         format!("TH_{}.meta.cbor", thread_id.xid())
     }
 
+    fn thread_messages_path(thread_id: &ThreadId) -> String {
+        format!("TH_{}.msgs.cbor", thread_id.xid())
+    }
+
+    fn thread_messages_page_path(thread_id: &ThreadId, page: u64) -> String {
+        format!("TH_{}.msgs_{}.cbor", thread_id.xid(), page)
+    }
+
     fn my_threads_path(id: &Principal) -> String {
         format!("MYTH_{}.cbor", id.to_text())
     }
 
+    fn pending_action_path(id: &str) -> Result<String, AndaError> {
+        if id.is_empty() || !id.chars().all(|c| c.is_ascii_alphanumeric()) {
+            return Err(AndaError::invalid_args(format!(
+                "invalid approval id {:?}",
+                id
+            )));
+        }
+        Ok(format!("PA_{}.cbor", id))
+    }
+
+    fn continuation_path(id: &str) -> String {
+        format!("PC_{}.cbor", id)
+    }
+
     /// Returns true if the caller is the controller of the engine.
     pub fn is_controller(&self, caller: &Principal) -> bool {
         caller == &self.controller
@@ -55,7 +166,252 @@ impl Management {
 
     /// Returns true if the caller is the controller or a manager of the engine.
     pub fn is_manager(&self, caller: &Principal) -> bool {
-        caller == &self.controller || self.managers.contains(caller)
+        caller == &self.controller
+            || self
+                .managers
+                .read()
+                .expect("managers lock poisoned")
+                .contains(caller)
+    }
+
+    /// Loads the managers saved under [`SYSTEM_PATH`].
+    pub(crate) async fn init_managers(&self) -> Result<(), BoxError> {
+        if let Ok(managers) = self
+            .ctx
+            .cache_store_get::<BTreeSet<Principal>>(MANAGERS_PATH)
+            .await
+        {
+            *self.managers.write().expect("managers lock poisoned") = managers;
+        }
+        Ok(())
+    }
+
+    /// Returns the managers of the engine, not including the controller.
+    /// Only the controller and managers can list them.
+    pub fn managers(&self, caller: &Principal) -> Result<BTreeSet<Principal>, AndaError> {
+        if caller == &ANONYMOUS || !self.is_manager(caller) {
+            return Err(AndaError::permission_denied(format!(
+                "caller {} does not have permission to list managers",
+                caller.to_text()
+            )));
+        }
+        Ok(self
+            .managers
+            .read()
+            .expect("managers lock poisoned")
+            .clone())
+    }
+
+    /// Adds managers to the engine. Only the controller can add managers.
+    /// Returns the updated managers.
+    pub async fn add_managers(
+        &self,
+        caller: &Principal,
+        managers: Vec<Principal>,
+    ) -> Result<BTreeSet<Principal>, BoxError> {
+        if managers.contains(&ANONYMOUS) {
+            return Err(AndaError::invalid_args("anonymous can not be a manager").into());
+        }
+        self.update_managers(caller, |m| m.extend(managers)).await
+    }
+
+    /// Removes managers from the engine. Only the controller can remove managers.
+    /// Returns the updated managers.
+    pub async fn remove_managers(
+        &self,
+        caller: &Principal,
+        managers: Vec<Principal>,
+    ) -> Result<BTreeSet<Principal>, BoxError> {
+        self.update_managers(caller, |m| m.retain(|p| !managers.contains(p)))
+            .await
+    }
+
+    async fn update_managers<F>(
+        &self,
+        caller: &Principal,
+        f: F,
+    ) -> Result<BTreeSet<Principal>, BoxError>
+    where
+        F: FnOnce(&mut BTreeSet<Principal>),
+    {
+        // the controller may be anonymous if not set
+        if caller == &ANONYMOUS || !self.is_controller(caller) {
+            return Err(AndaError::permission_denied(format!(
+                "caller {} does not have permission to update managers",
+                caller.to_text()
+            ))
+            .into());
+        }
+
+        let _guard = self.managers_lock.lock().await;
+        let mut managers = self
+            .managers
+            .read()
+            .expect("managers lock poisoned")
+            .clone();
+        f(&mut managers);
+        self.ctx
+            .cache_store_set_and_wait(MANAGERS_PATH, managers.clone())
+            .await?;
+        *self.managers.write().expect("managers lock poisoned") = managers.clone();
+        Ok(managers)
+    }
+
+    /// Loads the access control rules saved under [`SYSTEM_PATH`].
+    /// The given rules are used if no rules were saved.
+    pub(crate) async fn init_access_control(&self, acl: AccessControl) -> Result<(), BoxError> {
+        let acl = self
+            .ctx
+            .cache_store_get::<AccessControl>(ACCESS_CONTROL_PATH)
+            .await
+            .unwrap_or(acl);
+        *self
+            .access_control
+            .write()
+            .expect("access control lock poisoned") = acl;
+        Ok(())
+    }
+
+    /// Returns the access control rules.
+    pub fn access_control(&self) -> AccessControl {
+        self.access_control
+            .read()
+            .expect("access control lock poisoned")
+            .clone()
+    }
+
+    /// Checks whether the caller can run the agent.
+    pub fn check_agent_access(&self, caller: &Principal, agent: &str) -> Result<(), AndaError> {
+        let acl = self
+            .access_control
+            .read()
+            .expect("access control lock poisoned");
+        self.check_access(
+            caller,
+            acl.agents.get(agent).or(acl.default.as_ref()),
+            "agent",
+            agent,
+        )
+    }
+
+    /// Checks whether the caller can call the tool.
+    pub fn check_tool_access(&self, caller: &Principal, tool: &str) -> Result<(), AndaError> {
+        let acl = self
+            .access_control
+            .read()
+            .expect("access control lock poisoned");
+        self.check_access(
+            caller,
+            acl.tools.get(tool).or(acl.default.as_ref()),
+            "tool",
+            tool,
+        )
+    }
+
+    /// Sets or removes (if `rule` is None) the access rule for the agent.
+    /// Only the controller and managers can update the rules.
+    pub async fn set_agent_rule(
+        &self,
+        caller: &Principal,
+        agent: &str,
+        rule: Option<AccessRule>,
+    ) -> Result<(), BoxError> {
+        let agent = agent.to_ascii_lowercase();
+        self.update_access_control(caller, |acl| match rule {
+            Some(rule) => {
+                acl.agents.insert(agent, rule);
+            }
+            None => {
+                acl.agents.remove(&agent);
+            }
+        })
+        .await
+    }
+
+    /// Sets or removes (if `rule` is None) the access rule for the tool.
+    /// Only the controller and managers can update the rules.
+    pub async fn set_tool_rule(
+        &self,
+        caller: &Principal,
+        tool: &str,
+        rule: Option<AccessRule>,
+    ) -> Result<(), BoxError> {
+        self.update_access_control(caller, |acl| match rule {
+            Some(rule) => {
+                acl.tools.insert(tool.to_string(), rule);
+            }
+            None => {
+                acl.tools.remove(tool);
+            }
+        })
+        .await
+    }
+
+    /// Sets or removes (if `rule` is None) the default access rule.
+    /// Only the controller and managers can update the rules.
+    pub async fn set_default_rule(
+        &self,
+        caller: &Principal,
+        rule: Option<AccessRule>,
+    ) -> Result<(), BoxError> {
+        self.update_access_control(caller, |acl| acl.default = rule)
+            .await
+    }
+
+    async fn update_access_control<F>(&self, caller: &Principal, f: F) -> Result<(), BoxError>
+    where
+        F: FnOnce(&mut AccessControl),
+    {
+        if caller == &ANONYMOUS || !self.is_manager(caller) {
+            return Err(AndaError::permission_denied(format!(
+                "caller {} does not have permission to update access control rules",
+                caller.to_text()
+            ))
+            .into());
+        }
+
+        let _guard = self.access_control_lock.lock().await;
+        let mut acl = self.access_control();
+        f(&mut acl);
+        self.ctx
+            .cache_store_set_and_wait(ACCESS_CONTROL_PATH, acl.clone())
+            .await?;
+        *self
+            .access_control
+            .write()
+            .expect("access control lock poisoned") = acl;
+        Ok(())
+    }
+
+    fn check_access(
+        &self,
+        caller: &Principal,
+        rule: Option<&AccessRule>,
+        kind: &str,
+        name: &str,
+    ) -> Result<(), AndaError> {
+        let rule = match rule {
+            None => return Ok(()),
+            Some(rule) => rule,
+        };
+        // the controller may be anonymous if not set
+        if (caller != &ANONYMOUS && self.is_manager(caller)) || rule.allows(caller) {
+            return Ok(());
+        }
+
+        if caller == &ANONYMOUS {
+            Err(AndaError::unauthenticated(format!(
+                "anonymous caller can not access the {} {}",
+                kind, name
+            )))
+        } else {
+            Err(AndaError::permission_denied(format!(
+                "caller {} does not have permission to access the {} {}",
+                caller.to_text(),
+                kind,
+                name
+            )))
+        }
     }
 
     /// Retrieves the thread metadata from the cache store.
@@ -83,11 +439,11 @@ impl Management {
                         if thread.has_permission(caller) {
                             Ok(thread)
                         } else {
-                            Err(format!(
+                            Err(AndaError::permission_denied(format!(
                                 "caller {} does not have permission to access the thread {}",
                                 caller.to_text(),
                                 id
-                            )
+                            ))
                             .into())
                         }
                     }
@@ -147,13 +503,14 @@ impl Management {
         match self.ctx.cache_store_get::<ThreadMeta>(&thread_key).await {
             Ok(thread) => {
                 if thread.has_permission(caller) {
+                    self.delete_messages(thread_id).await?;
                     self.ctx.cache_store_delete(&thread_key).await
                 } else {
-                    Err(format!(
+                    Err(AndaError::permission_denied(format!(
                         "caller {} does not have permission to delete the thread {}",
                         caller.to_text(),
                         thread_id
-                    )
+                    ))
                     .into())
                 }
             }
@@ -177,6 +534,402 @@ impl Management {
             .cache_store_set_and_wait(&my_threads_key, threads)
             .await
     }
+
+    /// Appends messages to the history of the thread.
+    /// Returns the total number of messages in the thread.
+    pub async fn append_thread_messages(
+        &self,
+        caller: &Principal,
+        thread_id: &ThreadId,
+        messages: Vec<Message>,
+    ) -> Result<u64, BoxError> {
+        self.check_thread_permission(caller, thread_id, "append messages to")
+            .await?;
+
+        let _guard = self.messages_lock.lock().await;
+        let mut total = self.messages_total(thread_id).await;
+        let now_ms = unix_ms();
+        let mut page = total / THREAD_MESSAGES_PAGE_SIZE;
+        let mut page_messages = self.load_messages_page(thread_id, page).await;
+        for message in messages {
+            if total / THREAD_MESSAGES_PAGE_SIZE != page {
+                let key = Self::thread_messages_page_path(thread_id, page);
+                self.ctx
+                    .cache_store_set_and_wait(&key, std::mem::take(&mut page_messages))
+                    .await?;
+                page = total / THREAD_MESSAGES_PAGE_SIZE;
+            }
+
+            page_messages.push(ThreadMessage {
+                seq: total,
+                caller: *caller,
+                message,
+                timestamp: now_ms,
+            });
+            total += 1;
+        }
+
+        let key = Self::thread_messages_page_path(thread_id, page);
+        self.ctx
+            .cache_store_set_and_wait(&key, page_messages)
+            .await?;
+        self.ctx
+            .cache_store_set_and_wait(&Self::thread_messages_path(thread_id), total)
+            .await?;
+        Ok(total)
+    }
+
+    /// Loads the latest messages of the thread, at most `max_messages`, in chronological order.
+    /// It can be used as the `chat_history` of a [`anda_core::CompletionRequest`].
+    /// Tool messages at the beginning of the window are dropped because their tool calls
+    /// are out of the window.
+    pub async fn thread_history(
+        &self,
+        caller: &Principal,
+        thread_id: &ThreadId,
+        max_messages: usize,
+    ) -> Result<Vec<Message>, BoxError> {
+        self.check_thread_permission(caller, thread_id, "access")
+            .await?;
+
+        let total = self.messages_total(thread_id).await;
+        let start = total.saturating_sub(max_messages as u64);
+        let messages = self.load_messages(thread_id, start, total).await;
+        Ok(messages
+            .into_iter()
+            .map(|m| m.message)
+            .skip_while(|m| m.role == "tool")
+            .collect())
+    }
+
+    /// Lists the messages of the thread page by page, from the latest to the oldest.
+    ///
+    /// # Arguments
+    /// * `cursor` - The `next_cursor` of the previous page, None for the latest page;
+    /// * `limit` - Max number of messages in the page, capped at 100.
+    pub async fn list_thread_messages(
+        &self,
+        caller: &Principal,
+        thread_id: &ThreadId,
+        cursor: Option<u64>,
+        limit: usize,
+    ) -> Result<ThreadMessages, BoxError> {
+        self.check_thread_permission(caller, thread_id, "access")
+            .await?;
+
+        let total = self.messages_total(thread_id).await;
+        let end = cursor.unwrap_or(total).min(total);
+        let limit = (limit as u64).clamp(1, THREAD_MESSAGES_PAGE_SIZE);
+        let start = end.saturating_sub(limit);
+        let messages = self.load_messages(thread_id, start, end).await;
+        Ok(ThreadMessages {
+            messages,
+            next_cursor: if start > 0 { Some(start) } else { None },
+        })
+    }
+
+    /// Deletes all messages of the thread.
+    pub async fn delete_thread_messages(
+        &self,
+        caller: &Principal,
+        thread_id: &ThreadId,
+    ) -> Result<(), BoxError> {
+        self.check_thread_permission(caller, thread_id, "delete messages of")
+            .await?;
+        self.delete_messages(thread_id).await
+    }
+
+    /// Saves the pending action with the state of the suspended completion loop.
+    pub(crate) async fn save_pending_action(
+        &self,
+        action: PendingAction,
+        state: SuspendedCompletion,
+    ) -> Result<(), BoxError> {
+        let key = Self::pending_action_path(&action.id)?;
+        self.ctx
+            .cache_store_set_and_wait(
+                &key,
+                PendingActionRecord {
+                    action,
+                    state: Some(state),
+                    parent: None,
+                },
+            )
+            .await
+    }
+
+    /// Retrieves the pending action.
+    /// The caller must be the caller of the suspended execution, a manager or the controller.
+    pub async fn get_pending_action(
+        &self,
+        caller: &Principal,
+        id: &str,
+    ) -> Result<PendingAction, BoxError> {
+        let record = self.load_pending_action(id).await?;
+        self.check_approver(caller, &record.action, "access")?;
+        Ok(record.action)
+    }
+
+    /// Approves or rejects the pending action.
+    /// The caller must be the caller of the suspended execution, a manager or the controller.
+    /// Returns the reviewed action and the state to resume the suspended completion loop.
+    pub(crate) async fn review_pending_action(
+        &self,
+        caller: &Principal,
+        input: &ApprovalInput,
+    ) -> Result<(PendingAction, SuspendedCompletion), BoxError> {
+        let _guard = self.approvals_lock.lock().await;
+        let mut record = self.load_pending_action(&input.id).await?;
+        self.check_approver(caller, &record.action, "review")?;
+        let state = match (record.action.status, record.state.take()) {
+            (ApprovalStatus::Pending, Some(state)) => state,
+            (status, _) => {
+                return Err(AndaError::invalid_args(format!(
+                    "action {} is already reviewed, status: {:?}",
+                    input.id, status
+                ))
+                .into());
+            }
+        };
+
+        record.action.status = if input.approved {
+            ApprovalStatus::Approved
+        } else {
+            ApprovalStatus::Rejected
+        };
+        record.action.reviewer = Some(*caller);
+        record.action.reason = input.reason.clone();
+        record.action.updated_at = unix_ms();
+        let key = Self::pending_action_path(&input.id)?;
+        self.ctx
+            .cache_store_set_and_wait(&key, record.clone())
+            .await?;
+        Ok((record.action, state))
+    }
+
+    async fn load_pending_action(&self, id: &str) -> Result<PendingActionRecord, BoxError> {
+        let key = Self::pending_action_path(id)?;
+        self.ctx
+            .cache_store_get::<PendingActionRecord>(&key)
+            .await
+            .map_err(|_| AndaError::not_found(format!("action {} not found", id)).into())
+    }
+
+    /// Saves a completion loop waiting for its nested agent calls suspended for approval.
+    /// The nested calls are given as the tool call id and the approval id.
+    pub(crate) async fn save_continuation(
+        &self,
+        agent: String,
+        caller: Principal,
+        state: SuspendedCompletion,
+        nested: Vec<(String, String)>,
+    ) -> Result<String, BoxError> {
+        let _guard = self.approvals_lock.lock().await;
+        let id = xid::new().to_string();
+        let mut waiting = BTreeMap::new();
+        for (tool_call_id, approval_id) in nested {
+            let root = self.link_root(&approval_id, &id).await?;
+            waiting.insert(root, (tool_call_id, approval_id));
+        }
+        let continuation = Continuation {
+            id: id.clone(),
+            agent,
+            caller,
+            state,
+            waiting,
+            parent: None,
+        };
+        self.ctx
+            .cache_store_set_and_wait(&Self::continuation_path(&id), continuation)
+            .await?;
+        Ok(id)
+    }
+
+    /// Returns the continuation waiting for the reviewed action, if any.
+    pub(crate) async fn pending_action_parent(&self, id: &str) -> Option<String> {
+        self.load_pending_action(id).await.ok()?.parent
+    }
+
+    /// Sets the output of a resumed nested execution to the waiting continuation.
+    /// The continuation is removed and returned with no waiting executions once
+    /// all of them are completed.
+    pub(crate) async fn complete_nested(
+        &self,
+        parent: &str,
+        child: &str,
+        output: &AgentOutput,
+    ) -> Result<Continuation, BoxError> {
+        let _guard = self.approvals_lock.lock().await;
+        let key = Self::continuation_path(parent);
+        let mut continuation = self.load_continuation(parent).await?;
+        let (tool_call_id, _) = continuation.waiting.remove(child).ok_or_else(|| {
+            AndaError::internal(format!(
+                "continuation {} is not waiting for {}",
+                parent, child
+            ))
+        })?;
+        continuation.state.set_nested_output(&tool_call_id, output);
+        if continuation.waiting.is_empty() {
+            self.ctx.cache_store_delete(&key).await?;
+        } else {
+            self.ctx
+                .cache_store_set_and_wait(&key, continuation.clone())
+                .await?;
+        }
+        Ok(continuation)
+    }
+
+    /// Lets the continuation wait for the new approval of a nested execution
+    /// that was suspended again.
+    pub(crate) async fn relink_nested(
+        &self,
+        parent: &str,
+        child: &str,
+        approval_id: &str,
+    ) -> Result<(), BoxError> {
+        let _guard = self.approvals_lock.lock().await;
+        let mut continuation = self.load_continuation(parent).await?;
+        let (tool_call_id, _) = continuation.waiting.remove(child).ok_or_else(|| {
+            AndaError::internal(format!(
+                "continuation {} is not waiting for {}",
+                parent, child
+            ))
+        })?;
+        let root = self.link_root(approval_id, parent).await?;
+        continuation
+            .waiting
+            .insert(root, (tool_call_id, approval_id.to_string()));
+        self.ctx
+            .cache_store_set_and_wait(&Self::continuation_path(parent), continuation)
+            .await
+    }
+
+    /// Links the root of the suspended execution of the approval to the parent continuation,
+    /// returns the id of the root, a pending action or a continuation.
+    async fn link_root(&self, approval_id: &str, parent: &str) -> Result<String, BoxError> {
+        let mut record = self.load_pending_action(approval_id).await?;
+        let mut root = match record.parent.clone() {
+            None => {
+                record.parent = Some(parent.to_string());
+                let key = Self::pending_action_path(approval_id)?;
+                self.ctx.cache_store_set_and_wait(&key, record).await?;
+                return Ok(approval_id.to_string());
+            }
+            Some(id) => self.load_continuation(&id).await?,
+        };
+        while let Some(id) = root.parent.clone() {
+            root = self.load_continuation(&id).await?;
+        }
+        root.parent = Some(parent.to_string());
+        let id = root.id.clone();
+        self.ctx
+            .cache_store_set_and_wait(&Self::continuation_path(&id), root)
+            .await?;
+        Ok(id)
+    }
+
+    async fn load_continuation(&self, id: &str) -> Result<Continuation, BoxError> {
+        self.ctx
+            .cache_store_get::<Continuation>(&Self::continuation_path(id))
+            .await
+            .map_err(|_| AndaError::not_found(format!("continuation {} not found", id)).into())
+    }
+
+    fn check_approver(
+        &self,
+        caller: &Principal,
+        action: &PendingAction,
+        op: &str,
+    ) -> Result<(), AndaError> {
+        // the controller may be anonymous if not set
+        if caller != &ANONYMOUS && (caller == &action.caller || self.is_manager(caller)) {
+            Ok(())
+        } else {
+            Err(AndaError::permission_denied(format!(
+                "caller {} does not have permission to {} the action {}",
+                caller.to_text(),
+                op,
+                action.id
+            )))
+        }
+    }
+
+    async fn check_thread_permission(
+        &self,
+        caller: &Principal,
+        thread_id: &ThreadId,
+        action: &str,
+    ) -> Result<ThreadMeta, BoxError> {
+        let thread = self
+            .get_thread_meta(thread_id)
+            .await
+            .map_err(|_| AndaError::not_found(format!("thread {} not found", thread_id)))?;
+        if thread.has_permission(caller) {
+            Ok(thread)
+        } else {
+            Err(AndaError::permission_denied(format!(
+                "caller {} does not have permission to {} the thread {}",
+                caller.to_text(),
+                action,
+                thread_id
+            ))
+            .into())
+        }
+    }
+
+    async fn messages_total(&self, thread_id: &ThreadId) -> u64 {
+        self.ctx
+            .cache_store_get::<u64>(&Self::thread_messages_path(thread_id))
+            .await
+            .unwrap_or(0)
+    }
+
+    async fn load_messages_page(&self, thread_id: &ThreadId, page: u64) -> Vec<ThreadMessage> {
+        let key = Self::thread_messages_page_path(thread_id, page);
+        self.ctx
+            .cache_store_get::<Vec<ThreadMessage>>(&key)
+            .await
+            .unwrap_or_default()
+    }
+
+    /// Loads the messages with sequence number in [start, end).
+    async fn load_messages(
+        &self,
+        thread_id: &ThreadId,
+        start: u64,
+        end: u64,
+    ) -> Vec<ThreadMessage> {
+        let mut messages = Vec::new();
+        if start >= end {
+            return messages;
+        }
+
+        for page in (start / THREAD_MESSAGES_PAGE_SIZE)..=((end - 1) / THREAD_MESSAGES_PAGE_SIZE) {
+            let page_messages = self.load_messages_page(thread_id, page).await;
+            messages.extend(
+                page_messages
+                    .into_iter()
+                    .filter(|m| m.seq >= start && m.seq < end),
+            );
+        }
+        messages
+    }
+
+    async fn delete_messages(&self, thread_id: &ThreadId) -> Result<(), BoxError> {
+        let _guard = self.messages_lock.lock().await;
+        let total = self.messages_total(thread_id).await;
+        if total == 0 {
+            return Ok(());
+        }
+
+        for page in 0..=((total - 1) / THREAD_MESSAGES_PAGE_SIZE) {
+            let key = Self::thread_messages_page_path(thread_id, page);
+            self.ctx.cache_store_delete(&key).await?;
+        }
+        self.ctx
+            .cache_store_delete(&Self::thread_messages_path(thread_id))
+            .await
+    }
 }
 
 #[derive(Debug, Clone, Deserialize, Serialize, JsonSchema)]
@@ -234,6 +987,7 @@ impl Tool<BaseCtx> for ThreadMetaTool {
             description: self.description(),
             parameters: self.schema.clone(),
             strict: Some(true),
+            output_schema: Some(gen_schema_for::<Self::Output>()),
         }
     }
 
@@ -254,11 +1008,11 @@ impl Tool<BaseCtx> for ThreadMetaTool {
                 if thread.has_permission(&caller) {
                     Ok(ToolOutput::new(Some(thread)))
                 } else {
-                    Err(format!(
+                    Err(AndaError::permission_denied(format!(
                         "caller {} does not have permission to access the thread {}",
                         caller.to_text(),
                         thread_id
-                    )
+                    ))
                     .into())
                 }
             }
@@ -278,11 +1032,11 @@ impl Tool<BaseCtx> for ThreadMetaTool {
                     thread.participants.insert(user);
                     Ok(ToolOutput::new(Some(thread)))
                 } else {
-                    Err(format!(
+                    Err(AndaError::permission_denied(format!(
                         "caller {} does not have permission to add participant to the thread {}",
                         caller.to_text(),
                         thread_id
-                    )
+                    ))
                     .into())
                 }
             }
@@ -295,11 +1049,11 @@ impl Tool<BaseCtx> for ThreadMetaTool {
                     thread.participants.remove(&user);
                     Ok(ToolOutput::new(Some(thread)))
                 } else {
-                    Err(format!(
+                    Err(AndaError::permission_denied(format!(
                         "caller {} does not have permission to remove participant from the thread {}",
                         caller.to_text(),
                         thread_id
-                    )
+                    ))
                     .into())
                 }
             }
@@ -307,10 +1061,109 @@ impl Tool<BaseCtx> for ThreadMetaTool {
     }
 }
 
+#[derive(Debug, Clone, Deserialize, Serialize, JsonSchema)]
+pub struct ManagersToolArgs {
+    /// The method to call.
+    pub method: ManagersToolMethod,
+
+    /// The user IDs to add or remove, e.g. ["77ibd-jp5kr-moeco-kgoar-rro5v-5tng4-krif5-5h2i6-osf2f-2sjtv-kqe"].
+    pub user_ids: Vec<String>,
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize, JsonSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum ManagersToolMethod {
+    ListManagers,
+    AddManagers,
+    RemoveManagers,
+}
+
+/// Represents a tool to manage the managers of the engine.
+/// Managers can update the access control rules and review pending actions,
+/// only the controller can add or remove managers.
+/// The tool can only be called in the executions of the controller, so a model can not
+/// administer the managers on behalf of other callers.
+pub struct ManagersTool {
+    management: Arc<Management>,
+    schema: Value,
+}
+
+impl ManagersTool {
+    pub const NAME: &'static str = "sys_managers";
+
+    pub fn new(management: Arc<Management>) -> Self {
+        let schema = gen_schema_for::<ManagersToolArgs>();
+        Self { management, schema }
+    }
+}
+
+impl Tool<BaseCtx> for ManagersTool {
+    type Args = ManagersToolArgs;
+    type Output = Vec<String>;
+
+    fn name(&self) -> String {
+        Self::NAME.to_string()
+    }
+
+    fn description(&self) -> String {
+        "Lists, adds or removes the managers of the engine. Only the controller can add or remove managers.".to_string()
+    }
+
+    fn definition(&self) -> FunctionDefinition {
+        FunctionDefinition {
+            name: self.name(),
+            description: self.description(),
+            parameters: self.schema.clone(),
+            strict: Some(true),
+            output_schema: Some(gen_schema_for::<Self::Output>()),
+        }
+    }
+
+    async fn call(
+        &self,
+        ctx: BaseCtx,
+        args: Self::Args,
+        resources: Option<Vec<Resource>>,
+    ) -> Result<ToolOutput<Self::Output>, BoxError> {
+        if resources.is_some() {
+            return Err("resources are not supported".into());
+        }
+        let caller = ctx.caller();
+        if !self.management.is_controller(&caller) {
+            return Err(AndaError::permission_denied(format!(
+                "caller {} does not have permission to call {}",
+                caller.to_text(),
+                Self::NAME
+            ))
+            .into());
+        }
+        let users = args
+            .user_ids
+            .iter()
+            .map(|id| {
+                Principal::from_text(id).map_err(|err| {
+                    AndaError::invalid_args(format!("invalid user ID {}: {}", id, err))
+                })
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+        let managers = match args.method {
+            ManagersToolMethod::ListManagers => self.management.managers(&caller)?,
+            ManagersToolMethod::AddManagers => self.management.add_managers(&caller, users).await?,
+            ManagersToolMethod::RemoveManagers => {
+                self.management.remove_managers(&caller, users).await?
+            }
+        };
+        Ok(ToolOutput::new(
+            managers.iter().map(|p| p.to_text()).collect(),
+        ))
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
     use crate::engine::EngineBuilder;
+    use anda_core::ErrorCode;
 
     #[tokio::test]
     async fn test_thread_meta_tool() {
@@ -359,5 +1212,250 @@ mod tests {
         //     },
         //     "strict": true
         // }
+
+        // the output matches the output schema
+        assert!(definition.output_schema.is_some());
+        let mut thread = ThreadMeta::new(ctx.id(), Principal::anonymous(), unix_ms());
+        thread.participants.insert(Principal::management_canister());
+        thread
+            .children
+            .insert(Principal::management_canister(), ThreadId::new());
+        definition
+            .validate_output(&serde_json::to_value(Some(thread)).unwrap())
+            .unwrap();
+        definition
+            .validate_output(&serde_json::to_value(None::<ThreadMeta>).unwrap())
+            .unwrap();
+        assert!(definition.validate_output(&json!({"id": "x"})).is_err());
+    }
+
+    #[tokio::test]
+    async fn test_thread_messages() {
+        let engine = EngineBuilder::new();
+        let ctx = engine.mock_ctx();
+        let management = Management::new(&ctx.base, ctx.id());
+        let user = Principal::from_slice(&[1]);
+        let thread = ThreadMeta::new(ctx.id(), user, unix_ms());
+        let thread_id = thread.id.clone();
+
+        let msg = |i: u64| Message {
+            role: if i % 2 == 0 { "user" } else { "tool" }.to_string(),
+            content: format!("message {}", i).into(),
+            ..Default::default()
+        };
+
+        let res = management
+            .append_thread_messages(&user, &thread_id, vec![msg(0)])
+            .await;
+        assert!(res.unwrap_err().to_string().starts_with("not_found"));
+
+        management.save_thread_meta(thread).await.unwrap();
+        let total = management
+            .append_thread_messages(&user, &thread_id, (0..150).map(msg).collect())
+            .await
+            .unwrap();
+        assert_eq!(total, 150);
+        let total = management
+            .append_thread_messages(&user, &thread_id, (150..210).map(msg).collect())
+            .await
+            .unwrap();
+        assert_eq!(total, 210);
+
+        let other = Principal::from_slice(&[2]);
+        let res = management.thread_history(&other, &thread_id, 10).await;
+        assert!(
+            res.unwrap_err()
+                .to_string()
+                .starts_with("permission_denied")
+        );
+
+        // the leading tool message is dropped
+        let history = management
+            .thread_history(&user, &thread_id, 11)
+            .await
+            .unwrap();
+        assert_eq!(history.len(), 10);
+        assert_eq!(history[0].content, "message 200");
+        assert_eq!(history[9].content, "message 209");
+
+        let page = management
+            .list_thread_messages(&user, &thread_id, None, 50)
+            .await
+            .unwrap();
+        assert_eq!(page.messages.len(), 50);
+        assert_eq!(page.messages[0].seq, 160);
+        assert_eq!(page.next_cursor, Some(160));
+
+        let page = management
+            .list_thread_messages(&user, &thread_id, Some(40), 50)
+            .await
+            .unwrap();
+        assert_eq!(page.messages.len(), 40);
+        assert_eq!(page.messages[0].seq, 0);
+        assert_eq!(page.messages[39].seq, 39);
+        assert_eq!(page.next_cursor, None);
+
+        management
+            .delete_thread_messages(&user, &thread_id)
+            .await
+            .unwrap();
+        let history = management
+            .thread_history(&user, &thread_id, 10)
+            .await
+            .unwrap();
+        assert!(history.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_access_control() {
+        let engine = EngineBuilder::new();
+        let ctx = engine.mock_ctx();
+        let controller = Principal::from_slice(&[1]);
+        let user = Principal::from_slice(&[2]);
+        let other = Principal::from_slice(&[3]);
+        let management = Management::new(&ctx.base, controller);
+        management
+            .init_access_control(AccessControl::default())
+            .await
+            .unwrap();
+
+        // no rules, all callers can access
+        assert!(management.check_agent_access(&ANONYMOUS, "agent").is_ok());
+        assert!(management.check_tool_access(&other, "tool").is_ok());
+
+        let err = management
+            .set_agent_rule(&user, "agent", Some(AccessRule::default()))
+            .await
+            .unwrap_err();
+        assert_eq!(AndaError::from(err).code, ErrorCode::PermissionDenied);
+
+        management
+            .set_agent_rule(&controller, "Agent", Some(AccessRule::default()))
+            .await
+            .unwrap();
+        let err = management
+            .check_agent_access(&ANONYMOUS, "agent")
+            .unwrap_err();
+        assert_eq!(err.code, ErrorCode::Unauthenticated);
+        assert!(management.check_agent_access(&user, "agent").is_ok());
+
+        management
+            .set_tool_rule(
+                &controller,
+                "tool",
+                Some(AccessRule {
+                    allowed: BTreeSet::from([user]),
+                    ..Default::default()
+                }),
+            )
+            .await
+            .unwrap();
+        assert!(management.check_tool_access(&user, "tool").is_ok());
+        assert!(management.check_tool_access(&controller, "tool").is_ok());
+        let err = management.check_tool_access(&other, "tool").unwrap_err();
+        assert_eq!(err.code, ErrorCode::PermissionDenied);
+
+        management
+            .set_default_rule(
+                &controller,
+                Some(AccessRule {
+                    manager_only: true,
+                    ..Default::default()
+                }),
+            )
+            .await
+            .unwrap();
+        assert!(management.check_tool_access(&user, "other_tool").is_err());
+        assert!(
+            management
+                .check_agent_access(&controller, "other_agent")
+                .is_ok()
+        );
+
+        // the rules are saved and take precedence over the initial rules
+        let management2 = Management::new(&ctx.base, controller);
+        management2
+            .init_access_control(AccessControl::default())
+            .await
+            .unwrap();
+        assert_eq!(management2.access_control(), management.access_control());
+
+        management
+            .set_agent_rule(&controller, "agent", None)
+            .await
+            .unwrap();
+        assert!(management.access_control().agents.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_managers() {
+        let engine = EngineBuilder::new();
+        let ctx = engine.mock_ctx();
+        let controller = Principal::from_slice(&[1]);
+        let manager = Principal::from_slice(&[2]);
+        let management = Arc::new(Management::new(&ctx.base, controller));
+        assert!(!management.is_manager(&manager));
+
+        let err = management
+            .add_managers(&manager, vec![manager])
+            .await
+            .unwrap_err();
+        assert_eq!(AndaError::from(err).code, ErrorCode::PermissionDenied);
+        let err = management
+            .add_managers(&controller, vec![ANONYMOUS])
+            .await
+            .unwrap_err();
+        assert_eq!(AndaError::from(err).code, ErrorCode::InvalidArgs);
+
+        let managers = management
+            .add_managers(&controller, vec![manager])
+            .await
+            .unwrap();
+        assert_eq!(managers, BTreeSet::from([manager]));
+        assert!(management.is_manager(&manager));
+        assert_eq!(management.managers(&manager).unwrap(), managers);
+        assert!(management.managers(&ANONYMOUS).is_err());
+
+        // managers are saved under SYSTEM_PATH
+        let management2 = Management::new(&ctx.base, controller);
+        management2.init_managers().await.unwrap();
+        assert!(management2.is_manager(&manager));
+
+        // the system tool
+        let tool = ManagersTool::new(management.clone());
+        let base = ctx.child_base_with(controller, ManagersTool::NAME, RequestMeta::default());
+        let output = tool
+            .call(
+                base.unwrap(),
+                ManagersToolArgs {
+                    method: ManagersToolMethod::RemoveManagers,
+                    user_ids: vec![manager.to_text()],
+                },
+                None,
+            )
+            .await
+            .unwrap();
+        assert!(output.output.is_empty());
+        assert!(!management.is_manager(&manager));
+
+        // only the controller can call the tool, even to list the managers
+        let base = ctx.child_base_with(manager, ManagersTool::NAME, RequestMeta::default());
+        let err = tool
+            .call(
+                base.unwrap(),
+                ManagersToolArgs {
+                    method: ManagersToolMethod::ListManagers,
+                    user_ids: vec![],
+                },
+                None,
+            )
+            .await
+            .unwrap_err();
+        assert_eq!(AndaError::from(err).code, ErrorCode::PermissionDenied);
+        assert!(
+            tool.definition()
+                .validate_output(&json!(output.output))
+                .is_ok()
+        );
     }
 }
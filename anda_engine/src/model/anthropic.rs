@@ -0,0 +1,738 @@
+//! Anthropic API client implementation for Anda Engine
+//!
+//! This module provides integration with Anthropic's Messages API, including:
+//! - Client configuration and management
+//! - Completion model handling
+//! - Conversion between Anda's OpenAI style messages and Anthropic's content blocks
+//!
+//! The `full_history` of the output is kept in the OpenAI style like the other providers:
+//! the assistant messages carry the `tool_calls`, the tool results of the tool call loop
+//! are messages with "tool" role. They are converted to `tool_use` and `tool_result`
+//! blocks in the next request.
+
+use anda_core::{
+    AgentOutput, AndaError, BoxError, BoxPinFut, CONTENT_TYPE_JSON, CompletionFeatures,
+    CompletionRequest, CompletionStream, ContentPart, FunctionDefinition, Message, Resource,
+    ToolCall, Usage as ModelUsage, response_format_instruction,
+};
+use log::{Level::Debug, log_enabled};
+use serde::{Deserialize, Serialize};
+use serde_json::{Value, json};
+use std::time::Duration;
+
+use super::{CompletionFeaturesDyn, context_window_of};
+use crate::APP_USER_AGENT;
+
+// ================================================================
+// Main Anthropic Client
+// ================================================================
+const API_BASE_URL: &str = "https://api.anthropic.com/v1";
+const API_VERSION: &str = "2023-06-01";
+/// The default max tokens of the output, it is required by the Messages API.
+const DEFAULT_MAX_TOKENS: usize = 8192;
+
+/// `claude-sonnet-4-0` completion model
+pub static CLAUDE_SONNET_4: &str = "claude-sonnet-4-0";
+/// `claude-3-7-sonnet-latest` completion model
+pub static CLAUDE_3_7_SONNET: &str = "claude-3-7-sonnet-latest";
+/// `claude-3-5-haiku-latest` completion model
+pub static CLAUDE_3_5_HAIKU: &str = "claude-3-5-haiku-latest";
+
+/// Anthropic API client configuration and HTTP client
+#[derive(Clone)]
+pub struct Client {
+    endpoint: String,
+    http: reqwest::Client,
+}
+
+impl Client {
+    /// Creates a new Anthropic client instance with the provided API key
+    ///
+    /// # Arguments
+    /// * `api_key` - Anthropic API key for authentication
+    /// * `endpoint` - Optional API endpoint, defaults to `https://api.anthropic.com/v1`
+    pub fn new(api_key: &str, endpoint: Option<String>) -> Self {
+        let endpoint = endpoint.unwrap_or_else(|| API_BASE_URL.to_string());
+        let endpoint = if endpoint.is_empty() {
+            API_BASE_URL.to_string()
+        } else {
+            endpoint
+        };
+        Self {
+            endpoint,
+            http: reqwest::Client::builder()
+                .use_rustls_tls()
+                .https_only(true)
+                .http2_keep_alive_interval(Some(Duration::from_secs(25)))
+                .http2_keep_alive_timeout(Duration::from_secs(15))
+                .http2_keep_alive_while_idle(true)
+                .connect_timeout(Duration::from_secs(10))
+                .timeout(Duration::from_secs(300))
+                .gzip(true)
+                .user_agent(APP_USER_AGENT)
+                .default_headers({
+                    let mut headers = reqwest::header::HeaderMap::new();
+                    let ct: http::HeaderValue = CONTENT_TYPE_JSON.parse().unwrap();
+                    headers.insert(http::header::CONTENT_TYPE, ct.clone());
+                    headers.insert(http::header::ACCEPT, ct);
+                    headers.insert("x-api-key", api_key.parse().expect("API key should parse"));
+                    headers.insert(
+                        "anthropic-version",
+                        http::HeaderValue::from_static(API_VERSION),
+                    );
+                    headers
+                })
+                .build()
+                .expect("Anthropic reqwest client should build"),
+        }
+    }
+
+    /// Creates a POST request builder for the specified API path
+    fn post(&self, path: &str) -> reqwest::RequestBuilder {
+        let url = format!("{}{}", self.endpoint, path);
+        self.http.post(url)
+    }
+
+    /// Creates a new completion model instance, defaults to `claude-sonnet-4-0`
+    pub fn completion_model(&self, model: &str) -> CompletionModel {
+        CompletionModel::new(
+            self.clone(),
+            if model.is_empty() {
+                CLAUDE_SONNET_4
+            } else {
+                model
+            },
+        )
+    }
+}
+
+/// Token usage statistics from Anthropic API responses
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+pub struct Usage {
+    /// Number of input tokens that were not read from or written to the cache
+    pub input_tokens: usize,
+    /// Number of output tokens
+    pub output_tokens: usize,
+    /// Number of input tokens written to the cache
+    #[serde(default)]
+    pub cache_creation_input_tokens: Option<usize>,
+    /// Number of input tokens read from the cache
+    #[serde(default)]
+    pub cache_read_input_tokens: Option<usize>,
+}
+
+impl std::fmt::Display for Usage {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "Input tokens: {} output tokens: {}",
+            self.input_tokens, self.output_tokens
+        )
+    }
+}
+
+impl From<&Usage> for ModelUsage {
+    fn from(u: &Usage) -> Self {
+        let cached = u.cache_read_input_tokens.unwrap_or_default();
+        ModelUsage {
+            input_tokens: (u.input_tokens
+                + cached
+                + u.cache_creation_input_tokens.unwrap_or_default())
+                as u64,
+            output_tokens: u.output_tokens as u64,
+            requests: 1,
+            cached_input_tokens: cached as u64,
+            ..Default::default()
+        }
+    }
+}
+
+/// Completion response from Anthropic Messages API
+#[derive(Debug, Deserialize, Serialize)]
+pub struct CompletionResponse {
+    /// Unique identifier for the message
+    pub id: String,
+    /// Object type (always "message")
+    pub r#type: String,
+    /// Role of the message (always "assistant")
+    pub role: String,
+    /// Content blocks generated by the model
+    pub content: Vec<Value>,
+    /// Model used for the completion
+    pub model: String,
+    /// The reason that the model stopped
+    pub stop_reason: Option<String>,
+    /// Token usage statistics
+    pub usage: Usage,
+}
+
+impl CompletionResponse {
+    fn try_into(self, mut full_history: Vec<Value>) -> Result<AgentOutput, BoxError> {
+        let mut content = String::new();
+        let mut tool_calls = Vec::new();
+        for block in &self.content {
+            match block.get("type").and_then(Value::as_str) {
+                Some("text") => {
+                    if let Some(text) = block.get("text").and_then(Value::as_str) {
+                        content.push_str(text);
+                    }
+                }
+                Some("tool_use") => {
+                    let id = block.get("id").and_then(Value::as_str).unwrap_or_default();
+                    let name = block
+                        .get("name")
+                        .and_then(Value::as_str)
+                        .unwrap_or_default();
+                    let input = block.get("input").cloned().unwrap_or(json!({}));
+                    tool_calls.push(ToolCall {
+                        id: id.to_string(),
+                        name: name.to_string(),
+                        args: serde_json::to_string(&input)?,
+                        result: None,
+                    });
+                }
+                _ => {}
+            }
+        }
+
+        // the history is kept in the OpenAI style, it is converted back in `convert_messages`
+        let mut message = json!({
+            "role": "assistant",
+            "content": content,
+        });
+        if !tool_calls.is_empty() {
+            message["tool_calls"] = json!(
+                tool_calls
+                    .iter()
+                    .map(|tc| json!({
+                        "id": tc.id,
+                        "type": "function",
+                        "function": {"name": tc.name, "arguments": tc.args},
+                    }))
+                    .collect::<Vec<_>>()
+            );
+        }
+        full_history.push(message);
+        let mut output = AgentOutput {
+            content,
+            tool_calls: if tool_calls.is_empty() {
+                None
+            } else {
+                Some(tool_calls)
+            },
+            full_history: Some(full_history),
+            usage: ModelUsage::from(&self.usage),
+            ..Default::default()
+        };
+
+        let stop_reason = self.stop_reason.unwrap_or_default();
+        if !matches!(
+            stop_reason.as_str(),
+            "end_turn" | "tool_use" | "stop_sequence" | "pause_turn"
+        ) {
+            output.failed_reason = Some(stop_reason);
+        }
+
+        Ok(output)
+    }
+}
+
+/// Tool definition of Anthropic Messages API
+#[derive(Debug, Deserialize, Serialize)]
+pub struct ToolDefinition {
+    pub name: String,
+    pub description: String,
+    pub input_schema: Value,
+}
+
+impl From<FunctionDefinition> for ToolDefinition {
+    fn from(f: FunctionDefinition) -> Self {
+        Self {
+            name: f.name,
+            description: f.description,
+            input_schema: f.parameters,
+        }
+    }
+}
+
+/// Converts an OpenAI style content part to an Anthropic content block.
+/// Returns None for unsupported parts (audio).
+fn content_block(part: ContentPart) -> Option<Value> {
+    match part {
+        ContentPart::Text { text } => Some(json!({"type": "text", "text": text})),
+        ContentPart::Image { image_url } => Some(image_block(&image_url.url)),
+        ContentPart::Audio { .. } => None,
+    }
+}
+
+/// Converts an image URL or a data URL (`data:image/png;base64,...`) to an image block.
+fn image_block(url: &str) -> Value {
+    if let Some(data_url) = url.strip_prefix("data:") {
+        if let Some((media_type, data)) = data_url.split_once(";base64,") {
+            return json!({
+                "type": "image",
+                "source": {
+                    "type": "base64",
+                    "media_type": media_type,
+                    "data": data,
+                },
+            });
+        }
+    }
+    json!({
+        "type": "image",
+        "source": {
+            "type": "url",
+            "url": url,
+        },
+    })
+}
+
+/// Converts the content of a message to Anthropic content blocks.
+/// OpenAI style content parts are converted, Anthropic blocks are kept.
+fn content_blocks(content: &Value) -> Vec<Value> {
+    match content {
+        Value::Null => vec![],
+        Value::String(text) if text.is_empty() => vec![],
+        Value::String(text) => vec![json!({"type": "text", "text": text})],
+        Value::Array(parts) => parts
+            .iter()
+            .filter_map(|part| match part.get("type").and_then(Value::as_str) {
+                Some("image") if part.get("image_url").is_some() => {
+                    serde_json::from_value::<ContentPart>(part.clone())
+                        .ok()
+                        .and_then(content_block)
+                }
+                Some("image_url") => part
+                    .get("image_url")
+                    .and_then(|v| v.get("url"))
+                    .and_then(Value::as_str)
+                    .map(image_block),
+                Some("audio") | Some("input_audio") => None,
+                Some(_) => Some(part.clone()),
+                None => None,
+            })
+            .collect(),
+        other => vec![json!({"type": "text", "text": other.to_string()})],
+    }
+}
+
+/// Converts the chat history to the system prompt and Anthropic messages.
+/// - "system" and "developer" messages are merged into the system prompt;
+/// - "tool" messages are converted to `tool_result` blocks of a user message;
+/// - OpenAI style `tool_calls` of assistant messages are converted to `tool_use` blocks;
+/// - Consecutive messages with the same role are merged, as the API requires alternating roles.
+fn convert_messages(history: &[Value]) -> (Vec<String>, Vec<Value>) {
+    let mut system: Vec<String> = Vec::new();
+    let mut messages: Vec<(String, Vec<Value>)> = Vec::new();
+    for msg in history {
+        let role = msg.get("role").and_then(Value::as_str).unwrap_or("user");
+        let content = msg.get("content").unwrap_or(&Value::Null);
+        let (role, blocks) = match role {
+            "system" | "developer" => {
+                match content {
+                    Value::String(text) => system.push(text.clone()),
+                    other => {
+                        for block in content_blocks(other) {
+                            if let Some(text) = block.get("text").and_then(Value::as_str) {
+                                system.push(text.to_string());
+                            }
+                        }
+                    }
+                }
+                continue;
+            }
+            "tool" => {
+                let content = match content {
+                    Value::String(text) => Value::String(text.clone()),
+                    other => Value::String(other.to_string()),
+                };
+                let tool_use_id = msg
+                    .get("tool_call_id")
+                    .and_then(Value::as_str)
+                    .unwrap_or_default();
+                (
+                    "user",
+                    vec![json!({
+                        "type": "tool_result",
+                        "tool_use_id": tool_use_id,
+                        "content": content,
+                    })],
+                )
+            }
+            "assistant" => {
+                let mut blocks = content_blocks(content);
+                if let Some(tool_calls) = msg.get("tool_calls").and_then(Value::as_array) {
+                    for tc in tool_calls {
+                        let function = tc.get("function").unwrap_or(&Value::Null);
+                        let args = function
+                            .get("arguments")
+                            .and_then(Value::as_str)
+                            .unwrap_or("{}");
+                        blocks.push(json!({
+                            "type": "tool_use",
+                            "id": tc.get("id").cloned().unwrap_or_default(),
+                            "name": function.get("name").cloned().unwrap_or_default(),
+                            "input": serde_json::from_str::<Value>(args).unwrap_or(json!({})),
+                        }));
+                    }
+                }
+                ("assistant", blocks)
+            }
+            _ => ("user", content_blocks(content)),
+        };
+
+        if blocks.is_empty() {
+            continue;
+        }
+        match messages.last_mut() {
+            Some((last_role, last_blocks)) if last_role == role => {
+                last_blocks.extend(blocks);
+            }
+            _ => messages.push((role.to_string(), blocks)),
+        }
+    }
+
+    let messages = messages
+        .into_iter()
+        .map(|(role, blocks)| json!({"role": role, "content": blocks}))
+        .collect();
+    (system, messages)
+}
+
+/// Completion model wrapper for Anthropic Messages API
+#[derive(Clone)]
+pub struct CompletionModel {
+    /// Anthropic client instance
+    client: Client,
+    /// Model identifier
+    pub model: String,
+}
+
+impl CompletionModel {
+    /// Creates a new completion model instance
+    ///
+    /// # Arguments
+    /// * `client` - Anthropic client instance
+    /// * `model` - Model identifier string
+    pub fn new(client: Client, model: &str) -> Self {
+        Self {
+            client,
+            model: model.to_string(),
+        }
+    }
+
+    /// Builds the messages request body, returns the messages of the conversation
+    /// (including the system message) and the JSON body.
+    fn request_body(&self, mut req: CompletionRequest) -> (Vec<Value>, Value) {
+        // Add preamble to chat history (if available)
+        let mut full_history = if let Some(system) = &req.system {
+            vec![json!(Message {
+                role: "system".into(),
+                content: system.to_owned().into(),
+                ..Default::default()
+            })]
+        } else {
+            vec![]
+        };
+
+        // Extend existing chat history
+        full_history.append(&mut req.chat_history);
+
+        if !req.content_parts.is_empty() {
+            full_history.push(json!(Message {
+                role: "user".into(),
+                content: json!(req.content_parts),
+                ..Default::default()
+            }));
+        } else if let Some(prompt) = req.prompt_with_context() {
+            full_history.push(json!(Message {
+                role: "user".into(),
+                content: prompt.into(),
+                ..Default::default()
+            }));
+        }
+
+        let (mut system, messages) = convert_messages(&full_history);
+        if let Some(format) = &req.response_format {
+            // the Messages API has no response format, the format is required in the prompt
+            system.push(response_format_instruction(format));
+        }
+
+        let mut body = json!({
+            "model": self.model,
+            "max_tokens": req.max_tokens.unwrap_or(DEFAULT_MAX_TOKENS),
+            "messages": messages,
+        });
+
+        let obj = body.as_object_mut().unwrap();
+        if !system.is_empty() {
+            obj.insert("system".to_string(), Value::from(system.join("\n\n")));
+        }
+
+        if let Some(temperature) = req.temperature {
+            obj.insert("temperature".to_string(), Value::from(temperature));
+        }
+
+        if let Some(stop) = req.stop {
+            obj.insert("stop_sequences".to_string(), Value::from(stop));
+        }
+
+        if !req.tools.is_empty() {
+            obj.insert(
+                "tools".to_string(),
+                json!(
+                    req.tools
+                        .into_iter()
+                        .map(ToolDefinition::from)
+                        .collect::<Vec<_>>()
+                ),
+            );
+            obj.insert(
+                "tool_choice".to_string(),
+                json!({"type": if req.tool_choice_required { "any" } else { "auto" }}),
+            );
+        };
+
+        (full_history, body)
+    }
+}
+
+impl CompletionFeatures for CompletionModel {
+    async fn completion(
+        &self,
+        req: CompletionRequest,
+        _resources: Option<Vec<Resource>>,
+    ) -> Result<AgentOutput, BoxError> {
+        CompletionFeaturesDyn::completion(self, req).await
+    }
+
+    async fn completion_stream(
+        &self,
+        req: CompletionRequest,
+        _resources: Option<Vec<Resource>>,
+    ) -> Result<CompletionStream, BoxError> {
+        CompletionFeaturesDyn::completion_stream(self, req).await
+    }
+}
+
+impl CompletionFeaturesDyn for CompletionModel {
+    fn completion(&self, req: CompletionRequest) -> BoxPinFut<Result<AgentOutput, BoxError>> {
+        let (full_history, body) = self.request_body(req);
+        let client = self.client.clone();
+
+        Box::pin(async move {
+            if log_enabled!(Debug) {
+                if let Ok(val) = serde_json::to_string(&body) {
+                    log::debug!(request = val; "Anthropic messages request");
+                }
+            }
+
+            let response = client.post("/messages").json(&body).send().await?;
+            if response.status().is_success() {
+                let text = response.text().await?;
+                match serde_json::from_str::<CompletionResponse>(&text) {
+                    Ok(res) => {
+                        if log_enabled!(Debug) {
+                            if let Ok(val) = serde_json::to_string(&res) {
+                                log::debug!(response = val; "Anthropic messages response");
+                            }
+                        }
+                        res.try_into(full_history)
+                    }
+                    Err(err) => {
+                        Err(format!("Anthropic messages error: {}, body: {}", err, text).into())
+                    }
+                }
+            } else {
+                let status = response.status().as_u16();
+                let msg = response.text().await?;
+                Err(
+                    AndaError::from_status(status, format!("Anthropic messages error: {}", msg))
+                        .into(),
+                )
+            }
+        })
+    }
+
+    fn context_window(&self) -> Option<usize> {
+        context_window_of(&self.model)
+    }
+
+    fn model_name(&self) -> String {
+        self.model.clone()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::extension::character::Character;
+    use anda_core::ImageDetail;
+    use std::time::Instant;
+
+    #[test]
+    fn test_request_body() {
+        let model = Client::new("test", None).completion_model("");
+        let req = CompletionRequest {
+            system: Some("You are a helpful assistant.".to_string()),
+            chat_history: vec![
+                json!({"role": "user", "content": "Hi", "name": "Yan"}),
+                json!({"role": "assistant", "content": "Hello!"}),
+            ],
+            content_parts: vec![
+                ContentPart::Text {
+                    text: "What's in this image?".to_string(),
+                },
+                ContentPart::Image {
+                    image_url: ImageDetail {
+                        url: "data:image/png;base64,iVBORw0KGgo=".to_string(),
+                        detail: None,
+                    },
+                },
+            ],
+            tools: vec![FunctionDefinition {
+                name: "get_weather".to_string(),
+                description: "Gets the weather".to_string(),
+                parameters: json!({"type": "object", "properties": {}}),
+                strict: None,
+                output_schema: None,
+            }],
+            tool_choice_required: true,
+            stop: Some(vec!["END".to_string()]),
+            max_tokens: Some(1024),
+            ..Default::default()
+        };
+        let (full_history, body) = model.request_body(req);
+        assert_eq!(full_history.len(), 4);
+        // the history is kept in the OpenAI style
+        assert_eq!(
+            full_history[3]["content"][1]["image_url"]["url"],
+            "data:image/png;base64,iVBORw0KGgo="
+        );
+        assert_eq!(body["model"], CLAUDE_SONNET_4);
+        assert_eq!(body["system"], "You are a helpful assistant.");
+        assert_eq!(body["max_tokens"], 1024);
+        assert_eq!(body["stop_sequences"], json!(["END"]));
+        assert_eq!(body["tool_choice"], json!({"type": "any"}));
+        assert_eq!(
+            body["tools"],
+            json!([{
+                "name": "get_weather",
+                "description": "Gets the weather",
+                "input_schema": {"type": "object", "properties": {}},
+            }])
+        );
+        let messages = body["messages"].as_array().unwrap();
+        assert_eq!(messages.len(), 3);
+        assert_eq!(
+            messages[0],
+            json!({"role": "user", "content": [{"type": "text", "text": "Hi"}]})
+        );
+        assert_eq!(
+            messages[2]["content"][1],
+            json!({
+                "type": "image",
+                "source": {"type": "base64", "media_type": "image/png", "data": "iVBORw0KGgo="},
+            })
+        );
+    }
+
+    #[test]
+    fn test_tool_call_loop() {
+        let model = Client::new("test", None).completion_model("");
+        let res: CompletionResponse = serde_json::from_value(json!({
+            "id": "msg_1",
+            "type": "message",
+            "role": "assistant",
+            "model": CLAUDE_SONNET_4,
+            "content": [
+                {"type": "text", "text": "Let me check."},
+                {"type": "tool_use", "id": "toolu_1", "name": "get_weather", "input": {"city": "Paris"}},
+            ],
+            "stop_reason": "tool_use",
+            "usage": {"input_tokens": 10, "output_tokens": 20, "cache_read_input_tokens": 5},
+        }))
+        .unwrap();
+        let (full_history, _) = model.request_body(CompletionRequest {
+            system: Some("system".to_string()),
+            prompt: "Weather in Paris?".to_string(),
+            ..Default::default()
+        });
+        let output = res.try_into(full_history).unwrap();
+        assert_eq!(output.content, "Let me check.");
+        assert!(output.failed_reason.is_none());
+        assert_eq!(output.usage.input_tokens, 15);
+        assert_eq!(output.usage.cached_input_tokens, 5);
+        let tool_calls = output.tool_calls.unwrap();
+        assert_eq!(tool_calls[0].id, "toolu_1");
+        assert_eq!(tool_calls[0].args, r#"{"city":"Paris"}"#);
+
+        // the history is kept in the OpenAI style
+        let mut chat_history = output.full_history.unwrap();
+        assert_eq!(
+            chat_history[2],
+            json!({
+                "role": "assistant",
+                "content": "Let me check.",
+                "tool_calls": [{
+                    "id": "toolu_1",
+                    "type": "function",
+                    "function": {"name": "get_weather", "arguments": "{\"city\":\"Paris\"}"},
+                }],
+            })
+        );
+
+        // the next round of the tool call loop in `AgentCtx::completion`
+        chat_history.push(json!(Message {
+            role: "tool".to_string(),
+            content: json!({"temperature": 20}),
+            name: None,
+            tool_call_id: Some("toolu_1".to_string()),
+            ..Default::default()
+        }));
+        let (_, body) = model.request_body(CompletionRequest {
+            chat_history,
+            ..Default::default()
+        });
+        assert_eq!(body["system"], "system");
+        let messages = body["messages"].as_array().unwrap();
+        assert_eq!(messages.len(), 3);
+        assert_eq!(
+            messages[1],
+            json!({"role": "assistant", "content": [
+                {"type": "text", "text": "Let me check."},
+                {"type": "tool_use", "id": "toolu_1", "name": "get_weather", "input": {"city": "Paris"}},
+            ]})
+        );
+        assert_eq!(
+            messages[2],
+            json!({"role": "user", "content": [{
+                "type": "tool_result",
+                "tool_use_id": "toolu_1",
+                "content": "{\"temperature\":20}",
+            }]})
+        );
+    }
+
+    #[tokio::test(flavor = "current_thread")]
+    #[ignore]
+    async fn test_anthropic() {
+        dotenv::dotenv().ok();
+
+        let api_key = std::env::var("ANTHROPIC_API_KEY").expect("ANTHROPIC_API_KEY is not set");
+        let character_path = format!("{}/../characters/AndaICP.toml", env!("CARGO_MANIFEST_DIR"));
+        println!("Character path: {}", character_path);
+        let character = std::fs::read_to_string(character_path).expect("Character file not found");
+        let character = Character::from_toml(&character).expect("Character should parse");
+        let client = Client::new(&api_key, None);
+        let now = Instant::now();
+        let model = client.completion_model(CLAUDE_3_5_HAIKU);
+        let req = character.to_request("I am Yan, glad to see you".into(), Some("Yan".into()));
+        let res = CompletionFeatures::completion(&model, req, None)
+            .await
+            .unwrap();
+        println!("{}", res.content);
+        println!("Took: {:?}", now.elapsed());
+    }
+}
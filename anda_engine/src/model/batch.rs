@@ -0,0 +1,371 @@
+//! Embedding executor for large embedding jobs.
+//!
+//! Embedding providers limit the number of texts per call (e.g. 1024 for OpenAI, 96 for Cohere),
+//! and some of them the total tokens of the texts per call (e.g. 300,000 for OpenAI).
+//! [`EmbeddingExecutor`] splits arbitrary input into batches within the provider's max batch size
+//! and max batch tokens, runs the batches with bounded concurrency and merges the usage:
+//! - The embeddings are returned in the order of the input texts;
+//! - Retryable errors (rate limited, timeout, unavailable) of a batch are retried with
+//!   the [`RetryPolicy`], other errors fail the whole job.
+//!
+//! It is used by [`Model::embed`](super::Model::embed).
+//!
+//! # Example
+//! ```rust,ignore
+//! let model = Model::new(completer, Arc::new(cohere.embedding_model(EMBED_MULTILINGUAL_V3)))
+//!     .with_embedding_executor(EmbeddingExecutor::new().with_concurrency(8));
+//! let (embeddings, usage) = model.embed(texts).await?;
+//! ```
+
+use anda_core::{AndaError, BoxError, Embedding, ErrorCode, Tokenizer, Usage};
+use futures::stream::{self, StreamExt, TryStreamExt};
+
+use super::{EmbeddingFeaturesDyn, router::RetryPolicy};
+
+/// The default max number of batches running concurrently.
+pub const DEFAULT_CONCURRENCY: usize = 4;
+
+/// Splits embedding jobs into batches and runs them with bounded concurrency.
+#[derive(Debug, Clone)]
+pub struct EmbeddingExecutor {
+    /// Max number of texts per batch, it can only tighten the max batch size of the provider.
+    batch_size: Option<usize>,
+    /// Max number of tokens per batch, it can only tighten the max batch tokens of the provider.
+    batch_tokens: Option<usize>,
+    /// Max number of batches running concurrently.
+    concurrency: usize,
+    /// Retry policy of failed batches.
+    retry: RetryPolicy,
+}
+
+impl Default for EmbeddingExecutor {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl EmbeddingExecutor {
+    /// Creates an executor with the default concurrency and retry policy.
+    pub fn new() -> Self {
+        Self {
+            batch_size: None,
+            batch_tokens: None,
+            concurrency: DEFAULT_CONCURRENCY,
+            retry: RetryPolicy::default(),
+        }
+    }
+
+    /// Sets the max number of texts per batch.
+    pub fn with_batch_size(mut self, batch_size: usize) -> Self {
+        self.batch_size = Some(batch_size.max(1));
+        self
+    }
+
+    /// Sets the max number of tokens per batch, counted by the tokenizer of the model.
+    pub fn with_batch_tokens(mut self, batch_tokens: usize) -> Self {
+        self.batch_tokens = Some(batch_tokens.max(1));
+        self
+    }
+
+    /// Sets the max number of batches running concurrently.
+    pub fn with_concurrency(mut self, concurrency: usize) -> Self {
+        self.concurrency = concurrency.max(1);
+        self
+    }
+
+    /// Sets the retry policy of failed batches.
+    pub fn with_retry(mut self, retry: RetryPolicy) -> Self {
+        self.retry = retry;
+        self
+    }
+
+    /// Returns the batch size for the embedder, None means no limit.
+    fn batch_size_of(&self, embedder: &dyn EmbeddingFeaturesDyn) -> Option<usize> {
+        match (self.batch_size, embedder.max_batch_size()) {
+            (Some(a), Some(b)) => Some(a.min(b)),
+            (a, b) => a.or(b),
+        }
+    }
+
+    /// Returns the batch tokens for the embedder, None means no limit.
+    fn batch_tokens_of(&self, embedder: &dyn EmbeddingFeaturesDyn) -> Option<usize> {
+        match (self.batch_tokens, embedder.max_batch_tokens()) {
+            (Some(a), Some(b)) => Some(a.min(b)),
+            (a, b) => a.or(b),
+        }
+    }
+
+    /// Embeds the texts with the embedder, returns the embeddings in the input order
+    /// and the merged usage of all batches. The tokens of the texts are counted with the tokenizer.
+    pub async fn embed(
+        &self,
+        embedder: &dyn EmbeddingFeaturesDyn,
+        tokenizer: &dyn Tokenizer,
+        texts: Vec<String>,
+    ) -> Result<(Vec<Embedding>, Usage), BoxError> {
+        if texts.is_empty() {
+            return Ok((Vec::new(), Usage::default()));
+        }
+
+        let total = texts.len();
+        let mut batches = split_batches(
+            texts,
+            self.batch_size_of(embedder),
+            self.batch_tokens_of(embedder),
+            |text| tokenizer.count_tokens(text),
+        );
+        if batches.len() == 1 {
+            return self.embed_batch(embedder, batches.pop().unwrap()).await;
+        }
+
+        // `buffered` keeps the order of the batches
+        let results: Vec<(Vec<Embedding>, Usage)> = stream::iter(
+            batches
+                .into_iter()
+                .map(|batch| self.embed_batch(embedder, batch)),
+        )
+        .buffered(self.concurrency)
+        .try_collect()
+        .await?;
+
+        let mut embeddings: Vec<Embedding> = Vec::with_capacity(total);
+        let mut usage = Usage::default();
+        for (res, u) in results {
+            embeddings.extend(res);
+            usage.accumulate(&u);
+        }
+        Ok((embeddings, usage))
+    }
+
+    /// Embeds a batch, retryable errors are retried.
+    async fn embed_batch(
+        &self,
+        embedder: &dyn EmbeddingFeaturesDyn,
+        batch: Vec<String>,
+    ) -> Result<(Vec<Embedding>, Usage), BoxError> {
+        let mut attempt = 0;
+        loop {
+            match embedder.embed(batch.clone()).await {
+                Ok((embeddings, usage)) => {
+                    if embeddings.len() != batch.len() {
+                        return Err(AndaError::new(
+                            ErrorCode::Upstream,
+                            format!(
+                                "embedding model returned {} embeddings for {} texts",
+                                embeddings.len(),
+                                batch.len()
+                            ),
+                        )
+                        .into());
+                    }
+                    return Ok((embeddings, usage));
+                }
+                Err(err) => {
+                    let err = AndaError::from(err);
+                    if !err.retryable() || attempt >= self.retry.max_retries {
+                        return Err(err.into());
+                    }
+                    log::warn!(
+                        "embedding executor: batch of {} texts failed, attempt {}: {}",
+                        batch.len(),
+                        attempt,
+                        err
+                    );
+                    tokio::time::sleep(self.retry.backoff(attempt)).await;
+                    attempt += 1;
+                }
+            }
+        }
+    }
+}
+
+/// Splits the texts into batches in order, each batch has at most `max_texts` texts and
+/// `max_tokens` tokens. A text with more than `max_tokens` tokens is a batch on its own.
+pub fn split_batches(
+    texts: Vec<String>,
+    max_texts: Option<usize>,
+    max_tokens: Option<usize>,
+    count_tokens: impl Fn(&str) -> usize,
+) -> Vec<Vec<String>> {
+    let max_texts = max_texts.unwrap_or(usize::MAX).max(1);
+    let max_tokens = max_tokens.unwrap_or(usize::MAX);
+    let mut batches: Vec<Vec<String>> = Vec::new();
+    let mut batch: Vec<String> = Vec::new();
+    let mut batch_tokens = 0usize;
+    for text in texts {
+        let tokens = if max_tokens == usize::MAX {
+            0
+        } else {
+            count_tokens(&text)
+        };
+        if !batch.is_empty()
+            && (batch.len() >= max_texts || batch_tokens.saturating_add(tokens) > max_tokens)
+        {
+            batches.push(std::mem::take(&mut batch));
+            batch_tokens = 0;
+        }
+        batch_tokens = batch_tokens.saturating_add(tokens);
+        batch.push(text);
+    }
+    if !batch.is_empty() {
+        batches.push(batch);
+    }
+    batches
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use anda_core::{BoxPinFut, HeuristicTokenizer};
+    use std::{
+        sync::{
+            Arc,
+            atomic::{AtomicUsize, Ordering},
+        },
+        time::Duration,
+    };
+
+    /// An embedding model that accepts at most 3 texts per call,
+    /// the first call fails with a retryable error.
+    struct LimitedModel {
+        calls: Arc<AtomicUsize>,
+    }
+
+    impl EmbeddingFeaturesDyn for LimitedModel {
+        fn ndims(&self) -> usize {
+            1
+        }
+
+        fn max_batch_size(&self) -> Option<usize> {
+            Some(3)
+        }
+
+        fn embed(
+            &self,
+            texts: Vec<String>,
+        ) -> BoxPinFut<Result<(Vec<Embedding>, Usage), BoxError>> {
+            let call = self.calls.fetch_add(1, Ordering::SeqCst);
+            Box::pin(async move {
+                if call == 0 {
+                    return Err(AndaError::rate_limited("slow down").into());
+                }
+                if texts.len() > 3 {
+                    return Err("too many documents".into());
+                }
+                // later batches finish first
+                let delay = 10u64.saturating_sub(texts[0].parse::<u64>().unwrap());
+                tokio::time::sleep(Duration::from_millis(delay)).await;
+                let usage = Usage {
+                    input_tokens: texts.len() as u64,
+                    requests: 1,
+                    ..Default::default()
+                };
+                let embeddings = texts
+                    .into_iter()
+                    .map(|text| Embedding {
+                        vec: vec![text.parse::<f32>().unwrap()],
+                        text,
+                    })
+                    .collect();
+                Ok((embeddings, usage))
+            })
+        }
+
+        fn embed_query(&self, _text: String) -> BoxPinFut<Result<(Embedding, Usage), BoxError>> {
+            Box::pin(futures::future::ready(Err("not implemented".into())))
+        }
+    }
+
+    #[tokio::test(flavor = "current_thread")]
+    async fn test_embedding_executor() {
+        let calls = Arc::new(AtomicUsize::new(0));
+        let model = LimitedModel {
+            calls: calls.clone(),
+        };
+        let executor = EmbeddingExecutor::new()
+            .with_concurrency(2)
+            .with_retry(RetryPolicy {
+                max_retries: 1,
+                initial_backoff: Duration::from_millis(1),
+                max_backoff: Duration::from_millis(1),
+            });
+
+        let texts: Vec<String> = (0..10).map(|i| i.to_string()).collect();
+        let (embeddings, usage) = executor
+            .embed(&model, &HeuristicTokenizer, texts.clone())
+            .await
+            .unwrap();
+        assert_eq!(
+            embeddings
+                .iter()
+                .map(|e| e.text.clone())
+                .collect::<Vec<_>>(),
+            texts
+        );
+        assert_eq!(embeddings[9].vec, vec![9.0]);
+        assert_eq!(usage.input_tokens, 10);
+        assert_eq!(usage.requests, 4);
+        // 4 batches and 1 retry
+        assert_eq!(calls.load(Ordering::SeqCst), 5);
+
+        // the batch size can only be tightened
+        let executor = executor.with_batch_size(5);
+        let (embeddings, usage) = executor
+            .embed(&model, &HeuristicTokenizer, texts.clone())
+            .await
+            .unwrap();
+        assert_eq!(embeddings.len(), 10);
+        assert_eq!(usage.requests, 4);
+
+        let (embeddings, _) = executor
+            .embed(&model, &HeuristicTokenizer, vec![])
+            .await
+            .unwrap();
+        assert!(embeddings.is_empty());
+
+        // non-retryable errors fail the job
+        calls.store(0, Ordering::SeqCst);
+        let executor = EmbeddingExecutor::new().with_retry(RetryPolicy {
+            max_retries: 0,
+            ..Default::default()
+        });
+        let err = executor
+            .embed(&model, &HeuristicTokenizer, texts)
+            .await
+            .unwrap_err();
+        assert!(err.to_string().contains("slow down"));
+    }
+
+    #[test]
+    fn test_split_batches() {
+        let texts: Vec<String> = ["a", "bb", "ccc", "dddd", "e"]
+            .iter()
+            .map(|t| t.to_string())
+            .collect();
+        let count = |text: &str| text.len();
+
+        let batches = split_batches(texts.clone(), None, None, count);
+        assert_eq!(batches, vec![texts.clone()]);
+
+        let batches = split_batches(texts.clone(), Some(2), None, count);
+        assert_eq!(
+            batches.iter().map(Vec::len).collect::<Vec<_>>(),
+            vec![2, 2, 1]
+        );
+
+        // the text over the budget is a batch on its own
+        let batches = split_batches(texts.clone(), None, Some(3), count);
+        assert_eq!(
+            batches,
+            vec![
+                vec!["a".to_string(), "bb".to_string()],
+                vec!["ccc".to_string()],
+                vec!["dddd".to_string()],
+                vec!["e".to_string()],
+            ]
+        );
+
+        let batches = split_batches(texts, Some(1), Some(100), count);
+        assert_eq!(batches.len(), 5);
+    }
+}
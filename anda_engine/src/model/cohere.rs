@@ -5,7 +5,7 @@
 //! Cohere embedding models and handles API communication, error handling,
 //! and response parsing.
 
-use anda_core::{BoxError, BoxPinFut, CONTENT_TYPE_JSON, Embedding, Usage};
+use anda_core::{AndaError, BoxError, BoxPinFut, CONTENT_TYPE_JSON, Embedding, Usage};
 use serde::Deserialize;
 use serde_json::json;
 use std::time::Duration;
@@ -135,6 +135,7 @@ impl EmbeddingResponse {
                 input_tokens: m.billed_units.input_tokens as u64,
                 output_tokens: m.billed_units.output_tokens as u64,
                 requests: 1,
+                ..Default::default()
             }),
         ))
     }
@@ -229,6 +230,15 @@ impl EmbeddingFeaturesDyn for EmbeddingModel {
         self.ndims
     }
 
+    /// Returns the model identifier
+    fn model_name(&self) -> String {
+        self.model.clone()
+    }
+
+    fn max_batch_size(&self) -> Option<usize> {
+        Some(MAX_DOCUMENTS)
+    }
+
     /// Generates embeddings for a batch of texts
     ///
     /// # Arguments
@@ -265,8 +275,12 @@ impl EmbeddingFeaturesDyn for EmbeddingModel {
                     Err(err) => Err(format!("Cohere embeddings error: {}", err).into()),
                 }
             } else {
+                let status = response.status().as_u16();
                 let msg = response.text().await?;
-                Err(format!("Cohere embeddings error: {}", msg).into())
+                Err(
+                    AndaError::from_status(status, format!("Cohere embeddings error: {}", msg))
+                        .into(),
+                )
             }
         })
     }
@@ -301,14 +315,19 @@ impl EmbeddingFeaturesDyn for EmbeddingModel {
                             input_tokens: m.billed_units.input_tokens as u64,
                             output_tokens: m.billed_units.output_tokens as u64,
                             requests: 1,
+                            ..Default::default()
                         });
                         Ok((Embedding { text, vec: data }, usage))
                     }
                     Err(err) => Err(format!("Cohere embeddings error: {}", err).into()),
                 }
             } else {
+                let status = response.status().as_u16();
                 let msg = response.text().await?;
-                Err(format!("Cohere embeddings error: {}", msg).into())
+                Err(
+                    AndaError::from_status(status, format!("Cohere embeddings error: {}", msg))
+                        .into(),
+                )
             }
         })
     }
@@ -0,0 +1,511 @@
+//! Context window management for completion requests.
+//!
+//! A [`CompletionRequest`] is sent to the model provider as-is, so a long thread or
+//! a large set of [`Documents`](anda_core::Documents) will eventually go over the
+//! context window of the model and the provider rejects the request.
+//!
+//! [`ContextManager`] counts the tokens of a request with the tokenizer of the model and
+//! trims it to fit the context window before it is sent by
+//! [`Model::completion`](super::Model::completion):
+//! 1. The system prompt, the user prompt, the content parts and the tools are never trimmed,
+//!    nor the leading `system` messages of `chat_history`, they hold the system prompt
+//!    from the second round of the tool loop on;
+//! 2. The oldest `chat_history` messages are dropped first, the latest
+//!    `keep_recent_messages` messages are kept as long as possible;
+//! 3. Then the documents are dropped from the last one, they are usually sorted by relevance.
+//!    The leading text part of the content parts is rebuilt without the dropped documents;
+//! 4. At last the remaining `chat_history` messages are dropped from the oldest one.
+//!
+//! When summarization is enabled, the dropped messages are replaced with a summary
+//! generated by the model itself.
+//!
+//! # Example
+//! ```rust,ignore
+//! use anda_engine::model::{ContextManager, Model};
+//!
+//! let model = Model::with_completer(completer)
+//!     .with_context_manager(ContextManager::new().with_summary(512));
+//! ```
+
+use anda_core::{AgentOutput, CompletionRequest, ContentPart, Tokenizer, Usage};
+use serde_json::{Value, json};
+
+use super::CompletionFeaturesDyn;
+
+/// Returns the context window size in tokens of the well-known models.
+pub fn context_window_of(model: &str) -> Option<usize> {
+    let model = model.to_ascii_lowercase();
+    let size = match model.as_str() {
+        m if m.starts_with("gpt-4.1") => 1_047_576,
+        m if m.starts_with("gpt-4o") || m.starts_with("gpt-4-turbo") => 128_000,
+        m if m.starts_with("gpt-4") => 8_192,
+        m if m.starts_with("gpt-3.5-turbo") => 16_385,
+        m if m.starts_with("o1-mini") || m.starts_with("o1-preview") => 128_000,
+        m if m.starts_with("o1") || m.starts_with("o3") || m.starts_with("o4") => 200_000,
+        m if m.starts_with("deepseek-") => 64_000,
+        m if m.starts_with("grok-") => 131_072,
+        m if m.starts_with("claude-") => 200_000,
+        m if m.starts_with("gemini-1.5-pro") => 2_097_152,
+        m if m.starts_with("gemini-") => 1_048_576,
+        _ => return None,
+    };
+    Some(size)
+}
+
+const SUMMARY_SYSTEM_PROMPT: &str = "\
+You are a helpful assistant that summarizes conversations. \
+Summarize the following conversation concisely in the language of the conversation. \
+Keep the facts, decisions, user preferences and open questions that are needed to continue the conversation. \
+Output the summary only.";
+
+/// Trims the chat history and documents of completion requests to fit the context window of the model.
+#[derive(Debug, Clone)]
+pub struct ContextManager {
+    context_size: Option<usize>,
+    reserved_output_tokens: usize,
+    keep_recent_messages: usize,
+    summary_max_tokens: Option<usize>,
+}
+
+impl Default for ContextManager {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl ContextManager {
+    /// Creates a new context manager. The context window size is provided by the
+    /// completion model, see [`CompletionFeaturesDyn::context_window`].
+    pub fn new() -> Self {
+        Self {
+            context_size: None,
+            reserved_output_tokens: 4096,
+            keep_recent_messages: 6,
+            summary_max_tokens: None,
+        }
+    }
+
+    /// Sets the context window size in tokens, it overrides the size provided by the model.
+    pub fn with_context_size(mut self, tokens: usize) -> Self {
+        self.context_size = Some(tokens);
+        self
+    }
+
+    /// Sets the tokens reserved for the output when the request has no `max_tokens`.
+    /// Defaults to `4096`.
+    pub fn with_reserved_output_tokens(mut self, tokens: usize) -> Self {
+        self.reserved_output_tokens = tokens;
+        self
+    }
+
+    /// Sets the number of the latest chat history messages that are kept before the documents
+    /// are trimmed. Defaults to `6`.
+    pub fn with_keep_recent_messages(mut self, n: usize) -> Self {
+        self.keep_recent_messages = n;
+        self
+    }
+
+    /// Enables replacing the dropped chat history messages with a summary generated by the model,
+    /// the summary is limited to `max_tokens` tokens.
+    pub fn with_summary(mut self, max_tokens: usize) -> Self {
+        self.summary_max_tokens = Some(max_tokens);
+        self
+    }
+
+    /// Returns the estimated tokens of the parts of the request that are never trimmed,
+    /// including the tokens reserved for the output.
+    fn fixed_tokens(&self, tokenizer: &dyn Tokenizer, req: &CompletionRequest) -> usize {
+        let mut tokens = req.max_tokens.unwrap_or(self.reserved_output_tokens);
+        if let Some(system) = &req.system {
+            tokens += tokenizer.count_tokens(system);
+        }
+        for msg in &req.chat_history[..leading_system_messages(&req.chat_history)] {
+            tokens += tokenizer.count_tokens(&msg.to_string());
+        }
+        tokens += tokenizer.count_tokens(&req.prompt);
+        // the documents in the leading text part are counted with the other documents
+        let parts = &req.content_parts[usize::from(has_context_part(req))..];
+        if !parts.is_empty() {
+            tokens += tokenizer.count_tokens(&json!(parts).to_string());
+        }
+        if !req.tools.is_empty() {
+            tokens += tokenizer.count_tokens(&json!(req.tools).to_string());
+        }
+        if let Some(format) = &req.response_format {
+            tokens += tokenizer.count_tokens(&format.to_string());
+        }
+        tokens
+    }
+
+    /// Trims the chat history and documents of the request to fit the context window size.
+    /// Returns the dropped chat history messages in chronological order.
+    pub fn trim(
+        &self,
+        tokenizer: &dyn Tokenizer,
+        req: &mut CompletionRequest,
+        context_size: usize,
+    ) -> Vec<Value> {
+        let budget = context_size.saturating_sub(self.fixed_tokens(tokenizer, req));
+        let context_part = has_context_part(req);
+        // the leading system messages are fixed
+        let head = leading_system_messages(&req.chat_history);
+        let history_len = req.chat_history.len() - head;
+        let history_tokens: Vec<usize> = req.chat_history[head..]
+            .iter()
+            .map(|msg| tokenizer.count_tokens(&msg.to_string()))
+            .collect();
+        let docs_tokens: Vec<usize> = req
+            .documents
+            .iter()
+            .map(|doc| tokenizer.count_tokens(&doc.to_string()))
+            .collect();
+        let mut total: usize =
+            history_tokens.iter().sum::<usize>() + docs_tokens.iter().sum::<usize>();
+        if total <= budget {
+            return Vec::new();
+        }
+
+        // drops the oldest messages, keeps the latest `keep_recent_messages` messages
+        let mut drop_history = 0;
+        let keep_from = history_len.saturating_sub(self.keep_recent_messages);
+        while total > budget && drop_history < keep_from {
+            total -= history_tokens[drop_history];
+            drop_history += 1;
+        }
+
+        // drops the documents from the last one
+        let mut keep_docs = req.documents.len();
+        while total > budget && keep_docs > 0 {
+            keep_docs -= 1;
+            total -= docs_tokens[keep_docs];
+        }
+        if keep_docs < req.documents.len() {
+            req.documents.truncate(keep_docs);
+            // the leading text part holds the dropped documents too
+            if context_part {
+                match req.prompt_with_context() {
+                    Some(prompt) => req.content_parts[0] = prompt.into(),
+                    None => {
+                        req.content_parts.remove(0);
+                    }
+                }
+            }
+        }
+
+        // drops the remaining messages from the oldest one
+        while total > budget && drop_history < history_len {
+            total -= history_tokens[drop_history];
+            drop_history += 1;
+        }
+
+        // tool messages without the assistant message that requested them are invalid
+        while drop_history < history_len
+            && req.chat_history[head + drop_history]["role"].as_str() == Some("tool")
+        {
+            drop_history += 1;
+        }
+
+        req.chat_history.drain(head..head + drop_history).collect()
+    }
+
+    /// Prepares the request to fit the context window of the completer.
+    /// Returns the usage of the summary completion if any.
+    pub(crate) async fn prepare(
+        &self,
+        completer: &dyn CompletionFeaturesDyn,
+        tokenizer: &dyn Tokenizer,
+        req: &mut CompletionRequest,
+    ) -> Usage {
+        let context_size = match self.context_size.or_else(|| completer.context_window()) {
+            Some(size) => size,
+            None => return Usage::default(),
+        };
+
+        let summary_tokens = match self.summary_max_tokens {
+            Some(tokens) => tokens,
+            None => {
+                self.trim(tokenizer, req, context_size);
+                return Usage::default();
+            }
+        };
+
+        // reserves the tokens of the summary
+        let dropped = self.trim(tokenizer, req, context_size.saturating_sub(summary_tokens));
+        if dropped.is_empty() {
+            return Usage::default();
+        }
+
+        let mut transcript: Vec<String> = dropped.iter().map(message_text).collect();
+        // the summary request should fit the context window too
+        let transcript_budget = context_size
+            .saturating_sub(summary_tokens)
+            .saturating_sub(tokenizer.count_tokens(SUMMARY_SYSTEM_PROMPT));
+        let mut transcript_tokens: usize =
+            transcript.iter().map(|t| tokenizer.count_tokens(t)).sum();
+        while transcript_tokens > transcript_budget && !transcript.is_empty() {
+            transcript_tokens -= tokenizer.count_tokens(&transcript.remove(0));
+        }
+        if transcript.is_empty() {
+            return Usage::default();
+        }
+
+        let summary_req = CompletionRequest {
+            system: Some(SUMMARY_SYSTEM_PROMPT.to_string()),
+            prompt: transcript.join("\n\n"),
+            max_tokens: Some(summary_tokens),
+            ..Default::default()
+        };
+        match completer.completion(summary_req).await {
+            Ok(AgentOutput {
+                content,
+                usage,
+                failed_reason: None,
+                ..
+            }) if !content.is_empty() => {
+                // after the system messages
+                req.chat_history.insert(
+                    leading_system_messages(&req.chat_history),
+                    json!({
+                        "role": "user",
+                        "content": format!("Summary of the earlier conversation:\n{}", content),
+                    }),
+                );
+                usage
+            }
+            Ok(output) => {
+                log::warn!(
+                    "failed to summarize the conversation: {:?}",
+                    output.failed_reason
+                );
+                output.usage
+            }
+            Err(err) => {
+                log::warn!("failed to summarize the conversation: {}", err);
+                Usage::default()
+            }
+        }
+    }
+}
+
+/// Returns true if the leading text part of the content parts is the prompt with the documents,
+/// see [`CompletionRequest::append_resources`].
+fn has_context_part(req: &CompletionRequest) -> bool {
+    match req.content_parts.first() {
+        Some(ContentPart::Text { text }) => req.prompt_with_context().as_ref() == Some(text),
+        _ => false,
+    }
+}
+
+/// Returns the number of the leading `system` messages.
+fn leading_system_messages(history: &[Value]) -> usize {
+    history
+        .iter()
+        .take_while(|msg| msg["role"].as_str() == Some("system"))
+        .count()
+}
+
+fn message_text(msg: &Value) -> String {
+    let role = msg["role"].as_str().unwrap_or("user");
+    match &msg["content"] {
+        Value::String(content) => format!("{}: {}", role, content),
+        content => format!("{}: {}", role, content),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::model::{MockImplemented, Model};
+    use anda_core::{BoxError, BoxPinFut, CompletionChunk, Documents, HeuristicTokenizer};
+    use futures::StreamExt;
+    use std::sync::Arc;
+
+    /// A mock model that reports 1 request and 10 output tokens per completion.
+    struct UsageModel;
+
+    impl CompletionFeaturesDyn for UsageModel {
+        fn completion(&self, req: CompletionRequest) -> BoxPinFut<Result<AgentOutput, BoxError>> {
+            Box::pin(futures::future::ready(Ok(AgentOutput {
+                content: req.prompt,
+                usage: Usage {
+                    output_tokens: 10,
+                    requests: 1,
+                    ..Default::default()
+                },
+                ..Default::default()
+            })))
+        }
+    }
+
+    fn request(messages: usize, docs: usize) -> CompletionRequest {
+        CompletionRequest {
+            system: Some("You are a helpful assistant.".to_string()),
+            prompt: "What is the answer?".to_string(),
+            chat_history: (0..messages)
+                .map(|i| {
+                    json!({
+                        "role": if i % 2 == 0 { "user" } else { "assistant" },
+                        "content": format!("message {} {}", i, "x".repeat(300)),
+                    })
+                })
+                .collect(),
+            documents: Documents::from(
+                (0..docs)
+                    .map(|i| format!("document {} {}", i, "y".repeat(300)))
+                    .collect::<Vec<_>>(),
+            ),
+            max_tokens: Some(100),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_context_window_of() {
+        assert_eq!(context_window_of("gpt-4o-mini"), Some(128_000));
+        assert_eq!(context_window_of("o3-mini"), Some(200_000));
+        assert_eq!(context_window_of("deepseek-chat"), Some(64_000));
+        assert_eq!(context_window_of("unknown"), None);
+    }
+
+    #[test]
+    fn test_trim() {
+        let cm = ContextManager::new().with_keep_recent_messages(2);
+
+        let mut req = request(10, 3);
+        let dropped = cm.trim(&HeuristicTokenizer, &mut req, 100_000);
+        assert!(dropped.is_empty());
+        assert_eq!(req.chat_history.len(), 10);
+        assert_eq!(req.documents.len(), 3);
+
+        // drops the old messages, keeps the documents
+        let mut req = request(10, 3);
+        let dropped = cm.trim(&HeuristicTokenizer, &mut req, 850);
+        assert_eq!(req.documents.len(), 3);
+        assert!(!dropped.is_empty());
+        assert!(req.chat_history.len() >= 2);
+        assert_eq!(dropped.len() + req.chat_history.len(), 10);
+        assert!(
+            dropped[0]["content"]
+                .as_str()
+                .unwrap()
+                .starts_with("message 0 ")
+        );
+
+        // drops the documents after the old messages
+        let mut req = request(10, 3);
+        let dropped = cm.trim(&HeuristicTokenizer, &mut req, 450);
+        assert_eq!(dropped.len(), 8);
+        assert_eq!(req.chat_history.len(), 2);
+        assert!(req.documents.len() < 3);
+
+        // drops all
+        let mut req = request(10, 3);
+        let dropped = cm.trim(&HeuristicTokenizer, &mut req, 10);
+        assert_eq!(dropped.len(), 10);
+        assert!(req.chat_history.is_empty());
+        assert!(req.documents.is_empty());
+        assert_eq!(req.prompt, "What is the answer?");
+    }
+
+    #[test]
+    fn test_trim_content_parts() {
+        let cm = ContextManager::new().with_keep_recent_messages(2);
+        let image = ContentPart::Image {
+            image_url: anda_core::ImageDetail {
+                url: "https://example.com/image.jpg".to_string(),
+                detail: None,
+            },
+        };
+
+        // the documents in the leading text part are not counted twice
+        let mut req = request(0, 3);
+        req.content_parts = vec![req.prompt_with_context().unwrap().into(), image.clone()];
+        let dropped = cm.trim(&HeuristicTokenizer, &mut req, 550);
+        assert!(dropped.is_empty());
+        assert_eq!(req.documents.len(), 3);
+
+        // the leading text part is rebuilt without the dropped documents
+        let dropped = cm.trim(&HeuristicTokenizer, &mut req, 300);
+        assert!(dropped.is_empty());
+        assert!(req.documents.len() < 3);
+        assert_eq!(
+            req.content_parts,
+            vec![req.prompt_with_context().unwrap().into(), image.clone()]
+        );
+
+        // the content parts set by the caller are kept
+        let mut req = request(0, 3);
+        req.content_parts = vec!["Describe the image.".into(), image.clone()];
+        cm.trim(&HeuristicTokenizer, &mut req, 10);
+        assert!(req.documents.is_empty());
+        assert_eq!(req.content_parts, vec!["Describe the image.".into(), image]);
+    }
+
+    #[test]
+    fn test_trim_keeps_system_messages() {
+        let cm = ContextManager::new().with_keep_recent_messages(2);
+
+        // the system prompt is in the chat history from the second round of the tool loop
+        let mut req = request(10, 0);
+        let system = json!({
+            "role": "system",
+            "content": req.system.take().unwrap(),
+        });
+        req.chat_history.insert(0, system.clone());
+
+        let dropped = cm.trim(&HeuristicTokenizer, &mut req, 850);
+        assert!(!dropped.is_empty());
+        assert_eq!(req.chat_history[0], system);
+        assert!(dropped.iter().all(|msg| msg["role"] != "system"));
+        assert!(
+            dropped[0]["content"]
+                .as_str()
+                .unwrap()
+                .starts_with("message 0 ")
+        );
+
+        let dropped = cm.trim(&HeuristicTokenizer, &mut req, 10);
+        assert!(!dropped.is_empty());
+        assert_eq!(req.chat_history, vec![system]);
+    }
+
+    #[tokio::test(flavor = "current_thread")]
+    async fn test_prepare_with_summary() {
+        let cm = ContextManager::new()
+            .with_context_size(800)
+            .with_keep_recent_messages(2)
+            .with_summary(100);
+        let mut req = request(10, 0);
+        cm.prepare(&MockImplemented, &HeuristicTokenizer, &mut req)
+            .await;
+        assert!(req.chat_history.len() < 10);
+        let summary = req.chat_history[0]["content"].as_str().unwrap();
+        assert!(summary.starts_with("Summary of the earlier conversation:\n"));
+        // the mock model echoes the prompt, it is the transcript of the dropped messages
+        assert!(summary.contains("user: message 0 "));
+    }
+
+    #[tokio::test(flavor = "current_thread")]
+    async fn test_completion_stream_with_summary() {
+        let model = Model::with_completer(Arc::new(UsageModel)).with_context_manager(
+            ContextManager::new()
+                .with_context_size(800)
+                .with_keep_recent_messages(2)
+                .with_summary(100),
+        );
+        let chunks: Vec<CompletionChunk> = model
+            .completion_stream(request(10, 0))
+            .await
+            .unwrap()
+            .map(|chunk| chunk.unwrap())
+            .collect()
+            .await;
+        // the usage of the summary is included
+        assert!(
+            matches!(chunks.last(), Some(CompletionChunk::Done(output)) if output.usage.requests == 2)
+        );
+        assert!(chunks.iter().any(
+            |chunk| matches!(chunk, CompletionChunk::Usage(u) if u.requests == 2 && u.output_tokens == 20)
+        ));
+    }
+}
@@ -6,15 +6,19 @@
 //! - Response parsing and conversion to Anda's internal formats
 
 use anda_core::{
-    AgentOutput, BoxError, BoxPinFut, CONTENT_TYPE_JSON, CompletionFeatures, CompletionRequest,
-    FunctionDefinition, Message, Resource, ToolCall, Usage as ModelUsage,
+    AgentOutput, AndaError, BoxError, BoxPinFut, CONTENT_TYPE_JSON, CompletionFeatures,
+    CompletionRequest, CompletionStream, ContentPart, FunctionDefinition, Message, Resource,
+    ToolCall, Usage as ModelUsage,
 };
 use log::{Level::Debug, log_enabled};
 use serde::{Deserialize, Serialize};
 use serde_json::{Value, json};
 use std::time::Duration;
 
-use super::CompletionFeaturesDyn;
+use super::{
+    CompletionFeaturesDyn, context_window_of, push_format_instruction, sse::completion_stream,
+    without_reasoning,
+};
 use crate::APP_USER_AGENT;
 
 // ================================================================
@@ -98,6 +102,35 @@ pub struct Usage {
     pub prompt_tokens: usize,
     /// Number of tokens used in the completion
     pub completion_tokens: usize,
+    /// Number of prompt tokens that hit the context cache
+    #[serde(default)]
+    pub prompt_cache_hit_tokens: usize,
+    /// Breakdown of the completion tokens
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub completion_tokens_details: Option<CompletionTokensDetails>,
+}
+
+/// Breakdown of the completion tokens from DeepSeek API responses
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+pub struct CompletionTokensDetails {
+    /// Number of tokens generated for the chain of thought
+    #[serde(default)]
+    pub reasoning_tokens: usize,
+}
+
+impl From<&Usage> for ModelUsage {
+    fn from(u: &Usage) -> Self {
+        ModelUsage {
+            input_tokens: u.prompt_tokens as u64,
+            output_tokens: u.completion_tokens as u64,
+            requests: 1,
+            cached_input_tokens: u.prompt_cache_hit_tokens as u64,
+            reasoning_tokens: u
+                .completion_tokens_details
+                .as_ref()
+                .map_or(0, |d| d.reasoning_tokens as u64),
+        }
+    }
 }
 
 impl std::fmt::Display for Usage {
@@ -133,6 +166,7 @@ impl CompletionResponse {
         full_history.push(json!(choice.message));
         let mut output = AgentOutput {
             content: choice.message.content.unwrap_or_default(),
+            reasoning: choice.message.reasoning_content,
             tool_calls: choice.message.tool_calls.map(|tools| {
                 tools
                     .into_iter()
@@ -148,11 +182,7 @@ impl CompletionResponse {
             usage: self
                 .usage
                 .as_ref()
-                .map(|u| ModelUsage {
-                    input_tokens: u.prompt_tokens as u64,
-                    output_tokens: u.completion_tokens as u64,
-                    requests: 1,
-                })
+                .map(ModelUsage::from)
                 .unwrap_or_default(),
             ..Default::default()
         };
@@ -184,6 +214,9 @@ pub struct MessageOutput {
     pub content: Option<String>,
     pub refusal: Option<String>,
     pub tool_calls: Option<Vec<ToolCallOutput>>,
+    /// The chain of thought of `deepseek-reasoner`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub reasoning_content: Option<String>,
 }
 
 /// Tool call output structure from DeepSeek API
@@ -201,7 +234,8 @@ pub struct ToolDefinition {
 }
 
 impl From<FunctionDefinition> for ToolDefinition {
-    fn from(f: FunctionDefinition) -> Self {
+    fn from(mut f: FunctionDefinition) -> Self {
+        f.output_schema = None; // the output schema is not a part of the function calling API
         Self {
             r#type: "function".into(),
             function: f,
@@ -236,6 +270,106 @@ impl CompletionModel {
             model: model.to_string(),
         }
     }
+
+    /// Builds the chat completions request body, returns the messages sent to the model
+    /// and the JSON body.
+    fn request_body(&self, mut req: CompletionRequest) -> Result<(Vec<Value>, Value), BoxError> {
+        // Add system to chat history (if available)
+        let mut full_history = if let Some(system) = &req.system {
+            vec![json!(Message {
+                role: "system".into(),
+                content: system.to_owned().into(),
+                name: req.system_name.clone(),
+                ..Default::default()
+            })]
+        } else {
+            vec![]
+        };
+
+        // Extend existing chat history
+        full_history.append(&mut req.chat_history);
+
+        if !req.content_parts.is_empty() {
+            // DeepSeek only supports text inputs
+            let mut texts: Vec<String> = Vec::with_capacity(req.content_parts.len());
+            for part in req.content_parts {
+                match part {
+                    ContentPart::Text { text } => texts.push(text),
+                    _ => {
+                        return Err(AndaError::invalid_args(format!(
+                            "model {} does not support image or audio inputs",
+                            self.model
+                        ))
+                        .into());
+                    }
+                }
+            }
+            full_history.push(json!(Message {
+                role: "user".into(),
+                content: texts.join("\n").into(),
+                name: req.prompter_name,
+                ..Default::default()
+            }));
+        } else if let Some(prompt) = req.prompt_with_context() {
+            full_history.push(json!(Message {
+                role: "user".into(),
+                content: prompt.into(),
+                name: req.prompter_name,
+                ..Default::default()
+            }));
+        }
+
+        let mut body = json!({
+            "model": self.model,
+            "messages": without_reasoning(&full_history),
+        });
+
+        let obj = body.as_object_mut().unwrap();
+        if let Some(temperature) = req.temperature {
+            obj.insert("temperature".to_string(), Value::from(temperature));
+        }
+
+        if let Some(max_tokens) = req.max_tokens {
+            obj.insert("max_tokens".to_string(), Value::from(max_tokens));
+        }
+
+        if let Some(format) = &req.response_format {
+            // DeepSeek only supports `{"type": "json_object"}`, the schema is added to the prompt
+            obj.insert(
+                "response_format".to_string(),
+                json!({"type": "json_object"}),
+            );
+            if let Some(messages) = obj.get_mut("messages").and_then(Value::as_array_mut) {
+                push_format_instruction(messages, format, "system");
+            }
+        }
+
+        if let Some(stop) = req.stop {
+            obj.insert("stop".to_string(), Value::from(stop));
+        }
+
+        if !req.tools.is_empty() {
+            obj.insert(
+                "tools".to_string(),
+                json!(
+                    req.tools
+                        .into_iter()
+                        .map(ToolDefinition::from)
+                        .collect::<Vec<_>>()
+                ),
+            );
+            obj.insert(
+                "tool_choice".to_string(),
+                if req.tool_choice_required {
+                    Value::from("required")
+                } else {
+                    Value::from("auto")
+                },
+            );
+        };
+
+        Ok((full_history, body))
+    }
 }
 
 impl CompletionFeatures for CompletionModel {
@@ -246,98 +380,31 @@ impl CompletionFeatures for CompletionModel {
     ) -> Result<AgentOutput, BoxError> {
         CompletionFeaturesDyn::completion(self, req).await
     }
+
+    async fn completion_stream(
+        &self,
+        req: CompletionRequest,
+        _resources: Option<Vec<Resource>>,
+    ) -> Result<CompletionStream, BoxError> {
+        CompletionFeaturesDyn::completion_stream(self, req).await
+    }
 }
 
 impl CompletionFeaturesDyn for CompletionModel {
-    fn completion(&self, mut req: CompletionRequest) -> BoxPinFut<Result<AgentOutput, BoxError>> {
-        let model = self.model.clone();
+    fn completion(&self, req: CompletionRequest) -> BoxPinFut<Result<AgentOutput, BoxError>> {
+        let reasoning = req.reasoning;
+        let rt = self.request_body(req);
         let client = self.client.clone();
 
         Box::pin(async move {
-            // Add system to chat history (if available)
-            let mut full_history = if let Some(system) = &req.system {
-                vec![json!(Message {
-                    role: "system".into(),
-                    content: system.to_owned().into(),
-                    name: req.system_name.clone(),
-                    ..Default::default()
-                })]
-            } else {
-                vec![]
-            };
-
-            // Extend existing chat history
-            full_history.append(&mut req.chat_history);
-
-            if !req.content_parts.is_empty() {
-                full_history.push(json!(Message {
-                    role: "user".into(),
-                    content: json!(req.content_parts),
-                    name: req.prompter_name,
-                    ..Default::default()
-                }));
-            } else if let Some(prompt) = req.prompt_with_context() {
-                full_history.push(json!(Message {
-                    role: "user".into(),
-                    content: prompt.into(),
-                    name: req.prompter_name,
-                    ..Default::default()
-                }));
-            }
-
-            let mut body = json!({
-                "model": model,
-                "messages": full_history.clone(),
-            });
-
-            let body = body.as_object_mut().unwrap();
-            if let Some(temperature) = req.temperature {
-                body.insert("temperature".to_string(), Value::from(temperature));
-            }
-
-            if let Some(max_tokens) = req.max_tokens {
-                body.insert("max_tokens".to_string(), Value::from(max_tokens));
-            }
-
-            if req.response_format.is_some() {
-                // DeepSeek only supports `{"type": "json_object"}`
-                body.insert(
-                    "response_format".to_string(),
-                    json!({"type": "json_object"}),
-                );
-            }
-
-            if let Some(stop) = req.stop {
-                body.insert("stop".to_string(), Value::from(stop));
-            }
-
-            if !req.tools.is_empty() {
-                body.insert(
-                    "tools".to_string(),
-                    json!(
-                        req.tools
-                            .into_iter()
-                            .map(ToolDefinition::from)
-                            .collect::<Vec<_>>()
-                    ),
-                );
-                body.insert(
-                    "tool_choice".to_string(),
-                    if req.tool_choice_required {
-                        Value::from("required")
-                    } else {
-                        Value::from("auto")
-                    },
-                );
-            };
-
+            let (full_history, body) = rt?;
             if log_enabled!(Debug) {
                 if let Ok(val) = serde_json::to_string(&body) {
                     log::debug!(request = val; "DeepSeek completions request");
                 }
             }
 
-            let response = client.post("/chat/completions").json(body).send().await?;
+            let response = client.post("/chat/completions").json(&body).send().await?;
             if response.status().is_success() {
                 let text = response.text().await?;
                 match serde_json::from_str::<CompletionResponse>(&text) {
@@ -348,14 +415,71 @@ impl CompletionFeaturesDyn for CompletionModel {
                             }
                         }
                         res.try_into(full_history)
+                            .map(|output| output.with_reasoning_mode(reasoning))
                     }
                     Err(err) => {
                         Err(format!("DeepSeek completions error: {}, body: {}", err, text).into())
                     }
                 }
             } else {
+                let status = response.status().as_u16();
+                let msg = response.text().await?;
+                Err(
+                    AndaError::from_status(status, format!("DeepSeek completions error: {}", msg))
+                        .into(),
+                )
+            }
+        })
+    }
+
+    fn context_window(&self) -> Option<usize> {
+        context_window_of(&self.model)
+    }
+
+    fn model_name(&self) -> String {
+        self.model.clone()
+    }
+
+    fn completion_stream(
+        &self,
+        req: CompletionRequest,
+    ) -> BoxPinFut<Result<CompletionStream, BoxError>> {
+        let reasoning = req.reasoning;
+        let rt = self.request_body(req);
+        let client = self.client.clone();
+
+        Box::pin(async move {
+            let (full_history, mut body) = rt?;
+            let obj = body.as_object_mut().unwrap();
+            obj.insert("stream".to_string(), Value::from(true));
+            obj.insert("stream_options".to_string(), json!({"include_usage": true}));
+
+            if log_enabled!(Debug) {
+                if let Ok(val) = serde_json::to_string(&body) {
+                    log::debug!(request = val; "DeepSeek completions stream request");
+                }
+            }
+
+            let response = client
+                .post("/chat/completions")
+                .header(http::header::ACCEPT, "text/event-stream")
+                .json(&body)
+                .send()
+                .await?;
+            if response.status().is_success() {
+                Ok(completion_stream(
+                    "DeepSeek",
+                    response,
+                    full_history,
+                    reasoning,
+                ))
+            } else {
+                let status = response.status().as_u16();
                 let msg = response.text().await?;
-                Err(format!("DeepSeek completions error: {}", msg).into())
+                Err(
+                    AndaError::from_status(status, format!("DeepSeek completions error: {}", msg))
+                        .into(),
+                )
             }
         })
     }
@@ -365,8 +489,55 @@ impl CompletionFeaturesDyn for CompletionModel {
 mod tests {
     use super::*;
     use crate::extension::character::Character;
+    use anda_core::ReasoningMode;
     use std::time::Instant;
 
+    #[test]
+    fn test_reasoning_content() {
+        let res: CompletionResponse = serde_json::from_value(json!({
+            "id": "1",
+            "object": "chat.completion",
+            "created": 0,
+            "model": DEEKSEEK_R1,
+            "choices": [{
+                "index": 0,
+                "message": {
+                    "role": "assistant",
+                    "content": "42",
+                    "reasoning_content": "Let me think.",
+                },
+                "finish_reason": "stop",
+            }],
+            "usage": {
+                "prompt_tokens": 10,
+                "completion_tokens": 8,
+                "completion_tokens_details": {"reasoning_tokens": 5},
+            },
+        }))
+        .unwrap();
+        let output = res
+            .try_into(vec![json!({"role": "user", "content": "?"})])
+            .unwrap()
+            .with_reasoning_mode(ReasoningMode::Keep);
+        assert_eq!(output.reasoning.as_deref(), Some("Let me think."));
+        assert_eq!(output.usage.reasoning_tokens, 5);
+        let history = output.full_history.unwrap();
+        assert_eq!(history[1]["reasoning_content"], "Let me think.");
+
+        // the kept reasoning content is never sent back to the model
+        let model = Client::new("key", None).completion_model(DEEKSEEK_R1);
+        let (full_history, body) = model
+            .request_body(CompletionRequest {
+                chat_history: history,
+                prompt: "Why?".into(),
+                ..Default::default()
+            })
+            .unwrap();
+        assert_eq!(full_history[1]["reasoning_content"], "Let me think.");
+        assert!(body["messages"][1].get("reasoning_content").is_none());
+        assert_eq!(body["messages"][2]["content"], "Why?");
+    }
+
     #[tokio::test(flavor = "current_thread")]
     #[ignore]
     async fn test_deepseek() {
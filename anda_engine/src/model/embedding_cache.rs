@@ -0,0 +1,388 @@
+//! Persistent embedding cache keyed by model and text hash.
+//!
+//! [`CachedEmbedder`] is an [`EmbeddingFeaturesDyn`] wrapper that caches embedding vectors,
+//! so the same segments and queries are not embedded again and again:
+//! - Vectors are stored in [`Store`] at `{model}/{kind}/{sha3_256(text)}` under the namespace,
+//!   document and query embeddings are cached separately because some providers embed them
+//!   differently;
+//! - Recently used vectors are kept in an in-memory LRU tier;
+//! - Vectors with dimensions different from [`EmbeddingFeaturesDyn::ndims`] are never
+//!   returned or cached.
+//!
+//! The usage only counts the texts that miss the cache.
+//!
+//! # Example
+//! ```rust,ignore
+//! let embedder = CachedEmbedder::new(
+//!     Arc::new(cohere.embedding_model(EMBED_MULTILINGUAL_V3)),
+//!     Store::new(object_store.clone()),
+//! );
+//! let model = Model::new(completer, Arc::new(embedder));
+//! ```
+
+use anda_core::{AndaError, BoxError, BoxPinFut, Embedding, ErrorCode, Path, PutMode, Usage};
+use ciborium::from_reader;
+use futures::stream::{self, StreamExt};
+use ic_cose_types::{cose::sha3_256, to_cbor_bytes};
+use moka::future::Cache;
+use std::{collections::BTreeMap, fmt::Write, sync::Arc};
+
+use super::EmbeddingFeaturesDyn;
+use crate::store::Store;
+
+/// The default max number of vectors in the in-memory tier.
+pub const DEFAULT_HOT_CAPACITY: u64 = 10_000;
+
+/// The default namespace of the cached vectors in the store.
+pub static DEFAULT_NAMESPACE: &str = "_embedding_cache";
+
+/// Max number of concurrent store operations of a batch.
+const STORE_CONCURRENCY: usize = 16;
+
+static KIND_DOCUMENT: &str = "doc";
+static KIND_QUERY: &str = "query";
+
+/// An embedding model wrapper that caches the vectors in memory and in the store.
+#[derive(Clone)]
+pub struct CachedEmbedder {
+    inner: Arc<dyn EmbeddingFeaturesDyn>,
+    store: Store,
+    namespace: Path,
+    hot: Cache<String, Arc<Vec<f32>>>,
+}
+
+impl CachedEmbedder {
+    /// Creates a cache for the embedding model with the default namespace and hot capacity.
+    pub fn new(inner: Arc<dyn EmbeddingFeaturesDyn>, store: Store) -> Self {
+        Self {
+            inner,
+            store,
+            namespace: Path::from(DEFAULT_NAMESPACE),
+            hot: Cache::new(DEFAULT_HOT_CAPACITY),
+        }
+    }
+
+    /// Sets the namespace of the cached vectors in the store.
+    pub fn with_namespace(mut self, namespace: Path) -> Self {
+        self.namespace = namespace;
+        self
+    }
+
+    /// Sets the max number of vectors in the in-memory tier.
+    pub fn with_hot_capacity(mut self, capacity: u64) -> Self {
+        self.hot = Cache::new(capacity);
+        self
+    }
+
+    /// Returns the store path of the text's vector.
+    fn cache_path(&self, kind: &str, text: &str) -> Path {
+        let mut model = self.inner.model_name();
+        if model.is_empty() {
+            model = "default".to_string();
+        }
+        let mut hash = String::with_capacity(64);
+        for b in sha3_256(text.as_bytes()) {
+            let _ = write!(hash, "{:02x}", b);
+        }
+        Path::from_iter([model.as_str(), kind, hash.as_str()])
+    }
+
+    /// Checks the dimensions of the vector, 0 `ndims` means unknown.
+    fn check_ndims(&self, vec: &[f32]) -> Result<(), AndaError> {
+        let ndims = self.inner.ndims();
+        if ndims > 0 && vec.len() != ndims {
+            return Err(AndaError::new(
+                ErrorCode::Upstream,
+                format!(
+                    "embedding model {} returned {} dimensions, expected {}",
+                    self.inner.model_name(),
+                    vec.len(),
+                    ndims
+                ),
+            ));
+        }
+        Ok(())
+    }
+
+    /// Gets the cached vector from the in-memory tier, then from the store.
+    async fn get(&self, path: &Path) -> Option<Arc<Vec<f32>>> {
+        if let Some(vec) = self.hot.get(path.as_ref()).await {
+            return Some(vec);
+        }
+
+        let (data, _) = self.store.store_get(&self.namespace, path).await.ok()?;
+        let vec: Vec<f32> = from_reader(&data[..]).ok()?;
+        // the model may be configured with other dimensions
+        if self.check_ndims(&vec).is_err() {
+            return None;
+        }
+        let vec = Arc::new(vec);
+        self.hot.insert(path.to_string(), vec.clone()).await;
+        Some(vec)
+    }
+
+    /// Caches the vector in the in-memory tier and the store.
+    /// Failures of the store are logged, they don't fail the embedding.
+    async fn put(&self, path: &Path, vec: Arc<Vec<f32>>) {
+        let data = to_cbor_bytes(vec.as_ref());
+        self.hot.insert(path.to_string(), vec).await;
+        if let Err(err) = self
+            .store
+            .store_put(&self.namespace, path, PutMode::Overwrite, data.into())
+            .await
+        {
+            log::warn!("embedding cache: failed to store {}: {}", path, err);
+        }
+    }
+
+    async fn embed_texts(&self, texts: Vec<String>) -> Result<(Vec<Embedding>, Usage), BoxError> {
+        let paths: Vec<Path> = texts
+            .iter()
+            .map(|text| self.cache_path(KIND_DOCUMENT, text))
+            .collect();
+        let mut vecs: Vec<Option<Arc<Vec<f32>>>> = stream::iter(paths.iter().map(|p| self.get(p)))
+            .buffered(STORE_CONCURRENCY)
+            .collect()
+            .await;
+
+        // the missed texts, the same text is embedded once
+        let mut missed: Vec<(String, &Path)> = Vec::new();
+        let mut positions: BTreeMap<&str, usize> = BTreeMap::new();
+        for ((text, path), vec) in texts.iter().zip(paths.iter()).zip(vecs.iter()) {
+            if vec.is_none() && !positions.contains_key(text.as_str()) {
+                positions.insert(text.as_str(), missed.len());
+                missed.push((text.clone(), path));
+            }
+        }
+
+        let mut usage = Usage::default();
+        if !missed.is_empty() {
+            let (embeddings, u) = self
+                .inner
+                .embed(missed.iter().map(|(text, _)| text.clone()).collect())
+                .await?;
+            if embeddings.len() != missed.len() {
+                return Err(AndaError::new(
+                    ErrorCode::Upstream,
+                    format!(
+                        "embedding model returned {} embeddings for {} texts",
+                        embeddings.len(),
+                        missed.len()
+                    ),
+                )
+                .into());
+            }
+            usage = u;
+
+            let mut fresh: Vec<Arc<Vec<f32>>> = Vec::with_capacity(embeddings.len());
+            for embedding in embeddings {
+                self.check_ndims(&embedding.vec)?;
+                fresh.push(Arc::new(embedding.vec));
+            }
+            stream::iter(
+                missed
+                    .iter()
+                    .zip(fresh.iter())
+                    .map(|((_, path), vec)| self.put(path, vec.clone())),
+            )
+            .buffer_unordered(STORE_CONCURRENCY)
+            .collect::<Vec<_>>()
+            .await;
+
+            for (text, vec) in texts.iter().zip(vecs.iter_mut()) {
+                if vec.is_none() {
+                    *vec = Some(fresh[positions[text.as_str()]].clone());
+                }
+            }
+        }
+
+        let embeddings = texts
+            .into_iter()
+            .zip(vecs)
+            .map(|(text, vec)| Embedding {
+                text,
+                vec: vec.map(|v| v.as_ref().clone()).unwrap_or_default(),
+            })
+            .collect();
+        Ok((embeddings, usage))
+    }
+
+    async fn embed_query_text(&self, text: String) -> Result<(Embedding, Usage), BoxError> {
+        let path = self.cache_path(KIND_QUERY, &text);
+        if let Some(vec) = self.get(&path).await {
+            return Ok((
+                Embedding {
+                    text,
+                    vec: vec.as_ref().clone(),
+                },
+                Usage::default(),
+            ));
+        }
+
+        let (embedding, usage) = self.inner.embed_query(text).await?;
+        self.check_ndims(&embedding.vec)?;
+        self.put(&path, Arc::new(embedding.vec.clone())).await;
+        Ok((embedding, usage))
+    }
+}
+
+impl EmbeddingFeaturesDyn for CachedEmbedder {
+    fn ndims(&self) -> usize {
+        self.inner.ndims()
+    }
+
+    fn embed(&self, texts: Vec<String>) -> BoxPinFut<Result<(Vec<Embedding>, Usage), BoxError>> {
+        let this = self.clone();
+        Box::pin(async move { this.embed_texts(texts).await })
+    }
+
+    fn embed_query(&self, text: String) -> BoxPinFut<Result<(Embedding, Usage), BoxError>> {
+        let this = self.clone();
+        Box::pin(async move { this.embed_query_text(text).await })
+    }
+
+    fn model_name(&self) -> String {
+        self.inner.model_name()
+    }
+
+    fn max_batch_size(&self) -> Option<usize> {
+        self.inner.max_batch_size()
+    }
+
+    fn max_batch_tokens(&self) -> Option<usize> {
+        self.inner.max_batch_tokens()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::store::InMemory;
+    use std::sync::{
+        Mutex,
+        atomic::{AtomicUsize, Ordering},
+    };
+
+    /// An embedding model that records the embedded texts.
+    struct CountingModel {
+        ndims: usize,
+        texts: Arc<Mutex<Vec<String>>>,
+        dims: Arc<AtomicUsize>,
+    }
+
+    impl CountingModel {
+        fn vec_of(&self, text: &str) -> Vec<f32> {
+            vec![text.len() as f32; self.dims.load(Ordering::SeqCst)]
+        }
+    }
+
+    impl EmbeddingFeaturesDyn for CountingModel {
+        fn ndims(&self) -> usize {
+            self.ndims
+        }
+
+        fn model_name(&self) -> String {
+            "counting".to_string()
+        }
+
+        fn embed(
+            &self,
+            texts: Vec<String>,
+        ) -> BoxPinFut<Result<(Vec<Embedding>, Usage), BoxError>> {
+            self.texts.lock().unwrap().extend(texts.iter().cloned());
+            let embeddings = texts
+                .into_iter()
+                .map(|text| Embedding {
+                    vec: self.vec_of(&text),
+                    text,
+                })
+                .collect::<Vec<_>>();
+            let usage = Usage {
+                input_tokens: embeddings.len() as u64,
+                requests: 1,
+                ..Default::default()
+            };
+            Box::pin(futures::future::ready(Ok((embeddings, usage))))
+        }
+
+        fn embed_query(&self, text: String) -> BoxPinFut<Result<(Embedding, Usage), BoxError>> {
+            self.texts.lock().unwrap().push(text.clone());
+            let embedding = Embedding {
+                vec: self.vec_of(&text),
+                text,
+            };
+            let usage = Usage {
+                input_tokens: 1,
+                requests: 1,
+                ..Default::default()
+            };
+            Box::pin(futures::future::ready(Ok((embedding, usage))))
+        }
+    }
+
+    #[tokio::test(flavor = "current_thread")]
+    async fn test_cached_embedder() {
+        let store = Store::new(Arc::new(InMemory::new()));
+        let texts = Arc::new(Mutex::new(Vec::new()));
+        let dims = Arc::new(AtomicUsize::new(2));
+        let model: Arc<dyn EmbeddingFeaturesDyn> = Arc::new(CountingModel {
+            ndims: 2,
+            texts: texts.clone(),
+            dims: dims.clone(),
+        });
+        let embedder = CachedEmbedder::new(model.clone(), store.clone());
+
+        let input = vec!["a".to_string(), "bb".to_string(), "a".to_string()];
+        let (embeddings, usage) = embedder.embed(input.clone()).await.unwrap();
+        assert_eq!(embeddings.len(), 3);
+        assert_eq!(embeddings[0].text, "a");
+        assert_eq!(embeddings[1].vec, vec![2.0, 2.0]);
+        assert_eq!(embeddings[2].vec, vec![1.0, 1.0]);
+        assert_eq!(usage.input_tokens, 2);
+        assert_eq!(*texts.lock().unwrap(), vec!["a", "bb"]);
+
+        // hits the in-memory tier
+        let (embeddings, usage) = embedder
+            .embed(vec!["bb".to_string(), "ccc".to_string()])
+            .await
+            .unwrap();
+        assert_eq!(embeddings[0].vec, vec![2.0, 2.0]);
+        assert_eq!(embeddings[1].vec, vec![3.0, 3.0]);
+        assert_eq!(usage.input_tokens, 1);
+        assert_eq!(texts.lock().unwrap().len(), 3);
+
+        // queries are cached separately
+        let (embedding, usage) = embedder.embed_query("a".to_string()).await.unwrap();
+        assert_eq!(embedding.vec, vec![1.0, 1.0]);
+        assert_eq!(usage.requests, 1);
+        let (_, usage) = embedder.embed_query("a".to_string()).await.unwrap();
+        assert_eq!(usage.requests, 0);
+        assert_eq!(texts.lock().unwrap().len(), 4);
+
+        // hits the store with a new in-memory tier
+        let embedder = CachedEmbedder::new(model.clone(), store.clone());
+        let (embeddings, usage) = embedder.embed(input).await.unwrap();
+        assert_eq!(embeddings[1].vec, vec![2.0, 2.0]);
+        assert_eq!(usage.requests, 0);
+        assert_eq!(texts.lock().unwrap().len(), 4);
+
+        // the cached vectors with other dimensions are ignored
+        let model: Arc<dyn EmbeddingFeaturesDyn> = Arc::new(CountingModel {
+            ndims: 3,
+            texts: texts.clone(),
+            dims: dims.clone(),
+        });
+        let embedder = CachedEmbedder::new(model, store.clone());
+        // the model returns vectors with wrong dimensions
+        let err = embedder.embed(vec!["a".to_string()]).await.unwrap_err();
+        assert!(err.to_string().contains("expected 3"));
+        assert_eq!(texts.lock().unwrap().len(), 5);
+
+        dims.store(3, Ordering::SeqCst);
+        let (embeddings, usage) = embedder.embed(vec!["a".to_string()]).await.unwrap();
+        assert_eq!(embeddings[0].vec, vec![1.0, 1.0, 1.0]);
+        assert_eq!(usage.requests, 1);
+        let (_, usage) = embedder.embed(vec!["a".to_string()]).await.unwrap();
+        assert_eq!(usage.requests, 0);
+    }
+}
@@ -0,0 +1,1015 @@
+//! Google Gemini API client implementation for Anda Engine
+//!
+//! This module provides integration with the Gemini API, including:
+//! - Client configuration and management
+//! - Completion model handling with function calling and multimodal inputs
+//! - Embedding model handling
+//! - Response parsing and conversion to Anda's internal formats
+//!
+//! The `full_history` of the output keeps OpenAI style messages, they are converted to
+//! Gemini's contents on every request, so the tool call loop of `AgentCtx` works unchanged.
+
+use anda_core::{
+    AgentOutput, AndaError, BoxError, BoxPinFut, CONTENT_TYPE_JSON, CompletionFeatures,
+    CompletionRequest, CompletionStream, ContentPart, Embedding, FunctionDefinition, Message,
+    Resource, ToolCall, Usage as ModelUsage,
+};
+use log::{Level::Debug, log_enabled};
+use serde::{Deserialize, Serialize};
+use serde_json::{Value, json};
+use std::{collections::BTreeMap, time::Duration};
+
+use super::{CompletionFeaturesDyn, EmbeddingFeaturesDyn, context_window_of};
+use crate::APP_USER_AGENT;
+
+// ================================================================
+// Main Gemini Client
+// ================================================================
+const API_BASE_URL: &str = "https://generativelanguage.googleapis.com/v1beta";
+
+/// `gemini-2.5-pro` completion model
+pub static GEMINI_2_5_PRO: &str = "gemini-2.5-pro";
+/// `gemini-2.5-flash` completion model
+pub static GEMINI_2_5_FLASH: &str = "gemini-2.5-flash";
+/// `gemini-2.0-flash` completion model
+pub static GEMINI_2_0_FLASH: &str = "gemini-2.0-flash";
+
+/// `gemini-embedding-001` embedding model
+pub const GEMINI_EMBEDDING_001: &str = "gemini-embedding-001";
+/// `text-embedding-004` embedding model
+pub const TEXT_EMBEDDING_004: &str = "text-embedding-004";
+
+/// Gemini API client configuration and HTTP client
+#[derive(Clone)]
+pub struct Client {
+    endpoint: String,
+    http: reqwest::Client,
+}
+
+impl Client {
+    /// Creates a new Gemini client instance with the provided API key
+    ///
+    /// # Arguments
+    /// * `api_key` - Gemini API key for authentication
+    /// * `endpoint` - Optional API endpoint, defaults to `https://generativelanguage.googleapis.com/v1beta`
+    pub fn new(api_key: &str, endpoint: Option<String>) -> Self {
+        let endpoint = endpoint.unwrap_or_else(|| API_BASE_URL.to_string());
+        let endpoint = if endpoint.is_empty() {
+            API_BASE_URL.to_string()
+        } else {
+            endpoint
+        };
+        Self {
+            endpoint,
+            http: reqwest::Client::builder()
+                .use_rustls_tls()
+                .https_only(true)
+                .http2_keep_alive_interval(Some(Duration::from_secs(25)))
+                .http2_keep_alive_timeout(Duration::from_secs(15))
+                .http2_keep_alive_while_idle(true)
+                .connect_timeout(Duration::from_secs(10))
+                .timeout(Duration::from_secs(300))
+                .gzip(true)
+                .user_agent(APP_USER_AGENT)
+                .default_headers({
+                    let mut headers = reqwest::header::HeaderMap::new();
+                    let ct: http::HeaderValue = CONTENT_TYPE_JSON.parse().unwrap();
+                    headers.insert(http::header::CONTENT_TYPE, ct.clone());
+                    headers.insert(http::header::ACCEPT, ct);
+                    headers.insert(
+                        "x-goog-api-key",
+                        api_key.parse().expect("API key should parse"),
+                    );
+                    headers
+                })
+                .build()
+                .expect("Gemini reqwest client should build"),
+        }
+    }
+
+    /// Creates a POST request builder for the specified API path
+    fn post(&self, path: &str) -> reqwest::RequestBuilder {
+        let url = format!("{}{}", self.endpoint, path);
+        self.http.post(url)
+    }
+
+    /// Creates a new completion model instance, defaults to `gemini-2.5-flash`
+    pub fn completion_model(&self, model: &str) -> CompletionModel {
+        CompletionModel::new(
+            self.clone(),
+            if model.is_empty() {
+                GEMINI_2_5_FLASH
+            } else {
+                model
+            },
+        )
+    }
+
+    /// Creates a new embedding model instance, defaults to `gemini-embedding-001`
+    ///
+    /// # Arguments
+    /// * `model` - Model identifier (e.g., GEMINI_EMBEDDING_001)
+    /// * `ndims` - Number of dimensions of the embedding vectors, `0` uses the default
+    ///   dimensions of the well-known models
+    pub fn embedding_model(&self, model: &str, ndims: usize) -> Result<EmbeddingModel, BoxError> {
+        let model = if model.is_empty() {
+            GEMINI_EMBEDDING_001
+        } else {
+            model
+        };
+        let ndims = match (ndims, model) {
+            (0, GEMINI_EMBEDDING_001) => 3072,
+            (0, TEXT_EMBEDDING_004) => 768,
+            (0, _) => {
+                return Err(AndaError::invalid_args(format!(
+                    "unknown dimensions of the embedding model {}",
+                    model
+                ))
+                .into());
+            }
+            (ndims, _) => ndims,
+        };
+        Ok(EmbeddingModel::new(self.clone(), model, ndims))
+    }
+}
+
+/// Token usage metadata from Gemini API responses
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct UsageMetadata {
+    /// Number of tokens in the prompt, including the cached content
+    #[serde(default)]
+    pub prompt_token_count: usize,
+    /// Number of tokens in the generated candidates
+    #[serde(default)]
+    pub candidates_token_count: usize,
+    /// Number of tokens in the cached content
+    #[serde(default)]
+    pub cached_content_token_count: usize,
+    /// Number of tokens of the thoughts for thinking models
+    #[serde(default)]
+    pub thoughts_token_count: usize,
+    /// Total number of tokens
+    #[serde(default)]
+    pub total_token_count: usize,
+}
+
+impl std::fmt::Display for UsageMetadata {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "Prompt tokens: {} total tokens: {}",
+            self.prompt_token_count, self.total_token_count
+        )
+    }
+}
+
+impl From<&UsageMetadata> for ModelUsage {
+    fn from(u: &UsageMetadata) -> Self {
+        ModelUsage {
+            input_tokens: u.prompt_token_count as u64,
+            output_tokens: (u.candidates_token_count + u.thoughts_token_count) as u64,
+            requests: 1,
+            cached_input_tokens: u.cached_content_token_count as u64,
+            reasoning_tokens: u.thoughts_token_count as u64,
+        }
+    }
+}
+
+/// Content of a request or a candidate
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+pub struct Content {
+    /// "user" or "model"
+    #[serde(default)]
+    pub role: String,
+    /// Parts of the content, text, inline data, function calls and so on
+    #[serde(default)]
+    pub parts: Vec<Value>,
+}
+
+/// A response candidate generated by the model
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Candidate {
+    /// Generated content
+    #[serde(default)]
+    pub content: Content,
+    /// The reason why the model stopped generating tokens
+    pub finish_reason: Option<String>,
+}
+
+/// Completion response from Gemini `generateContent` API
+#[derive(Debug, Deserialize, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct CompletionResponse {
+    /// Candidate responses from the model
+    #[serde(default)]
+    pub candidates: Vec<Candidate>,
+    /// Token usage metadata
+    #[serde(default)]
+    pub usage_metadata: UsageMetadata,
+    /// The model version used to generate the response
+    #[serde(default)]
+    pub model_version: String,
+    /// The prompt feedback, it is present if the prompt was blocked
+    #[serde(default)]
+    pub prompt_feedback: Option<Value>,
+}
+
+impl CompletionResponse {
+    fn try_into(self, mut full_history: Vec<Value>) -> Result<AgentOutput, BoxError> {
+        let mut output = AgentOutput {
+            usage: ModelUsage::from(&self.usage_metadata),
+            ..Default::default()
+        };
+        let candidate = match self.candidates.into_iter().next() {
+            Some(candidate) => candidate,
+            None => {
+                output.failed_reason = Some(match self.prompt_feedback {
+                    Some(feedback) => format!("prompt blocked: {}", feedback),
+                    None => "no candidates".to_string(),
+                });
+                output.full_history = Some(full_history);
+                return Ok(output);
+            }
+        };
+
+        let mut tool_calls = Vec::new();
+        for part in candidate.content.parts {
+            // skip the thought summaries
+            if part.get("thought").and_then(Value::as_bool) == Some(true) {
+                continue;
+            }
+            if let Some(text) = part.get("text").and_then(Value::as_str) {
+                output.content.push_str(text);
+            } else if let Some(call) = part.get("functionCall") {
+                let name = call
+                    .get("name")
+                    .and_then(Value::as_str)
+                    .unwrap_or_default()
+                    .to_string();
+                let id = match call.get("id").and_then(Value::as_str) {
+                    Some(id) if !id.is_empty() => id.to_string(),
+                    _ => format!("{}_{}", name, tool_calls.len()),
+                };
+                let args = call.get("args").cloned().unwrap_or(json!({}));
+                tool_calls.push(ToolCall {
+                    id,
+                    name,
+                    args: serde_json::to_string(&args)?,
+                    result: None,
+                });
+            }
+        }
+
+        let mut message = json!({
+            "role": "assistant",
+            "content": output.content,
+        });
+        if !tool_calls.is_empty() {
+            message["tool_calls"] = json!(
+                tool_calls
+                    .iter()
+                    .map(|tc| json!({
+                        "id": tc.id,
+                        "type": "function",
+                        "function": {"name": tc.name, "arguments": tc.args},
+                    }))
+                    .collect::<Vec<_>>()
+            );
+            output.tool_calls = Some(tool_calls);
+        }
+        full_history.push(message);
+        output.full_history = Some(full_history);
+
+        let finish_reason = candidate.finish_reason.unwrap_or_default();
+        if !matches!(finish_reason.as_str(), "STOP" | "") {
+            output.failed_reason = Some(finish_reason);
+        }
+
+        Ok(output)
+    }
+}
+
+/// Function declaration of Gemini API, the parameters are JSON schema.
+#[derive(Debug, Deserialize, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct FunctionDeclaration {
+    pub name: String,
+    pub description: String,
+    pub parameters_json_schema: Value,
+}
+
+impl From<FunctionDefinition> for FunctionDeclaration {
+    fn from(f: FunctionDefinition) -> Self {
+        Self {
+            name: f.name,
+            description: f.description,
+            parameters_json_schema: f.parameters,
+        }
+    }
+}
+
+/// Guesses the MIME type of an image URL by the extension.
+fn image_mime_type(url: &str) -> &'static str {
+    let path = url.split(['?', '#']).next().unwrap_or_default();
+    match path.rsplit('.').next().map(|ext| ext.to_ascii_lowercase()) {
+        Some(ext) if ext == "png" => "image/png",
+        Some(ext) if ext == "webp" => "image/webp",
+        Some(ext) if ext == "gif" => "image/gif",
+        Some(ext) if ext == "heic" => "image/heic",
+        _ => "image/jpeg",
+    }
+}
+
+/// Converts an OpenAI style content part to a Gemini part.
+fn content_part(part: ContentPart) -> Value {
+    match part {
+        ContentPart::Text { text } => json!({"text": text}),
+        ContentPart::Image { image_url } => {
+            if let Some(data_url) = image_url.url.strip_prefix("data:") {
+                if let Some((mime_type, data)) = data_url.split_once(";base64,") {
+                    return json!({"inlineData": {"mimeType": mime_type, "data": data}});
+                }
+            }
+            json!({"fileData": {
+                "mimeType": image_mime_type(&image_url.url),
+                "fileUri": image_url.url,
+            }})
+        }
+        ContentPart::Audio { input_audio } => json!({"inlineData": {
+            "mimeType": format!("audio/{}", input_audio.format),
+            "data": input_audio.data,
+        }}),
+    }
+}
+
+/// Converts the content of a message to Gemini parts.
+fn content_parts(content: &Value) -> Vec<Value> {
+    match content {
+        Value::Null => vec![],
+        Value::String(text) if text.is_empty() => vec![],
+        Value::String(text) => vec![json!({"text": text})],
+        Value::Array(parts) => parts
+            .iter()
+            .filter_map(|part| {
+                let mut part = part.clone();
+                // the OpenAI chat completions format of other providers
+                let kind = match part.get("type").and_then(Value::as_str) {
+                    Some("image_url") => Some("image"),
+                    Some("input_audio") => Some("audio"),
+                    _ => None,
+                };
+                if let Some(kind) = kind {
+                    part["type"] = kind.into();
+                }
+                match serde_json::from_value::<ContentPart>(part.clone()) {
+                    Ok(part) => Some(content_part(part)),
+                    Err(_) => {
+                        log::warn!("Gemini: unsupported content part {}", part);
+                        None
+                    }
+                }
+            })
+            .collect(),
+        other => vec![json!({"text": other.to_string()})],
+    }
+}
+
+/// Converts the OpenAI style chat history to the system instruction and Gemini contents.
+/// - "system" and "developer" messages are merged into the system instruction;
+/// - "tool" messages are converted to `functionResponse` parts of a user content;
+/// - `tool_calls` of assistant messages are converted to `functionCall` parts;
+/// - Consecutive messages with the same role are merged.
+fn convert_messages(history: &[Value]) -> (Vec<Value>, Vec<Content>) {
+    let mut system: Vec<Value> = Vec::new();
+    let mut contents: Vec<Content> = Vec::new();
+    // the function name of tool calls, the function response requires it
+    let mut tool_names: BTreeMap<String, String> = BTreeMap::new();
+    for msg in history {
+        let role = msg.get("role").and_then(Value::as_str).unwrap_or("user");
+        let content = msg.get("content").unwrap_or(&Value::Null);
+        let (role, parts) = match role {
+            "system" | "developer" => {
+                system.extend(content_parts(content));
+                continue;
+            }
+            "tool" => {
+                let id = msg
+                    .get("tool_call_id")
+                    .and_then(Value::as_str)
+                    .unwrap_or_default();
+                let name = tool_names
+                    .get(id)
+                    .cloned()
+                    .unwrap_or_else(|| id.to_string());
+                // the response must be a JSON object
+                let response = match content {
+                    Value::Object(_) => content.clone(),
+                    Value::String(text) => match serde_json::from_str::<Value>(text) {
+                        Ok(val @ Value::Object(_)) => val,
+                        _ => json!({"content": text}),
+                    },
+                    other => json!({"content": other}),
+                };
+                (
+                    "user",
+                    vec![json!({"functionResponse": {
+                        "id": id,
+                        "name": name,
+                        "response": response,
+                    }})],
+                )
+            }
+            "assistant" | "model" => {
+                let mut parts = content_parts(content);
+                if let Some(tool_calls) = msg.get("tool_calls").and_then(Value::as_array) {
+                    for tc in tool_calls {
+                        let id = tc.get("id").and_then(Value::as_str).unwrap_or_default();
+                        let function = tc.get("function").unwrap_or(&Value::Null);
+                        let name = function
+                            .get("name")
+                            .and_then(Value::as_str)
+                            .unwrap_or_default();
+                        let args = function
+                            .get("arguments")
+                            .and_then(Value::as_str)
+                            .unwrap_or("{}");
+                        tool_names.insert(id.to_string(), name.to_string());
+                        parts.push(json!({"functionCall": {
+                            "id": id,
+                            "name": name,
+                            "args": serde_json::from_str::<Value>(args).unwrap_or(json!({})),
+                        }}));
+                    }
+                }
+                ("model", parts)
+            }
+            _ => ("user", content_parts(content)),
+        };
+
+        if parts.is_empty() {
+            continue;
+        }
+        match contents.last_mut() {
+            Some(last) if last.role == role => last.parts.extend(parts),
+            _ => contents.push(Content {
+                role: role.to_string(),
+                parts,
+            }),
+        }
+    }
+
+    (system, contents)
+}
+
+/// Completion model wrapper for Gemini API
+#[derive(Clone)]
+pub struct CompletionModel {
+    /// Gemini client instance
+    client: Client,
+    /// Model identifier
+    pub model: String,
+}
+
+impl CompletionModel {
+    /// Creates a new completion model instance
+    ///
+    /// # Arguments
+    /// * `client` - Gemini client instance
+    /// * `model` - Model identifier string
+    pub fn new(client: Client, model: &str) -> Self {
+        Self {
+            client,
+            model: model.to_string(),
+        }
+    }
+
+    /// Builds the `generateContent` request body, returns the messages of the conversation
+    /// (including the system message) and the JSON body.
+    fn request_body(&self, mut req: CompletionRequest) -> (Vec<Value>, Value) {
+        // Add preamble to chat history (if available)
+        let mut full_history = if let Some(system) = &req.system {
+            vec![json!(Message {
+                role: "system".into(),
+                content: system.to_owned().into(),
+                ..Default::default()
+            })]
+        } else {
+            vec![]
+        };
+
+        // Extend existing chat history
+        full_history.append(&mut req.chat_history);
+
+        if !req.content_parts.is_empty() {
+            full_history.push(json!(Message {
+                role: "user".into(),
+                content: json!(req.content_parts),
+                ..Default::default()
+            }));
+        } else if let Some(prompt) = req.prompt_with_context() {
+            full_history.push(json!(Message {
+                role: "user".into(),
+                content: prompt.into(),
+                ..Default::default()
+            }));
+        }
+
+        let (system, contents) = convert_messages(&full_history);
+        let mut body = json!({
+            "contents": contents,
+        });
+
+        let obj = body.as_object_mut().unwrap();
+        if !system.is_empty() {
+            obj.insert("systemInstruction".to_string(), json!({"parts": system}));
+        }
+
+        let mut config = serde_json::Map::new();
+        if let Some(temperature) = req.temperature {
+            config.insert("temperature".to_string(), Value::from(temperature));
+        }
+        if let Some(max_tokens) = req.max_tokens {
+            config.insert("maxOutputTokens".to_string(), Value::from(max_tokens));
+        }
+        if let Some(stop) = req.stop {
+            config.insert("stopSequences".to_string(), Value::from(stop));
+        }
+        if let Some(format) = &req.response_format {
+            config.insert(
+                "responseMimeType".to_string(),
+                Value::from(CONTENT_TYPE_JSON),
+            );
+            if let Some(schema) = format.get("json_schema").and_then(|v| v.get("schema")) {
+                config.insert("responseJsonSchema".to_string(), schema.clone());
+            }
+        }
+        if !config.is_empty() {
+            obj.insert("generationConfig".to_string(), Value::Object(config));
+        }
+
+        if !req.tools.is_empty() {
+            obj.insert(
+                "tools".to_string(),
+                json!([{
+                    "functionDeclarations": req
+                        .tools
+                        .into_iter()
+                        .map(FunctionDeclaration::from)
+                        .collect::<Vec<_>>(),
+                }]),
+            );
+            obj.insert(
+                "toolConfig".to_string(),
+                json!({"functionCallingConfig": {
+                    "mode": if req.tool_choice_required { "ANY" } else { "AUTO" },
+                }}),
+            );
+        };
+
+        (full_history, body)
+    }
+}
+
+impl CompletionFeatures for CompletionModel {
+    async fn completion(
+        &self,
+        req: CompletionRequest,
+        _resources: Option<Vec<Resource>>,
+    ) -> Result<AgentOutput, BoxError> {
+        CompletionFeaturesDyn::completion(self, req).await
+    }
+
+    async fn completion_stream(
+        &self,
+        req: CompletionRequest,
+        _resources: Option<Vec<Resource>>,
+    ) -> Result<CompletionStream, BoxError> {
+        CompletionFeaturesDyn::completion_stream(self, req).await
+    }
+}
+
+impl CompletionFeaturesDyn for CompletionModel {
+    fn completion(&self, req: CompletionRequest) -> BoxPinFut<Result<AgentOutput, BoxError>> {
+        let (full_history, body) = self.request_body(req);
+        let client = self.client.clone();
+        let path = format!("/models/{}:generateContent", self.model);
+
+        Box::pin(async move {
+            if log_enabled!(Debug) {
+                if let Ok(val) = serde_json::to_string(&body) {
+                    log::debug!(request = val; "Gemini completions request");
+                }
+            }
+
+            let response = client.post(&path).json(&body).send().await?;
+            if response.status().is_success() {
+                let text = response.text().await?;
+                match serde_json::from_str::<CompletionResponse>(&text) {
+                    Ok(res) => {
+                        if log_enabled!(Debug) {
+                            if let Ok(val) = serde_json::to_string(&res) {
+                                log::debug!(response = val; "Gemini completions response");
+                            }
+                        }
+                        res.try_into(full_history)
+                    }
+                    Err(err) => {
+                        Err(format!("Gemini completions error: {}, body: {}", err, text).into())
+                    }
+                }
+            } else {
+                let status = response.status().as_u16();
+                let msg = response.text().await?;
+                Err(
+                    AndaError::from_status(status, format!("Gemini completions error: {}", msg))
+                        .into(),
+                )
+            }
+        })
+    }
+
+    fn context_window(&self) -> Option<usize> {
+        context_window_of(&self.model)
+    }
+
+    fn model_name(&self) -> String {
+        self.model.clone()
+    }
+}
+
+/// Embedding values of Gemini API
+#[derive(Debug, Deserialize)]
+pub struct ContentEmbedding {
+    pub values: Vec<f32>,
+}
+
+/// Response of Gemini `batchEmbedContents` API
+#[derive(Debug, Deserialize)]
+pub struct BatchEmbeddingResponse {
+    pub embeddings: Vec<ContentEmbedding>,
+}
+
+/// Response of Gemini `embedContent` API
+#[derive(Debug, Deserialize)]
+pub struct EmbeddingResponse {
+    pub embedding: ContentEmbedding,
+}
+
+/// Gemini embedding model wrapper
+#[derive(Clone)]
+pub struct EmbeddingModel {
+    /// Gemini client instance
+    client: Client,
+    /// Model identifier
+    pub model: String,
+    /// Number of dimensions in the embedding vectors
+    ndims: usize,
+}
+
+impl EmbeddingModel {
+    /// Creates a new embedding model instance
+    ///
+    /// # Arguments
+    /// * `client` - Gemini client instance
+    /// * `model` - Model identifier
+    /// * `ndims` - Number of dimensions in the embedding vectors
+    pub fn new(client: Client, model: &str, ndims: usize) -> Self {
+        Self {
+            client,
+            model: model.to_string(),
+            ndims,
+        }
+    }
+
+    fn embed_request(&self, text: String, task_type: &str) -> Value {
+        let mut req = json!({
+            "model": format!("models/{}", self.model),
+            "content": {"parts": [{"text": text}]},
+            "taskType": task_type,
+        });
+        if self.ndims > 0 {
+            req["outputDimensionality"] = Value::from(self.ndims);
+        }
+        req
+    }
+}
+
+const MAX_DOCUMENTS: usize = 100;
+impl EmbeddingFeaturesDyn for EmbeddingModel {
+    /// Returns the number of dimensions for this embedding model
+    fn ndims(&self) -> usize {
+        self.ndims
+    }
+
+    /// Returns the model identifier
+    fn model_name(&self) -> String {
+        self.model.clone()
+    }
+
+    fn max_batch_size(&self) -> Option<usize> {
+        Some(MAX_DOCUMENTS)
+    }
+
+    /// Generates embeddings for a batch of texts
+    ///
+    /// https://ai.google.dev/api/embeddings#method:-models.batchembedcontents
+    /// Maximum number of texts per call is 100.
+    /// The API does not return token usage, only the request is counted.
+    fn embed(
+        &self,
+        texts: Vec<String>,
+    ) -> BoxPinFut<Result<(Vec<Embedding>, ModelUsage), BoxError>> {
+        let client = self.client.clone();
+        let path = format!("/models/{}:batchEmbedContents", self.model);
+        let requests: Vec<Value> = texts
+            .iter()
+            .map(|text| self.embed_request(text.clone(), "RETRIEVAL_DOCUMENT"))
+            .collect();
+        Box::pin(async move {
+            if texts.len() > MAX_DOCUMENTS {
+                return Err(format!("Too many documents, max is {}", MAX_DOCUMENTS).into());
+            }
+
+            let response = client
+                .post(&path)
+                .json(&json!({"requests": requests}))
+                .send()
+                .await?;
+
+            if response.status().is_success() {
+                match response.json::<BatchEmbeddingResponse>().await {
+                    Ok(res) => {
+                        if res.embeddings.len() != texts.len() {
+                            return Err(format!(
+                                "Expected {} embeddings, got {}",
+                                texts.len(),
+                                res.embeddings.len()
+                            )
+                            .into());
+                        }
+                        Ok((
+                            res.embeddings
+                                .into_iter()
+                                .zip(texts)
+                                .map(|(e, text)| Embedding {
+                                    text,
+                                    vec: e.values,
+                                })
+                                .collect(),
+                            ModelUsage {
+                                requests: 1,
+                                ..Default::default()
+                            },
+                        ))
+                    }
+                    Err(err) => Err(format!("Gemini embeddings error: {}", err).into()),
+                }
+            } else {
+                let status = response.status().as_u16();
+                let msg = response.text().await?;
+                Err(
+                    AndaError::from_status(status, format!("Gemini embeddings error: {}", msg))
+                        .into(),
+                )
+            }
+        })
+    }
+
+    /// Generates an embedding for a single query text
+    fn embed_query(&self, text: String) -> BoxPinFut<Result<(Embedding, ModelUsage), BoxError>> {
+        let client = self.client.clone();
+        let path = format!("/models/{}:embedContent", self.model);
+        let req = self.embed_request(text.clone(), "RETRIEVAL_QUERY");
+        Box::pin(async move {
+            let response = client.post(&path).json(&req).send().await?;
+
+            if response.status().is_success() {
+                match response.json::<EmbeddingResponse>().await {
+                    Ok(res) => Ok((
+                        Embedding {
+                            text,
+                            vec: res.embedding.values,
+                        },
+                        ModelUsage {
+                            requests: 1,
+                            ..Default::default()
+                        },
+                    )),
+                    Err(err) => Err(format!("Gemini embeddings error: {}", err).into()),
+                }
+            } else {
+                let status = response.status().as_u16();
+                let msg = response.text().await?;
+                Err(
+                    AndaError::from_status(status, format!("Gemini embeddings error: {}", msg))
+                        .into(),
+                )
+            }
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::extension::character::Character;
+    use anda_core::{AudioDetail, ImageDetail};
+    use std::time::Instant;
+
+    #[test]
+    fn test_request_body() {
+        let model = Client::new("test", None).completion_model("");
+        let req = CompletionRequest {
+            system: Some("You are a helpful assistant.".to_string()),
+            chat_history: vec![
+                json!({"role": "user", "content": "Hi"}),
+                json!({"role": "assistant", "content": "Hello!"}),
+            ],
+            content_parts: vec![
+                ContentPart::Text {
+                    text: "What's in this image?".to_string(),
+                },
+                ContentPart::Image {
+                    image_url: ImageDetail {
+                        url: "data:image/png;base64,iVBORw0KGgo=".to_string(),
+                        detail: None,
+                    },
+                },
+                ContentPart::Image {
+                    image_url: ImageDetail {
+                        url: "https://example.com/cat.webp?size=large".to_string(),
+                        detail: None,
+                    },
+                },
+                ContentPart::Audio {
+                    input_audio: AudioDetail {
+                        data: "UklGRg==".to_string(),
+                        format: "wav".to_string(),
+                    },
+                },
+            ],
+            tools: vec![FunctionDefinition {
+                name: "get_weather".to_string(),
+                description: "Gets the weather".to_string(),
+                parameters: json!({"type": "object", "properties": {}}),
+                strict: None,
+                output_schema: None,
+            }],
+            tool_choice_required: true,
+            stop: Some(vec!["END".to_string()]),
+            max_tokens: Some(1024),
+            ..Default::default()
+        };
+        let (full_history, body) = model.request_body(req);
+        assert_eq!(full_history.len(), 4);
+        assert_eq!(
+            body["systemInstruction"],
+            json!({"parts": [{"text": "You are a helpful assistant."}]})
+        );
+        assert_eq!(
+            body["generationConfig"],
+            json!({"maxOutputTokens": 1024, "stopSequences": ["END"]})
+        );
+        assert_eq!(
+            body["toolConfig"],
+            json!({"functionCallingConfig": {"mode": "ANY"}})
+        );
+        assert_eq!(
+            body["tools"][0]["functionDeclarations"][0]["parametersJsonSchema"],
+            json!({"type": "object", "properties": {}})
+        );
+        let contents = body["contents"].as_array().unwrap();
+        assert_eq!(contents.len(), 3);
+        assert_eq!(
+            contents[1],
+            json!({"role": "model", "parts": [{"text": "Hello!"}]})
+        );
+        assert_eq!(
+            contents[2]["parts"],
+            json!([
+                {"text": "What's in this image?"},
+                {"inlineData": {"mimeType": "image/png", "data": "iVBORw0KGgo="}},
+                {"fileData": {"mimeType": "image/webp", "fileUri": "https://example.com/cat.webp?size=large"}},
+                {"inlineData": {"mimeType": "audio/wav", "data": "UklGRg=="}},
+            ])
+        );
+    }
+
+    #[test]
+    fn test_tool_call_loop() {
+        let model = Client::new("test", None).completion_model("");
+        let res: CompletionResponse = serde_json::from_value(json!({
+            "candidates": [{
+                "content": {"role": "model", "parts": [
+                    {"text": "thinking...", "thought": true},
+                    {"functionCall": {"name": "get_weather", "args": {"city": "Paris"}}},
+                ]},
+                "finishReason": "STOP",
+            }],
+            "usageMetadata": {
+                "promptTokenCount": 10,
+                "candidatesTokenCount": 20,
+                "cachedContentTokenCount": 4,
+                "thoughtsTokenCount": 5,
+                "totalTokenCount": 35,
+            },
+            "modelVersion": GEMINI_2_5_FLASH,
+        }))
+        .unwrap();
+        let (full_history, _) = model.request_body(CompletionRequest {
+            system: Some("system".to_string()),
+            prompt: "Weather in Paris?".to_string(),
+            ..Default::default()
+        });
+        let output = res.try_into(full_history).unwrap();
+        assert_eq!(output.content, "");
+        assert!(output.failed_reason.is_none());
+        assert_eq!(output.usage.input_tokens, 10);
+        assert_eq!(output.usage.output_tokens, 25);
+        assert_eq!(output.usage.cached_input_tokens, 4);
+        assert_eq!(output.usage.reasoning_tokens, 5);
+        let tool_calls = output.tool_calls.unwrap();
+        assert_eq!(tool_calls[0].id, "get_weather_0");
+        assert_eq!(tool_calls[0].args, r#"{"city":"Paris"}"#);
+
+        // the next round of the tool call loop in `AgentCtx::completion`
+        let mut chat_history = output.full_history.unwrap();
+        chat_history.push(json!(Message {
+            role: "tool".to_string(),
+            content: json!("sunny"),
+            name: None,
+            tool_call_id: Some("get_weather_0".to_string()),
+            ..Default::default()
+        }));
+        let (_, body) = model.request_body(CompletionRequest {
+            chat_history,
+            ..Default::default()
+        });
+        assert_eq!(
+            body["systemInstruction"],
+            json!({"parts": [{"text": "system"}]})
+        );
+        let contents = body["contents"].as_array().unwrap();
+        assert_eq!(contents.len(), 3);
+        assert_eq!(
+            contents[1],
+            json!({"role": "model", "parts": [{"functionCall": {
+                "id": "get_weather_0",
+                "name": "get_weather",
+                "args": {"city": "Paris"},
+            }}]})
+        );
+        assert_eq!(
+            contents[2],
+            json!({"role": "user", "parts": [{"functionResponse": {
+                "id": "get_weather_0",
+                "name": "get_weather",
+                "response": {"content": "sunny"},
+            }}]})
+        );
+    }
+
+    #[test]
+    fn test_embedding_model() {
+        let client = Client::new("test", None);
+        let model = client.embedding_model("", 0).unwrap();
+        assert_eq!(model.ndims(), 3072);
+        let model = client
+            .embedding_model("gemini-embedding-exp", 1536)
+            .unwrap();
+        assert_eq!(model.ndims(), 1536);
+        let err = client
+            .embedding_model("gemini-embedding-exp", 0)
+            .unwrap_err();
+        assert!(err.to_string().contains("gemini-embedding-exp"));
+    }
+
+    #[tokio::test(flavor = "current_thread")]
+    #[ignore]
+    async fn test_gemini() {
+        dotenv::dotenv().ok();
+
+        let api_key = std::env::var("GEMINI_API_KEY").expect("GEMINI_API_KEY is not set");
+        let character_path = format!("{}/../characters/AndaICP.toml", env!("CARGO_MANIFEST_DIR"));
+        println!("Character path: {}", character_path);
+        let character = std::fs::read_to_string(character_path).expect("Character file not found");
+        let character = Character::from_toml(&character).expect("Character should parse");
+        let client = Client::new(&api_key, None);
+        let now = Instant::now();
+        let model = client.completion_model(GEMINI_2_5_FLASH);
+        let req = character.to_request("I am Yan, glad to see you".into(), Some("Yan".into()));
+        let res = CompletionFeatures::completion(&model, req, None)
+            .await
+            .unwrap();
+        println!("{}", res.content);
+        println!("Took: {:?}", now.elapsed());
+
+        let model = client.embedding_model(TEXT_EMBEDDING_004, 0).unwrap();
+        let (res, _) = EmbeddingFeaturesDyn::embed_query(&model, "Hello, Gemini".to_string())
+            .await
+            .unwrap();
+        assert_eq!(res.vec.len(), 768);
+    }
+}
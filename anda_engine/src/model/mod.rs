@@ -3,7 +3,17 @@
 //! This module provides implementations for various AI model providers, including:
 //! - OpenAI (completion and embedding models)
 //! - DeepSeek (completion models)
+//! - Anthropic (completion models)
+//! - Gemini (completion and embedding models)
 //! - Cohere (embedding models)
+//! - OpenAI compatible servers, such as Ollama, llama.cpp and vLLM (completion and embedding models)
+//!
+//! [`router::ModelRouter`] routes completion requests across providers with fallback,
+//! retries, timeouts and circuit breakers.
+//! [`prompted_tools::PromptedToolCalling`] calls tools by prompting for models without
+//! native function calling.
+//! [`batch::EmbeddingExecutor`] splits large embedding jobs into provider-sized batches.
+//! [`embedding_cache::CachedEmbedder`] caches embedding vectors in memory and in the store.
 //!
 //! Each provider implementation includes:
 //! - Client configuration and management
@@ -14,18 +24,63 @@
 //! while maintaining a consistent interface through the `CompletionFeaturesDyn` and
 //! `EmbeddingFeaturesDyn` traits.
 
-use anda_core::{AgentOutput, BoxError, BoxPinFut, CompletionRequest, Embedding, ToolCall, Usage};
-use std::sync::Arc;
+use anda_core::{
+    AgentOutput, BoxError, BoxPinFut, CompletionChunk, CompletionRequest, CompletionStream,
+    ContentPart, Embedding, Tokenizer, ToolCall, Usage, completion_stream_from, default_tokenizer,
+    response_format_instruction,
+};
+use futures::StreamExt;
+use serde_json::{Value, json};
+use std::{collections::BTreeMap, sync::Arc};
 
+pub mod anthropic;
+pub mod batch;
 pub mod cohere;
+pub mod context_window;
 pub mod deepseek;
+pub mod embedding_cache;
+pub mod gemini;
 pub mod openai;
+pub mod openai_compatible;
+pub mod prompted_tools;
+pub mod router;
 pub mod xai;
 
+mod sse;
+
+pub use batch::EmbeddingExecutor;
+pub use context_window::{ContextManager, context_window_of};
+pub use embedding_cache::CachedEmbedder;
+
 /// Trait for dynamic completion features that can be used across threads
 pub trait CompletionFeaturesDyn: Send + Sync + 'static {
     /// Performs a completion request and returns a future with the agent's output
     fn completion(&self, req: CompletionRequest) -> BoxPinFut<Result<AgentOutput, BoxError>>;
+
+    /// Performs a streaming completion request and returns a future with the chunks stream.
+    ///
+    /// The default implementation waits for [`CompletionFeaturesDyn::completion`]
+    /// and yields the finished output as a single stream.
+    fn completion_stream(
+        &self,
+        req: CompletionRequest,
+    ) -> BoxPinFut<Result<CompletionStream, BoxError>> {
+        let fut = self.completion(req);
+        Box::pin(async move {
+            let output = fut.await?;
+            Ok(completion_stream_from(output))
+        })
+    }
+
+    /// Returns the context window size in tokens of the model, None if unknown.
+    fn context_window(&self) -> Option<usize> {
+        None
+    }
+
+    /// Returns the name of the model, it is used to account the usage.
+    fn model_name(&self) -> String {
+        String::new()
+    }
 }
 
 /// Trait for dynamic embedding features that can be used across threads
@@ -38,6 +93,75 @@ pub trait EmbeddingFeaturesDyn: Send + Sync + 'static {
 
     /// Embeds a single query text and returns a future with the resulting embedding
     fn embed_query(&self, text: String) -> BoxPinFut<Result<(Embedding, Usage), BoxError>>;
+
+    /// Returns the name of the model, it is used to account the usage.
+    fn model_name(&self) -> String {
+        String::new()
+    }
+
+    /// Returns the max number of texts per `embed` call, None means no limit.
+    fn max_batch_size(&self) -> Option<usize> {
+        None
+    }
+
+    /// Returns the max number of tokens of the texts per `embed` call, None means no limit.
+    fn max_batch_tokens(&self) -> Option<usize> {
+        None
+    }
+}
+
+/// Adds the instruction of the response format to the system message of the chat messages,
+/// for providers that do not support the `json_schema` response format natively.
+pub(crate) fn push_format_instruction(
+    messages: &mut Vec<Value>,
+    format: &Value,
+    system_role: &str,
+) {
+    let instruction = response_format_instruction(format);
+    if let Some(first) = messages.first_mut() {
+        if first.get("role").and_then(Value::as_str) == Some(system_role) {
+            if let Some(Value::String(content)) = first.get_mut("content") {
+                content.push_str("\n\n");
+                content.push_str(&instruction);
+                return;
+            }
+        }
+    }
+    messages.insert(0, json!({"role": system_role, "content": instruction}));
+}
+
+/// Converts the content parts to the OpenAI chat completions format.
+pub(crate) fn openai_content_parts(parts: &[ContentPart]) -> Value {
+    json!(
+        parts
+            .iter()
+            .map(|part| match part {
+                ContentPart::Text { text } => json!({"type": "text", "text": text}),
+                ContentPart::Image { image_url } => {
+                    json!({"type": "image_url", "image_url": image_url})
+                }
+                ContentPart::Audio { input_audio } => {
+                    json!({"type": "input_audio", "input_audio": input_audio})
+                }
+            })
+            .collect::<Vec<_>>()
+    )
+}
+
+/// Returns the chat messages without the `reasoning_content` kept by
+/// [`anda_core::ReasoningMode::Keep`], the chat completions APIs don't accept it as input.
+pub(crate) fn without_reasoning(messages: &[Value]) -> Vec<Value> {
+    messages
+        .iter()
+        .map(|msg| match msg {
+            Value::Object(obj) if obj.contains_key("reasoning_content") => {
+                let mut obj = obj.clone();
+                obj.remove("reasoning_content");
+                Value::Object(obj)
+            }
+            msg => msg.clone(),
+        })
+        .collect()
 }
 
 /// A placeholder implementation for unimplemented features
@@ -128,6 +252,14 @@ pub struct Model {
     pub embedder: Arc<dyn EmbeddingFeaturesDyn>,
     /// Completion feature implementation
     pub completer: Arc<dyn CompletionFeaturesDyn>,
+    /// Optional context window manager applied to completion requests
+    pub context_manager: Option<Arc<ContextManager>>,
+    /// Optional tokenizer of the completion model, the default tokenizer is used if None
+    pub tokenizer: Option<Arc<dyn Tokenizer>>,
+    /// Completion implementations for specific agents, keyed by the lowercase agent name
+    pub agent_completers: BTreeMap<String, Arc<dyn CompletionFeaturesDyn>>,
+    /// Executor that splits embedding jobs into batches of the embedding model
+    pub embedding_executor: EmbeddingExecutor,
 }
 
 impl Model {
@@ -139,6 +271,10 @@ impl Model {
         Self {
             embedder,
             completer,
+            context_manager: None,
+            tokenizer: None,
+            agent_completers: BTreeMap::new(),
+            embedding_executor: EmbeddingExecutor::new(),
         }
     }
 
@@ -147,6 +283,10 @@ impl Model {
         Self {
             completer,
             embedder: Arc::new(NotImplemented),
+            context_manager: None,
+            tokenizer: None,
+            agent_completers: BTreeMap::new(),
+            embedding_executor: EmbeddingExecutor::new(),
         }
     }
 
@@ -155,6 +295,10 @@ impl Model {
         Self {
             completer: Arc::new(NotImplemented),
             embedder: Arc::new(NotImplemented),
+            context_manager: None,
+            tokenizer: None,
+            agent_completers: BTreeMap::new(),
+            embedding_executor: EmbeddingExecutor::new(),
         }
     }
 
@@ -163,11 +307,120 @@ impl Model {
         Self {
             completer: Arc::new(MockImplemented),
             embedder: Arc::new(MockImplemented),
+            context_manager: None,
+            tokenizer: None,
+            agent_completers: BTreeMap::new(),
+            embedding_executor: EmbeddingExecutor::new(),
         }
     }
 
-    pub async fn completion(&self, req: CompletionRequest) -> Result<AgentOutput, BoxError> {
-        self.completer.completion(req).await
+    /// Sets the context window manager that trims completion requests to fit
+    /// the context window of the model.
+    pub fn with_context_manager(mut self, context_manager: ContextManager) -> Self {
+        self.context_manager = Some(Arc::new(context_manager));
+        self
+    }
+
+    /// Sets the completion implementation for the agent, e.g. a
+    /// [`router::ModelRouter`] with another route.
+    pub fn with_agent_completer(
+        mut self,
+        agent: String,
+        completer: Arc<dyn CompletionFeaturesDyn>,
+    ) -> Self {
+        self.agent_completers
+            .insert(agent.to_ascii_lowercase(), completer);
+        self
+    }
+
+    /// Returns the model used by the agent.
+    pub(crate) fn for_agent(&self, agent: &str) -> Self {
+        let mut model = self.clone();
+        if let Some(completer) = self.agent_completers.get(agent) {
+            model.completer = completer.clone();
+        }
+        model
+    }
+
+    /// Sets the executor of embedding jobs.
+    pub fn with_embedding_executor(mut self, executor: EmbeddingExecutor) -> Self {
+        self.embedding_executor = executor;
+        self
+    }
+
+    /// Sets the tokenizer used by the completion model.
+    pub fn with_tokenizer(mut self, tokenizer: Arc<dyn Tokenizer>) -> Self {
+        self.tokenizer = Some(tokenizer);
+        self
+    }
+
+    /// Returns the tokenizer of the completion model,
+    /// or the default tokenizer if the model does not declare one.
+    pub fn tokenizer(&self) -> Arc<dyn Tokenizer> {
+        self.tokenizer.clone().unwrap_or_else(default_tokenizer)
+    }
+
+    /// Returns the number of tokens in the given text by the tokenizer of the model.
+    pub fn count_tokens(&self, text: &str) -> usize {
+        match &self.tokenizer {
+            Some(tokenizer) => tokenizer.count_tokens(text),
+            None => default_tokenizer().count_tokens(text),
+        }
+    }
+
+    pub async fn completion(&self, mut req: CompletionRequest) -> Result<AgentOutput, BoxError> {
+        match &self.context_manager {
+            Some(cm) => {
+                let tokenizer = self.tokenizer();
+                let usage = cm
+                    .prepare(self.completer.as_ref(), tokenizer.as_ref(), &mut req)
+                    .await;
+                let mut output = self.completer.completion(req).await?;
+                output.usage.accumulate(&usage);
+                Ok(output)
+            }
+            None => self.completer.completion(req).await,
+        }
+    }
+
+    pub async fn completion_stream(
+        &self,
+        mut req: CompletionRequest,
+    ) -> Result<CompletionStream, BoxError> {
+        let cm = match &self.context_manager {
+            Some(cm) => cm,
+            None => return self.completer.completion_stream(req).await,
+        };
+
+        let tokenizer = self.tokenizer();
+        let usage = cm
+            .prepare(self.completer.as_ref(), tokenizer.as_ref(), &mut req)
+            .await;
+        let stream = self.completer.completion_stream(req).await?;
+        // adds the usage of the summary to the final chunks
+        Ok(stream
+            .map(move |chunk| match chunk {
+                Ok(CompletionChunk::Usage(mut u)) => {
+                    u.accumulate(&usage);
+                    Ok(CompletionChunk::Usage(u))
+                }
+                Ok(CompletionChunk::Done(mut output)) => {
+                    output.usage.accumulate(&usage);
+                    Ok(CompletionChunk::Done(output))
+                }
+                chunk => chunk,
+            })
+            .boxed())
+    }
+
+    /// Returns the name of the completion model.
+    pub fn completion_model_name(&self) -> String {
+        self.completer.model_name()
+    }
+
+    /// Returns the name of the embedding model.
+    pub fn embedding_model_name(&self) -> String {
+        self.embedder.model_name()
     }
 
     pub fn ndims(&self) -> usize {
@@ -178,7 +431,13 @@ impl Model {
         &self,
         texts: impl IntoIterator<Item = String> + Send,
     ) -> Result<(Vec<Embedding>, Usage), BoxError> {
-        self.embedder.embed(texts.into_iter().collect()).await
+        self.embedding_executor
+            .embed(
+                self.embedder.as_ref(),
+                self.tokenizer().as_ref(),
+                texts.into_iter().collect(),
+            )
+            .await
     }
 
     pub async fn embed_query(&self, text: &str) -> Result<(Embedding, Usage), BoxError> {
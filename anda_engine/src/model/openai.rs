@@ -7,15 +7,18 @@
 //! - Response parsing and conversion to Anda's internal formats
 
 use anda_core::{
-    AgentOutput, BoxError, BoxPinFut, CONTENT_TYPE_JSON, CompletionRequest, Embedding,
-    FunctionDefinition, Message, ToolCall, Usage as ModelUsage,
+    AgentOutput, AndaError, BoxError, BoxPinFut, CONTENT_TYPE_JSON, CompletionRequest,
+    CompletionStream, Embedding, FunctionDefinition, Message, ToolCall, Usage as ModelUsage,
 };
 use log::{Level::Debug, log_enabled};
 use serde::{Deserialize, Serialize};
 use serde_json::{Value, json};
 use std::time::Duration;
 
-use super::{CompletionFeaturesDyn, EmbeddingFeaturesDyn};
+use super::{
+    CompletionFeaturesDyn, EmbeddingFeaturesDyn, context_window_of, openai_content_parts,
+    sse::completion_stream, without_reasoning,
+};
 use crate::APP_USER_AGENT;
 
 // ================================================================
@@ -131,7 +134,10 @@ pub struct EmbeddingResponse {
 }
 
 impl EmbeddingResponse {
-    fn try_into(self, texts: Vec<String>) -> Result<(Vec<Embedding>, ModelUsage), BoxError> {
+    pub(crate) fn try_into(
+        self,
+        texts: Vec<String>,
+    ) -> Result<(Vec<Embedding>, ModelUsage), BoxError> {
         if self.data.len() != texts.len() {
             return Err(format!(
                 "Expected {} embeddings, got {}",
@@ -157,6 +163,7 @@ impl EmbeddingResponse {
                     .total_tokens
                     .saturating_sub(self.usage.prompt_tokens) as u64,
                 requests: 1,
+                ..Default::default()
             },
         ))
     }
@@ -177,6 +184,44 @@ pub struct Usage {
     #[serde(default)]
     pub completion_tokens: usize, // no completion_tokens in embeddings API
     pub total_tokens: usize,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub prompt_tokens_details: Option<PromptTokensDetails>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub completion_tokens_details: Option<CompletionTokensDetails>,
+}
+
+/// Breakdown of the prompt tokens
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+pub struct PromptTokensDetails {
+    /// Tokens read from the prompt cache
+    #[serde(default)]
+    pub cached_tokens: usize,
+}
+
+/// Breakdown of the completion tokens
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+pub struct CompletionTokensDetails {
+    /// Tokens generated by the model for reasoning
+    #[serde(default)]
+    pub reasoning_tokens: usize,
+}
+
+impl From<&Usage> for ModelUsage {
+    fn from(u: &Usage) -> Self {
+        ModelUsage {
+            input_tokens: u.prompt_tokens as u64,
+            output_tokens: u.completion_tokens as u64,
+            requests: 1,
+            cached_input_tokens: u
+                .prompt_tokens_details
+                .as_ref()
+                .map_or(0, |d| d.cached_tokens as u64),
+            reasoning_tokens: u
+                .completion_tokens_details
+                .as_ref()
+                .map_or(0, |d| d.reasoning_tokens as u64),
+        }
+    }
 }
 
 impl std::fmt::Display for Usage {
@@ -201,11 +246,15 @@ pub struct CompletionResponse {
 }
 
 impl CompletionResponse {
-    fn try_into(mut self, mut full_history: Vec<Value>) -> Result<AgentOutput, BoxError> {
+    pub(crate) fn try_into(
+        mut self,
+        mut full_history: Vec<Value>,
+    ) -> Result<AgentOutput, BoxError> {
         let choice = self.choices.pop().ok_or("No completion choice")?;
         full_history.push(json!(choice.message));
         let mut output = AgentOutput {
             content: choice.message.content.unwrap_or_default(),
+            reasoning: choice.message.reasoning_content,
             tool_calls: choice.message.tool_calls.map(|tools| {
                 tools
                     .into_iter()
@@ -221,11 +270,7 @@ impl CompletionResponse {
             usage: self
                 .usage
                 .as_ref()
-                .map(|u| ModelUsage {
-                    input_tokens: u.prompt_tokens as u64,
-                    output_tokens: u.completion_tokens as u64,
-                    requests: 1,
-                })
+                .map(ModelUsage::from)
                 .unwrap_or_default(),
             ..Default::default()
         };
@@ -255,6 +300,9 @@ pub struct MessageOutput {
     pub content: Option<String>,
     pub refusal: Option<String>,
     pub tool_calls: Option<Vec<ToolCallOutput>>,
+    /// The reasoning content returned by some OpenAI compatible servers (e.g. vLLM)
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub reasoning_content: Option<String>,
 }
 
 #[derive(Debug, Deserialize, Serialize)]
@@ -271,7 +319,8 @@ pub struct ToolDefinition {
 }
 
 impl From<FunctionDefinition> for ToolDefinition {
-    fn from(f: FunctionDefinition) -> Self {
+    fn from(mut f: FunctionDefinition) -> Self {
+        f.output_schema = None; // the output schema is not a part of the function calling API
         Self {
             r#type: "function".into(),
             function: f,
@@ -294,12 +343,27 @@ pub struct EmbeddingModel {
 }
 
 const MAX_DOCUMENTS: usize = 1024;
+// the max total tokens of the inputs per embedding request
+const MAX_BATCH_TOKENS: usize = 300_000;
+
 impl EmbeddingFeaturesDyn for EmbeddingModel {
     /// The number of dimensions in the embedding vector.
     fn ndims(&self) -> usize {
         self.ndims
     }
 
+    fn model_name(&self) -> String {
+        self.model.clone()
+    }
+
+    fn max_batch_size(&self) -> Option<usize> {
+        Some(MAX_DOCUMENTS)
+    }
+
+    fn max_batch_tokens(&self) -> Option<usize> {
+        Some(MAX_BATCH_TOKENS)
+    }
+
     /// Generates embeddings for multiple texts in a batch
     /// Returns a vector of Embedding structs in the same order as input texts
     fn embed(
@@ -328,8 +392,12 @@ impl EmbeddingFeaturesDyn for EmbeddingModel {
                     Err(err) => Err(format!("OpenAI embeddings error: {}", err).into()),
                 }
             } else {
+                let status = response.status().as_u16();
                 let msg = response.text().await?;
-                Err(format!("OpenAI embeddings error: {}", msg).into())
+                Err(
+                    AndaError::from_status(status, format!("OpenAI embeddings error: {}", msg))
+                        .into(),
+                )
             }
         })
     }
@@ -366,14 +434,19 @@ impl EmbeddingFeaturesDyn for EmbeddingModel {
                                     .saturating_sub(res.usage.prompt_tokens)
                                     as u64,
                                 requests: 1,
+                                ..Default::default()
                             },
                         ))
                     }
                     Err(err) => Err(format!("OpenAI embeddings error: {}", err).into()),
                 }
             } else {
+                let status = response.status().as_u16();
                 let msg = response.text().await?;
-                Err(format!("OpenAI embeddings error: {}", msg).into())
+                Err(
+                    AndaError::from_status(status, format!("OpenAI embeddings error: {}", msg))
+                        .into(),
+                )
             }
         })
     }
@@ -419,6 +492,94 @@ impl CompletionModel {
     fn is_new_model(&self) -> bool {
         self.model.starts_with("o1-")
     }
+
+    /// Builds the chat completions request body, returns the messages sent to the model
+    /// and the JSON body.
+    fn request_body(&self, mut req: CompletionRequest) -> (Vec<Value>, Value) {
+        let is_new = self.is_new_model();
+        // Add preamble to chat history (if available)
+        let mut full_history = if let Some(system) = &req.system {
+            vec![json!(Message {
+                role: if is_new {
+                    "developer".into()
+                } else {
+                    "system".into()
+                },
+                content: system.to_owned().into(),
+                name: req.system_name.clone(),
+                ..Default::default()
+            })]
+        } else {
+            vec![]
+        };
+
+        // Extend existing chat history
+        full_history.append(&mut req.chat_history);
+
+        if !req.content_parts.is_empty() {
+            full_history.push(json!(Message {
+                role: "user".into(),
+                content: openai_content_parts(&req.content_parts),
+                name: req.prompter_name,
+                ..Default::default()
+            }));
+        } else if let Some(prompt) = req.prompt_with_context() {
+            full_history.push(json!(Message {
+                role: "user".into(),
+                content: prompt.into(),
+                name: req.prompter_name,
+                ..Default::default()
+            }));
+        }
+
+        let mut body = json!({
+            "model": self.model,
+            "messages": without_reasoning(&full_history),
+        });
+
+        let obj = body.as_object_mut().unwrap();
+        if let Some(temperature) = req.temperature {
+            obj.insert("temperature".to_string(), Value::from(temperature));
+        }
+
+        if let Some(max_tokens) = req.max_tokens {
+            if is_new {
+                obj.insert("max_completion_tokens".to_string(), Value::from(max_tokens));
+            } else {
+                obj.insert("max_tokens".to_string(), Value::from(max_tokens));
+            }
+        }
+
+        if let Some(response_format) = req.response_format {
+            obj.insert("response_format".to_string(), response_format);
+        }
+
+        if let Some(stop) = req.stop {
+            obj.insert("stop".to_string(), Value::from(stop));
+        }
+
+        if !req.tools.is_empty() {
+            obj.insert(
+                "tools".to_string(),
+                json!(
+                    req.tools
+                        .into_iter()
+                        .map(ToolDefinition::from)
+                        .collect::<Vec<_>>()
+                ),
+            );
+            obj.insert(
+                "tool_choice".to_string(),
+                if req.tool_choice_required {
+                    Value::from("required")
+                } else {
+                    Value::from("auto")
+                },
+            );
+        };
+
+        (full_history, body)
+    }
 }
 
 // impl CompletionFeatures for CompletionModel {
@@ -432,100 +593,19 @@ impl CompletionModel {
 // }
 
 impl CompletionFeaturesDyn for CompletionModel {
-    fn completion(&self, mut req: CompletionRequest) -> BoxPinFut<Result<AgentOutput, BoxError>> {
-        let is_new = self.is_new_model();
-        let model = self.model.clone();
+    fn completion(&self, req: CompletionRequest) -> BoxPinFut<Result<AgentOutput, BoxError>> {
+        let reasoning = req.reasoning;
+        let (full_history, body) = self.request_body(req);
         let client = self.client.clone();
 
         Box::pin(async move {
-            // Add preamble to chat history (if available)
-            let mut full_history = if let Some(system) = &req.system {
-                vec![json!(Message {
-                    role: if is_new {
-                        "developer".into()
-                    } else {
-                        "system".into()
-                    },
-                    content: system.to_owned().into(),
-                    name: req.system_name.clone(),
-                    ..Default::default()
-                })]
-            } else {
-                vec![]
-            };
-
-            // Extend existing chat history
-            full_history.append(&mut req.chat_history);
-
-            if !req.content_parts.is_empty() {
-                full_history.push(json!(Message {
-                    role: "user".into(),
-                    content: json!(req.content_parts),
-                    name: req.prompter_name,
-                    ..Default::default()
-                }));
-            } else if let Some(prompt) = req.prompt_with_context() {
-                full_history.push(json!(Message {
-                    role: "user".into(),
-                    content: prompt.into(),
-                    name: req.prompter_name,
-                    ..Default::default()
-                }));
-            }
-
-            let mut body = json!({
-                "model": model,
-                "messages": full_history.clone(),
-            });
-
-            let body = body.as_object_mut().unwrap();
-            if let Some(temperature) = req.temperature {
-                body.insert("temperature".to_string(), Value::from(temperature));
-            }
-
-            if let Some(max_tokens) = req.max_tokens {
-                if is_new {
-                    body.insert("max_completion_tokens".to_string(), Value::from(max_tokens));
-                } else {
-                    body.insert("max_tokens".to_string(), Value::from(max_tokens));
-                }
-            }
-
-            if let Some(response_format) = req.response_format {
-                body.insert("response_format".to_string(), response_format);
-            }
-
-            if let Some(stop) = req.stop {
-                body.insert("stop".to_string(), Value::from(stop));
-            }
-
-            if !req.tools.is_empty() {
-                body.insert(
-                    "tools".to_string(),
-                    json!(
-                        req.tools
-                            .into_iter()
-                            .map(ToolDefinition::from)
-                            .collect::<Vec<_>>()
-                    ),
-                );
-                body.insert(
-                    "tool_choice".to_string(),
-                    if req.tool_choice_required {
-                        Value::from("required")
-                    } else {
-                        Value::from("auto")
-                    },
-                );
-            };
-
             if log_enabled!(Debug) {
                 if let Ok(val) = serde_json::to_string(&body) {
                     log::debug!(request = val; "OpenAI completions request");
                 }
             }
 
-            let response = client.post("/chat/completions").json(body).send().await?;
+            let response = client.post("/chat/completions").json(&body).send().await?;
             if response.status().is_success() {
                 let text = response.text().await?;
                 match serde_json::from_str::<CompletionResponse>(&text) {
@@ -536,14 +616,70 @@ impl CompletionFeaturesDyn for CompletionModel {
                             }
                         }
                         res.try_into(full_history)
+                            .map(|output| output.with_reasoning_mode(reasoning))
                     }
                     Err(err) => {
                         Err(format!("OpenAI completions error: {}, body: {}", err, text).into())
                     }
                 }
             } else {
+                let status = response.status().as_u16();
+                let msg = response.text().await?;
+                Err(
+                    AndaError::from_status(status, format!("OpenAI completions error: {}", msg))
+                        .into(),
+                )
+            }
+        })
+    }
+
+    fn context_window(&self) -> Option<usize> {
+        context_window_of(&self.model)
+    }
+
+    fn model_name(&self) -> String {
+        self.model.clone()
+    }
+
+    fn completion_stream(
+        &self,
+        req: CompletionRequest,
+    ) -> BoxPinFut<Result<CompletionStream, BoxError>> {
+        let reasoning = req.reasoning;
+        let (full_history, mut body) = self.request_body(req);
+        let client = self.client.clone();
+
+        Box::pin(async move {
+            let obj = body.as_object_mut().unwrap();
+            obj.insert("stream".to_string(), Value::from(true));
+            obj.insert("stream_options".to_string(), json!({"include_usage": true}));
+
+            if log_enabled!(Debug) {
+                if let Ok(val) = serde_json::to_string(&body) {
+                    log::debug!(request = val; "OpenAI completions stream request");
+                }
+            }
+
+            let response = client
+                .post("/chat/completions")
+                .header(http::header::ACCEPT, "text/event-stream")
+                .json(&body)
+                .send()
+                .await?;
+            if response.status().is_success() {
+                Ok(completion_stream(
+                    "OpenAI",
+                    response,
+                    full_history,
+                    reasoning,
+                ))
+            } else {
+                let status = response.status().as_u16();
                 let msg = response.text().await?;
-                Err(format!("OpenAI completions error: {}", msg).into())
+                Err(
+                    AndaError::from_status(status, format!("OpenAI completions error: {}", msg))
+                        .into(),
+                )
             }
         })
     }
@@ -0,0 +1,687 @@
+//! OpenAI compatible API client implementation for Anda Engine
+//!
+//! This module provides integration with self-hosted model servers that implement
+//! the OpenAI chat completions API, such as Ollama, llama.cpp and vLLM, including:
+//! - Client configuration and model discovery via `/models`
+//! - Completion model handling with explicit [`Capabilities`]
+//! - Embedding model handling
+//!
+//! Unlike [`super::openai`], no model name quirks are assumed, what the served model
+//! supports is declared by its [`Capabilities`].
+//!
+//! # Example
+//! ```rust,ignore
+//! let client = Client::new("http://localhost:11434/v1", None);
+//! let models = client.list_models().await?;
+//! let model = client.completion_model(
+//!     &models[0].id,
+//!     Capabilities {
+//!         tools: false,
+//!         context_window: models[0].max_model_len,
+//!         ..Default::default()
+//!     },
+//! );
+//! ```
+
+use anda_core::{
+    AgentOutput, AndaError, BoxError, BoxPinFut, CONTENT_TYPE_JSON, CompletionRequest,
+    CompletionStream, Embedding, Message, Usage as ModelUsage,
+};
+use log::{Level::Debug, log_enabled};
+use serde::{Deserialize, Serialize};
+use serde_json::{Value, json};
+use std::time::Duration;
+
+use super::{
+    CompletionFeaturesDyn, EmbeddingFeaturesDyn, context_window_of,
+    openai::{CompletionResponse, EmbeddingResponse, ToolDefinition},
+    openai_content_parts, push_format_instruction,
+    sse::completion_stream,
+    without_reasoning,
+};
+use crate::APP_USER_AGENT;
+
+/// What the served model supports.
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize, Serialize)]
+pub struct Capabilities {
+    /// Whether the model supports function calling with `tools`.
+    /// Requests with tools fail if it is false.
+    pub tools: bool,
+
+    /// Whether the server supports `response_format` with `json_schema`.
+    /// It falls back to `json_object` with the schema in the prompt if it is false.
+    pub json_schema: bool,
+
+    /// The role name of the system message, "system" by default.
+    /// Some models only accept "user" and "assistant" roles.
+    pub system_role: String,
+
+    /// The context window of the model, in tokens.
+    pub context_window: Option<usize>,
+}
+
+impl Default for Capabilities {
+    fn default() -> Self {
+        Self {
+            tools: true,
+            json_schema: true,
+            system_role: "system".to_string(),
+            context_window: None,
+        }
+    }
+}
+
+/// Model information from the `/models` API.
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct ModelInfo {
+    pub id: String,
+    #[serde(default)]
+    pub object: String,
+    #[serde(default)]
+    pub created: Option<u64>,
+    #[serde(default)]
+    pub owned_by: Option<String>,
+    /// The max context length of the model, reported by vLLM.
+    #[serde(default)]
+    pub max_model_len: Option<usize>,
+}
+
+#[derive(Debug, Deserialize)]
+struct ModelList {
+    data: Vec<ModelInfo>,
+}
+
+/// OpenAI compatible API client
+#[derive(Clone)]
+pub struct Client {
+    endpoint: String,
+    http: reqwest::Client,
+}
+
+impl Client {
+    /// Creates a new client for an OpenAI compatible server
+    ///
+    /// # Arguments
+    /// * `endpoint` - API base URL including the version path, e.g. `http://localhost:11434/v1`
+    /// * `api_key` - Optional API key, sent as a bearer token
+    pub fn new(endpoint: &str, api_key: Option<&str>) -> Self {
+        Self {
+            endpoint: endpoint.trim_end_matches('/').to_string(),
+            http: reqwest::Client::builder()
+                .use_rustls_tls()
+                .connect_timeout(Duration::from_secs(10))
+                .timeout(Duration::from_secs(600))
+                .gzip(true)
+                .user_agent(APP_USER_AGENT)
+                .default_headers({
+                    let mut headers = reqwest::header::HeaderMap::new();
+                    let ct: http::HeaderValue = CONTENT_TYPE_JSON.parse().unwrap();
+                    headers.insert(http::header::CONTENT_TYPE, ct.clone());
+                    headers.insert(http::header::ACCEPT, ct);
+                    if let Some(api_key) = api_key {
+                        headers.insert(
+                            http::header::AUTHORIZATION,
+                            format!("Bearer {}", api_key)
+                                .parse()
+                                .expect("Bearer token should parse"),
+                        );
+                    }
+                    headers
+                })
+                .build()
+                .expect("OpenAI compatible reqwest client should build"),
+        }
+    }
+
+    /// Creates a POST request builder for the given API path
+    fn post(&self, path: &str) -> reqwest::RequestBuilder {
+        let url = format!("{}{}", self.endpoint, path);
+        self.http.post(url)
+    }
+
+    /// Lists the models served by the server
+    pub async fn list_models(&self) -> Result<Vec<ModelInfo>, BoxError> {
+        let url = format!("{}/models", self.endpoint);
+        let response = self.http.get(url).send().await?;
+        if response.status().is_success() {
+            let res: ModelList = response.json().await?;
+            Ok(res.data)
+        } else {
+            let status = response.status().as_u16();
+            let msg = response.text().await?;
+            Err(
+                AndaError::from_status(status, format!("OpenAI compatible models error: {}", msg))
+                    .into(),
+            )
+        }
+    }
+
+    /// Creates a completion model with the given name and capabilities
+    pub fn completion_model(&self, model: &str, capabilities: Capabilities) -> CompletionModel {
+        CompletionModel::new(self.clone(), model, capabilities)
+    }
+
+    /// Creates an embedding model with the given name and dimensions
+    pub fn embedding_model(&self, model: &str, ndims: usize) -> EmbeddingModel {
+        EmbeddingModel::new(self.clone(), model, ndims)
+    }
+}
+
+/// Completion model implementation for OpenAI compatible servers
+#[derive(Clone)]
+pub struct CompletionModel {
+    client: Client,
+    pub model: String,
+    pub capabilities: Capabilities,
+}
+
+impl CompletionModel {
+    /// Creates a new completion model instance
+    ///
+    /// # Arguments
+    /// * `client` - OpenAI compatible client instance
+    /// * `model` - Name of the completion model
+    /// * `capabilities` - What the model supports
+    pub fn new(client: Client, model: &str, capabilities: Capabilities) -> Self {
+        Self {
+            client,
+            model: model.to_string(),
+            capabilities,
+        }
+    }
+
+    /// Builds the chat completions request body, returns the messages sent to the model
+    /// and the JSON body.
+    fn request_body(&self, mut req: CompletionRequest) -> Result<(Vec<Value>, Value), BoxError> {
+        if !req.tools.is_empty() && !self.capabilities.tools {
+            return Err(AndaError::invalid_args(format!(
+                "model {} does not support tools",
+                self.model
+            ))
+            .into());
+        }
+
+        let system_role = self.capabilities.system_role.as_str();
+        // Add preamble to chat history (if available)
+        let mut full_history = if let Some(system) = &req.system {
+            vec![json!(Message {
+                role: system_role.into(),
+                content: system.to_owned().into(),
+                name: req.system_name.clone(),
+                ..Default::default()
+            })]
+        } else {
+            vec![]
+        };
+
+        // Extend existing chat history, the system messages use the role of the model
+        for mut msg in req.chat_history.drain(..) {
+            if let Some(role) = msg.get_mut("role") {
+                if matches!(role.as_str(), Some("system") | Some("developer")) {
+                    *role = Value::from(system_role);
+                }
+            }
+            full_history.push(msg);
+        }
+
+        if !req.content_parts.is_empty() {
+            full_history.push(json!(Message {
+                role: "user".into(),
+                content: openai_content_parts(&req.content_parts),
+                name: req.prompter_name,
+                ..Default::default()
+            }));
+        } else if let Some(prompt) = req.prompt_with_context() {
+            full_history.push(json!(Message {
+                role: "user".into(),
+                content: prompt.into(),
+                name: req.prompter_name,
+                ..Default::default()
+            }));
+        }
+
+        let mut body = json!({
+            "model": self.model,
+            "messages": without_reasoning(&full_history),
+        });
+
+        let obj = body.as_object_mut().unwrap();
+        if let Some(temperature) = req.temperature {
+            obj.insert("temperature".to_string(), Value::from(temperature));
+        }
+
+        if let Some(max_tokens) = req.max_tokens {
+            obj.insert("max_tokens".to_string(), Value::from(max_tokens));
+        }
+
+        if let Some(response_format) = req.response_format {
+            let is_schema =
+                response_format.get("type").and_then(Value::as_str) == Some("json_schema");
+            if is_schema && !self.capabilities.json_schema {
+                obj.insert(
+                    "response_format".to_string(),
+                    json!({"type": "json_object"}),
+                );
+                // the schema is added to the prompt
+                if let Some(messages) = obj.get_mut("messages").and_then(Value::as_array_mut) {
+                    push_format_instruction(messages, &response_format, system_role);
+                }
+            } else {
+                obj.insert("response_format".to_string(), response_format);
+            }
+        }
+
+        if let Some(stop) = req.stop {
+            obj.insert("stop".to_string(), Value::from(stop));
+        }
+
+        if !req.tools.is_empty() {
+            obj.insert(
+                "tools".to_string(),
+                json!(
+                    req.tools
+                        .into_iter()
+                        .map(ToolDefinition::from)
+                        .collect::<Vec<_>>()
+                ),
+            );
+            obj.insert(
+                "tool_choice".to_string(),
+                if req.tool_choice_required {
+                    Value::from("required")
+                } else {
+                    Value::from("auto")
+                },
+            );
+        };
+
+        Ok((full_history, body))
+    }
+}
+
+impl CompletionFeaturesDyn for CompletionModel {
+    fn completion(&self, req: CompletionRequest) -> BoxPinFut<Result<AgentOutput, BoxError>> {
+        let reasoning = req.reasoning;
+        let rt = self.request_body(req);
+        let client = self.client.clone();
+
+        Box::pin(async move {
+            let (full_history, body) = rt?;
+            if log_enabled!(Debug) {
+                if let Ok(val) = serde_json::to_string(&body) {
+                    log::debug!(request = val; "OpenAI compatible completions request");
+                }
+            }
+
+            let response = client.post("/chat/completions").json(&body).send().await?;
+            if response.status().is_success() {
+                let text = response.text().await?;
+                match serde_json::from_str::<CompletionResponse>(&text) {
+                    Ok(res) => {
+                        if log_enabled!(Debug) {
+                            if let Ok(val) = serde_json::to_string(&res) {
+                                log::debug!(response = val; "OpenAI compatible completions response");
+                            }
+                        }
+                        res.try_into(full_history)
+                            .map(|output| output.with_reasoning_mode(reasoning))
+                    }
+                    Err(err) => Err(format!(
+                        "OpenAI compatible completions error: {}, body: {}",
+                        err, text
+                    )
+                    .into()),
+                }
+            } else {
+                let status = response.status().as_u16();
+                let msg = response.text().await?;
+                Err(AndaError::from_status(
+                    status,
+                    format!("OpenAI compatible completions error: {}", msg),
+                )
+                .into())
+            }
+        })
+    }
+
+    fn context_window(&self) -> Option<usize> {
+        self.capabilities
+            .context_window
+            .or_else(|| context_window_of(&self.model))
+    }
+
+    fn model_name(&self) -> String {
+        self.model.clone()
+    }
+
+    fn completion_stream(
+        &self,
+        req: CompletionRequest,
+    ) -> BoxPinFut<Result<CompletionStream, BoxError>> {
+        let reasoning = req.reasoning;
+        let rt = self.request_body(req);
+        let client = self.client.clone();
+
+        Box::pin(async move {
+            let (full_history, mut body) = rt?;
+            let obj = body.as_object_mut().unwrap();
+            obj.insert("stream".to_string(), Value::from(true));
+            obj.insert("stream_options".to_string(), json!({"include_usage": true}));
+
+            if log_enabled!(Debug) {
+                if let Ok(val) = serde_json::to_string(&body) {
+                    log::debug!(request = val; "OpenAI compatible completions stream request");
+                }
+            }
+
+            let response = client
+                .post("/chat/completions")
+                .header(http::header::ACCEPT, "text/event-stream")
+                .json(&body)
+                .send()
+                .await?;
+            if response.status().is_success() {
+                Ok(completion_stream(
+                    "OpenAI compatible",
+                    response,
+                    full_history,
+                    reasoning,
+                ))
+            } else {
+                let status = response.status().as_u16();
+                let msg = response.text().await?;
+                Err(AndaError::from_status(
+                    status,
+                    format!("OpenAI compatible completions error: {}", msg),
+                )
+                .into())
+            }
+        })
+    }
+}
+
+/// Embedding model implementation for OpenAI compatible servers
+#[derive(Clone)]
+pub struct EmbeddingModel {
+    client: Client,
+    pub model: String,
+    ndims: usize,
+}
+
+impl EmbeddingModel {
+    /// Creates a new embedding model instance
+    ///
+    /// # Arguments
+    /// * `client` - OpenAI compatible client instance
+    /// * `model` - Name of the embedding model
+    /// * `ndims` - Number of dimensions for the embedding
+    pub fn new(client: Client, model: &str, ndims: usize) -> Self {
+        Self {
+            client,
+            model: model.to_string(),
+            ndims,
+        }
+    }
+}
+
+impl EmbeddingFeaturesDyn for EmbeddingModel {
+    fn ndims(&self) -> usize {
+        self.ndims
+    }
+
+    fn model_name(&self) -> String {
+        self.model.clone()
+    }
+
+    fn embed(
+        &self,
+        texts: Vec<String>,
+    ) -> BoxPinFut<Result<(Vec<Embedding>, ModelUsage), BoxError>> {
+        let model = self.model.clone();
+        let client = self.client.clone();
+        Box::pin(async move {
+            let response = client
+                .post("/embeddings")
+                .json(&json!({
+                    "model": model,
+                    "input": texts,
+                }))
+                .send()
+                .await?;
+
+            if response.status().is_success() {
+                match response.json::<EmbeddingResponse>().await {
+                    Ok(res) => res.try_into(texts),
+                    Err(err) => Err(format!("OpenAI compatible embeddings error: {}", err).into()),
+                }
+            } else {
+                let status = response.status().as_u16();
+                let msg = response.text().await?;
+                Err(AndaError::from_status(
+                    status,
+                    format!("OpenAI compatible embeddings error: {}", msg),
+                )
+                .into())
+            }
+        })
+    }
+
+    fn embed_query(&self, text: String) -> BoxPinFut<Result<(Embedding, ModelUsage), BoxError>> {
+        let fut = self.embed(vec![text]);
+        Box::pin(async move {
+            let (mut embeddings, usage) = fut.await?;
+            let embedding = embeddings.pop().ok_or("no embedding data")?;
+            Ok((embedding, usage))
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use anda_core::{FunctionDefinition, Resource};
+    use std::sync::{Arc, Mutex};
+    use tokio::{
+        io::{AsyncReadExt, AsyncWriteExt},
+        net::TcpListener,
+    };
+
+    /// A minimal OpenAI compatible server, it records the requests and
+    /// responds with canned JSON bodies.
+    async fn stub_server(requests: Arc<Mutex<Vec<(String, Value)>>>) -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            loop {
+                let (mut stream, _) = listener.accept().await.unwrap();
+                let requests = requests.clone();
+                tokio::spawn(async move {
+                    let mut buf = Vec::new();
+                    let mut chunk = [0u8; 4096];
+                    let header_end = loop {
+                        let n = stream.read(&mut chunk).await.unwrap();
+                        buf.extend_from_slice(&chunk[..n]);
+                        if let Some(i) = buf.windows(4).position(|w| w == b"\r\n\r\n") {
+                            break i + 4;
+                        }
+                    };
+                    let head = String::from_utf8_lossy(&buf[..header_end]).to_string();
+                    let content_length = head
+                        .lines()
+                        .find_map(|l| {
+                            let (k, v) = l.split_once(':')?;
+                            k.eq_ignore_ascii_case("content-length")
+                                .then(|| v.trim().parse::<usize>().ok())?
+                        })
+                        .unwrap_or(0);
+                    while buf.len() < header_end + content_length {
+                        let n = stream.read(&mut chunk).await.unwrap();
+                        buf.extend_from_slice(&chunk[..n]);
+                    }
+                    let route = head.lines().next().unwrap_or_default().to_string();
+                    let body: Value =
+                        serde_json::from_slice(&buf[header_end..]).unwrap_or(Value::Null);
+
+                    let res = if route.starts_with("GET /v1/models ") {
+                        json!({"object": "list", "data": [
+                            {"id": "llama3.2", "object": "model", "owned_by": "library", "max_model_len": 8192},
+                        ]})
+                    } else if route.starts_with("POST /v1/chat/completions ") {
+                        json!({
+                            "id": "chatcmpl-1",
+                            "object": "chat.completion",
+                            "created": 1,
+                            "model": "llama3.2",
+                            "choices": [{
+                                "index": 0,
+                                "message": {
+                                    "role": "assistant",
+                                    "content": "",
+                                    "tool_calls": [{
+                                        "id": "call_1",
+                                        "type": "function",
+                                        "function": {"name": "get_weather", "arguments": "{\"city\":\"Paris\"}"},
+                                    }],
+                                },
+                                "finish_reason": "tool_calls",
+                            }],
+                            "usage": {"prompt_tokens": 10, "completion_tokens": 5, "total_tokens": 15},
+                        })
+                    } else if route.starts_with("POST /v1/embeddings ") {
+                        json!({
+                            "object": "list",
+                            "data": [{"object": "embedding", "embedding": [0.1, 0.2], "index": 0}],
+                            "model": "nomic-embed-text",
+                            "usage": {"prompt_tokens": 3, "total_tokens": 3},
+                        })
+                    } else {
+                        Value::Null
+                    };
+                    requests.lock().unwrap().push((route, body));
+
+                    let (status, res) = if res.is_null() {
+                        ("404 Not Found", "not found".to_string())
+                    } else {
+                        ("200 OK", res.to_string())
+                    };
+                    let response = format!(
+                        "HTTP/1.1 {}\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+                        status,
+                        res.len(),
+                        res
+                    );
+                    stream.write_all(response.as_bytes()).await.unwrap();
+                    stream.shutdown().await.ok();
+                });
+            }
+        });
+        format!("http://{}/v1/", addr)
+    }
+
+    #[tokio::test(flavor = "current_thread")]
+    async fn test_openai_compatible() {
+        let requests = Arc::new(Mutex::new(Vec::new()));
+        let endpoint = stub_server(requests.clone()).await;
+        let client = Client::new(&endpoint, None);
+
+        let models = client.list_models().await.unwrap();
+        assert_eq!(models.len(), 1);
+        assert_eq!(models[0].id, "llama3.2");
+
+        let model = client.completion_model(
+            &models[0].id,
+            Capabilities {
+                json_schema: false,
+                system_role: "user".to_string(),
+                context_window: models[0].max_model_len,
+                ..Default::default()
+            },
+        );
+        assert_eq!(model.context_window(), Some(8192));
+
+        let req = CompletionRequest {
+            system: Some("You are a helpful assistant.".to_string()),
+            prompt: "Weather in Paris?".to_string(),
+            tools: vec![FunctionDefinition {
+                name: "get_weather".to_string(),
+                description: "Gets the weather".to_string(),
+                parameters: json!({"type": "object", "properties": {}}),
+                strict: None,
+                output_schema: None,
+            }],
+            response_format: Some(
+                json!({"type": "json_schema", "json_schema": {"name": "weather", "schema": {}}}),
+            ),
+            max_tokens: Some(100),
+            ..Default::default()
+        };
+        let output = CompletionFeaturesDyn::completion(&model, req.clone())
+            .await
+            .unwrap();
+        assert!(output.failed_reason.is_none());
+        assert_eq!(output.usage.input_tokens, 10);
+        assert_eq!(output.usage.output_tokens, 5);
+        let tool_calls = output.tool_calls.unwrap();
+        assert_eq!(tool_calls[0].name, "get_weather");
+        assert_eq!(tool_calls[0].args, r#"{"city":"Paris"}"#);
+        assert_eq!(output.full_history.unwrap().len(), 3);
+
+        {
+            let requests = requests.lock().unwrap();
+            let (_, body) = requests.last().unwrap();
+            assert_eq!(body["model"], "llama3.2");
+            assert_eq!(body["messages"][0]["role"], "user");
+            let system = body["messages"][0]["content"].as_str().unwrap();
+            assert!(
+                system.starts_with("You are a helpful assistant.\n\nRespond with a JSON object")
+            );
+            assert_eq!(body["response_format"], json!({"type": "json_object"}));
+            assert_eq!(body["max_tokens"], 100);
+            assert_eq!(body["tools"][0]["function"]["name"], "get_weather");
+        }
+
+        // the request fails without calling the server if tools are not supported
+        let model = client.completion_model(
+            "llama3.2",
+            Capabilities {
+                tools: false,
+                ..Default::default()
+            },
+        );
+        let err = CompletionFeaturesDyn::completion(&model, req)
+            .await
+            .unwrap_err();
+        assert!(err.to_string().contains("does not support tools"));
+        assert_eq!(requests.lock().unwrap().len(), 2);
+
+        let model = client.embedding_model("nomic-embed-text", 2);
+        let (embedding, usage) = model.embed_query("hello".to_string()).await.unwrap();
+        assert_eq!(embedding.text, "hello");
+        assert_eq!(embedding.vec, vec![0.1, 0.2]);
+        assert_eq!(usage.input_tokens, 3);
+    }
+
+    #[test]
+    fn test_content_parts() {
+        let client = Client::new("http://localhost:11434/v1", None);
+        let model = client.completion_model("llava", Capabilities::default());
+        let mut resources = vec![Resource {
+            tag: "image".to_string(),
+            uri: Some("https://example.com/cat.png".to_string()),
+            ..Default::default()
+        }];
+        let req = CompletionRequest {
+            prompt: "What's in this image?".to_string(),
+            ..Default::default()
+        }
+        .append_resources(&mut resources);
+        let (_, body) = model.request_body(req).unwrap();
+        assert_eq!(
+            body["messages"][0]["content"],
+            json!([
+                {"type": "text", "text": "What's in this image?"},
+                {"type": "image_url", "image_url": {"url": "https://example.com/cat.png"}},
+            ])
+        );
+    }
+}
@@ -0,0 +1,428 @@
+//! Prompted tool calling for models without native function calling.
+//!
+//! [`PromptedToolCalling`] is a [`CompletionFeaturesDyn`] that wraps a completion model:
+//! - The `tools` of the request are rendered into the system prompt, the model is asked
+//!   to call tools with `<tool_call>` JSON blocks in its text;
+//! - The tool call blocks are parsed out of the text and returned as normal [`ToolCall`]s;
+//! - The tool results (messages with "tool" role) are sent back to the model as user
+//!   messages with `<tool_response>` blocks.
+//!
+//! So the tool call loop of `AgentCtx::completion` and the `Extractor` work unchanged.
+//!
+//! # Example
+//! ```rust,ignore
+//! let model = client.completion_model(
+//!     "gemma3",
+//!     Capabilities {
+//!         tools: false,
+//!         ..Default::default()
+//!     },
+//! );
+//! let model = Model::with_completer(Arc::new(PromptedToolCalling::new(Arc::new(model))));
+//! ```
+
+use anda_core::{
+    AgentOutput, BoxError, BoxPinFut, CompletionRequest, FunctionDefinition, Message, ToolCall,
+};
+use serde_json::{Value, json};
+use std::sync::Arc;
+
+use super::CompletionFeaturesDyn;
+
+/// The header of the tools prompt, it is used to detect the prompt in the chat history.
+/// The whole header up to the opening `<tools>` tag is matched, so a system prompt of the
+/// user with a "# Tools" section is not mistaken for it.
+const TOOLS_PROMPT_HEADER: &str = "# Tools\n\nYou may call one or more tools to assist with the user query. \
+    The tools are described by JSON schemas within <tools></tools> XML tags:\n<tools>\n";
+const TOOL_CALL_START: &str = "<tool_call>";
+const TOOL_CALL_END: &str = "</tool_call>";
+
+/// Completion model wrapper that calls tools by prompting.
+#[derive(Clone)]
+pub struct PromptedToolCalling {
+    inner: Arc<dyn CompletionFeaturesDyn>,
+}
+
+impl PromptedToolCalling {
+    /// Creates a new wrapper of the completion model.
+    pub fn new(inner: Arc<dyn CompletionFeaturesDyn>) -> Self {
+        Self { inner }
+    }
+
+    /// Renders the tool definitions into the tools prompt.
+    pub fn render_tools(tools: &[FunctionDefinition], required: bool) -> String {
+        let mut prompt = TOOLS_PROMPT_HEADER.to_string();
+        for tool in tools {
+            prompt.push_str(
+                &json!({
+                    "name": tool.name,
+                    "description": tool.description,
+                    "parameters": tool.parameters,
+                })
+                .to_string(),
+            );
+            prompt.push('\n');
+        }
+        prompt.push_str(
+            "</tools>\n\nTo call a tool, respond with a JSON object with the tool name and arguments \
+            within <tool_call></tool_call> XML tags:\n<tool_call>\n\
+            {\"name\": \"<tool-name>\", \"arguments\": <args-json-object>}\n</tool_call>\n\
+            The results of the tool calls will be given within <tool_response></tool_response> XML tags.",
+        );
+        if required {
+            prompt.push_str("\nYou must call at least one tool in the response.");
+        }
+        prompt
+    }
+
+    /// Parses the tool calls out of the model's text.
+    /// Returns the text without the tool call blocks and the tool calls,
+    /// the ids of tool calls are `{id_prefix}_{index}`.
+    /// Blocks that can not be parsed are kept in the text.
+    pub fn parse_tool_calls(text: &str, id_prefix: &str) -> (String, Vec<ToolCall>) {
+        let mut content = String::new();
+        let mut tool_calls: Vec<ToolCall> = Vec::new();
+        let mut rest = text;
+        while let Some(start) = rest.find(TOOL_CALL_START) {
+            content.push_str(&rest[..start]);
+            let block = &rest[start + TOOL_CALL_START.len()..];
+            // the closing tag may be cut off by stop sequences
+            let (inner, next) = match block.find(TOOL_CALL_END) {
+                Some(end) => (&block[..end], &block[end + TOOL_CALL_END.len()..]),
+                None => (block, ""),
+            };
+            match parse_tool_call(inner) {
+                Some((name, args)) => tool_calls.push(ToolCall {
+                    id: format!("{}_{}", id_prefix, tool_calls.len()),
+                    name,
+                    args,
+                    result: None,
+                }),
+                None => content.push_str(&rest[start..rest.len() - next.len()]),
+            }
+            rest = next;
+        }
+        content.push_str(rest);
+        (content.trim().to_string(), tool_calls)
+    }
+
+    /// Moves the tools of the request into the system prompt and converts the tool
+    /// messages in the chat history to text. Returns the tools of the request.
+    fn prepare(req: &mut CompletionRequest) -> Vec<FunctionDefinition> {
+        let tools = std::mem::take(&mut req.tools);
+        let required = std::mem::take(&mut req.tool_choice_required);
+
+        let mut chat_history: Vec<Value> = Vec::with_capacity(req.chat_history.len());
+        let mut has_prompt = false;
+        for msg in req.chat_history.drain(..) {
+            match msg.get("role").and_then(Value::as_str).unwrap_or_default() {
+                "system" | "developer" => {
+                    has_prompt = has_prompt
+                        || msg
+                            .get("content")
+                            .and_then(Value::as_str)
+                            .is_some_and(|c| c.contains(TOOLS_PROMPT_HEADER));
+                    chat_history.push(msg);
+                }
+                "tool" => {
+                    let id = msg
+                        .get("tool_call_id")
+                        .and_then(Value::as_str)
+                        .unwrap_or_default();
+                    let content = match msg.get("content") {
+                        Some(Value::String(text)) => text.clone(),
+                        Some(other) => other.to_string(),
+                        None => String::new(),
+                    };
+                    let response = format!(
+                        "<tool_response id=\"{}\">\n{}\n</tool_response>",
+                        id, content
+                    );
+                    // the results of a round are merged into one user message
+                    match chat_history.last_mut() {
+                        Some(last) if is_tool_response(last) => {
+                            if let Some(Value::String(text)) = last.get_mut("content") {
+                                text.push('\n');
+                                text.push_str(&response);
+                            }
+                        }
+                        _ => chat_history.push(json!(Message {
+                            role: "user".into(),
+                            content: response.into(),
+                            ..Default::default()
+                        })),
+                    }
+                }
+                "assistant" if msg.get("tool_calls").is_some_and(Value::is_array) => {
+                    // native tool calls from other models
+                    let mut text = msg
+                        .get("content")
+                        .and_then(Value::as_str)
+                        .unwrap_or_default()
+                        .to_string();
+                    for tc in msg["tool_calls"].as_array().into_iter().flatten() {
+                        let function = &tc["function"];
+                        let args = function["arguments"].as_str().unwrap_or("{}");
+                        text.push_str(&format!(
+                            "\n{}\n{{\"name\": {}, \"arguments\": {}}}\n{}",
+                            TOOL_CALL_START, function["name"], args, TOOL_CALL_END
+                        ));
+                    }
+                    chat_history.push(json!(Message {
+                        role: "assistant".into(),
+                        content: text.trim().to_string().into(),
+                        ..Default::default()
+                    }));
+                }
+                _ => chat_history.push(msg),
+            }
+        }
+        req.chat_history = chat_history;
+
+        if !tools.is_empty() && !has_prompt {
+            let prompt = Self::render_tools(&tools, required);
+            req.system = Some(match req.system.take() {
+                Some(system) if !system.is_empty() => format!("{}\n\n{}", system, prompt),
+                _ => prompt,
+            });
+        }
+        tools
+    }
+}
+
+fn is_tool_response(msg: &Value) -> bool {
+    msg.get("role").and_then(Value::as_str) == Some("user")
+        && msg
+            .get("content")
+            .and_then(Value::as_str)
+            .is_some_and(|c| c.starts_with("<tool_response "))
+}
+
+/// Parses `{"name": "...", "arguments": {...}}` in a tool call block,
+/// returns the tool name and the arguments in JSON string.
+fn parse_tool_call(block: &str) -> Option<(String, String)> {
+    let block = block.trim();
+    // some models wrap the JSON in a code block
+    let block = block
+        .strip_prefix("```json")
+        .or_else(|| block.strip_prefix("```"))
+        .map(|b| b.trim_end().trim_end_matches("```").trim())
+        .unwrap_or(block);
+    let val: Value = serde_json::from_str(block).ok()?;
+    let name = val.get("name")?.as_str()?.to_string();
+    let args = match val.get("arguments").or_else(|| val.get("parameters")) {
+        // the arguments may be a JSON string
+        Some(Value::String(args)) => args.clone(),
+        Some(args) => args.to_string(),
+        None => "{}".to_string(),
+    };
+    Some((name, args))
+}
+
+impl CompletionFeaturesDyn for PromptedToolCalling {
+    fn completion(&self, mut req: CompletionRequest) -> BoxPinFut<Result<AgentOutput, BoxError>> {
+        let tools = Self::prepare(&mut req);
+        let round = req
+            .chat_history
+            .iter()
+            .filter(|msg| msg.get("role").and_then(Value::as_str) == Some("assistant"))
+            .count();
+        let fut = self.inner.completion(req);
+        Box::pin(async move {
+            let mut output = fut.await?;
+            if tools.is_empty() || output.tool_calls.is_some() {
+                return Ok(output);
+            }
+
+            let (content, tool_calls) =
+                Self::parse_tool_calls(&output.content, &format!("call_{}", round));
+            if !tool_calls.is_empty() {
+                output.content = content;
+                output.tool_calls = Some(tool_calls);
+            }
+            Ok(output)
+        })
+    }
+
+    fn context_window(&self) -> Option<usize> {
+        self.inner.context_window()
+    }
+
+    fn model_name(&self) -> String {
+        self.inner.model_name()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Mutex;
+
+    /// Records the requests and responds with the canned texts.
+    struct TextModel {
+        texts: Mutex<Vec<String>>,
+        requests: Arc<Mutex<Vec<CompletionRequest>>>,
+    }
+
+    impl CompletionFeaturesDyn for TextModel {
+        fn completion(&self, req: CompletionRequest) -> BoxPinFut<Result<AgentOutput, BoxError>> {
+            let content = self.texts.lock().unwrap().remove(0);
+            let mut full_history = Vec::new();
+            if let Some(system) = &req.system {
+                full_history.push(json!({"role": "system", "content": system}));
+            }
+            full_history.extend(req.chat_history.clone());
+            if !req.prompt.is_empty() {
+                full_history.push(json!({"role": "user", "content": req.prompt}));
+            }
+            full_history.push(json!({"role": "assistant", "content": content}));
+            self.requests.lock().unwrap().push(req);
+            Box::pin(futures::future::ready(Ok(AgentOutput {
+                content,
+                full_history: Some(full_history),
+                ..Default::default()
+            })))
+        }
+    }
+
+    fn weather_tool() -> FunctionDefinition {
+        FunctionDefinition {
+            name: "get_weather".to_string(),
+            description: "Gets the weather".to_string(),
+            parameters: json!({"type": "object", "properties": {"city": {"type": "string"}}}),
+            strict: None,
+            output_schema: None,
+        }
+    }
+
+    #[test]
+    fn test_parse_tool_calls() {
+        let text = r#"Let me check.
+<tool_call>
+{"name": "get_weather", "arguments": {"city": "Paris"}}
+</tool_call>
+<tool_call>
+```json
+{"name": "get_weather", "arguments": "{\"city\":\"Tokyo\"}"}
+```
+</tool_call>
+<tool_call>not json</tool_call>
+<tool_call>
+{"name": "get_time", "parameters": {}}"#;
+        let (content, tool_calls) = PromptedToolCalling::parse_tool_calls(text, "call_0");
+        assert_eq!(
+            content,
+            "Let me check.\n\n\n<tool_call>not json</tool_call>"
+        );
+        assert_eq!(tool_calls.len(), 3);
+        assert_eq!(tool_calls[0].id, "call_0_0");
+        assert_eq!(tool_calls[0].name, "get_weather");
+        assert_eq!(tool_calls[0].args, r#"{"city":"Paris"}"#);
+        assert_eq!(tool_calls[1].args, r#"{"city":"Tokyo"}"#);
+        assert_eq!(tool_calls[2].id, "call_0_2");
+        assert_eq!(tool_calls[2].name, "get_time");
+
+        let (content, tool_calls) = PromptedToolCalling::parse_tool_calls("Hello", "call_0");
+        assert_eq!(content, "Hello");
+        assert!(tool_calls.is_empty());
+    }
+
+    #[tokio::test(flavor = "current_thread")]
+    async fn test_prompted_tool_calling() {
+        let requests = Arc::new(Mutex::new(Vec::new()));
+        let model = PromptedToolCalling::new(Arc::new(TextModel {
+            texts: Mutex::new(vec![
+                "<tool_call>\n{\"name\": \"get_weather\", \"arguments\": {\"city\": \"Paris\"}}\n</tool_call>"
+                    .to_string(),
+                "It is sunny in Paris.".to_string(),
+            ]),
+            requests: requests.clone(),
+        }));
+
+        let output = model
+            .completion(CompletionRequest {
+                system: Some("You are a helpful assistant.".to_string()),
+                prompt: "Weather in Paris?".to_string(),
+                tools: vec![weather_tool()],
+                tool_choice_required: true,
+                ..Default::default()
+            })
+            .await
+            .unwrap();
+        assert_eq!(output.content, "");
+        let tool_calls = output.tool_calls.unwrap();
+        assert_eq!(tool_calls[0].id, "call_0_0");
+        assert_eq!(tool_calls[0].args, r#"{"city":"Paris"}"#);
+        {
+            let requests = requests.lock().unwrap();
+            let req = &requests[0];
+            assert!(req.tools.is_empty());
+            assert!(!req.tool_choice_required);
+            let system = req.system.as_ref().unwrap();
+            assert!(system.starts_with("You are a helpful assistant.\n\n# Tools"));
+            assert!(system.contains(r#""name":"get_weather""#));
+            assert!(system.contains("You must call at least one tool"));
+        }
+
+        // the next round of the tool call loop in `AgentCtx::completion`
+        let mut chat_history = output.full_history.unwrap();
+        chat_history.push(json!(Message {
+            role: "tool".to_string(),
+            content: json!({"weather": "sunny"}),
+            name: None,
+            tool_call_id: Some("call_0_0".to_string()),
+            ..Default::default()
+        }));
+        let output = model
+            .completion(CompletionRequest {
+                chat_history,
+                tools: vec![weather_tool()],
+                ..Default::default()
+            })
+            .await
+            .unwrap();
+        assert_eq!(output.content, "It is sunny in Paris.");
+        assert!(output.tool_calls.is_none());
+        {
+            let requests = requests.lock().unwrap();
+            let req = &requests[1];
+            // the tools prompt is in the chat history already
+            assert!(req.system.is_none());
+            assert_eq!(req.chat_history.len(), 4);
+            assert_eq!(
+                req.chat_history[3],
+                json!({
+                    "role": "user",
+                    "content": "<tool_response id=\"call_0_0\">\n{\"weather\":\"sunny\"}\n</tool_response>",
+                })
+            );
+        }
+    }
+
+    #[tokio::test(flavor = "current_thread")]
+    async fn test_prompted_tool_calling_with_tools_section() {
+        let requests = Arc::new(Mutex::new(Vec::new()));
+        let model = PromptedToolCalling::new(Arc::new(TextModel {
+            texts: Mutex::new(vec!["Hello!".to_string()]),
+            requests: requests.clone(),
+        }));
+
+        // a "# Tools" section of the user is not the tools prompt
+        model
+            .completion(CompletionRequest {
+                chat_history: vec![json!({
+                    "role": "system",
+                    "content": "You are a helpful assistant.\n\n# Tools\n\nUse the tools wisely.",
+                })],
+                prompt: "Hi".to_string(),
+                tools: vec![weather_tool()],
+                ..Default::default()
+            })
+            .await
+            .unwrap();
+        let requests = requests.lock().unwrap();
+        let system = requests[0].system.as_ref().unwrap();
+        assert!(system.starts_with(TOOLS_PROMPT_HEADER));
+        assert!(system.contains(r#""name":"get_weather""#));
+    }
+}
@@ -0,0 +1,653 @@
+//! Model router with fallback, retries and circuit breakers across providers.
+//!
+//! [`ModelRouter`] is a [`CompletionFeaturesDyn`] that wraps several completion providers:
+//! - The providers are tried in the order of the route, the next provider is used
+//!   when a provider fails;
+//! - Retryable errors (rate limited, timeout, unavailable) are retried on the same
+//!   provider with exponential backoff;
+//! - Every provider has an optional timeout;
+//! - A provider is skipped for a cooldown period after consecutive failed requests (circuit breaker),
+//!   then a single probe request decides whether it is used again (half-open).
+//!
+//! Invalid arguments are not retried on the same provider but fall back to the next one,
+//! as providers reject different features (e.g. tools or image inputs). Unauthenticated and
+//! cancelled requests are returned immediately. These errors are not counted by the circuit breaker.
+//!
+//! The output reports the model of the provider that served it in [`AgentOutput::model`].
+//!
+//! Routes can be selected per agent with [`Model::with_agent_completer`](super::Model::with_agent_completer),
+//! the routers created by [`ModelRouter::with_route`] share the state of circuit breakers.
+//!
+//! # Example
+//! ```rust,ignore
+//! let router = ModelRouter::new(vec![
+//!     RouterProvider::new("deepseek".to_string(), Arc::new(deepseek.completion_model("")))
+//!         .with_timeout(Duration::from_secs(60)),
+//!     RouterProvider::new("openai".to_string(), Arc::new(openai.completion_model(""))),
+//! ])?;
+//! let model = Model::with_completer(Arc::new(router.clone()))
+//!     .with_agent_completer("coder".to_string(), Arc::new(router.with_route(vec!["openai".to_string()])?));
+//! ```
+
+use anda_core::{
+    AgentOutput, AndaError, BoxError, BoxPinFut, CompletionChunk, CompletionRequest,
+    CompletionStream, ErrorCode,
+};
+use futures::StreamExt;
+use std::{
+    collections::BTreeMap,
+    future::Future,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use super::CompletionFeaturesDyn;
+
+/// Retry policy for retryable errors of a provider.
+#[derive(Debug, Clone)]
+pub struct RetryPolicy {
+    /// Max number of retries on the same provider.
+    pub max_retries: u32,
+    /// Backoff before the first retry, doubled for every next retry.
+    pub initial_backoff: Duration,
+    /// Max backoff between retries.
+    pub max_backoff: Duration,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        Self {
+            max_retries: 2,
+            initial_backoff: Duration::from_millis(500),
+            max_backoff: Duration::from_secs(10),
+        }
+    }
+}
+
+impl RetryPolicy {
+    /// Returns the backoff before the retry `attempt` (starts from 0), with up to 25% jitter.
+    pub fn backoff(&self, attempt: u32) -> Duration {
+        let backoff = self
+            .initial_backoff
+            .saturating_mul(2u32.saturating_pow(attempt))
+            .min(self.max_backoff);
+        let jitter = backoff.as_millis() as u64 / 4;
+        if jitter == 0 {
+            return backoff;
+        }
+        backoff + Duration::from_millis(crate::rand_number(0..=jitter))
+    }
+}
+
+/// Circuit breaker policy of providers.
+#[derive(Debug, Clone)]
+pub struct CircuitBreakerPolicy {
+    /// Number of consecutive failed requests to open the circuit,
+    /// the retries of a request are counted as one failure.
+    pub failure_threshold: u32,
+    /// Duration to skip the provider once the circuit is open.
+    /// A probe request is allowed after the cooldown, other requests skip the provider
+    /// until it succeeds, a failed probe opens the circuit again.
+    pub cooldown: Duration,
+}
+
+impl Default for CircuitBreakerPolicy {
+    fn default() -> Self {
+        Self {
+            failure_threshold: 5,
+            cooldown: Duration::from_secs(30),
+        }
+    }
+}
+
+#[derive(Debug, Default)]
+struct CircuitState {
+    failures: u32,
+    open_until: Option<Instant>,
+    /// A probe request is running after the cooldown.
+    half_open: bool,
+}
+
+/// A completion provider of the router.
+#[derive(Clone)]
+pub struct RouterProvider {
+    name: String,
+    completer: Arc<dyn CompletionFeaturesDyn>,
+    timeout: Option<Duration>,
+    state: Arc<Mutex<CircuitState>>,
+}
+
+impl RouterProvider {
+    /// Creates a new provider with a unique name in the router.
+    pub fn new(name: String, completer: Arc<dyn CompletionFeaturesDyn>) -> Self {
+        Self {
+            name,
+            completer,
+            timeout: None,
+            state: Arc::new(Mutex::new(CircuitState::default())),
+        }
+    }
+
+    /// Sets the timeout of every request to the provider.
+    pub fn with_timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = Some(timeout);
+        self
+    }
+
+    /// Returns the name of the provider.
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// Returns true if the circuit of the provider is open.
+    pub fn is_open(&self) -> bool {
+        let state = self.state.lock().expect("circuit state lock poisoned");
+        state.open_until.is_some_and(|until| Instant::now() < until)
+    }
+
+    /// Returns true if the request can be sent to the provider.
+    /// Once the cooldown is over, only one probe request is allowed in the next cooldown.
+    fn try_acquire(&self, policy: &CircuitBreakerPolicy) -> bool {
+        let mut state = self.state.lock().expect("circuit state lock poisoned");
+        let now = Instant::now();
+        match state.open_until {
+            None => true,
+            Some(until) if now < until => false,
+            Some(_) => {
+                state.half_open = true;
+                state.open_until = Some(now + policy.cooldown);
+                true
+            }
+        }
+    }
+
+    fn on_success(&self) {
+        let mut state = self.state.lock().expect("circuit state lock poisoned");
+        state.failures = 0;
+        state.open_until = None;
+        state.half_open = false;
+    }
+
+    fn on_failure(&self, policy: &CircuitBreakerPolicy) {
+        let mut state = self.state.lock().expect("circuit state lock poisoned");
+        state.failures = state.failures.saturating_add(1);
+        if state.half_open || state.failures >= policy.failure_threshold.max(1) {
+            state.open_until = Some(Instant::now() + policy.cooldown);
+            state.half_open = false;
+        }
+    }
+
+    /// The request failed by itself, the next request can probe the provider.
+    fn on_ignored(&self) {
+        let mut state = self.state.lock().expect("circuit state lock poisoned");
+        if state.half_open {
+            state.open_until = Some(Instant::now());
+            state.half_open = false;
+        }
+    }
+
+    async fn call<T>(
+        &self,
+        fut: impl Future<Output = Result<T, BoxError>>,
+    ) -> Result<T, AndaError> {
+        let res = match self.timeout {
+            Some(timeout) => match tokio::time::timeout(timeout, fut).await {
+                Ok(res) => res,
+                Err(_) => {
+                    return Err(AndaError::timeout(format!(
+                        "provider {} timeout after {}ms",
+                        self.name,
+                        timeout.as_millis()
+                    )));
+                }
+            },
+            None => fut.await,
+        };
+        res.map_err(AndaError::from)
+    }
+}
+
+/// A completion model that routes requests to several providers with fallback,
+/// retries, timeouts and circuit breakers.
+#[derive(Clone)]
+pub struct ModelRouter {
+    providers: Arc<BTreeMap<String, RouterProvider>>,
+    route: Vec<String>,
+    retry: RetryPolicy,
+    breaker: CircuitBreakerPolicy,
+}
+
+impl ModelRouter {
+    /// Creates a new router, the route is the order of the providers.
+    pub fn new(providers: Vec<RouterProvider>) -> Result<Self, BoxError> {
+        if providers.is_empty() {
+            return Err("model router requires at least one provider".into());
+        }
+
+        let route: Vec<String> = providers.iter().map(|p| p.name.clone()).collect();
+        let mut map = BTreeMap::new();
+        for provider in providers {
+            let name = provider.name.clone();
+            if map.insert(name.clone(), provider).is_some() {
+                return Err(format!("duplicate provider {} in model router", name).into());
+            }
+        }
+
+        Ok(Self {
+            providers: Arc::new(map),
+            route,
+            retry: RetryPolicy::default(),
+            breaker: CircuitBreakerPolicy::default(),
+        })
+    }
+
+    /// Returns a router with another route of the providers, e.g. for an agent.
+    /// The routers share the state of circuit breakers.
+    pub fn with_route(&self, route: Vec<String>) -> Result<Self, BoxError> {
+        if route.is_empty() {
+            return Err("model router requires at least one provider".into());
+        }
+        for name in &route {
+            if !self.providers.contains_key(name) {
+                return Err(format!("provider {} not found in model router", name).into());
+            }
+        }
+
+        Ok(Self {
+            providers: self.providers.clone(),
+            route,
+            retry: self.retry.clone(),
+            breaker: self.breaker.clone(),
+        })
+    }
+
+    /// Sets the retry policy.
+    pub fn with_retry(mut self, retry: RetryPolicy) -> Self {
+        self.retry = retry;
+        self
+    }
+
+    /// Sets the circuit breaker policy.
+    pub fn with_circuit_breaker(mut self, breaker: CircuitBreakerPolicy) -> Self {
+        self.breaker = breaker;
+        self
+    }
+
+    /// Returns the names of the providers in the route order.
+    pub fn route(&self) -> &[String] {
+        &self.route
+    }
+
+    fn providers(&self) -> impl Iterator<Item = &RouterProvider> {
+        self.route
+            .iter()
+            .filter_map(|name| self.providers.get(name))
+    }
+
+    /// Runs the request on the providers of the route.
+    async fn run<T, F, Fut>(
+        providers: Vec<RouterProvider>,
+        retry: RetryPolicy,
+        breaker: CircuitBreakerPolicy,
+        call: F,
+    ) -> Result<T, BoxError>
+    where
+        F: Fn(&RouterProvider) -> Fut,
+        Fut: Future<Output = Result<T, BoxError>>,
+    {
+        let mut last_err: Option<AndaError> = None;
+        'providers: for provider in &providers {
+            if !provider.try_acquire(&breaker) {
+                log::warn!(
+                    "model router: skip provider {}, circuit is open",
+                    provider.name
+                );
+                continue;
+            }
+
+            let mut attempt = 0;
+            let err = loop {
+                match provider.call(call(provider)).await {
+                    Ok(res) => {
+                        provider.on_success();
+                        return Ok(res);
+                    }
+                    Err(err) => {
+                        // the request itself is invalid, other providers will not help
+                        if matches!(err.code, ErrorCode::Unauthenticated | ErrorCode::Cancelled) {
+                            provider.on_ignored();
+                            return Err(err.into());
+                        }
+                        // rejected by the provider, e.g. unsupported tools or inputs
+                        if err.code == ErrorCode::InvalidArgs {
+                            provider.on_ignored();
+                            log::warn!(
+                                "model router: provider {} rejected the request: {}",
+                                provider.name,
+                                err
+                            );
+                            last_err = Some(err);
+                            continue 'providers;
+                        }
+
+                        log::warn!(
+                            "model router: provider {} failed, attempt {}: {}",
+                            provider.name,
+                            attempt,
+                            err
+                        );
+                        if !err.retryable() || attempt >= retry.max_retries {
+                            break err;
+                        }
+                        tokio::time::sleep(retry.backoff(attempt)).await;
+                        attempt += 1;
+                    }
+                }
+            };
+            // the retries of a request are counted as one failure
+            provider.on_failure(&breaker);
+            last_err = Some(err);
+        }
+
+        Err(last_err
+            .unwrap_or_else(|| {
+                AndaError::unavailable("all providers of model router are unavailable")
+            })
+            .into())
+    }
+}
+
+impl CompletionFeaturesDyn for ModelRouter {
+    fn completion(&self, req: CompletionRequest) -> BoxPinFut<Result<AgentOutput, BoxError>> {
+        let providers: Vec<RouterProvider> = self.providers().cloned().collect();
+        let retry = self.retry.clone();
+        let breaker = self.breaker.clone();
+        Box::pin(async move {
+            Self::run(providers, retry, breaker, |provider| {
+                let model = provider.completer.model_name();
+                let res = provider.completer.completion(req.clone());
+                async move {
+                    let mut output = res.await?;
+                    output.model.get_or_insert(model);
+                    Ok::<_, BoxError>(output)
+                }
+            })
+            .await
+        })
+    }
+
+    /// Falls back to the next provider only if the stream can not be opened,
+    /// errors in the opened stream are not retried.
+    fn completion_stream(
+        &self,
+        req: CompletionRequest,
+    ) -> BoxPinFut<Result<CompletionStream, BoxError>> {
+        let providers: Vec<RouterProvider> = self.providers().cloned().collect();
+        let retry = self.retry.clone();
+        let breaker = self.breaker.clone();
+        Box::pin(async move {
+            Self::run(providers, retry, breaker, |provider| {
+                let model = provider.completer.model_name();
+                let res = provider.completer.completion_stream(req.clone());
+                async move {
+                    let stream = res.await?;
+                    let stream = stream.map(move |chunk| match chunk {
+                        Ok(CompletionChunk::Done(mut output)) => {
+                            output.model.get_or_insert_with(|| model.clone());
+                            Ok(CompletionChunk::Done(output))
+                        }
+                        chunk => chunk,
+                    });
+                    Ok::<_, BoxError>(stream.boxed())
+                }
+            })
+            .await
+        })
+    }
+
+    /// Returns the smallest context window of the providers in the route.
+    fn context_window(&self) -> Option<usize> {
+        self.providers()
+            .filter_map(|provider| provider.completer.context_window())
+            .min()
+    }
+
+    /// Returns the model name of the first provider in the route,
+    /// the output reports the model that served it.
+    fn model_name(&self) -> String {
+        self.providers()
+            .next()
+            .map(|provider| provider.completer.model_name())
+            .unwrap_or_default()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    struct FlakyModel {
+        name: String,
+        errors: Vec<AndaError>,
+        calls: Arc<AtomicUsize>,
+        delay: Duration,
+    }
+
+    impl CompletionFeaturesDyn for FlakyModel {
+        fn completion(&self, _req: CompletionRequest) -> BoxPinFut<Result<AgentOutput, BoxError>> {
+            let n = self.calls.fetch_add(1, Ordering::SeqCst);
+            let res = match self.errors.get(n) {
+                Some(err) => Err(err.clone().into()),
+                None => Ok(AgentOutput {
+                    content: self.name.clone(),
+                    ..Default::default()
+                }),
+            };
+            let delay = self.delay;
+            Box::pin(async move {
+                tokio::time::sleep(delay).await;
+                res
+            })
+        }
+
+        fn model_name(&self) -> String {
+            self.name.clone()
+        }
+    }
+
+    fn provider(name: &str, errors: Vec<AndaError>) -> (RouterProvider, Arc<AtomicUsize>) {
+        let calls = Arc::new(AtomicUsize::new(0));
+        let model = FlakyModel {
+            name: name.to_string(),
+            errors,
+            calls: calls.clone(),
+            delay: Duration::ZERO,
+        };
+        (
+            RouterProvider::new(name.to_string(), Arc::new(model)),
+            calls,
+        )
+    }
+
+    fn retry() -> RetryPolicy {
+        RetryPolicy {
+            max_retries: 2,
+            initial_backoff: Duration::from_millis(1),
+            max_backoff: Duration::from_millis(4),
+        }
+    }
+
+    #[tokio::test(flavor = "current_thread")]
+    async fn test_model_router() {
+        // retries on retryable errors
+        let (p1, c1) = provider(
+            "p1",
+            vec![
+                AndaError::rate_limited("429"),
+                AndaError::unavailable("503"),
+            ],
+        );
+        let (p2, c2) = provider("p2", vec![]);
+        let router = ModelRouter::new(vec![p1, p2]).unwrap().with_retry(retry());
+        assert_eq!(router.model_name(), "p1");
+        let output = router
+            .completion(CompletionRequest::default())
+            .await
+            .unwrap();
+        assert_eq!(output.content, "p1");
+        assert_eq!(c1.load(Ordering::SeqCst), 3);
+        assert_eq!(c2.load(Ordering::SeqCst), 0);
+
+        // falls back on non-retryable errors and exhausted retries
+        let (p1, c1) = provider("p1", vec![AndaError::new(ErrorCode::Upstream, "401")]);
+        let (p2, c2) = provider("p2", vec![AndaError::timeout("t"); 3]);
+        let (p3, c3) = provider("p3", vec![]);
+        let router = ModelRouter::new(vec![p1, p2, p3])
+            .unwrap()
+            .with_retry(retry());
+        let output = router
+            .completion(CompletionRequest::default())
+            .await
+            .unwrap();
+        assert_eq!(output.content, "p3");
+        // the model that served the request
+        assert_eq!(output.model.as_deref(), Some("p3"));
+        assert_eq!(c1.load(Ordering::SeqCst), 1);
+        assert_eq!(c2.load(Ordering::SeqCst), 3);
+        assert_eq!(c3.load(Ordering::SeqCst), 1);
+
+        // per-route
+        let router = router.with_route(vec!["p3".to_string()]).unwrap();
+        let output = router
+            .completion(CompletionRequest::default())
+            .await
+            .unwrap();
+        assert_eq!(output.content, "p3");
+        assert!(router.with_route(vec!["p4".to_string()]).is_err());
+
+        // rejected requests are not retried but fall back
+        let (p1, c1) = provider("p1", vec![AndaError::invalid_args("tools not supported")]);
+        let (p2, c2) = provider("p2", vec![]);
+        let router = ModelRouter::new(vec![p1, p2]).unwrap().with_retry(retry());
+        let output = router
+            .completion(CompletionRequest::default())
+            .await
+            .unwrap();
+        assert_eq!(output.model.as_deref(), Some("p2"));
+        assert_eq!(c1.load(Ordering::SeqCst), 1);
+        assert_eq!(c2.load(Ordering::SeqCst), 1);
+        assert!(!router.providers.get("p1").unwrap().is_open());
+
+        let (p1, _) = provider("p1", vec![AndaError::invalid_args("bad request")]);
+        let router = ModelRouter::new(vec![p1]).unwrap().with_retry(retry());
+        let err = router
+            .completion(CompletionRequest::default())
+            .await
+            .unwrap_err();
+        assert_eq!(AndaError::from(err).code, ErrorCode::InvalidArgs);
+    }
+
+    #[tokio::test(flavor = "current_thread")]
+    async fn test_model_router_timeout_and_circuit_breaker() {
+        let calls = Arc::new(AtomicUsize::new(0));
+        let slow = RouterProvider::new(
+            "slow".to_string(),
+            Arc::new(FlakyModel {
+                name: "slow".to_string(),
+                errors: vec![],
+                calls: calls.clone(),
+                delay: Duration::from_millis(200),
+            }),
+        )
+        .with_timeout(Duration::from_millis(10));
+        let (p2, c2) = provider("p2", vec![]);
+        let router = ModelRouter::new(vec![slow, p2])
+            .unwrap()
+            .with_retry(RetryPolicy {
+                max_retries: 0,
+                ..retry()
+            })
+            .with_circuit_breaker(CircuitBreakerPolicy {
+                failure_threshold: 2,
+                cooldown: Duration::from_secs(60),
+            });
+
+        for _ in 0..3 {
+            let output = router
+                .completion(CompletionRequest::default())
+                .await
+                .unwrap();
+            assert_eq!(output.content, "p2");
+        }
+        // the circuit is open after 2 failures
+        assert_eq!(calls.load(Ordering::SeqCst), 2);
+        assert_eq!(c2.load(Ordering::SeqCst), 3);
+        assert!(router.providers.get("slow").unwrap().is_open());
+
+        // all providers are unavailable
+        let router = router.with_route(vec!["slow".to_string()]).unwrap();
+        let err = router
+            .completion(CompletionRequest::default())
+            .await
+            .unwrap_err();
+        assert_eq!(AndaError::from(err).code, ErrorCode::Unavailable);
+    }
+
+    #[tokio::test(flavor = "current_thread")]
+    async fn test_circuit_breaker_half_open() {
+        let (p1, c1) = provider(
+            "p1",
+            vec![
+                AndaError::unavailable("503"),
+                AndaError::unavailable("503"),
+                AndaError::unauthenticated("401"),
+                AndaError::unavailable("503"),
+                AndaError::unavailable("503"),
+                AndaError::unavailable("503"),
+                AndaError::unavailable("503"),
+            ],
+        );
+        let (p2, _) = provider("p2", vec![]);
+        let router = ModelRouter::new(vec![p1, p2])
+            .unwrap()
+            .with_retry(RetryPolicy {
+                max_retries: 1,
+                ..retry()
+            })
+            .with_circuit_breaker(CircuitBreakerPolicy {
+                failure_threshold: 2,
+                cooldown: Duration::from_millis(30),
+            });
+        let p1 = router.providers.get("p1").unwrap().clone();
+        let complete = || router.completion(CompletionRequest::default());
+
+        // the retries of a request are one failure
+        assert_eq!(complete().await.unwrap().content, "p2");
+        assert_eq!(c1.load(Ordering::SeqCst), 2);
+        assert!(!p1.is_open());
+
+        // errors of the request itself are not counted
+        let err = complete().await.unwrap_err();
+        assert_eq!(AndaError::from(err).code, ErrorCode::Unauthenticated);
+        assert!(!p1.is_open());
+
+        assert_eq!(complete().await.unwrap().content, "p2");
+        assert_eq!(c1.load(Ordering::SeqCst), 5);
+        assert!(p1.is_open());
+        assert_eq!(complete().await.unwrap().content, "p2");
+        assert_eq!(c1.load(Ordering::SeqCst), 5);
+
+        // a failed probe opens the circuit again
+        tokio::time::sleep(Duration::from_millis(40)).await;
+        assert_eq!(complete().await.unwrap().content, "p2");
+        assert_eq!(c1.load(Ordering::SeqCst), 7);
+        assert!(p1.is_open());
+
+        // a successful probe closes the circuit
+        tokio::time::sleep(Duration::from_millis(40)).await;
+        assert_eq!(complete().await.unwrap().content, "p1");
+        assert_eq!(c1.load(Ordering::SeqCst), 8);
+        assert!(!p1.is_open());
+    }
+}
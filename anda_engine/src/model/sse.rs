@@ -0,0 +1,463 @@
+//! Server-Sent Events parser for OpenAI compatible chat completions streams.
+//!
+//! OpenAI, DeepSeek and Grok share the same streaming format: every event is a
+//! `data: {...}` line with a `chat.completion.chunk` object, and the stream is
+//! terminated by `data: [DONE]`. The parser turns these events into
+//! [`CompletionChunk`]s and assembles the final [`AgentOutput`] at the end.
+//! A stream that ends with neither `data: [DONE]` nor a `finish_reason` is an error.
+
+use anda_core::{
+    AgentOutput, BoxError, CompletionChunk, CompletionStream, ReasoningMode, ToolCall,
+    ToolCallDelta, Usage as ModelUsage,
+};
+use bytes::Bytes;
+use futures::stream::{self, BoxStream, StreamExt};
+use serde::Deserialize;
+use serde_json::{Value, json};
+use std::collections::VecDeque;
+
+/// Streaming chunk object of the chat completions API
+#[derive(Debug, Deserialize)]
+struct StreamResponse {
+    #[serde(default)]
+    choices: Vec<StreamChoice>,
+    usage: Option<StreamUsage>,
+    error: Option<Value>,
+}
+
+#[derive(Debug, Deserialize)]
+struct StreamChoice {
+    delta: Option<StreamDelta>,
+    finish_reason: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+struct StreamDelta {
+    content: Option<String>,
+    refusal: Option<String>,
+    tool_calls: Option<Vec<StreamToolCall>>,
+    // the reasoning content of DeepSeek reasoner and Grok
+    reasoning_content: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+struct StreamToolCall {
+    #[serde(default)]
+    index: usize,
+    id: Option<String>,
+    function: Option<StreamFunction>,
+}
+
+#[derive(Debug, Deserialize)]
+struct StreamFunction {
+    name: Option<String>,
+    arguments: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+struct StreamUsage {
+    #[serde(default)]
+    prompt_tokens: usize,
+    #[serde(default)]
+    completion_tokens: usize,
+    // the cached prompt tokens of OpenAI
+    prompt_tokens_details: Option<StreamTokensDetails>,
+    // the cached prompt tokens of DeepSeek
+    #[serde(default)]
+    prompt_cache_hit_tokens: usize,
+    completion_tokens_details: Option<StreamTokensDetails>,
+}
+
+#[derive(Debug, Deserialize)]
+struct StreamTokensDetails {
+    #[serde(default)]
+    cached_tokens: usize,
+    #[serde(default)]
+    reasoning_tokens: usize,
+}
+
+struct StreamState {
+    provider: &'static str,
+    body: BoxStream<'static, reqwest::Result<Bytes>>,
+    buf: Vec<u8>,
+    pending: VecDeque<Result<CompletionChunk, BoxError>>,
+    full_history: Vec<Value>,
+    reasoning_mode: ReasoningMode,
+    content: String,
+    reasoning: String,
+    refusal: Option<String>,
+    tool_calls: Vec<ToolCallDelta>,
+    finish_reason: Option<String>,
+    usage: Option<ModelUsage>,
+    ended: bool,
+    finished: bool,
+}
+
+impl StreamState {
+    fn on_line(&mut self, line: &[u8]) {
+        let line = String::from_utf8_lossy(line);
+        let line = line.trim();
+        // ignore empty lines, comments (e.g. ": keep-alive") and other fields
+        let data = match line.strip_prefix("data:") {
+            Some(data) => data.trim_start(),
+            None => return,
+        };
+
+        if data == "[DONE]" {
+            self.ended = true;
+            return;
+        }
+
+        let res: StreamResponse = match serde_json::from_str(data) {
+            Ok(res) => res,
+            Err(err) => {
+                self.fail(format!(
+                    "{} completions stream error: {}, data: {}",
+                    self.provider, err, data
+                ));
+                return;
+            }
+        };
+
+        if let Some(err) = res.error {
+            self.fail(format!(
+                "{} completions stream error: {}",
+                self.provider, err
+            ));
+            return;
+        }
+
+        for choice in res.choices {
+            if let Some(delta) = choice.delta {
+                if let Some(content) = delta.content {
+                    if !content.is_empty() {
+                        self.content.push_str(&content);
+                        self.pending.push_back(Ok(CompletionChunk::Text(content)));
+                    }
+                }
+
+                if let Some(reasoning) = delta.reasoning_content {
+                    self.reasoning.push_str(&reasoning);
+                }
+
+                if let Some(refusal) = delta.refusal {
+                    self.refusal.get_or_insert_default().push_str(&refusal);
+                }
+
+                for tc in delta.tool_calls.unwrap_or_default() {
+                    let (name, args) = match tc.function {
+                        Some(f) => (f.name, f.arguments.unwrap_or_default()),
+                        None => (None, String::new()),
+                    };
+
+                    match self.tool_calls.iter_mut().find(|t| t.index == tc.index) {
+                        Some(call) => {
+                            if let Some(id) = &tc.id {
+                                call.id = Some(id.clone());
+                            }
+                            if call.name.is_none() {
+                                call.name = name.clone();
+                            }
+                            call.args.push_str(&args);
+                        }
+                        None => self.tool_calls.push(ToolCallDelta {
+                            index: tc.index,
+                            id: tc.id.clone(),
+                            name: name.clone(),
+                            args: args.clone(),
+                        }),
+                    }
+
+                    self.pending
+                        .push_back(Ok(CompletionChunk::ToolCall(ToolCallDelta {
+                            index: tc.index,
+                            id: tc.id,
+                            name,
+                            args,
+                        })));
+                }
+            }
+
+            if let Some(reason) = choice.finish_reason {
+                self.finish_reason = Some(reason);
+            }
+        }
+
+        if let Some(usage) = res.usage {
+            self.usage = Some(ModelUsage {
+                input_tokens: usage.prompt_tokens as u64,
+                output_tokens: usage.completion_tokens as u64,
+                requests: 1,
+                cached_input_tokens: usage
+                    .prompt_tokens_details
+                    .map_or(usage.prompt_cache_hit_tokens, |d| d.cached_tokens)
+                    as u64,
+                reasoning_tokens: usage
+                    .completion_tokens_details
+                    .map_or(0, |d| d.reasoning_tokens) as u64,
+            });
+        }
+    }
+
+    fn fail(&mut self, err: String) {
+        self.pending.push_back(Err(err.into()));
+        self.finished = true;
+    }
+
+    /// Assembles the final output, the same as the non-streaming completion.
+    fn finish(&mut self) {
+        self.finished = true;
+        self.tool_calls.sort_by_key(|t| t.index);
+        let tool_calls: Vec<ToolCall> = self
+            .tool_calls
+            .drain(..)
+            .map(|t| ToolCall {
+                id: t.id.unwrap_or_default(),
+                name: t.name.unwrap_or_default(),
+                args: t.args,
+                result: None,
+            })
+            .collect();
+
+        let mut message = json!({
+            "role": "assistant",
+            "content": self.content,
+        });
+        let reasoning = std::mem::take(&mut self.reasoning);
+        if !reasoning.is_empty() {
+            message["reasoning_content"] = json!(reasoning);
+        }
+        if !tool_calls.is_empty() {
+            message["tool_calls"] = json!(
+                tool_calls
+                    .iter()
+                    .map(|tc| json!({
+                        "id": tc.id,
+                        "type": "function",
+                        "function": {
+                            "name": tc.name,
+                            "arguments": tc.args,
+                        },
+                    }))
+                    .collect::<Vec<_>>()
+            );
+        }
+
+        let mut full_history = std::mem::take(&mut self.full_history);
+        full_history.push(message);
+        let usage = self.usage.take().unwrap_or_default();
+        let mut output = AgentOutput {
+            content: std::mem::take(&mut self.content),
+            tool_calls: if tool_calls.is_empty() {
+                None
+            } else {
+                Some(tool_calls)
+            },
+            full_history: Some(full_history),
+            reasoning: if reasoning.is_empty() {
+                None
+            } else {
+                Some(reasoning)
+            },
+            usage: usage.clone(),
+            ..Default::default()
+        }
+        .with_reasoning_mode(self.reasoning_mode);
+
+        if let Some(reason) = self.finish_reason.take() {
+            if !matches!(reason.as_str(), "stop" | "tool_calls") {
+                output.failed_reason = Some(reason);
+            }
+        }
+        if let Some(refusal) = self.refusal.take() {
+            output.failed_reason = Some(refusal);
+        }
+
+        self.pending.push_back(Ok(CompletionChunk::Usage(usage)));
+        self.pending.push_back(Ok(CompletionChunk::Done(output)));
+    }
+}
+
+/// Parses a successful chat completions SSE response into a [`CompletionStream`].
+///
+/// # Arguments
+/// * `provider` - Provider name used in error messages;
+/// * `response` - The HTTP response with `text/event-stream` body;
+/// * `full_history` - The messages sent to the model, the assistant message will be appended to it;
+/// * `reasoning_mode` - How to handle the reasoning content of the output.
+pub(crate) fn completion_stream(
+    provider: &'static str,
+    response: reqwest::Response,
+    full_history: Vec<Value>,
+    reasoning_mode: ReasoningMode,
+) -> CompletionStream {
+    let state = StreamState {
+        provider,
+        body: response.bytes_stream().boxed(),
+        buf: Vec::new(),
+        pending: VecDeque::new(),
+        full_history,
+        reasoning_mode,
+        content: String::new(),
+        reasoning: String::new(),
+        refusal: None,
+        tool_calls: Vec::new(),
+        finish_reason: None,
+        usage: None,
+        ended: false,
+        finished: false,
+    };
+
+    stream::unfold(state, |mut state| async move {
+        loop {
+            if let Some(chunk) = state.pending.pop_front() {
+                return Some((chunk, state));
+            }
+
+            if state.finished {
+                return None;
+            }
+
+            if state.ended {
+                state.finish();
+                continue;
+            }
+
+            if let Some(pos) = state.buf.iter().position(|b| *b == b'\n') {
+                let line: Vec<u8> = state.buf.drain(..=pos).collect();
+                state.on_line(&line);
+                continue;
+            }
+
+            match state.body.next().await {
+                Some(Ok(bytes)) => state.buf.extend_from_slice(&bytes),
+                Some(Err(err)) => {
+                    let err = format!("{} completions stream error: {}", state.provider, err);
+                    state.fail(err);
+                }
+                None => {
+                    let line = std::mem::take(&mut state.buf);
+                    state.on_line(&line);
+                    if state.finished || state.ended {
+                        continue;
+                    }
+                    // a dropped connection should not look like a complete answer
+                    if state.finish_reason.is_none() {
+                        let err = format!(
+                            "{} completions stream ended unexpectedly before completion",
+                            state.provider
+                        );
+                        state.fail(err);
+                    } else {
+                        state.ended = true;
+                    }
+                }
+            }
+        }
+    })
+    .boxed()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test(flavor = "current_thread")]
+    async fn test_completion_stream() {
+        let body = [
+            r#"data: {"id":"1","choices":[{"index":0,"delta":{"role":"assistant","reasoning_content":"Think"},"finish_reason":null}]}"#,
+            "",
+            r#"data: {"id":"1","choices":[{"index":0,"delta":{"content":"Hel"},"finish_reason":null}]}"#,
+            "",
+            ": keep-alive",
+            "",
+            r#"data: {"id":"1","choices":[{"index":0,"delta":{"content":"lo"},"finish_reason":null}]}"#,
+            "",
+            r#"data: {"id":"1","choices":[{"index":0,"delta":{"tool_calls":[{"index":0,"id":"call_1","type":"function","function":{"name":"tool_1","arguments":"{\"a\":"}}]},"finish_reason":null}]}"#,
+            "",
+            r#"data: {"id":"1","choices":[{"index":0,"delta":{"tool_calls":[{"index":0,"function":{"arguments":"1}"}}]},"finish_reason":"tool_calls"}]}"#,
+            "",
+            r#"data: {"id":"1","choices":[],"usage":{"prompt_tokens":10,"completion_tokens":5,"total_tokens":15,"prompt_tokens_details":{"cached_tokens":4},"completion_tokens_details":{"reasoning_tokens":2}}}"#,
+            "",
+            "data: [DONE]",
+            "",
+        ]
+        .join("\n");
+        let response = reqwest::Response::from(http::Response::new(body));
+        let chunks: Vec<CompletionChunk> =
+            completion_stream("Test", response, vec![], ReasoningMode::Return)
+                .map(|c| c.unwrap())
+                .collect()
+                .await;
+        assert_eq!(chunks.len(), 6);
+        assert!(matches!(&chunks[0], CompletionChunk::Text(text) if text == "Hel"));
+        assert!(matches!(&chunks[1], CompletionChunk::Text(text) if text == "lo"));
+        assert!(
+            matches!(&chunks[2], CompletionChunk::ToolCall(tc) if tc.name.as_deref() == Some("tool_1"))
+        );
+        assert!(matches!(&chunks[3], CompletionChunk::ToolCall(tc) if tc.args == "1}"));
+        assert!(matches!(&chunks[4], CompletionChunk::Usage(u) if u.input_tokens == 10));
+        match &chunks[5] {
+            CompletionChunk::Done(output) => {
+                assert_eq!(output.content, "Hello");
+                assert!(output.failed_reason.is_none());
+                assert_eq!(output.usage.output_tokens, 5);
+                assert_eq!(output.usage.cached_input_tokens, 4);
+                assert_eq!(output.usage.reasoning_tokens, 2);
+                let tool_calls = output.tool_calls.as_ref().unwrap();
+                assert_eq!(tool_calls[0].id, "call_1");
+                assert_eq!(tool_calls[0].args, r#"{"a":1}"#);
+                assert_eq!(output.full_history.as_ref().unwrap().len(), 1);
+                assert_eq!(output.reasoning.as_deref(), Some("Think"));
+                assert!(
+                    output.full_history.as_ref().unwrap()[0]
+                        .get("reasoning_content")
+                        .is_none()
+                );
+            }
+            _ => panic!("expected Done chunk"),
+        }
+
+        let body = "data: {\"error\":{\"message\":\"boom\"}}\n\n";
+        let response = reqwest::Response::from(http::Response::new(body.to_string()));
+        let chunks: Vec<Result<CompletionChunk, BoxError>> =
+            completion_stream("Test", response, vec![], ReasoningMode::Omit)
+                .collect()
+                .await;
+        assert_eq!(chunks.len(), 1);
+        assert!(chunks[0].is_err());
+
+        // the body ends without [DONE] or finish_reason
+        let body = [
+            r#"data: {"id":"1","choices":[{"index":0,"delta":{"content":"Hel"},"finish_reason":null}]}"#,
+            "",
+        ]
+        .join("\n");
+        let response = reqwest::Response::from(http::Response::new(body));
+        let chunks: Vec<Result<CompletionChunk, BoxError>> =
+            completion_stream("Test", response, vec![], ReasoningMode::Omit)
+                .collect()
+                .await;
+        assert_eq!(chunks.len(), 2);
+        assert!(matches!(&chunks[0], Ok(CompletionChunk::Text(text)) if text == "Hel"));
+        assert!(
+            chunks[1]
+                .as_ref()
+                .unwrap_err()
+                .to_string()
+                .contains("ended unexpectedly")
+        );
+
+        // the body ends with finish_reason but without [DONE]
+        let body = r#"data: {"id":"1","choices":[{"index":0,"delta":{"content":"Hi"},"finish_reason":"stop"}]}"#;
+        let response = reqwest::Response::from(http::Response::new(body.to_string()));
+        let chunks: Vec<CompletionChunk> =
+            completion_stream("Test", response, vec![], ReasoningMode::Omit)
+                .map(|c| c.unwrap())
+                .collect()
+                .await;
+        assert!(
+            matches!(chunks.last(), Some(CompletionChunk::Done(output)) if output.content == "Hi")
+        );
+    }
+}
@@ -6,15 +6,18 @@
 //! - Response parsing and conversion to Anda's internal formats
 
 use anda_core::{
-    AgentOutput, BoxError, BoxPinFut, CONTENT_TYPE_JSON, CompletionRequest, FunctionDefinition,
-    Message, ToolCall,
+    AgentOutput, AndaError, BoxError, BoxPinFut, CONTENT_TYPE_JSON, CompletionRequest,
+    CompletionStream, FunctionDefinition, Message, ToolCall,
 };
 use log::{Level::Debug, log_enabled};
 use serde::{Deserialize, Serialize};
 use serde_json::{Value, json};
 use std::time::Duration;
 
-use super::CompletionFeaturesDyn;
+use super::{
+    CompletionFeaturesDyn, context_window_of, openai_content_parts, sse::completion_stream,
+    without_reasoning,
+};
 use crate::APP_USER_AGENT;
 
 // ================================================================
@@ -132,6 +135,7 @@ impl CompletionResponse {
         full_history.push(json!(choice.message));
         let mut output = AgentOutput {
             content: choice.message.content.unwrap_or_default(),
+            reasoning: choice.message.reasoning_content,
             tool_calls: choice.message.tool_calls.map(|tools| {
                 tools
                     .into_iter()
@@ -174,6 +178,9 @@ pub struct MessageOutput {
     pub content: Option<String>,
     pub refusal: Option<String>,
     pub tool_calls: Option<Vec<ToolCallOutput>>,
+    /// The reasoning content of `grok-3-mini`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub reasoning_content: Option<String>,
 }
 
 /// Tool call output structure from Grok API
@@ -193,6 +200,7 @@ pub struct ToolDefinition {
 impl From<FunctionDefinition> for ToolDefinition {
     fn from(mut f: FunctionDefinition) -> Self {
         f.strict = None; // Grok does not support strict mode
+        f.output_schema = None; // the output schema is not a part of the function calling API
         Self {
             r#type: "function".into(),
             function: f,
@@ -227,94 +235,101 @@ impl CompletionModel {
             model: model.to_string(),
         }
     }
-}
 
-impl CompletionFeaturesDyn for CompletionModel {
-    fn completion(&self, mut req: CompletionRequest) -> BoxPinFut<Result<AgentOutput, BoxError>> {
-        let model = self.model.clone();
-        let client = self.client.clone();
+    /// Builds the chat completions request body, returns the messages sent to the model
+    /// and the JSON body.
+    fn request_body(&self, mut req: CompletionRequest) -> (Vec<Value>, Value) {
+        // Add system to chat history (if available)
+        let mut full_history = if let Some(system) = &req.system {
+            vec![json!(Message {
+                role: "system".into(),
+                content: system.to_owned().into(),
+                name: req.system_name.clone(),
+                ..Default::default()
+            })]
+        } else {
+            vec![]
+        };
 
-        Box::pin(async move {
-            // Add system to chat history (if available)
-            let mut full_history = if let Some(system) = &req.system {
-                vec![json!(Message {
-                    role: "system".into(),
-                    content: system.to_owned().into(),
-                    name: req.system_name.clone(),
-                    ..Default::default()
-                })]
-            } else {
-                vec![]
-            };
-
-            // Extend existing chat history
-            full_history.append(&mut req.chat_history);
-
-            if !req.content_parts.is_empty() {
-                full_history.push(json!(Message {
-                    role: "user".into(),
-                    content: json!(req.content_parts),
-                    name: req.prompter_name,
-                    ..Default::default()
-                }));
-            } else if let Some(prompt) = req.prompt_with_context() {
-                full_history.push(json!(Message {
-                    role: "user".into(),
-                    content: prompt.into(),
-                    name: req.prompter_name,
-                    ..Default::default()
-                }));
-            }
+        // Extend existing chat history
+        full_history.append(&mut req.chat_history);
+
+        if !req.content_parts.is_empty() {
+            full_history.push(json!(Message {
+                role: "user".into(),
+                content: openai_content_parts(&req.content_parts),
+                name: req.prompter_name,
+                ..Default::default()
+            }));
+        } else if let Some(prompt) = req.prompt_with_context() {
+            full_history.push(json!(Message {
+                role: "user".into(),
+                content: prompt.into(),
+                name: req.prompter_name,
+                ..Default::default()
+            }));
+        }
 
-            let mut body = json!({
-                "model": model,
-                "messages": full_history.clone(),
-            });
+        let mut body = json!({
+            "model": self.model,
+            "messages": without_reasoning(&full_history),
+        });
 
-            let body = body.as_object_mut().unwrap();
-            if let Some(temperature) = req.temperature {
-                body.insert("temperature".to_string(), Value::from(temperature));
-            }
+        let obj = body.as_object_mut().unwrap();
+        if let Some(temperature) = req.temperature {
+            obj.insert("temperature".to_string(), Value::from(temperature));
+        }
 
-            if let Some(max_tokens) = req.max_tokens {
-                body.insert("max_tokens".to_string(), Value::from(max_tokens));
-            }
+        if let Some(max_tokens) = req.max_tokens {
+            obj.insert("max_tokens".to_string(), Value::from(max_tokens));
+        }
 
-            if let Some(response_format) = req.response_format {
-                body.insert("response_format".to_string(), response_format);
-            }
+        if let Some(response_format) = req.response_format {
+            obj.insert("response_format".to_string(), response_format);
+        }
 
-            if let Some(stop) = req.stop {
-                body.insert("stop".to_string(), Value::from(stop));
-            }
+        if let Some(stop) = req.stop {
+            obj.insert("stop".to_string(), Value::from(stop));
+        }
+
+        if !req.tools.is_empty() {
+            obj.insert(
+                "tools".to_string(),
+                json!(
+                    req.tools
+                        .into_iter()
+                        .map(ToolDefinition::from)
+                        .collect::<Vec<_>>()
+                ),
+            );
+            obj.insert(
+                "tool_choice".to_string(),
+                if req.tool_choice_required {
+                    Value::from("required")
+                } else {
+                    Value::from("auto")
+                },
+            );
+        };
+
+        (full_history, body)
+    }
+}
 
-            if !req.tools.is_empty() {
-                body.insert(
-                    "tools".to_string(),
-                    json!(
-                        req.tools
-                            .into_iter()
-                            .map(ToolDefinition::from)
-                            .collect::<Vec<_>>()
-                    ),
-                );
-                body.insert(
-                    "tool_choice".to_string(),
-                    if req.tool_choice_required {
-                        Value::from("required")
-                    } else {
-                        Value::from("auto")
-                    },
-                );
-            };
+impl CompletionFeaturesDyn for CompletionModel {
+    fn completion(&self, req: CompletionRequest) -> BoxPinFut<Result<AgentOutput, BoxError>> {
+        let reasoning = req.reasoning;
+        let (full_history, body) = self.request_body(req);
+        let client = self.client.clone();
 
+        Box::pin(async move {
             if log_enabled!(Debug) {
                 if let Ok(val) = serde_json::to_string(&body) {
                     log::debug!(request = val; "Grok completions request");
                 }
             }
 
-            let response = client.post("/chat/completions").json(body).send().await?;
+            let response = client.post("/chat/completions").json(&body).send().await?;
             if response.status().is_success() {
                 let text = response.text().await?;
                 match serde_json::from_str::<CompletionResponse>(&text) {
@@ -325,14 +340,65 @@ impl CompletionFeaturesDyn for CompletionModel {
                             }
                         }
                         res.try_into(full_history)
+                            .map(|output| output.with_reasoning_mode(reasoning))
                     }
                     Err(err) => {
                         Err(format!("Grok completions error: {}, body: {}", err, text).into())
                     }
                 }
             } else {
+                let status = response.status().as_u16();
+                let msg = response.text().await?;
+                Err(
+                    AndaError::from_status(status, format!("Grok completions error: {}", msg))
+                        .into(),
+                )
+            }
+        })
+    }
+
+    fn context_window(&self) -> Option<usize> {
+        context_window_of(&self.model)
+    }
+
+    fn model_name(&self) -> String {
+        self.model.clone()
+    }
+
+    fn completion_stream(
+        &self,
+        req: CompletionRequest,
+    ) -> BoxPinFut<Result<CompletionStream, BoxError>> {
+        let reasoning = req.reasoning;
+        let (full_history, mut body) = self.request_body(req);
+        let client = self.client.clone();
+
+        Box::pin(async move {
+            let obj = body.as_object_mut().unwrap();
+            obj.insert("stream".to_string(), Value::from(true));
+            obj.insert("stream_options".to_string(), json!({"include_usage": true}));
+
+            if log_enabled!(Debug) {
+                if let Ok(val) = serde_json::to_string(&body) {
+                    log::debug!(request = val; "Grok completions stream request");
+                }
+            }
+
+            let response = client
+                .post("/chat/completions")
+                .header(http::header::ACCEPT, "text/event-stream")
+                .json(&body)
+                .send()
+                .await?;
+            if response.status().is_success() {
+                Ok(completion_stream("Grok", response, full_history, reasoning))
+            } else {
+                let status = response.status().as_u16();
                 let msg = response.text().await?;
-                Err(format!("Grok completions error: {}", msg).into())
+                Err(
+                    AndaError::from_status(status, format!("Grok completions error: {}", msg))
+                        .into(),
+                )
             }
         })
     }
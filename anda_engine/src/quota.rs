@@ -0,0 +1,432 @@
+//! Per-caller rate limits and usage quotas for the engine.
+//!
+//! The limits are set for the engine (agent runs and tool calls) and for every agent.
+//! The counters are kept in memory and saved to the [`Store`](crate::store::Store) under
+//! [`SYSTEM_PATH`] every [`FLUSH_INTERVAL`] and when the engine is cancelled, so they survive
+//! restarts. The requests counted since the last save are lost if the process crashes.
+//!
+//! All anonymous clients are the same [`ANONYMOUS`] caller and share one counter per scope,
+//! so one anonymous client can use up the limits of all of them.
+//! Set [`Limits::anonymous`] to limit them separately from the other callers.
+//!
+//! # Example
+//! ```rust,ignore
+//! let limits = Limits {
+//!     engine: CallerLimits {
+//!         rate_limit: Some(RateLimit { max_requests: 60, window_secs: 60 }),
+//!         daily_quota: Some(UsageQuota { max_input_tokens: Some(1_000_000), max_output_tokens: None }),
+//!     },
+//!     agents: BTreeMap::new(),
+//!     anonymous: None,
+//! };
+//! let engine = EngineBuilder::new().with_limits(limits);
+//! ```
+
+use anda_core::{ANONYMOUS, AndaError, CacheStoreFeatures, Usage};
+use candid::Principal;
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::{BTreeMap, HashMap},
+    sync::{Arc, RwLock},
+    time::Duration,
+};
+use structured_logger::unix_ms;
+use tokio::sync::Mutex;
+use tokio_util::sync::CancellationToken;
+
+use crate::{context::BaseCtx, management::SYSTEM_PATH};
+
+const DAY_MS: u64 = 24 * 3600 * 1000;
+
+/// How often the counters in memory are saved to the store.
+pub const FLUSH_INTERVAL: Duration = Duration::from_secs(10);
+
+/// Max number of requests of a caller in a fixed time window.
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize, Serialize)]
+pub struct RateLimit {
+    pub max_requests: u64,
+    pub window_secs: u64,
+}
+
+/// Max tokens of a caller per UTC day.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Deserialize, Serialize)]
+pub struct UsageQuota {
+    pub max_input_tokens: Option<u64>,
+    pub max_output_tokens: Option<u64>,
+}
+
+/// Limits applied to every caller.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Deserialize, Serialize)]
+pub struct CallerLimits {
+    pub rate_limit: Option<RateLimit>,
+    pub daily_quota: Option<UsageQuota>,
+}
+
+impl CallerLimits {
+    fn is_empty(&self) -> bool {
+        self.rate_limit.is_none() && self.daily_quota.is_none()
+    }
+}
+
+/// Limits of the engine and its agents.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Deserialize, Serialize)]
+pub struct Limits {
+    /// Limits for all agent runs and tool calls of the engine.
+    pub engine: CallerLimits,
+
+    /// Limits for the agent runs, keyed by the lowercase agent name.
+    pub agents: BTreeMap<String, CallerLimits>,
+
+    /// Limits for all agent runs and tool calls of the anonymous callers, they replace
+    /// the `engine` limits for them. The anonymous callers share one counter.
+    #[serde(default)]
+    pub anonymous: Option<CallerLimits>,
+}
+
+/// Counters of a caller in a scope (the engine or an agent).
+#[derive(Debug, Clone, Default, Deserialize, Serialize)]
+struct Counter {
+    /// The index of the rate limit window.
+    window: u64,
+    /// The number of requests in the window.
+    requests: u64,
+    /// The days since the Unix epoch.
+    day: u64,
+    /// The usage in the day.
+    usage: Usage,
+}
+
+impl Counter {
+    fn roll(&mut self, limits: &CallerLimits, now_ms: u64) {
+        if let Some(rate) = &limits.rate_limit {
+            let window = now_ms / (rate.window_secs.max(1) * 1000);
+            if window != self.window {
+                self.window = window;
+                self.requests = 0;
+            }
+        }
+        let day = now_ms / DAY_MS;
+        if day != self.day {
+            self.day = day;
+            self.usage = Usage::default();
+        }
+    }
+
+    fn check(
+        &self,
+        limits: &CallerLimits,
+        caller: &Principal,
+        scope: &str,
+    ) -> Result<(), AndaError> {
+        if let Some(rate) = &limits.rate_limit {
+            if self.requests >= rate.max_requests {
+                return Err(AndaError::rate_limited(format!(
+                    "caller {} exceeded the rate limit of {}: {} requests per {}s",
+                    caller.to_text(),
+                    scope,
+                    rate.max_requests,
+                    rate.window_secs
+                )));
+            }
+        }
+        if let Some(quota) = &limits.daily_quota {
+            if let Some(max) = quota.max_input_tokens {
+                if self.usage.input_tokens >= max {
+                    return Err(AndaError::quota_exceeded(format!(
+                        "caller {} exceeded the daily quota of {}: {} input tokens",
+                        caller.to_text(),
+                        scope,
+                        max
+                    )));
+                }
+            }
+            if let Some(max) = quota.max_output_tokens {
+                if self.usage.output_tokens >= max {
+                    return Err(AndaError::quota_exceeded(format!(
+                        "caller {} exceeded the daily quota of {}: {} output tokens",
+                        caller.to_text(),
+                        scope,
+                        max
+                    )));
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+/// A counter loaded from the store, with its persistence state.
+#[derive(Default)]
+struct Slot {
+    counter: Option<Counter>,
+    dirty: bool,
+}
+
+/// Enforces the [`Limits`] for callers.
+pub struct QuotaLimiter {
+    ctx: BaseCtx,
+    limits: Limits,
+    // The counters in memory, each one is locked on its own so that callers don't wait for each other.
+    slots: RwLock<HashMap<String, Arc<Mutex<Slot>>>>,
+}
+
+impl QuotaLimiter {
+    pub(crate) fn new(ctx: &BaseCtx, limits: Limits) -> Self {
+        Self {
+            ctx: ctx
+                .child(SYSTEM_PATH.to_string())
+                .expect("failed to create system context"),
+            limits,
+            slots: RwLock::new(HashMap::new()),
+        }
+    }
+
+    /// Returns the limits.
+    pub fn limits(&self) -> &Limits {
+        &self.limits
+    }
+
+    /// Checks the limits of the engine and the agent (if any) for the caller,
+    /// and counts the request if it is allowed.
+    /// Returns [`ErrorCode::RateLimited`](anda_core::ErrorCode::RateLimited) or
+    /// [`ErrorCode::QuotaExceeded`](anda_core::ErrorCode::QuotaExceeded) if not.
+    pub async fn acquire(&self, caller: &Principal, agent: Option<&str>) -> Result<(), AndaError> {
+        let scopes = self.scopes(caller, agent);
+        if scopes.is_empty() {
+            return Ok(());
+        }
+
+        // the scopes are always locked in the same order: the engine (or anonymous), then the agent
+        let now_ms = unix_ms();
+        let mut guards = Vec::with_capacity(scopes.len());
+        for (scope, limits) in &scopes {
+            let key = Self::counter_path(caller, scope);
+            let slot = self.slot(&key);
+            let mut guard = slot.lock_owned().await;
+            let counter = self.load(&key, &mut guard).await;
+            counter.roll(limits, now_ms);
+            counter.check(limits, caller, scope)?;
+            guards.push(guard);
+        }
+
+        for mut guard in guards {
+            if let Some(counter) = guard.counter.as_mut() {
+                counter.requests += 1;
+            }
+            guard.dirty = true;
+        }
+        Ok(())
+    }
+
+    /// Adds the usage to the daily counters of the engine and the agent (if any) for the caller.
+    pub async fn record_usage(&self, caller: &Principal, agent: Option<&str>, usage: &Usage) {
+        let scopes: Vec<_> = self
+            .scopes(caller, agent)
+            .into_iter()
+            .filter(|(_, limits)| limits.daily_quota.is_some())
+            .collect();
+        if scopes.is_empty() {
+            return;
+        }
+
+        let now_ms = unix_ms();
+        for (scope, limits) in scopes {
+            let key = Self::counter_path(caller, &scope);
+            let slot = self.slot(&key);
+            let mut guard = slot.lock().await;
+            let counter = self.load(&key, &mut guard).await;
+            counter.roll(limits, now_ms);
+            counter.usage.accumulate(usage);
+            guard.dirty = true;
+        }
+    }
+
+    /// Saves the changed counters to the store, and drops the counters of the past days
+    /// that have been saved.
+    pub async fn flush(&self) {
+        let slots: Vec<_> = self
+            .slots
+            .read()
+            .expect("RwLock poisoned")
+            .iter()
+            .map(|(key, slot)| (key.clone(), slot.clone()))
+            .collect();
+        let today = unix_ms() / DAY_MS;
+        for (key, slot) in slots {
+            let mut guard = slot.lock().await;
+            if guard.dirty {
+                if let Some(counter) = &guard.counter {
+                    match self
+                        .ctx
+                        .cache_store_set_and_wait(&key, counter.clone())
+                        .await
+                    {
+                        Ok(_) => guard.dirty = false,
+                        Err(err) => log::warn!("failed to save quota counter {}: {}", key, err),
+                    }
+                }
+            }
+            let stale = !guard.dirty && guard.counter.as_ref().is_none_or(|c| c.day < today);
+            drop(guard);
+            if stale {
+                let mut slots = self.slots.write().expect("RwLock poisoned");
+                // a counter in use by a request is kept
+                if slots.get(&key).is_some_and(|s| Arc::strong_count(s) == 2) {
+                    slots.remove(&key);
+                }
+            }
+        }
+    }
+
+    /// Saves the counters to the store every [`FLUSH_INTERVAL`] until the token is cancelled,
+    /// then saves them one last time.
+    pub(crate) fn spawn_flush(self: &Arc<Self>, cancellation_token: CancellationToken) {
+        let limiter = self.clone();
+        tokio::spawn(async move {
+            let mut interval = tokio::time::interval(FLUSH_INTERVAL);
+            interval.tick().await;
+            loop {
+                tokio::select! {
+                    _ = interval.tick() => limiter.flush().await,
+                    _ = cancellation_token.cancelled() => {
+                        limiter.flush().await;
+                        return;
+                    }
+                }
+            }
+        });
+    }
+
+    fn slot(&self, key: &str) -> Arc<Mutex<Slot>> {
+        if let Some(slot) = self.slots.read().expect("RwLock poisoned").get(key) {
+            return slot.clone();
+        }
+        self.slots
+            .write()
+            .expect("RwLock poisoned")
+            .entry(key.to_string())
+            .or_default()
+            .clone()
+    }
+
+    async fn load<'a>(&self, key: &str, slot: &'a mut Slot) -> &'a mut Counter {
+        if slot.counter.is_none() {
+            let counter = self
+                .ctx
+                .cache_store_get::<Counter>(key)
+                .await
+                .unwrap_or_default();
+            slot.counter = Some(counter);
+        }
+        slot.counter.as_mut().unwrap()
+    }
+
+    fn scopes(&self, caller: &Principal, agent: Option<&str>) -> Vec<(String, &CallerLimits)> {
+        let mut scopes = Vec::new();
+        match &self.limits.anonymous {
+            Some(limits) if caller == &ANONYMOUS => {
+                if !limits.is_empty() {
+                    scopes.push(("anonymous".to_string(), limits));
+                }
+            }
+            _ => {
+                if !self.limits.engine.is_empty() {
+                    scopes.push(("engine".to_string(), &self.limits.engine));
+                }
+            }
+        }
+        if let Some(agent) = agent {
+            if let Some(limits) = self.limits.agents.get(agent) {
+                if !limits.is_empty() {
+                    scopes.push((format!("agent {}", agent), limits));
+                }
+            }
+        }
+        scopes
+    }
+
+    fn counter_path(caller: &Principal, scope: &str) -> String {
+        format!("QT_{}_{}.cbor", scope.replace(' ', "_"), caller.to_text())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::engine::EngineBuilder;
+    use anda_core::ErrorCode;
+
+    #[tokio::test(flavor = "current_thread")]
+    async fn test_quota_limiter() {
+        let ctx = EngineBuilder::new().mock_ctx();
+        let caller = Principal::from_slice(&[1]);
+        let other = Principal::from_slice(&[2]);
+        let limits = Limits {
+            engine: CallerLimits {
+                rate_limit: Some(RateLimit {
+                    max_requests: 3,
+                    window_secs: 3600,
+                }),
+                daily_quota: None,
+            },
+            agents: BTreeMap::from([(
+                "assistant".to_string(),
+                CallerLimits {
+                    rate_limit: None,
+                    daily_quota: Some(UsageQuota {
+                        max_input_tokens: Some(100),
+                        max_output_tokens: None,
+                    }),
+                },
+            )]),
+            anonymous: Some(CallerLimits {
+                rate_limit: Some(RateLimit {
+                    max_requests: 1,
+                    window_secs: 3600,
+                }),
+                daily_quota: None,
+            }),
+        };
+        let limiter = QuotaLimiter::new(&ctx.base, limits.clone());
+
+        limiter.acquire(&caller, Some("assistant")).await.unwrap();
+        limiter
+            .record_usage(
+                &caller,
+                Some("assistant"),
+                &Usage {
+                    input_tokens: 100,
+                    output_tokens: 10,
+                    requests: 1,
+                    ..Default::default()
+                },
+            )
+            .await;
+        let err = limiter
+            .acquire(&caller, Some("assistant"))
+            .await
+            .unwrap_err();
+        assert_eq!(err.code, ErrorCode::QuotaExceeded);
+        assert_eq!(err.status(), 429);
+
+        // the quota of other agents and callers is not affected
+        limiter.acquire(&caller, None).await.unwrap();
+        limiter.acquire(&caller, Some("other")).await.unwrap();
+        let err = limiter.acquire(&caller, None).await.unwrap_err();
+        assert_eq!(err.code, ErrorCode::RateLimited);
+        assert!(err.retryable());
+        limiter.acquire(&other, Some("assistant")).await.unwrap();
+
+        // the anonymous callers have their own limits
+        limiter.acquire(&ANONYMOUS, None).await.unwrap();
+        let err = limiter.acquire(&ANONYMOUS, None).await.unwrap_err();
+        assert_eq!(err.code, ErrorCode::RateLimited);
+        assert!(err.message.contains("anonymous"));
+
+        // the counters are saved to the store
+        limiter.flush().await;
+        let limiter = QuotaLimiter::new(&ctx.base, limits);
+        let err = limiter.acquire(&caller, None).await.unwrap_err();
+        assert_eq!(err.code, ErrorCode::RateLimited);
+    }
+}
@@ -1,5 +1,11 @@
-use anda_core::{AgentInput, ToolInput, Value};
-use anda_engine::engine::{Engine, Information, InformationJSON};
+use anda_core::{
+    AgentInput, AndaError, ApprovalInput, RPC_VERSION, RPC_VERSION_HEADER, RPCResponse, ToolInput,
+    Value,
+};
+use anda_engine::{
+    engine::{Engine, Information, InformationJSON},
+    ledger::UsageQuery,
+};
 use axum::{
     extract::{Path, State},
     http::StatusCode,
@@ -10,7 +16,7 @@ use ciborium::from_reader;
 use ic_auth_verifier::envelope::{ANONYMOUS_PRINCIPAL, SignedEnvelope, unix_ms};
 use ic_cose_types::to_cbor_bytes;
 use ic_tee_agent::{
-    RPCRequest, RPCResponse,
+    RPCRequest,
     http::{Content, ContentWithSHA3},
 };
 use std::collections::BTreeMap;
@@ -23,6 +29,7 @@ pub struct AppState {
     pub(crate) engines: Arc<BTreeMap<Principal, Engine>>,
     pub(crate) default_engine: Principal,
     pub(crate) start_time_ms: u64,
+    pub(crate) redact_reasoning: bool,
 }
 
 /// GET /.well-known/information
@@ -139,7 +146,25 @@ pub async fn anda_engine(
         "anda_engine",
     );
     let res = engine_run(&req, &app, caller, id).await;
-    Content::CBOR(res, None).into_response()
+    let version = headers
+        .get(RPC_VERSION_HEADER)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.parse::<u32>().ok())
+        .unwrap_or(1);
+    match res {
+        // the error is also encoded in the body, the status follows its code
+        Err(err) if version >= RPC_VERSION => {
+            let status =
+                StatusCode::from_u16(err.status()).unwrap_or(StatusCode::INTERNAL_SERVER_ERROR);
+            (status, Content::CBOR(RPCResponse::Err(err), None)).into_response()
+        }
+        // older clients decode the error as a plain string
+        Err(err) => {
+            let res: Result<(), String> = Err(err.to_plain());
+            Content::CBOR(res, None).into_response()
+        }
+        res => Content::CBOR(res, None).into_response(),
+    }
 }
 
 async fn engine_run(
@@ -151,34 +176,98 @@ async fn engine_run(
     let engine = app
         .engines
         .get(&id)
-        .ok_or_else(|| format!("engine {} not found", id.to_text()))?;
+        .ok_or_else(|| AndaError::not_found(format!("engine {} not found", id.to_text())))?;
 
     match req.method.as_str() {
         "agent_run" => {
-            let args: (AgentInput,) = from_reader(req.params.as_slice())
-                .map_err(|err| format!("failed to decode params: {err:?}"))?;
-            let res = engine
+            let args: (AgentInput,) = from_reader(req.params.as_slice()).map_err(|err| {
+                AndaError::invalid_args(format!("failed to decode params: {err:?}"))
+            })?;
+            let mut res = engine
                 .agent_run(caller, args.0)
                 .await
-                .map_err(|err| format!("failed to run agent: {err:?}"))?;
+                .map_err(AndaError::from)?;
+            if app.redact_reasoning {
+                res.redact_reasoning();
+            }
             Ok(to_cbor_bytes(&res).into())
         }
         "tool_call" => {
-            let args: (ToolInput<Value>,) = from_reader(req.params.as_slice())
-                .map_err(|err| format!("failed to decode params: {err:?}"))?;
+            let args: (ToolInput<Value>,) = from_reader(req.params.as_slice()).map_err(|err| {
+                AndaError::invalid_args(format!("failed to decode params: {err:?}"))
+            })?;
             let res = engine
                 .tool_call(caller, args.0)
                 .await
-                .map_err(|err| format!("failed to call tool: {err:?}"))?;
+                .map_err(AndaError::from)?;
+            Ok(to_cbor_bytes(&res).into())
+        }
+        "approve_action" => {
+            let args: (ApprovalInput,) = from_reader(req.params.as_slice()).map_err(|err| {
+                AndaError::invalid_args(format!("failed to decode params: {err:?}"))
+            })?;
+            let mut res = engine
+                .approve_action(caller, args.0)
+                .await
+                .map_err(AndaError::from)?;
+            if app.redact_reasoning {
+                res.redact_reasoning();
+            }
+            Ok(to_cbor_bytes(&res).into())
+        }
+        "pending_action" => {
+            let args: (String,) = from_reader(req.params.as_slice()).map_err(|err| {
+                AndaError::invalid_args(format!("failed to decode params: {err:?}"))
+            })?;
+            let res = engine
+                .pending_action(&caller, &args.0)
+                .await
+                .map_err(AndaError::from)?;
+            Ok(to_cbor_bytes(&res).into())
+        }
+        "list_managers" => {
+            let res = engine.management().managers(&caller)?;
+            Ok(to_cbor_bytes(&res).into())
+        }
+        "add_managers" => {
+            let args: (Vec<Principal>,) = from_reader(req.params.as_slice()).map_err(|err| {
+                AndaError::invalid_args(format!("failed to decode params: {err:?}"))
+            })?;
+            let res = engine
+                .management()
+                .add_managers(&caller, args.0)
+                .await
+                .map_err(AndaError::from)?;
+            Ok(to_cbor_bytes(&res).into())
+        }
+        "remove_managers" => {
+            let args: (Vec<Principal>,) = from_reader(req.params.as_slice()).map_err(|err| {
+                AndaError::invalid_args(format!("failed to decode params: {err:?}"))
+            })?;
+            let res = engine
+                .management()
+                .remove_managers(&caller, args.0)
+                .await
+                .map_err(AndaError::from)?;
+            Ok(to_cbor_bytes(&res).into())
+        }
+        "query_usage" => {
+            let args: (UsageQuery,) = from_reader(req.params.as_slice()).map_err(|err| {
+                AndaError::invalid_args(format!("failed to decode params: {err:?}"))
+            })?;
+            let res = engine
+                .query_usage(&caller, args.0)
+                .await
+                .map_err(AndaError::from)?;
             Ok(to_cbor_bytes(&res).into())
         }
         "information" => {
             let res = engine.information();
             Ok(to_cbor_bytes(&res).into())
         }
-        method => Err(format!(
+        method => Err(AndaError::not_found(format!(
             "{method} on engine {} not implemented",
             id.to_text()
-        )),
+        ))),
     }
 }
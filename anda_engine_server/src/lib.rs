@@ -21,6 +21,7 @@ pub struct ServerBuilder {
     addr: String,
     engines: BTreeMap<Principal, Engine>,
     default_engine: Option<Principal>,
+    redact_reasoning: bool,
 }
 
 impl Default for ServerBuilder {
@@ -40,6 +41,7 @@ impl ServerBuilder {
             addr: "127.0.0.1:8042".to_string(),
             engines: BTreeMap::new(),
             default_engine: None,
+            redact_reasoning: false,
         }
     }
 
@@ -68,6 +70,13 @@ impl ServerBuilder {
         self
     }
 
+    /// Removes the reasoning content of reasoning models from the agent outputs
+    /// before they are sent to the callers.
+    pub fn with_redact_reasoning(mut self, redact_reasoning: bool) -> Self {
+        self.redact_reasoning = redact_reasoning;
+        self
+    }
+
     pub async fn serve(
         self,
         signal: impl Future<Output = ()> + Send + 'static,
@@ -87,6 +96,7 @@ impl ServerBuilder {
             engines: Arc::new(self.engines),
             default_engine,
             start_time_ms: unix_ms(),
+            redact_reasoning: self.redact_reasoning,
         };
         let app = Router::new()
             .route("/", routing::get(get_information))
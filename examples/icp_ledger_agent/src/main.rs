@@ -6,7 +6,7 @@ use anda_engine::{
     store::{InMemory, Store},
 };
 use anda_engine_server::{ServerBuilder, shutdown_signal};
-use anda_icp::ledger::BalanceOfTool;
+use anda_icp::ledger::{BalanceOfTool, TransferTool};
 use anda_web3_client::client::{Client as Web3Client, load_identity};
 use clap::Parser;
 use std::{collections::BTreeMap, sync::Arc, time::Duration};
@@ -160,6 +160,8 @@ async fn main() -> Result<(), BoxError> {
         .with_store(Store::new(object_store))
         .register_tools(agent.tools()?)?
         .register_agent(agent)?
+        // transfers requested by the model must be approved by the caller or a manager
+        .with_tool_approval(vec![TransferTool::NAME.to_string()])
         .export_tools(vec![BalanceOfTool::NAME.to_string()]);
 
     // Initialize and start the server
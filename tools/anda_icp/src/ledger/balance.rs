@@ -70,6 +70,7 @@ impl Tool<BaseCtx> for BalanceOfTool {
             description: self.description(),
             parameters: self.schema.clone(),
             strict: Some(true),
+            output_schema: Some(gen_schema_for::<Self::Output>()),
         }
     }
 
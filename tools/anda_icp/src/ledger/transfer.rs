@@ -83,6 +83,7 @@ impl Tool<BaseCtx> for TransferTool {
             description: self.description(),
             parameters: self.schema.clone(),
             strict: Some(true),
+            output_schema: Some(gen_schema_for::<Self::Output>()),
         }
     }
 